@@ -1,148 +1,1105 @@
-use crate::pdb::{Atom, Point};
+mod ball_tree;
+pub mod circular;
+mod cluster;
+mod embedding;
+mod fingerprint;
+mod grid;
+mod kmer;
+mod linalg;
+mod loops;
+mod sasa;
+mod shape;
+mod similarity;
+mod superpose;
+
+pub use ball_tree::BallTree;
+pub use cluster::{greedy_clusters, sequence_identity, DEFAULT_CLUSTER_IDENTITY};
+pub use embedding::{fab_embedding, EMBEDDING_DIM};
+pub use fingerprint::{fingerprint_from_bytes, fingerprint_similarity, fingerprint_to_bytes, rama_fingerprint, FINGERPRINT_BINS};
+pub use kmer::{kmer_profile, kmer_similarity, KmerProfile};
+pub use loops::{loop_descriptors, residue_loop_descriptors, KinkedOrExtended, LoopDescriptors};
+pub use sasa::{atom_sasa, sasa, vdw_radius};
+pub use shape::{shape_descriptors, Shape};
+pub use similarity::{ca_rmsd, gdt_ts, lddt, tm_score, LddtScore, LDDT_INCLUSION_RADIUS};
+pub use superpose::{kabsch, kabsch_weighted, qcp_rmsd, Superposition};
+
+use circular::angular_distance;
+use grid::SpatialGrid;
+use crate::pdb::{Pdb, group_residues, is_amino_acid, standard_parent, three_to_one, Atom, Point, Residue, ResidueId};
+use crate::numbering::{ChainType, Position, Region};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::f64::consts::PI;
 
-// Helper to calculate torsion angle between 4 points
-fn torsion_angle(p1: Point, p2: Point, p3: Point, p4: Point) -> f64 {
+/// Cross-product norms (A^2) below this make a torsion undefined.
+const TORSION_EPSILON: f64 = 1e-6;
+
+// Helper to calculate torsion angle between 4 points.
+// None when three consecutive points are (nearly) collinear.
+fn torsion_angle(p1: Point, p2: Point, p3: Point, p4: Point) -> Option<f64> {
     let b1 = p2.sub(&p1);
     let b2 = p3.sub(&p2);
     let b3 = p4.sub(&p3);
 
-    let n1 = b1.cross(&b2).normalize();
-    let n2 = b2.cross(&b3).normalize();
+    let c1 = b1.cross(&b2);
+    let c2 = b2.cross(&b3);
+    if c1.norm() < TORSION_EPSILON || c2.norm() < TORSION_EPSILON {
+        return None;
+    }
+    let n1 = c1.normalize();
+    let n2 = c2.normalize();
     let m1 = n1.cross(&b2.normalize());
 
     let x = n1.dot(&n2);
     let y = m1.dot(&n2);
 
-    -y.atan2(x) // Returns radians [-PI, PI]
-}
-
-pub fn ramachandran(atoms: &[Atom]) -> Vec<(f64, f64)> {
-    // We need to group atoms by residue to find N, CA, C
-    // Then iterate sliding window of residues
-    let mut residues = Vec::new();
-    
-    // Simple grouping by res_seq. 
-    // Assumes atoms are sorted. A robust way is a HashMap or just strict iteration.
-    // Let's assume standard PDB sorting.
-    let mut current_res = Vec::new();
-    let mut last_seq = -999;
-    
-    for atom in atoms {
-        if atom.res_seq != last_seq {
-            if !current_res.is_empty() {
-                residues.push(current_res);
-            }
-            current_res = Vec::new();
-            last_seq = atom.res_seq;
-        }
-        current_res.push(atom.clone());
+    Some(-y.atan2(x)) // Returns radians [-PI, PI]
+}
+
+/// Residue filters applied before computing backbone torsions.
+#[derive(Debug, Clone, Copy)]
+pub struct RamaOptions {
+    /// Skip waters, ligands and other non amino acid residues
+    pub amino_acids_only: bool,
+    /// Keep only the primary conformer of residues with altLocs
+    pub primary_altloc_only: bool,
+}
+
+impl Default for RamaOptions {
+    fn default() -> Self {
+        Self { amino_acids_only: true, primary_altloc_only: true }
     }
-    if !current_res.is_empty() {
-        residues.push(current_res);
+}
+
+impl RamaOptions {
+    /// No filtering at all: every residue group with N/CA/C atoms counts.
+    pub fn raw() -> Self {
+        Self { amino_acids_only: false, primary_altloc_only: false }
     }
+}
+
+/// Backbone torsions of a single residue, in radians.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RamaPoint {
+    pub chain_id: char,
+    pub res_seq: i32,
+    pub i_code: char,
+    pub res_name: String,
+    pub phi: f64,
+    pub psi: f64,
+}
+
+/// Backbone torsions of every residue with a defined phi and psi.
+pub fn ramachandran(atoms: &[Atom]) -> Vec<RamaPoint> {
+    ramachandran_filtered(atoms, RamaOptions::default())
+}
+
+pub fn ramachandran_filtered(atoms: &[Atom], opts: RamaOptions) -> Vec<RamaPoint> {
+    phi_psi(atoms, opts).0.into_iter().map(|(_, p)| p).collect()
+}
+
+/// Bare (phi, psi) pairs, for plotting.
+pub fn ramachandran_angles(atoms: &[Atom]) -> Vec<(f64, f64)> {
+    ramachandran(atoms).into_iter().map(|p| (p.phi, p.psi)).collect()
+}
+
+/// Residues left out of `ramachandran` because phi or psi is undefined
+/// (collinear backbone atoms, typically from corrupted coordinates).
+pub fn degenerate_torsion_count(atoms: &[Atom]) -> usize {
+    phi_psi(atoms, RamaOptions::default()).1
+}
 
+// (phi, psi) per residue together with its reference-map class, plus the
+// number of residues skipped for degenerate geometry
+fn phi_psi(atoms: &[Atom], opts: RamaOptions) -> (Vec<(RamaClass, RamaPoint)>, usize) {
+    // Residues are keyed by (chain_id, res_seq, i_code), so chain boundaries
+    // and insertion codes (100, 100A, 100B) are kept apart.
+    let mut residues = group_residues(atoms);
+    if opts.amino_acids_only {
+        residues.retain(|r| is_amino_acid(r.res_name));
+    }
+    if opts.primary_altloc_only {
+        residues.iter_mut().for_each(|r| r.collapse_altlocs());
+    }
     let mut angles = Vec::new();
+    let mut degenerate = 0;
+
+    for window in residues.windows(3) {
+        let (prev, curr, next) = (&window[0], &window[1], &window[2]);
 
-    for i in 1..residues.len().saturating_sub(1) {
-        let prev = &residues[i-1];
-        let curr = &residues[i];
-        let next = &residues[i+1];
+        // Never compute a torsion across a chain change or a geometric break
+        if !prev.is_bonded_to(curr) || !curr.is_bonded_to(next) {
+            continue;
+        }
 
         // Find necessary atoms: C(prev), N(curr), CA(curr), C(curr), N(next)
-        let c_prev = prev.iter().find(|a| a.name == "C");
-        let n_curr = curr.iter().find(|a| a.name == "N");
-        let ca_curr = curr.iter().find(|a| a.name == "CA");
-        let c_curr = curr.iter().find(|a| a.name == "C");
-        let n_next = next.iter().find(|a| a.name == "N");
+        let c_prev = prev.atom("C");
+        let n_curr = curr.atom("N");
+        let ca_curr = curr.atom("CA");
+        let c_curr = curr.atom("C");
+        let n_next = next.atom("N");
 
         if let (Some(cp), Some(n), Some(ca), Some(c), Some(nn)) = (c_prev, n_curr, ca_curr, c_curr, n_next) {
-            let phi = torsion_angle(cp.pos, n.pos, ca.pos, c.pos);
-            let psi = torsion_angle(n.pos, ca.pos, c.pos, nn.pos);
-            angles.push((phi, psi));
+            let (Some(phi), Some(psi)) = (
+                torsion_angle(cp.pos, n.pos, ca.pos, c.pos),
+                torsion_angle(n.pos, ca.pos, c.pos, nn.pos),
+            ) else {
+                degenerate += 1;
+                continue;
+            };
+            let point = RamaPoint {
+                chain_id: curr.id.chain_id,
+                res_seq: curr.id.res_seq,
+                i_code: curr.id.i_code,
+                res_name: curr.res_name.to_string(),
+                phi,
+                psi,
+            };
+            angles.push((RamaClass::of(curr.res_name, next.res_name), point));
         }
     }
 
-    angles
+    (angles, degenerate)
 }
 
-pub fn ramachandran_score(target: &[(f64, f64)], candidate: &[(f64, f64)]) -> f64 {
-    // Simple metric: Mean Squared Difference of angles
-    // Problem: Angles are periodic. -PI is close to PI.
-    // Distance d = min(|a-b|, 2PI - |a-b|)
-    
-    if target.is_empty() || candidate.is_empty() {
+/// Reference map a residue is judged against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RamaClass {
+    General,
+    Glycine,
+    Proline,
+    PreProline,
+}
+
+impl RamaClass {
+    pub fn of(res_name: &str, next_res_name: &str) -> Self {
+        match (res_name, next_res_name) {
+            ("GLY", _) => RamaClass::Glycine,
+            ("PRO", _) => RamaClass::Proline,
+            (_, "PRO") => RamaClass::PreProline,
+            _ => RamaClass::General,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum RamaRegion {
+    Favored,
+    Allowed,
+    Outlier,
+}
+
+// Coarse 10-degree reference maps: rows are psi from +180 down to -180,
+// columns are phi from -180 to +180. 'F' favored, 'a' allowed, '.' outlier.
+type RamaGrid = [&'static str; 36];
+
+const RAMA_GENERAL: RamaGrid = [
+    "FFFFFFFFFFFFFFa.....................",
+    "FFFFFFFFFFFFFFa.....................",
+    "FFFFFFFFFFFFFFa.....................",
+    "FFFFFFFFFFFFFFa.....................",
+    "FFFFFFFFFFFFFFa.....................",
+    "FFFFFFFFFFFFFFa.....................",
+    "FFFFFFFFFFFFFFa.....................",
+    "FFFFFFFFFFFFFFa.....................",
+    "aaaaaaaaaaaaaaa.......aaaaa.........",
+    "aaaaaaaaaaaaaaa.......aaaaa.........",
+    "aaaaaaaaaaaaaaa.......aaaaa.........",
+    "aaaaaaaaaaaaaaa.......aaaaa.........",
+    "aaaaaaaaaaaaaaa.......aaaaa.........",
+    "aaaaaaaaaaaaaaa.......aaaaa.........",
+    "aaaaaaaaaaaaaaa.......aaaaa.........",
+    "aaaaaaaaaaaaaaa.......aaaaa.........",
+    "aaaaaaaaaaaaaaa.......aaaaa.........",
+    "aaaaaaaaaaaaaaa.......aaaaa.........",
+    "aaaaaaaaaaaaaaa.....................",
+    "aaFFFFFFFFFFFFa.....................",
+    "aaFFFFFFFFFFFFa.....................",
+    "aaFFFFFFFFFFFFa.....................",
+    "aaFFFFFFFFFFFFa.....................",
+    "aaFFFFFFFFFFFFa.....................",
+    "aaFFFFFFFFFFFFa.....................",
+    "aaaaaaaaaaaaaaa.....................",
+    "aaaaaaaaaaaaaaa.....................",
+    "aaaaaaaaaaaaaaa.....................",
+    "aaaaaaaaaaaaaaa.....................",
+    "....................................",
+    "....................................",
+    "....................................",
+    "....................................",
+    "aaaaaaaaaaaaaaa.....................",
+    "aaaaaaaaaaaaaaa.....................",
+    "FFFFFFFFFFFFFFa.....................",
+];
+
+const RAMA_GLYCINE: RamaGrid = [
+    "FFFFFFFFFFFFFF........aaaaaaaaaaaFFF",
+    "FFFFFFFFFFFFFF........aaaaaaaaaaaFFF",
+    "FFFFFFFFFFFFFF........aaaaaaaaaaaFFF",
+    "FFFFFFFFFFFFFF........aaaaaaaaaaaaaa",
+    "FFFFFFFFFFFFFF........aaaaaaaaaaaaaa",
+    "FFFFFFFFFFFFFF........aaaaaaaaaaaaaa",
+    "FFFFFFFFFFFFFF........aaaaaaaaaaaaaa",
+    "FFFFFFFFFFFFFF........aaaaaaaaaaaaaa",
+    "aaaaaaaaaaaaaa........aaaaaaaaaaaaaa",
+    "aaaaaaaaaaaaaa........aaaaaaaaaaaaaa",
+    "aaaaaaaaaaaaaa........aaaaaaaaaaaaaa",
+    "aaaaaaaaaaaaaa........aaaaaaaaaaaaaa",
+    "aaaaaaaaaaaaaa........FFFFFFFaaaaaaa",
+    "aaaaaaaaaaaaaa........FFFFFFFaaaaaaa",
+    "aaaaaaaaaaaaaa........FFFFFFFaaaaaaa",
+    "aaaaaaaaaaaaaa........FFFFFFFaaaaaaa",
+    "aaaaaaaaaaaaaa........FFFFFFFaaaaaaa",
+    "aaaaaaaaaaaaaa........FFFFFFFaaaaaaa",
+    "aaaaaaaFFFFFFF........aaaaaaaaaaaaaa",
+    "aaaaaaaFFFFFFF........aaaaaaaaaaaaaa",
+    "aaaaaaaFFFFFFF........aaaaaaaaaaaaaa",
+    "aaaaaaaFFFFFFF........aaaaaaaaaaaaaa",
+    "aaaaaaaFFFFFFF........aaaaaaaaaaaaaa",
+    "aaaaaaaFFFFFFF........aaaaaaaaaaaaaa",
+    "aaaaaaaaaaaaaa........aaaaaaaaaaaaaa",
+    "aaaaaaaaaaaaaa........aaaaaaaaaaaaaa",
+    "aaaaaaaaaaaaaa........aaaaaaaaaaaaaa",
+    "aaaaaaaaaaaaaa........aaaaaaaaaaaaaa",
+    "aaaaaaaaaaaaaa........FFFFFFFFFFFFFF",
+    "aaaaaaaaaaaaaa........FFFFFFFFFFFFFF",
+    "aaaaaaaaaaaaaa........FFFFFFFFFFFFFF",
+    "aaaaaaaaaaaaaa........FFFFFFFFFFFFFF",
+    "aaaaaaaaaaaaaa........FFFFFFFFFFFFFF",
+    "FFFaaaaaaaaaaa........FFFFFFFFFFFFFF",
+    "FFFaaaaaaaaaaa........FFFFFFFFFFFFFF",
+    "FFFaaaaaaaaaaa........FFFFFFFFFFFFFF",
+];
+
+const RAMA_PROLINE: RamaGrid = [
+    ".......aFFFFFFa.....................",
+    ".......aFFFFFFa.....................",
+    ".......aFFFFFFa.....................",
+    ".......aFFFFFFa.....................",
+    ".......aFFFFFFa.....................",
+    ".......aFFFFFFa.....................",
+    ".......aFFFFFFa.....................",
+    ".......aaaaaaaa.....................",
+    ".......aaaaaaaa.....................",
+    ".......aaaaaaaa.....................",
+    ".......aaaaaaaa.....................",
+    ".......aaaaaaaa.....................",
+    ".......aaaaaaaa.....................",
+    "....................................",
+    "....................................",
+    "....................................",
+    ".......aaaaaaaa.....................",
+    ".......aaaaaaaa.....................",
+    ".......aaaaaaaa.....................",
+    ".......aFFFFFFa.....................",
+    ".......aFFFFFFa.....................",
+    ".......aFFFFFFa.....................",
+    ".......aFFFFFFa.....................",
+    ".......aFFFFFFa.....................",
+    ".......aaaaaaaa.....................",
+    ".......aaaaaaaa.....................",
+    "....................................",
+    "....................................",
+    "....................................",
+    "....................................",
+    "....................................",
+    "....................................",
+    "....................................",
+    "....................................",
+    "....................................",
+    ".......aaaaaaaa.....................",
+];
+
+const RAMA_PRE_PROLINE: RamaGrid = [
+    "FFFFFFFFFFFFFFa.....................",
+    "FFFFFFFFFFFFFFa.....................",
+    "FFFFFFFFFFFFFFa.....................",
+    "FFFFFFFFFFFFFFa.....................",
+    "FFFFFFFFFFFFFFa.....................",
+    "FFFFFFFFFFFFFFa.....................",
+    "FFFFFFFFFFFFFFa.....................",
+    "FFFFFFFFFFFFFFa.....................",
+    "aaaaaaaaaaaaaaa.....................",
+    "aaaaaaaaaaaaaaa.....................",
+    "aaaaaaaaaaaaaaa.....................",
+    "aaaaaaaaaaaaaaa.....................",
+    "aaaaaaaaaaaaaaa.....................",
+    "....................................",
+    "....................................",
+    "....................................",
+    "....................................",
+    "....................................",
+    "aaaaaaaaaaaaaaa.....................",
+    "aaaaaaaaaaaaaaa.....................",
+    "aaaaaaaaFFFFFFa.....................",
+    "aaaaaaaaFFFFFFa.....................",
+    "aaaaaaaaFFFFFFa.....................",
+    "aaaaaaaaFFFFFFa.....................",
+    "aaaaaaaaaaaaaaa.....................",
+    "aaaaaaaaaaaaaaa.....................",
+    "....................................",
+    "....................................",
+    "....................................",
+    "....................................",
+    "....................................",
+    "....................................",
+    "....................................",
+    "....................................",
+    "aaaaaaaaaaaaaaa.....................",
+    "aaaaaaaaaaaaaaa.....................",
+];
+
+pub fn classify_rama(phi: f64, psi: f64, class: RamaClass) -> RamaRegion {
+    let grid = match class {
+        RamaClass::General => &RAMA_GENERAL,
+        RamaClass::Glycine => &RAMA_GLYCINE,
+        RamaClass::Proline => &RAMA_PROLINE,
+        RamaClass::PreProline => &RAMA_PRE_PROLINE,
+    };
+    let col = (((phi.to_degrees() + 180.0) / 10.0).floor() as usize).min(35);
+    let row = (((180.0 - psi.to_degrees()) / 10.0).floor() as usize).min(35);
+    match grid[row].as_bytes()[col] {
+        b'F' => RamaRegion::Favored,
+        b'a' => RamaRegion::Allowed,
+        _ => RamaRegion::Outlier,
+    }
+}
+
+/// Fraction of residues with backbone torsions in the outlier region.
+pub fn rama_outlier_fraction(atoms: &[Atom]) -> f64 {
+    let (angles, _) = phi_psi(atoms, RamaOptions::default());
+    if angles.is_empty() {
         return 0.0;
     }
+    let outliers = angles.iter()
+        .filter(|(class, p)| classify_rama(p.phi, p.psi, *class) == RamaRegion::Outlier)
+        .count();
+    outliers as f64 / angles.len() as f64
+}
 
-    let len = target.len().min(candidate.len());
-    let mut sum_sq = 0.0;
+/// Peptide bond torsion preceding residue (chain, res_seq).
+#[derive(Debug, Clone, Serialize)]
+pub struct OmegaRecord {
+    pub chain: char,
+    pub res_seq: i32,
+    pub res_name: String,
+    pub omega: f64,
+    pub is_cis: bool,
+}
+
+/// |omega| below this is a cis peptide bond (30 degrees)
+pub const CIS_OMEGA_MAX: f64 = PI / 6.0;
+
+pub fn omega_angles(atoms: &[Atom]) -> Vec<OmegaRecord> {
+    let mut residues = group_residues(atoms);
+    residues.retain(|r| is_amino_acid(r.res_name));
+    residues.iter_mut().for_each(|r| r.collapse_altlocs());
+
+    let mut omegas = Vec::new();
+    for pair in residues.windows(2) {
+        let (curr, next) = (&pair[0], &pair[1]);
+        if !curr.is_bonded_to(next) {
+            continue;
+        }
+        // CA(i) - C(i) - N(i+1) - CA(i+1)
+        if let (Some(ca), Some(c), Some(n), Some(ca_next)) = (curr.atom("CA"), curr.atom("C"), next.atom("N"), next.atom("CA")) {
+            let Some(omega) = torsion_angle(ca.pos, c.pos, n.pos, ca_next.pos) else { continue };
+            omegas.push(OmegaRecord {
+                chain: next.id.chain_id,
+                res_seq: next.id.res_seq,
+                res_name: next.res_name.to_string(),
+                omega,
+                is_cis: omega.abs() < CIS_OMEGA_MAX,
+            });
+        }
+    }
+    omegas
+}
+
+/// Side-chain gamma atom defining chi1 (N-CA-CB-XG), None for GLY/ALA.
+fn chi1_gamma_atom(res_name: &str) -> Option<&'static str> {
+    match standard_parent(res_name).unwrap_or(res_name) {
+        "GLY" | "ALA" => None,
+        "ILE" | "VAL" => Some("CG1"),
+        "THR" => Some("OG1"),
+        "CYS" => Some("SG"),
+        "SER" => Some("OG"),
+        "MET" | "LEU" | "PHE" | "TYR" | "TRP" | "HIS" | "ASP" | "ASN"
+        | "GLU" | "GLN" | "LYS" | "ARG" | "PRO" => Some("CG"),
+        _ => None,
+    }
+}
+
+/// Chi1 per residue in radians; None for GLY/ALA, unknown residues or missing atoms.
+pub fn chi1_angles(residues: &[Residue]) -> Vec<(ResidueId, Option<f64>)> {
+    residues.iter().map(|res| {
+        let chi1 = chi1_gamma_atom(res.res_name).and_then(|gamma| {
+            let n = res.atom("N")?;
+            let ca = res.atom("CA")?;
+            let cb = res.atom("CB")?;
+            let g = res.atom(gamma)?;
+            torsion_angle(n.pos, ca.pos, cb.pos, g.pos)
+        });
+        (res.id, chi1)
+    }).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Rotamer {
+    /// Around +60 degrees
+    GauchePlus,
+    /// Around -60 degrees
+    GaucheMinus,
+    /// Around 180 degrees
+    Trans,
+}
+
+pub fn classify_rotamer(chi: f64) -> Rotamer {
+    let deg = chi.to_degrees();
+    if (0.0..120.0).contains(&deg) {
+        Rotamer::GauchePlus
+    } else if (-120.0..0.0).contains(&deg) {
+        Rotamer::GaucheMinus
+    } else {
+        Rotamer::Trans
+    }
+}
+
+/// Result of comparing two annotated Ramachandran profiles.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct RamaComparison {
+    /// Similarity in [0, 1]
+    pub score: f64,
+    /// Number of aligned residue pairs that were compared
+    pub compared: usize,
+    /// Compared pairs as a fraction of the target residues
+    pub coverage: f64,
+}
 
-    for i in 0..len {
-        let (phi1, psi1) = target[i];
-        let (phi2, psi2) = candidate[i];
+/// `band_width` bands the sequence alignment (see `align_banded`); None runs
+/// the full DP.
+pub fn ramachandran_score(target: &[RamaPoint], candidate: &[RamaPoint], band_width: Option<usize>) -> RamaComparison {
+    // Mean Squared Difference of angles over sequence-aligned residues, so a
+    // missing terminal residue does not shift every comparison by one.
 
-        let d_phi = (phi1 - phi2).abs();
-        let d_phi = if d_phi > PI { 2.0 * PI - d_phi } else { d_phi };
+    if target.is_empty() || candidate.is_empty() {
+        return RamaComparison::default();
+    }
+
+    let s1: Vec<char> = target.iter().map(|p| three_to_one(&p.res_name)).collect();
+    let s2: Vec<char> = candidate.iter().map(|p| three_to_one(&p.res_name)).collect();
+    let alignment = align_for(&s1, &s2, band_width);
 
-        let d_psi = (psi1 - psi2).abs();
-        let d_psi = if d_psi > PI { 2.0 * PI - d_psi } else { d_psi };
+    let mut sum_sq = 0.0;
+    let mut compared = 0;
+    for (i, j) in alignment.aligned_pairs() {
+        let (t, c) = (&target[i], &candidate[j]);
 
+        let d_phi = angular_distance(t.phi, c.phi);
+        let d_psi = angular_distance(t.psi, c.psi);
         sum_sq += d_phi.powi(2) + d_psi.powi(2);
+        compared += 1;
+    }
+
+    if compared == 0 {
+        return RamaComparison::default();
     }
 
     // Convert to similarity score [0, 1]
-    let mse = sum_sq / len as f64;
-    1.0 / (1.0 + mse)
+    let mse = sum_sq / compared as f64;
+    RamaComparison {
+        score: 1.0 / (1.0 + mse),
+        compared,
+        coverage: compared as f64 / target.len() as f64,
+    }
+}
+
+/// Global alignment with the column-by-column path.
+#[derive(Debug, Clone)]
+pub struct Alignment {
+    pub score: f64,
+    /// (index in s1, index in s2); None marks a gap
+    pub columns: Vec<(Option<usize>, Option<usize>)>,
+}
+
+impl Alignment {
+    /// Index pairs of non-gap columns.
+    pub fn aligned_pairs(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.columns.iter().filter_map(|&(i, j)| Some((i?, j?)))
+    }
+}
+
+const GAP_OPEN: f64 = -11.0;
+const GAP_EXTEND: f64 = -1.0;
+
+fn match_score(c1: char, c2: char) -> f64 {
+    if c1 == c2 { 4.0 } else { -1.0 }
 }
 
-#[allow(dead_code)]
 pub fn align(s1: &[char], s2: &[char]) -> f64 {
-    let gap_open = -11.0;
-    let gap_extend = -1.0;
-    
-    let match_score = |c1: char, c2: char| -> f64 {
-        if c1 == c2 { 4.0 } else { -1.0 }
-    };
+    align_with_traceback(s1, s2).score
+}
 
+/// Global alignment with affine gaps (Gotoh): a gap of length k costs
+/// GAP_OPEN + (k - 1) * GAP_EXTEND, terminal gaps included.
+pub fn align_with_traceback(s1: &[char], s2: &[char]) -> Alignment {
+    gotoh(s1, s2, None, match_score).0
+}
+
+/// `align_with_traceback` restricted to cells within `band_width` of the
+/// diagonal. Falls back to the full alignment when the lengths differ by more
+/// than the band or the best banded path touches the band edge, where a better
+/// path outside the band could exist.
+pub fn align_banded(s1: &[char], s2: &[char], band_width: usize) -> Alignment {
+    if s1.len().abs_diff(s2.len()) >= band_width {
+        return align_with_traceback(s1, s2);
+    }
+    match gotoh(s1, s2, Some(band_width), match_score) {
+        (alignment, false) => alignment,
+        (_, true) => align_with_traceback(s1, s2),
+    }
+}
+
+/// Conformational state of a residue: H helix, E sheet, P polyproline II,
+/// L left-handed, O other.
+pub fn rama_state(phi: f64, psi: f64) -> char {
+    let (phi, psi) = (phi.to_degrees(), psi.to_degrees());
+    if phi > 0.0 {
+        'L'
+    } else if phi > -160.0 && phi < -20.0 && psi > -120.0 && psi < 50.0 {
+        'H'
+    } else if phi > -110.0 && phi < -50.0 && psi > 100.0 {
+        'P'
+    } else if phi < -45.0 && !(-150.0..=90.0).contains(&psi) {
+        'E'
+    } else {
+        'O'
+    }
+}
+
+/// One state letter per residue, see `rama_state`.
+pub fn rama_string(angles: &[(f64, f64)]) -> String {
+    angles.iter().map(|&(phi, psi)| rama_state(phi, psi)).collect()
+}
+
+// Substitution scores between conformational states: the two extended
+// states (E, P) are close, left-handed residues are rare and distinctive
+fn rama_state_score(a: char, b: char) -> f64 {
+    match (a, b) {
+        _ if a == b && a == 'O' => 1.0,
+        _ if a == b => 4.0,
+        ('E', 'P') | ('P', 'E') => 2.0,
+        ('H', 'O') | ('O', 'H') | ('E', 'O') | ('O', 'E') | ('P', 'O') | ('O', 'P') => 0.0,
+        ('L', _) | (_, 'L') => -2.0,
+        _ => -1.0,
+    }
+}
+
+/// Global alignment score of two state strings divided by the mean of their
+/// self-alignment scores: 1.0 for identical strings, 0.0 floor.
+pub fn rama_string_score(a: &str, b: &str) -> f64 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let self_score = |s: &[char]| s.iter().map(|&c| rama_state_score(c, c)).sum::<f64>();
+    let norm = (self_score(&a) + self_score(&b)) / 2.0;
+    if norm <= 0.0 {
+        return 0.0;
+    }
+    let (alignment, _) = gotoh(&a, &b, None, rama_state_score);
+    (alignment.score / norm).clamp(0.0, 1.0)
+}
+
+/// Per-region weights for `align_weighted`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionWeights {
+    pub fr1: f64,
+    pub cdr1: f64,
+    pub fr2: f64,
+    pub cdr2: f64,
+    pub fr3: f64,
+    pub cdr3: f64,
+    pub fr4: f64,
+}
+
+impl Default for RegionWeights {
+    fn default() -> Self {
+        Self::new(1.0, 1.0)
+    }
+}
+
+impl RegionWeights {
+    /// One weight for all framework regions and one for all CDRs.
+    pub fn new(framework: f64, cdr: f64) -> Self {
+        Self { fr1: framework, cdr1: cdr, fr2: framework, cdr2: cdr, fr3: framework, cdr3: cdr, fr4: framework }
+    }
+
+    pub fn weight(&self, region: Region) -> f64 {
+        match region {
+            Region::Fr1 => self.fr1,
+            Region::Cdr1 => self.cdr1,
+            Region::Fr2 => self.fr2,
+            Region::Cdr2 => self.cdr2,
+            Region::Fr3 => self.fr3,
+            Region::Cdr3 => self.cdr3,
+            Region::Fr4 => self.fr4,
+        }
+    }
+}
+
+/// Region-weighted identity of two Martin-numbered chains in [0, 1]. Residues
+/// are paired by shared position, so no DP is needed; a position present in
+/// only one chain counts as a mismatch. 0.0 when all weights are zero.
+pub fn align_weighted(s1_numbered: &[(Position, char)], s2_numbered: &[(Position, char)], chain: ChainType, weights: &RegionWeights) -> f64 {
+    let mut columns: BTreeMap<Position, (Option<char>, Option<char>)> = BTreeMap::new();
+    for &(pos, aa) in s1_numbered {
+        columns.entry(pos).or_default().0 = Some(aa);
+    }
+    for &(pos, aa) in s2_numbered {
+        columns.entry(pos).or_default().1 = Some(aa);
+    }
+
+    let mut matched = 0.0;
+    let mut total = 0.0;
+    for (pos, (a, b)) in columns {
+        let w = weights.weight(Region::martin(pos, chain));
+        total += w;
+        if a.is_some() && a == b {
+            matched += w;
+        }
+    }
+    if total > 0.0 { matched / total } else { 0.0 }
+}
+
+// Full alignment for None, banded otherwise
+fn align_for(s1: &[char], s2: &[char], band_width: Option<usize>) -> Alignment {
+    match band_width {
+        Some(w) => align_banded(s1, s2, w),
+        None => align_with_traceback(s1, s2),
+    }
+}
+
+// Gotoh DP with substitution scores from `subst`, optionally limited to
+// |i - j| <= band. Also reports whether the traceback ran along the band edge.
+fn gotoh(s1: &[char], s2: &[char], band: Option<usize>, subst: impl Fn(char, char) -> f64) -> (Alignment, bool) {
     let n = s1.len();
     let m = s2.len();
-    let mut dp = vec![vec![0.0; m + 1]; n + 1];
-    
+    let w = m + 1;
+    let idx = |i: usize, j: usize| i * w + j;
+
+    // mat: ends in a match/mismatch, del: s1 residue against a gap, ins: gap against s2 residue
+    let mut mat = vec![f64::NEG_INFINITY; (n + 1) * w];
+    let mut del = vec![f64::NEG_INFINITY; (n + 1) * w];
+    let mut ins = vec![f64::NEG_INFINITY; (n + 1) * w];
+
     // Init
-    for (i, row) in dp.iter_mut().enumerate().take(n + 1).skip(1) {
-        row[0] = gap_open + (i as f64 - 1.0) * gap_extend;
+    mat[0] = 0.0;
+    for i in 1..=n {
+        del[idx(i, 0)] = GAP_OPEN + (i as f64 - 1.0) * GAP_EXTEND;
     }
-    for (j, val) in dp[0].iter_mut().enumerate().take(m + 1).skip(1) {
-        *val = gap_open + (j as f64 - 1.0) * gap_extend;
+    for j in 1..=m {
+        ins[idx(0, j)] = GAP_OPEN + (j as f64 - 1.0) * GAP_EXTEND;
     }
 
     for i in 1..=n {
-        for j in 1..=m {
-            let match_val = dp[i-1][j-1] + match_score(s1[i-1], s2[j-1]);
-            let delete = dp[i-1][j] + gap_extend; 
-            let insert = dp[i][j-1] + gap_extend;
-            dp[i][j] = match_val.max(delete).max(insert);
+        let (lo, hi) = match band {
+            Some(w) => (i.saturating_sub(w).max(1), (i + w).min(m)),
+            None => (1, m),
+        };
+        for j in lo..=hi {
+            let d = idx(i - 1, j - 1);
+            mat[idx(i, j)] = mat[d].max(del[d]).max(ins[d]) + subst(s1[i-1], s2[j-1]);
+            let u = idx(i - 1, j);
+            del[idx(i, j)] = (mat[u] + GAP_OPEN).max(del[u] + GAP_EXTEND).max(ins[u] + GAP_OPEN);
+            let l = idx(i, j - 1);
+            ins[idx(i, j)] = (mat[l] + GAP_OPEN).max(ins[l] + GAP_EXTEND).max(del[l] + GAP_OPEN);
+        }
+    }
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum State { Mat, Del, Ins }
+    let best = |k: usize| -> (f64, State) {
+        let mut best = (mat[k], State::Mat);
+        if del[k] > best.0 { best = (del[k], State::Del); }
+        if ins[k] > best.0 { best = (ins[k], State::Ins); }
+        best
+    };
+
+    // Traceback from the bottom-right corner
+    let (score, mut state) = best(idx(n, m));
+    let mut columns = Vec::with_capacity(n.max(m));
+    let (mut i, mut j) = (n, m);
+    let mut on_edge = false;
+    while i > 0 || j > 0 {
+        on_edge |= band.is_some_and(|w| i.abs_diff(j) >= w);
+        let k = idx(i, j);
+        match state {
+            State::Mat => {
+                columns.push((Some(i - 1), Some(j - 1)));
+                let prev = mat[k] - subst(s1[i-1], s2[j-1]);
+                i -= 1;
+                j -= 1;
+                let p = idx(i, j);
+                state = if mat[p] == prev { State::Mat } else if del[p] == prev { State::Del } else { State::Ins };
+            }
+            State::Del => {
+                columns.push((Some(i - 1), None));
+                let u = idx(i - 1, j);
+                state = if del[k] == del[u] + GAP_EXTEND { State::Del }
+                    else if del[k] == mat[u] + GAP_OPEN { State::Mat } else { State::Ins };
+                i -= 1;
+            }
+            State::Ins => {
+                columns.push((None, Some(j - 1)));
+                let l = idx(i, j - 1);
+                state = if ins[k] == ins[l] + GAP_EXTEND { State::Ins }
+                    else if ins[k] == mat[l] + GAP_OPEN { State::Mat } else { State::Del };
+                j -= 1;
+            }
+        }
+    }
+    columns.reverse();
+
+    (Alignment { score, columns }, on_edge)
+}
+
+/// CA atoms of amino acid residues (primary conformer), one per residue.
+pub fn ca_trace(atoms: &[Atom]) -> Vec<Atom> {
+    let mut residues = group_residues(atoms);
+    residues.retain(|r| is_amino_acid(r.res_name));
+    residues.iter_mut()
+        .filter_map(|r| {
+            r.collapse_altlocs();
+            r.atom("CA").cloned()
+        })
+        .collect()
+}
+
+/// One-letter sequence of all amino acid residues, chains concatenated.
+pub fn structure_sequence(atoms: &[Atom]) -> String {
+    ca_trace(atoms).iter().map(|a| three_to_one(&a.res_name)).collect()
+}
+
+/// Index pairs of residues aligned by sequence, optionally with a banded alignment.
+pub fn sequence_pairing(a: &[Atom], b: &[Atom], band_width: Option<usize>) -> Vec<(usize, usize)> {
+    let s1: Vec<char> = a.iter().map(|x| three_to_one(&x.res_name)).collect();
+    let s2: Vec<char> = b.iter().map(|x| three_to_one(&x.res_name)).collect();
+    align_for(&s1, &s2, band_width).aligned_pairs().collect()
+}
+
+/// Amino acid residues of one chain, primary conformer only.
+fn chain_residues<'a>(pdb: &'a Pdb, chain: &str) -> Vec<Residue<'a>> {
+    let Some(chain_id) = chain.chars().next() else { return Vec::new() };
+    let mut residues: Vec<Residue> = pdb.residues().into_iter()
+        .filter(|r| r.id.chain_id == chain_id && is_amino_acid(r.res_name))
+        .collect();
+    residues.iter_mut().for_each(|r| r.collapse_altlocs());
+    residues
+}
+
+/// Aligned atom pairs between two structures. Each `(chain in a, chain in b)`
+/// of `chain_map` is sequence-aligned by residue; inside aligned residues the
+/// atoms named in `atom_filter` are paired. Gaps and missing atoms are skipped.
+pub fn pair_atoms(a: &Pdb, b: &Pdb, chain_map: &[(String, String)], atom_filter: &[&str]) -> Vec<(Atom, Atom)> {
+    let mut pairs = Vec::new();
+    for (chain_a, chain_b) in chain_map {
+        let res_a = chain_residues(a, chain_a);
+        let res_b = chain_residues(b, chain_b);
+        let s1: Vec<char> = res_a.iter().map(|r| three_to_one(r.res_name)).collect();
+        let s2: Vec<char> = res_b.iter().map(|r| three_to_one(r.res_name)).collect();
+        for (i, j) in align_with_traceback(&s1, &s2).aligned_pairs() {
+            for name in atom_filter {
+                if let (Some(x), Some(y)) = (res_a[i].atom(name), res_b[j].atom(name)) {
+                    pairs.push((x.clone(), y.clone()));
+                }
+            }
+        }
+    }
+    pairs
+}
+
+/// Residues closer in sequence than this are never counted as contacts.
+const MIN_CONTACT_SEPARATION: usize = 3;
+
+/// CA distance within which residues of two chains count as in contact
+pub const INTERFACE_CUTOFF: f64 = 10.0;
+
+/// Pairs of CA atoms, one from each chain, within `cutoff`; none for chains
+/// that do not pack against each other.
+pub fn interface_contacts(a: &[Atom], b: &[Atom], cutoff: f64) -> usize {
+    let points: Vec<Point> = b.iter().map(|y| y.pos).collect();
+    let grid = SpatialGrid::new(&points, cutoff);
+    a.iter().map(|x| grid.candidates(&x.pos, cutoff).into_iter().filter(|&j| x.pos.distance(&points[j]) <= cutoff).count()).sum()
+}
+
+/// Sparse residue contact map: sorted (i, j) pairs with i < j.
+#[derive(Debug, Clone, Default)]
+pub struct ContactMap {
+    pub len: usize,
+    pub contacts: Vec<(usize, usize)>,
+}
+
+impl ContactMap {
+    pub fn contains(&self, i: usize, j: usize) -> bool {
+        let key = if i < j { (i, j) } else { (j, i) };
+        self.contacts.binary_search(&key).is_ok()
+    }
+}
+
+pub fn contact_map(ca_atoms: &[Atom], cutoff: f64) -> ContactMap {
+    let mut contacts = Vec::new();
+    for i in 0..ca_atoms.len() {
+        for j in (i + MIN_CONTACT_SEPARATION)..ca_atoms.len() {
+            if ca_atoms[i].pos.distance(&ca_atoms[j].pos) <= cutoff {
+                contacts.push((i, j));
+            }
+        }
+    }
+    ContactMap { len: ca_atoms.len(), contacts }
+}
+
+/// Fraction of contacts shared between `a` and `b` over aligned positions
+/// (2 * shared / (contacts_a + contacts_b)), 0.0 when neither has contacts.
+pub fn contact_map_overlap(a: &ContactMap, b: &ContactMap, pairing: &[(usize, usize)]) -> f64 {
+    let mut a_to_b = vec![None; a.len];
+    let mut b_aligned = vec![false; b.len];
+    for &(i, j) in pairing {
+        if i < a.len && j < b.len {
+            a_to_b[i] = Some(j);
+            b_aligned[j] = true;
+        }
+    }
+
+    let mut in_a = 0;
+    let mut shared = 0;
+    for &(i, j) in &a.contacts {
+        if let (Some(bi), Some(bj)) = (a_to_b[i], a_to_b[j]) {
+            in_a += 1;
+            if b.contains(bi, bj) {
+                shared += 1;
+            }
+        }
+    }
+    let in_b = b.contacts.iter().filter(|&&(i, j)| b_aligned[i] && b_aligned[j]).count();
+
+    if in_a + in_b == 0 {
+        return 0.0;
+    }
+    2.0 * shared as f64 / (in_a + in_b) as f64
+}
+
+/// Above this many pairs dRMSD samples a fixed number of partners per residue.
+const DRMSD_FULL_LIMIT: usize = 300;
+const DRMSD_PARTNERS: usize = 64;
+
+/// Distance-matrix RMSD: RMS difference of intramolecular distances between
+/// the two structures. Needs no superposition; 0.0 for fewer than two pairs.
+pub fn drmsd(paired_ca: &[(Point, Point)]) -> f64 {
+    let n = paired_ca.len();
+    if n < 2 {
+        return 0.0;
+    }
+
+    let diff_sq = |i: usize, j: usize| {
+        let (a1, b1) = paired_ca[i];
+        let (a2, b2) = paired_ca[j];
+        (a1.distance(&a2) - b1.distance(&b2)).powi(2)
+    };
+
+    let (sum_sq, count) = if n <= DRMSD_FULL_LIMIT {
+        let mut sum = 0.0;
+        for i in 0..n {
+            for j in (i + 1)..n {
+                sum += diff_sq(i, j);
+            }
+        }
+        (sum, n * (n - 1) / 2)
+    } else {
+        // Deterministic strided sample keeps this O(n * k)
+        let stride = n / DRMSD_PARTNERS;
+        let mut sum = 0.0;
+        let mut count = 0;
+        for i in 0..n {
+            for m in 1..=DRMSD_PARTNERS {
+                let j = (i + m * stride) % n;
+                if j != i {
+                    sum += diff_sq(i, j);
+                    count += 1;
+                }
+            }
+        }
+        (sum, count)
+    };
+
+    (sum_sq / count as f64).sqrt()
+}
+
+/// Distance of each paired CA after superposing the candidate onto the target,
+/// keyed by the target residue. `pairing` indexes into the CA traces of both.
+pub fn per_residue_deviation(target: &Pdb, candidate: &Pdb, pairing: &[(usize, usize)]) -> Vec<(ResidueId, f64)> {
+    let t_ca = ca_trace(&target.atoms);
+    let c_ca = ca_trace(&candidate.atoms);
+    let pairs: Vec<(&Atom, &Atom)> = pairing.iter()
+        .filter(|&&(i, j)| i < t_ca.len() && j < c_ca.len())
+        .map(|&(i, j)| (&t_ca[i], &c_ca[j]))
+        .collect();
+
+    let reference: Vec<Point> = pairs.iter().map(|(t, _)| t.pos).collect();
+    let mobile: Vec<Point> = pairs.iter().map(|(_, c)| c.pos).collect();
+    let Some(sup) = kabsch(&reference, &mobile) else {
+        return Vec::new();
+    };
+
+    pairs.iter().map(|(t, c)| {
+        let id = ResidueId { chain_id: t.chain_id, res_seq: t.res_seq, i_code: t.i_code };
+        (id, sup.apply(&c.pos).distance(&t.pos))
+    }).collect()
+}
+
+/// Percentiles of a per-residue deviation profile plus its worst stretch.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviationSummary {
+    pub median: f64,
+    pub p90: f64,
+    pub max: f64,
+    /// First and last residue of the contiguous stretch around the maximum
+    /// whose deviation stays above half of it
+    pub worst_region: (ResidueId, ResidueId),
+}
+
+impl DeviationSummary {
+    pub fn annotation(&self) -> String {
+        let (start, end) = self.worst_region;
+        if start == end {
+            format!("max deviation {:.1} Å at {}", self.max, start)
+        } else {
+            format!("max deviation {:.1} Å at {}–{}", self.max, start, end)
         }
     }
-    
-    dp[n][m]
 }
 
-pub fn rmsd(atoms1: &[Atom], atoms2: &[Atom]) -> f64 {
-    if atoms1.len() != atoms2.len() || atoms1.is_empty() {
-        return f64::INFINITY;
+pub fn deviation_summary(profile: &[(ResidueId, f64)]) -> Option<DeviationSummary> {
+    if profile.is_empty() {
+        return None;
+    }
+    let mut sorted: Vec<f64> = profile.iter().map(|(_, d)| *d).collect();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let percentile = |q: f64| sorted[((sorted.len() - 1) as f64 * q).round() as usize];
+
+    let (worst, max) = profile.iter().enumerate()
+        .fold((0, f64::NEG_INFINITY), |best, (k, (_, d))| if *d > best.1 { (k, *d) } else { best });
+    let chain = profile[worst].0.chain_id;
+    let above = |k: usize| profile[k].0.chain_id == chain && profile[k].1 >= max / 2.0;
+    let mut start = worst;
+    while start > 0 && above(start - 1) {
+        start -= 1;
+    }
+    let mut end = worst;
+    while end + 1 < profile.len() && above(end + 1) {
+        end += 1;
+    }
+
+    Some(DeviationSummary {
+        median: percentile(0.5),
+        p90: percentile(0.9),
+        max,
+        worst_region: (profile[start].0, profile[end].0),
+    })
+}
+
+/// Why an RMSD could not be computed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RmsdError {
+    EmptyInput,
+    LengthMismatch { left: usize, right: usize },
+    /// All pair weights are zero
+    ZeroWeight,
+}
+
+impl std::fmt::Display for RmsdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RmsdError::EmptyInput => write!(f, "empty atom set"),
+            RmsdError::LengthMismatch { left, right } => write!(f, "atom count mismatch ({} vs {})", left, right),
+            RmsdError::ZeroWeight => write!(f, "total pair weight is zero"),
+        }
+    }
+}
+
+impl std::error::Error for RmsdError {}
+
+pub fn rmsd(atoms1: &[Atom], atoms2: &[Atom]) -> Result<f64, RmsdError> {
+    if atoms1.len() != atoms2.len() {
+        return Err(RmsdError::LengthMismatch { left: atoms1.len(), right: atoms2.len() });
+    }
+    if atoms1.is_empty() {
+        return Err(RmsdError::EmptyInput);
     }
     let sum_sq: f64 = atoms1.iter().zip(atoms2.iter())
         .map(|(a, b)| a.pos.distance(&b.pos).powi(2))
         .sum();
-    (sum_sq / atoms1.len() as f64).sqrt()
+    Ok((sum_sq / atoms1.len() as f64).sqrt())
+}
+
+/// Per-atom weights for RMSD and superposition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Weighting {
+    #[default]
+    Uniform,
+    /// w = occupancy
+    Occupancy,
+    /// w = 1 / (1 + B / INVERSE_B_SCALE)
+    InverseB,
+}
+
+/// B0 in the inverse B-factor weighting, in A^2
+pub const INVERSE_B_SCALE: f64 = 30.0;
+
+impl Weighting {
+    pub fn atom_weight(&self, atom: &Atom) -> f64 {
+        match self {
+            Weighting::Uniform => 1.0,
+            Weighting::Occupancy => atom.occupancy.max(0.0),
+            Weighting::InverseB => 1.0 / (1.0 + atom.temp_factor.max(0.0) / INVERSE_B_SCALE),
+        }
+    }
+
+    /// Weight of a pair: product of both atoms' weights.
+    pub fn pair_weight(&self, a: &Atom, b: &Atom) -> f64 {
+        self.atom_weight(a) * self.atom_weight(b)
+    }
+}
+
+/// RMSD (no superposition) with each pair weighted, normalized by the weight sum.
+pub fn weighted_rmsd(pairs: &[(Atom, Atom)], weighting: Weighting) -> Result<f64, RmsdError> {
+    if pairs.is_empty() {
+        return Err(RmsdError::EmptyInput);
+    }
+    let mut sum_sq = 0.0;
+    let mut total = 0.0;
+    for (a, b) in pairs {
+        let w = weighting.pair_weight(a, b);
+        sum_sq += w * a.pos.distance(&b.pos).powi(2);
+        total += w;
+    }
+    if total <= 0.0 {
+        return Err(RmsdError::ZeroWeight);
+    }
+    Ok((sum_sq / total).sqrt())
+}
+
+/// Superposes with per-pair weights and returns the transform.
+pub fn weighted_superposition(pairs: &[(Atom, Atom)], weighting: Weighting) -> Option<Superposition> {
+    let reference: Vec<Point> = pairs.iter().map(|(a, _)| a.pos).collect();
+    let mobile: Vec<Point> = pairs.iter().map(|(_, b)| b.pos).collect();
+    let weights: Vec<f64> = pairs.iter().map(|(a, b)| weighting.pair_weight(a, b)).collect();
+    kabsch_weighted(&reference, &mobile, &weights)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::pdb::Point;
+    use crate::testing::backbone;
 
     fn mock_atom(x: f64, y: f64, z: f64) -> Atom {
         Atom {
@@ -155,14 +1112,22 @@ mod tests {
     #[test]
     fn test_rmsd_identical() {
         let atoms = vec![mock_atom(0.0, 0.0, 0.0), mock_atom(1.0, 0.0, 0.0)];
-        assert_eq!(rmsd(&atoms, &atoms), 0.0);
+        assert_eq!(rmsd(&atoms, &atoms), Ok(0.0));
     }
 
     #[test]
     fn test_rmsd_offset() {
         let a = vec![mock_atom(0.0, 0.0, 0.0)];
         let b = vec![mock_atom(2.0, 0.0, 0.0)];
-        assert_eq!(rmsd(&a, &b), 2.0);
+        assert_eq!(rmsd(&a, &b), Ok(2.0));
+    }
+
+    #[test]
+    fn test_rmsd_errors() {
+        let a = vec![mock_atom(0.0, 0.0, 0.0), mock_atom(1.0, 0.0, 0.0)];
+        let b = vec![mock_atom(0.0, 0.0, 0.0)];
+        assert_eq!(rmsd(&a, &b), Err(RmsdError::LengthMismatch { left: 2, right: 1 }));
+        assert_eq!(rmsd(&[], &[]), Err(RmsdError::EmptyInput));
     }
 
     #[test]
@@ -178,6 +1143,438 @@ mod tests {
         assert_eq!(align(&s1, &s2), -1.0);
     }
 
+    #[test]
+    fn test_ramachandran_two_chains() {
+        // H1-H4 followed by L5-L8, placed so that C(H4)-N(L5) looks like a peptide bond.
+        let h: Vec<(i32, char)> = (1..=4).map(|i| (i, ' ')).collect();
+        let l: Vec<(i32, char)> = (5..=8).map(|i| (i, ' ')).collect();
+        let mut atoms = backbone('H', &h, Point::new(0.0, 0.0, 0.0));
+        atoms.extend(backbone('L', &l, Point::new(12.0 * 1.2, 0.0, 0.0)));
+
+        // Before: grouping by res_seq only fused the chains and gave 6 pairs.
+        // After: 2 interior residues per chain.
+        let ids: Vec<(char, i32)> = ramachandran(&atoms).iter().map(|p| (p.chain_id, p.res_seq)).collect();
+        assert_eq!(ids, vec![('H', 2), ('H', 3), ('L', 6), ('L', 7)]);
+        assert_eq!(ramachandran_angles(&atoms).len(), 4);
+    }
+
+    #[test]
+    fn test_ramachandran_insertion_codes() {
+        let res = [(99, ' '), (100, ' '), (100, 'A'), (100, 'B'), (101, ' ')];
+        let atoms = backbone('H', &res, Point::new(0.0, 0.0, 0.0));
+
+        // Before: 100/100A/100B collapsed into one residue, leaving 1 pair.
+        assert_eq!(ramachandran(&atoms).len(), 3);
+    }
+
+    #[test]
+    fn test_ramachandran_geometric_break() {
+        let first: Vec<(i32, char)> = (1..=3).map(|i| (i, ' ')).collect();
+        let second: Vec<(i32, char)> = (4..=6).map(|i| (i, ' ')).collect();
+        let mut atoms = backbone('H', &first, Point::new(0.0, 0.0, 0.0));
+        atoms.extend(backbone('H', &second, Point::new(9.0 * 1.2, 0.0, 10.0)));
+
+        // Before: 4 pairs, two of them spanning the 10A gap. After: one per segment.
+        let angles = ramachandran(&atoms);
+        assert_eq!(angles.len(), 2);
+        assert_eq!(ramachandran_score(&angles, &angles, None).score, 1.0);
+    }
+
+    #[test]
+    fn test_ramachandran_skips_hetero_and_altlocs() {
+        let res: Vec<(i32, char)> = (1..=5).map(|i| (i, ' ')).collect();
+        let clean = backbone('H', &res, Point::new(0.0, 0.0, 0.0));
+
+        let mut dirty = Vec::new();
+        for atom in &clean {
+            if atom.res_seq == 3 {
+                // B conformer listed first and displaced, A conformer matches the clean copy
+                let mut b = atom.clone();
+                b.alt_loc = 'B';
+                b.pos = b.pos.add(&Point::new(0.0, 0.0, 0.7));
+                let mut a = atom.clone();
+                a.alt_loc = 'A';
+                dirty.push(b);
+                dirty.push(a);
+            } else {
+                dirty.push(atom.clone());
+            }
+        }
+        let mut hetero = |name: &str, res_name: &str, res_seq: i32, pos: Point| {
+            let mut a = clean[0].clone();
+            a.name = name.into();
+            a.res_name = res_name.into();
+            a.res_seq = res_seq;
+            a.pos = pos;
+            dirty.push(a);
+        };
+        hetero("O", "HOH", 6, Point::new(18.6, 0.8, 0.0));
+        hetero("C1", "NAG", 7, Point::new(19.8, 0.0, 0.0));
+        hetero("N2", "NAG", 7, Point::new(21.0, 0.8, 0.0));
+        hetero("O", "HOH", 8, Point::new(2.0, 2.0, 2.0));
+
+        let expected = ramachandran(&clean);
+        let angles = ramachandran(&dirty);
+        assert_eq!(angles.len(), expected.len());
+        assert_eq!(angles, expected);
+
+        // Raw mode picks the first listed (B) conformer instead
+        let raw = ramachandran_filtered(&dirty, RamaOptions::raw());
+        assert_ne!(raw, expected);
+    }
+
+    fn peptide(second: &str, ca_next: Point) -> Vec<Atom> {
+        let mut atoms = Vec::new();
+        let mut push = |name: &str, res_name: &str, res_seq: i32, pos: Point| {
+            let mut a = mock_atom(pos.x, pos.y, pos.z);
+            a.name = name.into();
+            a.res_name = res_name.into();
+            a.res_seq = res_seq;
+            atoms.push(a);
+        };
+        push("N", "ALA", 1, Point::new(-1.0, 1.5, 0.0));
+        push("CA", "ALA", 1, Point::new(0.0, 1.0, 0.0));
+        push("C", "ALA", 1, Point::new(0.0, 0.0, 0.0));
+        push("N", second, 2, Point::new(1.33, 0.0, 0.0));
+        push("CA", second, 2, ca_next);
+        push("C", second, 2, ca_next.add(&Point::new(1.0, 0.0, 0.5)));
+        atoms
+    }
+
+    #[test]
+    fn test_omega_trans() {
+        let omegas = omega_angles(&peptide("GLY", Point::new(1.33, -1.0, 0.0)));
+        assert_eq!(omegas.len(), 1);
+        assert!((omegas[0].omega.abs() - PI).abs() < 1e-6);
+        assert!(!omegas[0].is_cis);
+    }
+
+    #[test]
+    fn test_omega_cis() {
+        let omegas = omega_angles(&peptide("PRO", Point::new(1.33, 1.0, 0.0)));
+        assert_eq!(omegas.len(), 1);
+        assert!(omegas[0].omega.abs() < 1e-6);
+        assert!(omegas[0].is_cis);
+        assert_eq!(omegas[0].res_seq, 2);
+        assert_eq!(omegas[0].res_name, "PRO");
+    }
+
+    // N, CA, CB and a gamma atom placed so that chi1 equals `chi_deg`
+    fn side_chain(res_name: &str, gamma: &str, chi_deg: f64) -> Vec<Atom> {
+        let chi = chi_deg.to_radians();
+        [
+            ("N", Point::new(1.0, 0.0, 0.0)),
+            ("CA", Point::new(0.0, 0.0, 0.0)),
+            ("CB", Point::new(0.0, 0.0, 1.5)),
+            (gamma, Point::new(chi.cos(), chi.sin(), 2.0)),
+        ].iter().map(|(name, pos)| {
+            let mut a = mock_atom(pos.x, pos.y, pos.z);
+            a.name = name.to_string();
+            a.res_name = res_name.into();
+            a
+        }).collect()
+    }
+
+    #[test]
+    fn test_chi1_leu_ser_gly() {
+        let leu = side_chain("LEU", "CG", -60.0);
+        let ser = side_chain("SER", "OG", 180.0);
+        let mut gly = side_chain("GLY", "CG", 60.0);
+        gly.truncate(2);
+
+        let chi = chi1_angles(&group_residues(&leu));
+        let leu_chi = chi[0].1.unwrap();
+        assert!((leu_chi.to_degrees() + 60.0).abs() < 1e-6);
+        assert_eq!(classify_rotamer(leu_chi), Rotamer::GaucheMinus);
+
+        let chi = chi1_angles(&group_residues(&ser));
+        let ser_chi = chi[0].1.unwrap();
+        assert!((ser_chi.abs().to_degrees() - 180.0).abs() < 1e-6);
+        assert_eq!(classify_rotamer(ser_chi), Rotamer::Trans);
+
+        assert_eq!(chi1_angles(&group_residues(&gly))[0].1, None);
+        assert_eq!(classify_rotamer(60f64.to_radians()), Rotamer::GauchePlus);
+    }
+
+    #[test]
+    fn test_classify_rama() {
+        let r = |phi: f64, psi: f64, class| classify_rama(phi.to_radians(), psi.to_radians(), class);
+        assert_eq!(r(-57.0, -47.0, RamaClass::General), RamaRegion::Favored);
+        assert_eq!(r(-120.0, 130.0, RamaClass::General), RamaRegion::Favored);
+        assert_eq!(r(60.0, -60.0, RamaClass::General), RamaRegion::Outlier);
+        assert_eq!(r(-65.0, 140.0, RamaClass::Proline), RamaRegion::Favored);
+        assert_eq!(r(80.0, 20.0, RamaClass::Glycine), RamaRegion::Favored);
+        assert_eq!(r(-180.0, 180.0, RamaClass::General), RamaRegion::Favored);
+    }
+
+    #[test]
+    fn test_align_traceback_gap() {
+        let s1: Vec<char> = "ACDEF".chars().collect();
+        let s2: Vec<char> = "CDEF".chars().collect();
+        let aln = align_with_traceback(&s1, &s2);
+        assert_eq!(aln.columns[0], (Some(0), None));
+        assert_eq!(aln.aligned_pairs().collect::<Vec<_>>(), vec![(1, 0), (2, 1), (3, 2), (4, 3)]);
+        assert_eq!(aln.score, align(&s1, &s2));
+    }
+
+    const VH: &str = "EVQLVESGGGLVQPGGSLRLSCAASGFTFSSYAMSWVRQAPGKGLEWVSAISGSGGSTYYADSVKGRFTISRDNSKNTLYLQMNSLRAEDTAVYYCAK";
+
+    #[test]
+    fn test_align_banded_matches_full() {
+        let s1: Vec<char> = VH.chars().collect();
+        // Point mutations plus a 3-residue deletion, well inside a band of 15
+        let mut s2 = s1.clone();
+        s2[10] = 'W';
+        s2[60] = 'P';
+        s2.drain(40..43);
+
+        let (banded, on_edge) = gotoh(&s1, &s2, Some(15), match_score);
+        let full = align_with_traceback(&s1, &s2);
+        assert!(!on_edge);
+        assert_eq!(banded.score, full.score);
+        assert_eq!(banded.columns, full.columns);
+        assert_eq!(align_banded(&s1, &s2, 15).columns, full.columns);
+    }
+
+    #[test]
+    fn test_align_banded_falls_back() {
+        let s1: Vec<char> = VH.chars().collect();
+
+        // Long insertion: the length difference alone exceeds the band
+        let mut inserted = s1.clone();
+        inserted.splice(50..50, "GGGGSGGGGSGGGGSGGGGS".chars());
+        let full = align_with_traceback(&s1, &inserted);
+        assert_eq!(align_banded(&s1, &inserted, 15).columns, full.columns);
+
+        // Same length, but a deletion early and an insertion late run the path along the edge of a band of 10
+        let mut shifted: Vec<char> = s1.clone();
+        shifted.drain(10..20);
+        shifted.splice(70..70, "WWWWWWWWWW".chars());
+        let (_, on_edge) = gotoh(&s1, &shifted, Some(10), match_score);
+        assert!(on_edge);
+        let full = align_with_traceback(&s1, &shifted);
+        let banded = align_banded(&s1, &shifted, 10);
+        assert_eq!(banded.score, full.score);
+        assert_eq!(banded.columns, full.columns);
+    }
+
+    fn numbered(seq: &[(&str, char)]) -> Vec<(Position, char)> {
+        seq.iter().map(|(p, c)| (p.parse().unwrap(), *c)).collect()
+    }
+
+    #[test]
+    fn test_align_weighted_cdr_h3() {
+        // Same framework, different CDR-H3 (positions 95-102, with a 100A insertion)
+        let a = numbered(&[("92", 'C'), ("93", 'A'), ("94", 'R'), ("95", 'D'), ("96", 'Y'), ("100", 'G'), ("100A", 'F'), ("101", 'D'), ("102", 'Y'), ("103", 'W'), ("104", 'G')]);
+        let b = numbered(&[("92", 'C'), ("93", 'A'), ("94", 'R'), ("95", 'E'), ("96", 'W'), ("100", 'S'), ("101", 'D'), ("102", 'V'), ("103", 'W'), ("104", 'G')]);
+
+        let even = align_weighted(&a, &b, ChainType::Heavy, &RegionWeights::default());
+        assert!((even - 6.0 / 11.0).abs() < 1e-12);
+        let framework_only = align_weighted(&a, &b, ChainType::Heavy, &RegionWeights::new(1.0, 0.0));
+        assert_eq!(framework_only, 1.0);
+        let cdr_only = align_weighted(&a, &b, ChainType::Heavy, &RegionWeights::new(0.0, 1.0));
+        assert!((cdr_only - 1.0 / 6.0).abs() < 1e-12);
+        assert_eq!(align_weighted(&a, &b, ChainType::Heavy, &RegionWeights::new(0.0, 0.0)), 0.0);
+    }
+
+    #[test]
+    fn test_rama_string() {
+        let helix = (-57f64.to_radians(), -47f64.to_radians());
+        let sheet = (-120f64.to_radians(), 130f64.to_radians());
+        let ppii = (-75f64.to_radians(), 145f64.to_radians());
+        let left = (60f64.to_radians(), 40f64.to_radians());
+        assert_eq!(rama_string(&[helix, helix, sheet, ppii, left]), "HHEPL");
+
+        let a = "HHHHHHEEEEEE";
+        assert_eq!(rama_string_score(a, a), 1.0);
+        // A PPII residue in place of a strand residue costs less than a left-handed one
+        let with_ppii = rama_string_score(a, "HHHHHHEEPEEE");
+        let with_left = rama_string_score(a, "HHHHHHEELEEE");
+        assert!(with_ppii > with_left);
+        assert!(rama_string_score(a, "EEEEEEHHHHHH") < with_left);
+    }
+
+    #[test]
+    fn test_ramachandran_score_missing_terminal_residue() {
+        let names = ["GLU", "VAL", "GLN", "LEU", "VAL", "GLU", "SER", "GLY", "GLY", "GLY", "LEU", "VAL"];
+        let target: Vec<RamaPoint> = names.iter().enumerate().map(|(i, name)| {
+            // Alternate helix-like and strand-like residues so a shift is costly
+            let (phi, psi) = if i % 2 == 0 { (-1.0, -0.8) } else { (-2.2, 2.4) };
+            RamaPoint { chain_id: 'H', res_seq: i as i32 + 1, i_code: ' ', res_name: name.to_string(), phi, psi }
+        }).collect();
+        let candidate = &target[1..];
+
+        let full = ramachandran_score(&target, &target, None);
+        let trimmed = ramachandran_score(&target, candidate, None);
+        assert_eq!(full.score, 1.0);
+        assert!((full.score - trimmed.score).abs() < 0.05, "score dropped to {}", trimmed.score);
+        assert_eq!(trimmed.compared, names.len() - 1);
+        assert!(trimmed.coverage < 1.0);
+    }
+
+    // Ideal CA helix (rise 1.5A, radius 2.3A, 100 degrees per residue) bent at `hinge`
+    // by `bend` radians around the x axis.
+    fn bent_helix(n: usize, hinge: usize, bend: f64) -> Vec<Atom> {
+        let mut atoms: Vec<Atom> = (0..n).map(|i| {
+            let t = (i as f64 * 100.0).to_radians();
+            mock_atom(2.3 * t.cos(), 2.3 * t.sin(), 1.5 * i as f64)
+        }).collect();
+        let pivot = atoms[hinge].pos;
+        for a in atoms.iter_mut().skip(hinge) {
+            let p = a.pos.sub(&pivot);
+            let rotated = Point::new(p.x, p.y * bend.cos() - p.z * bend.sin(), p.y * bend.sin() + p.z * bend.cos());
+            a.pos = rotated.add(&pivot);
+        }
+        atoms
+    }
+
+    #[test]
+    fn test_interface_contacts() {
+        // Two rows of atoms 1A apart, overlapping along x and 6A apart in y
+        let a: Vec<Atom> = (0..20).map(|i| mock_atom(i as f64, 0.0, 0.0)).collect();
+        let b: Vec<Atom> = (0..20).map(|i| mock_atom(i as f64 + 10.5, 6.0, -0.5)).collect();
+        let brute = |a: &[Atom], b: &[Atom], cutoff: f64| a.iter().map(|x| b.iter().filter(|y| x.pos.distance(&y.pos) <= cutoff).count()).sum::<usize>();
+        for cutoff in [4.0, 10.0, 15.0] {
+            assert_eq!(interface_contacts(&a, &b, cutoff), brute(&a, &b, cutoff), "cutoff {}", cutoff);
+        }
+        assert!(interface_contacts(&a, &b, 10.0) > 0);
+        assert_eq!(interface_contacts(&a, &b, 4.0), 0);
+        assert_eq!(interface_contacts(&a, &[], 10.0), 0);
+    }
+
+    #[test]
+    fn test_contact_map_overlap_hinge_motion() {
+        let straight = bent_helix(40, 20, 0.0);
+        let bent = bent_helix(40, 20, PI / 2.0);
+        let pairing: Vec<(usize, usize)> = (0..40).map(|i| (i, i)).collect();
+
+        let a = contact_map(&straight, 8.0);
+        let b = contact_map(&bent, 8.0);
+        assert!(!a.contacts.is_empty());
+        assert_eq!(contact_map_overlap(&a, &a, &pairing), 1.0);
+
+        let cmo = contact_map_overlap(&a, &b, &pairing);
+        let raw_rmsd = rmsd(&straight, &bent).unwrap();
+        assert!(cmo > 0.8, "cmo {}", cmo);
+        assert!(raw_rmsd > 10.0, "rmsd {}", raw_rmsd);
+    }
+
+    #[test]
+    fn test_drmsd() {
+        let helix = bent_helix(30, 15, 0.0);
+        let pairs: Vec<(Point, Point)> = helix.iter().map(|a| (a.pos, a.pos)).collect();
+        assert_eq!(drmsd(&pairs), 0.0);
+
+        let shift = Point::new(10.0, -4.0, 3.0);
+        let translated: Vec<(Point, Point)> = helix.iter().map(|a| (a.pos, a.pos.add(&shift))).collect();
+        assert!(drmsd(&translated) < 1e-9);
+
+        let bent = bent_helix(30, 15, PI / 3.0);
+        let distorted: Vec<(Point, Point)> = helix.iter().zip(&bent).map(|(a, b)| (a.pos, b.pos)).collect();
+        assert!(drmsd(&distorted) > 1.0);
+    }
+
+    #[test]
+    fn test_drmsd_sampled() {
+        let helix = bent_helix(400, 200, 0.0);
+        let bent = bent_helix(400, 200, PI / 3.0);
+        let same: Vec<(Point, Point)> = helix.iter().map(|a| (a.pos, a.pos)).collect();
+        let distorted: Vec<(Point, Point)> = helix.iter().zip(&bent).map(|(a, b)| (a.pos, b.pos)).collect();
+        assert_eq!(drmsd(&same), 0.0);
+        assert!(drmsd(&distorted) > 1.0);
+    }
+
+    #[test]
+    fn test_per_residue_deviation_single_displaced_residue() {
+        let mut target_atoms = bent_helix(30, 15, 0.0);
+        for (i, a) in target_atoms.iter_mut().enumerate() {
+            a.res_seq = i as i32 + 1;
+            a.chain_id = 'H';
+        }
+        let mut candidate_atoms = target_atoms.clone();
+        candidate_atoms[9].pos = candidate_atoms[9].pos.add(&Point::new(6.0, 0.0, 0.0));
+        let target = Pdb { atoms: target_atoms };
+        let candidate = Pdb { atoms: candidate_atoms };
+        let pairing: Vec<(usize, usize)> = (0..30).map(|i| (i, i)).collect();
+
+        let profile = per_residue_deviation(&target, &candidate, &pairing);
+        assert_eq!(profile.len(), 30);
+        for (k, (id, d)) in profile.iter().enumerate() {
+            if k == 9 {
+                assert_eq!(id.res_seq, 10);
+                assert!(*d > 4.0);
+            } else {
+                assert!(*d < 1.0, "residue {} deviates {}", id, d);
+            }
+        }
+
+        let summary = deviation_summary(&profile).unwrap();
+        assert_eq!(summary.worst_region.0.res_seq, 10);
+        assert_eq!(summary.worst_region.1.res_seq, 10);
+        assert!(summary.annotation().contains("at H10"));
+        assert!(summary.median < 1.0);
+    }
+
+    #[test]
+    fn test_weighted_rmsd_zero_occupancy_outlier() {
+        let a: Vec<Atom> = (0..10).map(|i| mock_atom(i as f64, 0.0, 0.0)).collect();
+        let mut b = a.clone();
+        for atom in b.iter_mut() {
+            atom.pos.y += 0.1;
+        }
+        b[5].pos.y += 20.0;
+        b[5].occupancy = 0.0;
+        let pairs: Vec<(Atom, Atom)> = a.into_iter().zip(b).collect();
+
+        let uniform = weighted_rmsd(&pairs, Weighting::Uniform).unwrap();
+        let weighted = weighted_rmsd(&pairs, Weighting::Occupancy).unwrap();
+        assert!(uniform > 5.0);
+        assert!((weighted - 0.1).abs() < 1e-9);
+
+        let sup = weighted_superposition(&pairs, Weighting::Occupancy).unwrap();
+        assert!(sup.rmsd < 1e-6);
+    }
+
+    #[test]
+    fn test_inverse_b_weight() {
+        let mut atom = mock_atom(0.0, 0.0, 0.0);
+        atom.temp_factor = INVERSE_B_SCALE;
+        assert_eq!(Weighting::InverseB.atom_weight(&atom), 0.5);
+    }
+
+    #[test]
+    fn test_pair_atoms_skips_missing_ca() {
+        let residues: Vec<(i32, char)> = (1..=4).map(|i| (i, ' ')).collect();
+        let a = Pdb { atoms: backbone('H', &residues, Point::new(0.0, 0.0, 0.0)) };
+        let mut b_atoms = backbone('A', &residues, Point::new(0.0, 0.0, 0.0));
+        b_atoms.retain(|x| !(x.res_seq == 2 && x.name == "CA"));
+        let b = Pdb { atoms: b_atoms };
+        let chains = vec![("H".to_string(), "A".to_string())];
+
+        let ca = pair_atoms(&a, &b, &chains, &["CA"]);
+        let seqs: Vec<i32> = ca.iter().map(|(x, _)| x.res_seq).collect();
+        assert_eq!(seqs, vec![1, 3, 4]);
+        assert_eq!(pair_atoms(&a, &b, &chains, &["N", "CA", "C"]).len(), 11);
+    }
+
+    #[test]
+    fn test_pair_atoms_gapped_alignment() {
+        // Candidate lacks the target's third residue, a GLY in an otherwise ALA chain
+        let residues: Vec<(i32, char)> = (1..=8).map(|i| (i, ' ')).collect();
+        let mut a_atoms = backbone('H', &residues, Point::new(0.0, 0.0, 0.0));
+        for atom in a_atoms.iter_mut().filter(|x| x.res_seq == 3) {
+            atom.res_name = "GLY".into();
+        }
+        let mut b_atoms = a_atoms.clone();
+        b_atoms.retain(|x| x.res_seq != 3);
+        let a = Pdb { atoms: a_atoms };
+        let b = Pdb { atoms: b_atoms };
+
+        let pairs = pair_atoms(&a, &b, &[("H".to_string(), "H".to_string())], &["CA"]);
+        assert_eq!(pairs.len(), 7);
+        assert!(pairs.iter().all(|(x, y)| x.res_seq == y.res_seq));
+    }
+
     #[test]
     fn test_torsion_angle() {
         let p1 = Point::new(1.0, 0.0, 0.0);
@@ -185,7 +1582,35 @@ mod tests {
         let p3 = Point::new(0.0, 1.0, 0.0);
         let p4 = Point::new(0.0, 1.0, 1.0);
         
-        let angle = torsion_angle(p1, p2, p3, p4);
+        let angle = torsion_angle(p1, p2, p3, p4).unwrap();
         assert!((angle.abs() - PI/2.0).abs() < 1e-6);
     }
+
+    #[test]
+    fn test_torsion_angle_degenerate() {
+        let p1 = Point::new(-1.0, 0.0, 0.0);
+        let p2 = Point::new(0.0, 0.0, 0.0);
+        let p3 = Point::new(1.0, 0.0, 0.0);
+        let p4 = Point::new(1.0, 1.0, 0.0);
+        assert_eq!(torsion_angle(p1, p2, p3, p4), None);
+        assert_eq!(torsion_angle(p4, p3, p2, p1), None);
+
+        // |b1 x b2| just above the epsilon still yields an angle
+        let bent = Point::new(-1.0, 2.0 * TORSION_EPSILON, 0.0);
+        assert!(torsion_angle(bent, p2, p3, Point::new(1.0, 0.0, 1.0)).is_some());
+    }
+
+    #[test]
+    fn test_ramachandran_skips_collinear_residue() {
+        let residues: Vec<(i32, char)> = (1..=5).map(|i| (i, ' ')).collect();
+        let mut atoms = backbone('H', &residues, Point::new(0.0, 0.0, 0.0));
+        let before = ramachandran(&atoms).len();
+        // Put N, CA, C of residue 3 on one line
+        let n = atoms.iter().find(|a| a.res_seq == 3 && a.name == "N").unwrap().pos;
+        for atom in atoms.iter_mut().filter(|a| a.res_seq == 3) {
+            atom.pos.y = n.y;
+        }
+        assert_eq!(ramachandran(&atoms).len(), before - 1);
+        assert_eq!(degenerate_torsion_count(&atoms), 1);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,188 @@
+//! Ball tree over fixed-length vectors for nearest-neighbour lookup by
+//! Euclidean distance, pruning whole subtrees whose ball cannot hold a
+//! closer point.
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+/// Points per leaf, scanned linearly
+const LEAF_SIZE: usize = 16;
+
+struct Node {
+    center: Vec<f32>,
+    radius: f32,
+    /// Range of `BallTree::order` the node covers
+    start: usize,
+    end: usize,
+    /// Child nodes, None for leaves
+    children: Option<(usize, usize)>,
+}
+
+pub struct BallTree {
+    dim: usize,
+    /// Points, `dim` values each, in input order
+    points: Vec<f32>,
+    /// Point indices, each node's contiguous
+    order: Vec<usize>,
+    nodes: Vec<Node>,
+}
+
+fn distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum::<f32>().sqrt()
+}
+
+// A found neighbour, ordered by distance so the heap keeps the farthest on top
+struct Neighbour(f32, usize);
+
+impl PartialEq for Neighbour {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Neighbour {}
+
+impl PartialOrd for Neighbour {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Neighbour {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+impl BallTree {
+    /// Indexes `points`, which must all have `dim` values.
+    pub fn new(dim: usize, points: &[Vec<f32>]) -> Self {
+        assert!(points.iter().all(|p| p.len() == dim), "points must have {} values", dim);
+        let mut tree = Self { dim, points: points.concat(), order: (0..points.len()).collect(), nodes: Vec::new() };
+        if !points.is_empty() {
+            tree.build(0, points.len());
+        }
+        tree
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    fn point(&self, i: usize) -> &[f32] {
+        &self.points[i * self.dim..(i + 1) * self.dim]
+    }
+
+    // Adds the node over order[start..end] and its subtree; returns its index
+    fn build(&mut self, start: usize, end: usize) -> usize {
+        let mut center = vec![0.0f32; self.dim];
+        for &i in &self.order[start..end] {
+            center.iter_mut().zip(self.point(i)).for_each(|(c, v)| *c += v);
+        }
+        center.iter_mut().for_each(|c| *c /= (end - start) as f32);
+        let radius = self.order[start..end].iter().map(|&i| distance(&center, self.point(i))).fold(0.0, f32::max);
+        let index = self.nodes.len();
+        self.nodes.push(Node { center, radius, start, end, children: None });
+        if end - start <= LEAF_SIZE || radius == 0.0 {
+            return index;
+        }
+
+        // Split at the median along the line through two far apart points
+        let farthest = |from: &[f32], order: &[usize]| {
+            *order.iter().max_by(|&&a, &&b| distance(from, self.point(a)).total_cmp(&distance(from, self.point(b)))).unwrap()
+        };
+        let a = farthest(&self.nodes[index].center, &self.order[start..end]);
+        let b = farthest(self.point(a), &self.order[start..end]);
+        let axis: Vec<f32> = self.point(b).iter().zip(self.point(a)).map(|(x, y)| x - y).collect();
+        let projection = |i: usize| self.point(i).iter().zip(&axis).map(|(v, w)| v * w).sum::<f32>();
+        let mut order = self.order[start..end].to_vec();
+        let mid = order.len() / 2;
+        order.select_nth_unstable_by(mid, |&x, &y| projection(x).total_cmp(&projection(y)));
+        self.order[start..end].copy_from_slice(&order);
+
+        let left = self.build(start, start + mid);
+        let right = self.build(start + mid, end);
+        self.nodes[index].children = Some((left, right));
+        index
+    }
+
+    /// The `k` points closest to `query` as (index, distance), nearest
+    /// first; fewer if the tree holds fewer.
+    pub fn nearest(&self, query: &[f32], k: usize) -> Vec<(usize, f32)> {
+        self.nearest_where(query, k, |_| true)
+    }
+
+    /// As `nearest`, over the points whose index passes `keep`.
+    pub fn nearest_where(&self, query: &[f32], k: usize, keep: impl Fn(usize) -> bool) -> Vec<(usize, f32)> {
+        let mut found = BinaryHeap::with_capacity(k + 1);
+        if k > 0 && !self.is_empty() {
+            self.search(0, query, k, &keep, &mut found);
+        }
+        found.into_sorted_vec().into_iter().map(|Neighbour(d, i)| (i, d)).collect()
+    }
+
+    fn search(&self, node: usize, query: &[f32], k: usize, keep: &impl Fn(usize) -> bool, found: &mut BinaryHeap<Neighbour>) {
+        let n = &self.nodes[node];
+        let bound = |found: &BinaryHeap<Neighbour>| if found.len() < k { f32::INFINITY } else { found.peek().unwrap().0 };
+        if distance(query, &n.center) - n.radius >= bound(found) {
+            return;
+        }
+        match n.children {
+            None => {
+                for &i in self.order[n.start..n.end].iter().filter(|&&i| keep(i)) {
+                    let d = distance(query, self.point(i));
+                    if d < bound(found) {
+                        found.push(Neighbour(d, i));
+                        if found.len() > k {
+                            found.pop();
+                        }
+                    }
+                }
+            }
+            Some((left, right)) => {
+                // The nearer ball first, so the farther one is more likely pruned
+                let (near, far) = if distance(query, &self.nodes[left].center) <= distance(query, &self.nodes[right].center) {
+                    (left, right)
+                } else {
+                    (right, left)
+                };
+                self.search(near, query, k, keep, found);
+                self.search(far, query, k, keep, found);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_ball_tree_nearest() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let points: Vec<Vec<f32>> = (0..400).map(|_| (0..8).map(|_| rng.random_range(-1.0..1.0)).collect()).collect();
+        let tree = BallTree::new(8, &points);
+        assert_eq!(tree.len(), 400);
+        for _ in 0..20 {
+            let query: Vec<f32> = (0..8).map(|_| rng.random_range(-1.0..1.0)).collect();
+            let mut brute: Vec<(usize, f32)> = points.iter().enumerate().map(|(i, p)| (i, distance(&query, p))).collect();
+            brute.sort_by(|a, b| a.1.total_cmp(&b.1));
+            let found = tree.nearest(&query, 10);
+            assert_eq!(found.iter().map(|&(i, _)| i).collect::<Vec<_>>(), brute[..10].iter().map(|&(i, _)| i).collect::<Vec<_>>());
+            // Filtered out points are skipped, not counted against k
+            let kept: Vec<usize> = brute.iter().map(|&(i, _)| i).filter(|i| i % 5 == 0).take(10).collect();
+            let found = tree.nearest_where(&query, 10, |i| i % 5 == 0);
+            assert_eq!(found.iter().map(|&(i, _)| i).collect::<Vec<_>>(), kept);
+        }
+
+        // Duplicates, more neighbours asked for than there are points
+        let tree = BallTree::new(2, &vec![vec![1.0, 1.0]; 40]);
+        assert_eq!(tree.nearest(&[0.0, 1.0], 50).len(), 40);
+        assert!(BallTree::new(2, &[]).nearest(&[0.0, 0.0], 3).is_empty());
+    }
+}
@@ -0,0 +1,109 @@
+//! Circular statistics for angles in radians.
+use std::f64::consts::PI;
+
+/// Shortest distance between two angles, in [0, PI].
+pub fn angular_distance(a: f64, b: f64) -> f64 {
+    let d = (a - b).rem_euclid(2.0 * PI);
+    if d > PI { 2.0 * PI - d } else { d }
+}
+
+// Mean resultant vector (mean cos, mean sin)
+fn mean_resultant(angles: &[f64]) -> (f64, f64) {
+    let n = angles.len() as f64;
+    let c = angles.iter().map(|a| a.cos()).sum::<f64>() / n;
+    let s = angles.iter().map(|a| a.sin()).sum::<f64>() / n;
+    (c, s)
+}
+
+/// Mean direction in [-PI, PI]; None for empty input or a zero resultant.
+pub fn circular_mean(angles: &[f64]) -> Option<f64> {
+    if angles.is_empty() {
+        return None;
+    }
+    let (c, s) = mean_resultant(angles);
+    if c.hypot(s) < 1e-12 {
+        return None;
+    }
+    Some(s.atan2(c))
+}
+
+/// 1 - R, where R is the mean resultant length: 0 for identical angles, 1 for uniform spread.
+pub fn circular_variance(angles: &[f64]) -> f64 {
+    if angles.is_empty() {
+        return 0.0;
+    }
+    let (c, s) = mean_resultant(angles);
+    1.0 - c.hypot(s)
+}
+
+/// Approximate maximum-likelihood concentration of a von Mises fit (Best & Fisher, 1981).
+pub fn von_mises_kappa_estimate(angles: &[f64]) -> f64 {
+    if angles.is_empty() {
+        return 0.0;
+    }
+    let (c, s) = mean_resultant(angles);
+    let r = c.hypot(s);
+    let kappa = if r < 0.53 {
+        2.0 * r + r.powi(3) + 5.0 * r.powi(5) / 6.0
+    } else if r < 0.85 {
+        -0.4 + 1.39 * r + 0.43 / (1.0 - r)
+    } else {
+        1.0 / (r.powi(3) - 4.0 * r.powi(2) + 3.0 * r)
+    };
+
+    // Small-sample bias correction
+    let n = angles.len() as f64;
+    if n < 15.0 {
+        if kappa < 2.0 {
+            (kappa - 2.0 / (n * kappa)).max(0.0)
+        } else {
+            kappa * (n - 1.0).powi(3) / (n.powi(3) + n)
+        }
+    } else {
+        kappa
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_angular_distance_wraparound() {
+        assert!((angular_distance(PI - 0.1, -PI + 0.1) - 0.2).abs() < 1e-12);
+        for k in -20..=20 {
+            let a = k as f64 * 0.37;
+            for d in [0.0, 0.5, 1.0, 3.0] {
+                let b = a + d + 2.0 * PI * (k % 3) as f64;
+                assert!((angular_distance(a, b) - d).abs() < 1e-9);
+                assert!((angular_distance(a, b) - angular_distance(b, a)).abs() < 1e-12);
+                assert!(angular_distance(a, b) <= PI);
+            }
+        }
+    }
+
+    #[test]
+    fn test_circular_mean_near_pi() {
+        // Naive arithmetic mean of these is ~0, the circular mean is PI
+        let angles = [PI - 0.1, -PI + 0.1, PI - 0.05, -PI + 0.05];
+        let mean = circular_mean(&angles).unwrap();
+        assert!(angular_distance(mean, PI) < 1e-9);
+
+        for k in 0..36 {
+            let center = -PI + k as f64 * PI / 18.0;
+            let spread = [center - 0.2, center, center + 0.2];
+            assert!(angular_distance(circular_mean(&spread).unwrap(), center) < 1e-9);
+        }
+        assert_eq!(circular_mean(&[0.0, PI]), None);
+    }
+
+    #[test]
+    fn test_circular_variance_and_kappa() {
+        let tight = [PI - 0.01, -PI + 0.01, PI];
+        let uniform: Vec<f64> = (0..8).map(|i| i as f64 * PI / 4.0).collect();
+        assert!(circular_variance(&tight) < 1e-3);
+        assert!((circular_variance(&uniform) - 1.0).abs() < 1e-9);
+        assert!(von_mises_kappa_estimate(&tight) > von_mises_kappa_estimate(&uniform));
+        assert_eq!(von_mises_kappa_estimate(&uniform), 0.0);
+    }
+}
@@ -0,0 +1,105 @@
+//! Greedy sequence-identity clustering of H/L pairs, for collapsing
+//! re-deposits of the same antibody.
+use super::{align_with_traceback, kmer_profile, kmer_similarity, KmerProfile};
+use rayon::prelude::*;
+
+/// Default identity both chains must reach to join a cluster
+pub const DEFAULT_CLUSTER_IDENTITY: f64 = 0.99;
+
+/// k-mer length of the clustering prefilter
+const CLUSTER_KMER_K: usize = 3;
+
+/// Allowance for unresolved residues in the k-mer prefilter, whose cosine
+/// drops with length differences the identity ignores
+const KMER_LENGTH_SLACK: f64 = 0.1;
+
+/// Identical aligned residues over the length of the shorter sequence, so
+/// disordered termini in one crystal form do not count. Two empty sequences
+/// are identical, one empty sequence matches nothing.
+pub fn sequence_identity(a: &str, b: &str) -> f64 {
+    let s1: Vec<char> = a.chars().collect();
+    let s2: Vec<char> = b.chars().collect();
+    let shorter = s1.len().min(s2.len());
+    if shorter == 0 {
+        return if s1.len() == s2.len() { 1.0 } else { 0.0 };
+    }
+    let identical = align_with_traceback(&s1, &s2).aligned_pairs().filter(|&(i, j)| s1[i] == s2[j]).count();
+    identical as f64 / shorter as f64
+}
+
+// Profiles of both chains of one member
+struct Profiles {
+    heavy: KmerProfile,
+    light: KmerProfile,
+}
+
+/// Clusters (heavy, light) sequence pairs greedily: in input order, each pair
+/// joins the first representative whose chains are both at least `threshold`
+/// identical, or becomes a representative itself. Callers order the input by
+/// preference. Returns the index of each pair's representative.
+///
+/// Pairs whose k-mer profiles are too dissimilar to reach the threshold are
+/// never aligned.
+pub fn greedy_clusters(pairs: &[(String, String)], threshold: f64) -> Vec<usize> {
+    // Each substitution breaks up to k k-mers
+    let kmer_floor = (1.0 - CLUSTER_KMER_K as f64 * (1.0 - threshold) - KMER_LENGTH_SLACK).max(0.0);
+    let profiles: Vec<Profiles> = pairs.par_iter().map(|(h, l)| Profiles {
+        heavy: kmer_profile(h, CLUSTER_KMER_K),
+        light: kmer_profile(l, CLUSTER_KMER_K),
+    }).collect();
+    // Chains without residues have no k-mers to compare
+    let kmers_close = |a: &KmerProfile, b: &KmerProfile| {
+        a.counts.is_empty() || b.counts.is_empty() || kmer_similarity(a, b) >= kmer_floor
+    };
+
+    let mut representatives: Vec<usize> = Vec::new();
+    let mut assignment = Vec::with_capacity(pairs.len());
+    for (i, (h, l)) in pairs.iter().enumerate() {
+        let joined = representatives.par_iter().find_first(|&&r| {
+            kmers_close(&profiles[i].heavy, &profiles[r].heavy)
+                && kmers_close(&profiles[i].light, &profiles[r].light)
+                && sequence_identity(h, &pairs[r].0) >= threshold
+                && sequence_identity(l, &pairs[r].1) >= threshold
+        });
+        match joined {
+            Some(&r) => assignment.push(r),
+            None => {
+                representatives.push(i);
+                assignment.push(i);
+            }
+        }
+    }
+    assignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VH: &str = "EVQLVESGGGLVQPGGSLRLSCAASGFTFSSYAMSWVRQAPGKGLEWVSAISGSGGSTYYADSVKGRFTISRDNSKNTLYLQMNSLRAEDTAVYYCAKDRGYSSGWYFDYWGQGTLVTVSS";
+    const VL: &str = "DIQMTQSPSSLSASVGDRVTITCRASQSISSYLNWYQQKPGKAPKLLIYAASSLQSGVPSRFSGSGSGTDFTLTISSLQPEDFATYYCQQSYSTPLTFGQGTKVEIK";
+
+    #[test]
+    fn test_greedy_clusters() {
+        // A re-deposit missing its first and last residues
+        let redeposit = (VH[1..VH.len() - 1].to_string(), VL.to_string());
+        // Same light chain, different CDR-H3
+        let mut other_h3 = VH.to_string();
+        other_h3.replace_range(99..108, "GGPYNWNDA");
+        let unrelated_h = "QVQLQQWGAGLLKPSETLSLTCAVYGGSFSGYYWSWIRQPPGKGLEWIGEINHSGSTNYNPSLKSRVTISVDTSKNQFSLKLSSVTAADTAVYYCAR";
+
+        let pairs = vec![
+            (VH.to_string(), VL.to_string()),
+            (other_h3, VL.to_string()),
+            redeposit,
+            (unrelated_h.to_string(), VL.to_string()),
+        ];
+        assert_eq!(greedy_clusters(&pairs, DEFAULT_CLUSTER_IDENTITY), [0, 1, 0, 3]);
+        // Loose enough to merge the H3 variant as well
+        assert_eq!(greedy_clusters(&pairs, 0.9), [0, 0, 0, 3]);
+
+        assert_eq!(sequence_identity(VH, VH), 1.0);
+        assert_eq!(sequence_identity("", ""), 1.0);
+        assert_eq!(sequence_identity(VH, ""), 0.0);
+    }
+}
@@ -0,0 +1,23 @@
+//! Fixed-length vectors describing a Fab, for nearest-neighbour retrieval
+//! of matching candidates.
+use super::FINGERPRINT_BINS;
+
+/// Length of `fab_embedding` vectors: the Ramachandran fingerprint, then
+/// the CDR-H1 to H3 lengths
+pub const EMBEDDING_DIM: usize = FINGERPRINT_BINS * FINGERPRINT_BINS + 3;
+
+/// Weight per residue of the CDR lengths, so a few residues of H3 weigh
+/// about as much as a shifted fingerprint
+const CDR_LENGTH_SCALE: f32 = 0.05;
+
+/// Embedding of a Fab from its Ramachandran fingerprint (of
+/// `FINGERPRINT_BINS` squared values) and heavy chain CDR lengths; None for
+/// a fingerprint of another size.
+pub fn fab_embedding(fingerprint: &[f32], heavy_cdr_lengths: [usize; 3]) -> Option<Vec<f32>> {
+    if fingerprint.len() != FINGERPRINT_BINS * FINGERPRINT_BINS {
+        return None;
+    }
+    let mut embedding = fingerprint.to_vec();
+    embedding.extend(heavy_cdr_lengths.map(|length| length as f32 * CDR_LENGTH_SCALE));
+    Some(embedding)
+}
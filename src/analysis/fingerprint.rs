@@ -0,0 +1,74 @@
+//! Fixed-length Ramachandran histograms for indexing and pre-ranking.
+use std::f64::consts::PI;
+
+/// Default grid size of stored fingerprints (bins x bins)
+pub const FINGERPRINT_BINS: usize = 16;
+
+/// (phi, psi) histogram on a bins x bins grid over [-PI, PI)^2, normalized to
+/// sum to 1 (all zeros for no angles). Row-major with phi along the rows.
+pub fn rama_fingerprint(angles: &[(f64, f64)], bins: usize) -> Vec<f32> {
+    let mut grid = vec![0.0f32; bins * bins];
+    if bins == 0 || angles.is_empty() {
+        return grid;
+    }
+    let cell = |a: f64| (((a + PI) / (2.0 * PI) * bins as f64) as usize).min(bins - 1);
+    for &(phi, psi) in angles {
+        grid[cell(phi) * bins + cell(psi)] += 1.0;
+    }
+    let n = angles.len() as f32;
+    grid.iter_mut().for_each(|v| *v /= n);
+    grid
+}
+
+/// Cosine similarity of two fingerprints; 0.0 for mismatched sizes or empty ones.
+pub fn fingerprint_similarity(a: &[f32], b: &[f32]) -> f64 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f64 = a.iter().zip(b).map(|(x, y)| *x as f64 * *y as f64).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| (*x as f64).powi(2)).sum::<f64>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 { 0.0 } else { dot / norms }
+}
+
+pub fn fingerprint_to_bytes(fingerprint: &[f32]) -> Vec<u8> {
+    fingerprint.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+/// None for a blob whose length is not a whole number of f32 values.
+pub fn fingerprint_from_bytes(bytes: &[u8]) -> Option<Vec<f32>> {
+    if !bytes.len().is_multiple_of(4) {
+        return None;
+    }
+    Some(bytes.chunks_exact(4).map(|c| f32::from_le_bytes(c.try_into().unwrap())).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Angles scattered around a (phi, psi) center, in radians
+    fn cloud(phi: f64, psi: f64, n: usize) -> Vec<(f64, f64)> {
+        (0..n).map(|i| {
+            let t = i as f64 * 2.4;
+            (phi + 0.15 * t.cos(), psi + 0.15 * t.sin())
+        }).collect()
+    }
+
+    #[test]
+    fn test_fingerprint_similarity() {
+        let helix = cloud(-1.1, -0.75, 40);
+        let sheet = cloud(-2.1, 2.3, 40);
+        let fh = rama_fingerprint(&helix, FINGERPRINT_BINS);
+        let fs = rama_fingerprint(&sheet, FINGERPRINT_BINS);
+        assert!((fingerprint_similarity(&fh, &fh) - 1.0).abs() < 1e-6);
+        assert!((fh.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+
+        let mostly_helix: Vec<(f64, f64)> = helix.iter().chain(&sheet[..5]).cloned().collect();
+        let fm = rama_fingerprint(&mostly_helix, FINGERPRINT_BINS);
+        assert!(fingerprint_similarity(&fh, &fs) < 0.1);
+        assert!(fingerprint_similarity(&fh, &fm) > 0.9);
+
+        assert_eq!(fingerprint_from_bytes(&fingerprint_to_bytes(&fh)), Some(fh));
+    }
+}
@@ -0,0 +1,41 @@
+//! Uniform cell grid for fixed-radius neighbour lookup.
+use crate::pdb::Point;
+use std::collections::HashMap;
+
+pub struct SpatialGrid {
+    cell: f64,
+    cells: HashMap<(i64, i64, i64), Vec<usize>>,
+}
+
+impl SpatialGrid {
+    /// Indexes `points`; queries are efficient for radii up to `cell`.
+    pub fn new(points: &[Point], cell: f64) -> Self {
+        let mut cells: HashMap<(i64, i64, i64), Vec<usize>> = HashMap::new();
+        for (i, p) in points.iter().enumerate() {
+            cells.entry(Self::key(p, cell)).or_default().push(i);
+        }
+        Self { cell, cells }
+    }
+
+    fn key(p: &Point, cell: f64) -> (i64, i64, i64) {
+        ((p.x / cell).floor() as i64, (p.y / cell).floor() as i64, (p.z / cell).floor() as i64)
+    }
+
+    /// Indices of all points in cells overlapping the cube of half-width `radius` around `p`.
+    /// Callers still need to check the actual distance.
+    pub fn candidates(&self, p: &Point, radius: f64) -> Vec<usize> {
+        let reach = (radius / self.cell).ceil() as i64;
+        let (cx, cy, cz) = Self::key(p, self.cell);
+        let mut out = Vec::new();
+        for dx in -reach..=reach {
+            for dy in -reach..=reach {
+                for dz in -reach..=reach {
+                    if let Some(idx) = self.cells.get(&(cx + dx, cy + dy, cz + dz)) {
+                        out.extend_from_slice(idx);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
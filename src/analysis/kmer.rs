@@ -0,0 +1,113 @@
+//! Hashed k-mer count profiles for cheap sequence similarity.
+use std::collections::BTreeMap;
+
+/// Number of hash buckets k-mers are folded into
+const KMER_BUCKETS: u32 = 1 << 16;
+
+/// Sparse k-mer count vector: (bucket, count) sorted by bucket.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct KmerProfile {
+    pub k: usize,
+    pub counts: Vec<(u32, u32)>,
+}
+
+// FNV-1a, stable across runs and platforms so stored profiles stay valid
+fn bucket(kmer: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for &b in kmer {
+        hash ^= b as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash % KMER_BUCKETS
+}
+
+pub fn kmer_profile(seq: &str, k: usize) -> KmerProfile {
+    let mut counts: BTreeMap<u32, u32> = BTreeMap::new();
+    if k > 0 {
+        for kmer in seq.as_bytes().windows(k) {
+            *counts.entry(bucket(kmer)).or_default() += 1;
+        }
+    }
+    KmerProfile { k, counts: counts.into_iter().collect() }
+}
+
+impl KmerProfile {
+    /// Compact little-endian encoding: k, then (bucket, count) pairs.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(4 + self.counts.len() * 8);
+        out.extend_from_slice(&(self.k as u32).to_le_bytes());
+        for (b, c) in &self.counts {
+            out.extend_from_slice(&b.to_le_bytes());
+            out.extend_from_slice(&c.to_le_bytes());
+        }
+        out
+    }
+
+    /// None for a truncated or otherwise malformed blob.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 4 || !(bytes.len() - 4).is_multiple_of(8) {
+            return None;
+        }
+        let word = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let counts = (4..bytes.len()).step_by(8).map(|i| (word(i), word(i + 4))).collect();
+        Some(Self { k: word(0) as usize, counts })
+    }
+
+    fn norm(&self) -> f64 {
+        self.counts.iter().map(|(_, c)| (*c as f64).powi(2)).sum::<f64>().sqrt()
+    }
+}
+
+/// Cosine similarity of two profiles in [0, 1]; 0.0 for empty profiles or
+/// profiles built with different k.
+pub fn kmer_similarity(a: &KmerProfile, b: &KmerProfile) -> f64 {
+    if a.k != b.k {
+        return 0.0;
+    }
+    let norms = a.norm() * b.norm();
+    if norms == 0.0 {
+        return 0.0;
+    }
+    let (mut i, mut j, mut dot) = (0, 0, 0.0);
+    while i < a.counts.len() && j < b.counts.len() {
+        let (ba, ca) = a.counts[i];
+        let (bb, cb) = b.counts[j];
+        match ba.cmp(&bb) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                dot += ca as f64 * cb as f64;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    dot / norms
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VH: &str = "EVQLVESGGGLVQPGGSLRLSCAASGFTFSSYAMSWVRQAPGKGLEWVSAISGSGGSTYYADSVKGRFTISRDNSKNTLYLQMNSLRAEDTAVYYCAK";
+
+    #[test]
+    fn test_kmer_similarity() {
+        let a = kmer_profile(VH, 3);
+        assert!((kmer_similarity(&a, &a) - 1.0).abs() < 1e-12);
+
+        let unrelated = kmer_profile("MKWVTFISLLFLFSSAYSRGVFRRDAHKSEVAHRFKDLGEENFKALVLIAFAQYLQQCPFEDHVKL", 3);
+        assert!(kmer_similarity(&a, &unrelated) < 0.1);
+
+        let mut mutated = VH.to_string();
+        mutated.replace_range(40..41, "W");
+        assert!(kmer_similarity(&a, &kmer_profile(&mutated, 3)) > 0.9);
+    }
+
+    #[test]
+    fn test_profile_round_trip() {
+        let p = kmer_profile(VH, 3);
+        assert_eq!(KmerProfile::from_bytes(&p.to_bytes()), Some(p));
+        assert_eq!(KmerProfile::from_bytes(&[1, 2, 3]), None);
+    }
+}
@@ -0,0 +1,83 @@
+//! Small dense symmetric eigen-solver used by superposition and shape analysis.
+
+/// Eigen-decomposition of a symmetric matrix with the cyclic Jacobi method.
+/// Returns eigenvalues in descending order and the matching eigenvectors as columns.
+pub fn symmetric_eigen<const N: usize>(mut a: [[f64; N]; N]) -> ([f64; N], [[f64; N]; N]) {
+    let mut v = [[0.0; N]; N];
+    for (i, row) in v.iter_mut().enumerate() {
+        row[i] = 1.0;
+    }
+
+    for _sweep in 0..100 {
+        let off: f64 = (0..N).flat_map(|i| (0..N).filter(move |&j| j != i).map(move |j| (i, j)))
+            .map(|(i, j)| a[i][j] * a[i][j])
+            .sum();
+        if off < 1e-22 {
+            break;
+        }
+        for p in 0..N {
+            for q in (p + 1)..N {
+                if a[p][q].abs() < 1e-300 {
+                    continue;
+                }
+                let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let t = if theta == 0.0 { 1.0 } else { t };
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+
+                for row in a.iter_mut() {
+                    let akp = row[p];
+                    let akq = row[q];
+                    row[p] = c * akp - s * akq;
+                    row[q] = s * akp + c * akq;
+                }
+                let (row_p, row_q) = (a[p], a[q]);
+                for k in 0..N {
+                    a[p][k] = c * row_p[k] - s * row_q[k];
+                    a[q][k] = s * row_p[k] + c * row_q[k];
+                }
+                for row in v.iter_mut() {
+                    let vkp = row[p];
+                    let vkq = row[q];
+                    row[p] = c * vkp - s * vkq;
+                    row[q] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+
+    // Sort eigenpairs by descending eigenvalue
+    let mut order: Vec<usize> = (0..N).collect();
+    order.sort_by(|&i, &j| a[j][j].partial_cmp(&a[i][i]).unwrap_or(std::cmp::Ordering::Equal));
+    let mut values = [0.0; N];
+    let mut vectors = [[0.0; N]; N];
+    for (col, &k) in order.iter().enumerate() {
+        values[col] = a[k][k];
+        for row in 0..N {
+            vectors[row][col] = v[row][k];
+        }
+    }
+    (values, vectors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symmetric_eigen() {
+        let m = [[2.0, 1.0, 0.0], [1.0, 2.0, 0.0], [0.0, 0.0, 5.0]];
+        let (values, vectors) = symmetric_eigen(m);
+        assert!((values[0] - 5.0).abs() < 1e-9);
+        assert!((values[1] - 3.0).abs() < 1e-9);
+        assert!((values[2] - 1.0).abs() < 1e-9);
+        // A v = lambda v for every column
+        for col in 0..3 {
+            for row in 0..3 {
+                let av: f64 = (0..3).map(|k| m[row][k] * vectors[k][col]).sum();
+                assert!((av - values[col] * vectors[row][col]).abs() < 1e-9);
+            }
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Geometric descriptors of a single loop, aimed at CDR-H3.
+use super::circular::angular_distance;
+use super::{ca_trace, torsion_angle};
+use crate::pdb::{Atom, Pdb, Point, ResidueId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Kinked H3 base: tau101 within this range (degrees)...
+const KINKED_TAU: (f64, f64) = (85.0, 110.0);
+/// ...and alpha101 within this one (degrees)
+const KINKED_ALPHA: (f64, f64) = (0.0, 90.0);
+
+/// Conformation of the H3 C-terminal base (Shirai/Weitzner kinked vs extended).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KinkedOrExtended {
+    Kinked,
+    Extended,
+    /// Fewer than four CA atoms, the pseudo-angles are not defined
+    Undetermined,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoopDescriptors {
+    /// Loop residues, not counting the C-terminal anchor
+    pub length: usize,
+    /// CA(first) to CA(last), in Angstrom
+    pub end_to_end_distance: f64,
+    /// Largest CA-CA distance within the loop, in Angstrom
+    pub max_ca_ca_span: f64,
+    /// CA pseudo-torsions over consecutive residue quadruplets, in radians;
+    /// degenerate (collinear) quadruplets are left out
+    pub torsion_signature: Vec<f64>,
+    pub base_geometry: KinkedOrExtended,
+}
+
+// Pseudo-bond angle a-b-c in degrees
+fn pseudo_angle(a: &Point, b: &Point, c: &Point) -> f64 {
+    let u = a.sub(b).normalize();
+    let v = c.sub(b).normalize();
+    u.dot(&v).clamp(-1.0, 1.0).acos().to_degrees()
+}
+
+/// Descriptors of a loop given its atoms, which should run from the first H3
+/// residue through the conserved W103 anchor: the last four CAs are taken as
+/// 100x, 101, 102 and 103 for the tau101 angle (100x-101-102) and the alpha101
+/// pseudo-dihedral (100x-101-102-103).
+pub fn loop_descriptors(atoms_of_loop: &[Atom]) -> LoopDescriptors {
+    let ca: Vec<Point> = ca_trace(atoms_of_loop).iter().map(|a| a.pos).collect();
+    let n = ca.len();
+
+    let mut max_ca_ca_span: f64 = 0.0;
+    for i in 0..n {
+        for j in (i + 1)..n {
+            max_ca_ca_span = max_ca_ca_span.max(ca[i].distance(&ca[j]));
+        }
+    }
+
+    let torsion_signature = ca.windows(4)
+        .filter_map(|w| torsion_angle(w[0], w[1], w[2], w[3]))
+        .collect();
+
+    let base_geometry = if n < 4 {
+        KinkedOrExtended::Undetermined
+    } else {
+        let (a, b, c, d) = (ca[n - 4], ca[n - 3], ca[n - 2], ca[n - 1]);
+        let tau = pseudo_angle(&a, &b, &c);
+        match torsion_angle(a, b, c, d) {
+            Some(alpha) => {
+                let alpha = alpha.to_degrees();
+                let kinked = (KINKED_TAU.0..=KINKED_TAU.1).contains(&tau)
+                    && (KINKED_ALPHA.0..=KINKED_ALPHA.1).contains(&alpha);
+                if kinked { KinkedOrExtended::Kinked } else { KinkedOrExtended::Extended }
+            }
+            None => KinkedOrExtended::Undetermined,
+        }
+    };
+
+    LoopDescriptors {
+        length: n.saturating_sub(1),
+        end_to_end_distance: if n > 1 { ca[0].distance(&ca[n - 1]) } else { 0.0 },
+        max_ca_ca_span,
+        torsion_signature,
+        base_geometry,
+    }
+}
+
+/// `loop_descriptors` of the given residues of a structure; None if it has
+/// none of them.
+pub fn residue_loop_descriptors(pdb: &Pdb, residues: &HashSet<ResidueId>) -> Option<LoopDescriptors> {
+    let atoms: Vec<Atom> = pdb.atoms.iter()
+        .filter(|a| residues.contains(&ResidueId { chain_id: a.chain_id, res_seq: a.res_seq, i_code: a.i_code }))
+        .cloned()
+        .collect();
+    (!atoms.is_empty()).then(|| loop_descriptors(&atoms))
+}
+
+impl LoopDescriptors {
+    /// Dissimilarity of two loops: length difference in residues, distance
+    /// differences in Angstrom, mean pseudo-torsion difference in radians over
+    /// the C-terminal aligned signature, and 1.0 for a different base.
+    pub fn distance(&self, other: &LoopDescriptors) -> f64 {
+        let length = self.length.abs_diff(other.length) as f64;
+        let geometry = (self.end_to_end_distance - other.end_to_end_distance).abs()
+            + (self.max_ca_ca_span - other.max_ca_ca_span).abs();
+
+        // Align signatures at the base, where H3 conformations are most conserved
+        let paired: Vec<f64> = self.torsion_signature.iter().rev()
+            .zip(other.torsion_signature.iter().rev())
+            .map(|(a, b)| angular_distance(*a, *b))
+            .collect();
+        let torsion = if paired.is_empty() { 0.0 } else { paired.iter().sum::<f64>() / paired.len() as f64 };
+
+        let base = if self.base_geometry == other.base_geometry { 0.0 } else { 1.0 };
+        length + geometry + torsion + base
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CA_CA: f64 = 3.8;
+
+    // Next point at bond length r with angle b-c-d = theta and torsion a-b-c-d = phi
+    fn place(a: Point, b: Point, c: Point, theta: f64, phi: f64) -> Point {
+        let bc = c.sub(&b).normalize();
+        let n = b.sub(&a).cross(&bc).normalize();
+        let m = n.cross(&bc);
+        let (x, y, z) = (-CA_CA * theta.cos(), CA_CA * theta.sin() * phi.cos(), CA_CA * theta.sin() * phi.sin());
+        let offset = Point::new(
+            bc.x * x + m.x * y + n.x * z,
+            bc.y * x + m.y * y + n.y * z,
+            bc.z * x + m.z * y + n.z * z,
+        );
+        c.add(&offset)
+    }
+
+    // CA-only loop from (pseudo-angle, pseudo-torsion) pairs in degrees
+    fn build_loop(angles: &[(f64, f64)]) -> Vec<Atom> {
+        let mut ca = vec![Point::new(0.0, 0.0, 0.0), Point::new(CA_CA, 0.0, 0.0), Point::new(CA_CA * 1.5, CA_CA * 0.866, 0.0)];
+        for &(theta, phi) in angles {
+            let k = ca.len();
+            ca.push(place(ca[k - 3], ca[k - 2], ca[k - 1], theta.to_radians(), phi.to_radians()));
+        }
+        ca.iter().enumerate().map(|(i, &pos)| Atom {
+            serial: i as i32 + 1, name: "CA".into(), alt_loc: ' ', res_name: "GLY".into(),
+            chain_id: 'H', res_seq: 95 + i as i32, i_code: ' ',
+            pos, occupancy: 1.0, temp_factor: 0.0, element: "C".into()
+        }).collect()
+    }
+
+    #[test]
+    fn test_extended_loop() {
+        let atoms = build_loop(&[(120.0, 180.0); 6]);
+        let d = loop_descriptors(&atoms);
+        assert_eq!(d.length, 8);
+        assert_eq!(d.base_geometry, KinkedOrExtended::Extended);
+        assert_eq!(d.torsion_signature.len(), 6);
+        assert!((d.end_to_end_distance - d.max_ca_ca_span).abs() < 1e-9);
+        assert!(d.end_to_end_distance > 20.0);
+    }
+
+    #[test]
+    fn test_kinked_loop() {
+        // The second-to-last pair sets tau101 (angle at 101), the last one alpha101
+        let atoms = build_loop(&[(90.0, 50.0), (90.0, 50.0), (90.0, 50.0), (100.0, 50.0), (100.0, 40.0)]);
+        let bent = loop_descriptors(&atoms);
+        assert_eq!(bent.base_geometry, KinkedOrExtended::Kinked);
+
+        let extended = loop_descriptors(&build_loop(&[(120.0, 180.0); 5]));
+        assert!(bent.end_to_end_distance < extended.end_to_end_distance);
+        assert_eq!(bent.distance(&bent), 0.0);
+        assert!(bent.distance(&extended) > 1.0);
+        assert_eq!(loop_descriptors(&atoms[..3]).base_geometry, KinkedOrExtended::Undetermined);
+    }
+}
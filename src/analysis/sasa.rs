@@ -0,0 +1,198 @@
+//! Solvent accessible surface area with the Shrake–Rupley method.
+use super::grid::SpatialGrid;
+use crate::pdb::{Atom, Point, ResidueId};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+/// Bondi van der Waals radius for an element symbol.
+pub fn vdw_radius(element: &str) -> f64 {
+    match element {
+        "H" | "D" => 1.20,
+        "C" => 1.70,
+        "N" => 1.55,
+        "O" => 1.52,
+        "S" => 1.80,
+        "P" => 1.80,
+        "SE" | "Se" => 1.90,
+        _ => 1.80,
+    }
+}
+
+// Element column is optional in older files; fall back to the atom name
+fn element_of(atom: &Atom) -> &str {
+    if !atom.element.is_empty() {
+        &atom.element
+    } else {
+        atom.name.get(..1).unwrap_or("")
+    }
+}
+
+// Evenly spread unit vectors (golden section spiral)
+fn sphere_points(n: usize) -> Vec<Point> {
+    let inc = PI * (3.0 - 5f64.sqrt());
+    let offset = 2.0 / n as f64;
+    (0..n).map(|k| {
+        let y = k as f64 * offset - 1.0 + offset / 2.0;
+        let r = (1.0 - y * y).sqrt();
+        let phi = k as f64 * inc;
+        Point::new(phi.cos() * r, y, phi.sin() * r)
+    }).collect()
+}
+
+/// Per-atom accessible area in A^2. Hydrogens are ignored (area 0).
+pub fn atom_sasa(atoms: &[Atom], probe_radius: f64, n_points: usize) -> Vec<f64> {
+    let heavy: Vec<usize> = (0..atoms.len())
+        .filter(|&i| !matches!(element_of(&atoms[i]), "H" | "D"))
+        .collect();
+    let centers: Vec<Point> = heavy.iter().map(|&i| atoms[i].pos).collect();
+    let radii: Vec<f64> = heavy.iter().map(|&i| vdw_radius(element_of(&atoms[i])) + probe_radius).collect();
+    let max_radius = radii.iter().cloned().fold(0.0, f64::max);
+    let grid = SpatialGrid::new(&centers, 2.0 * max_radius);
+    let sphere = sphere_points(n_points.max(1));
+
+    let areas: Vec<f64> = (0..centers.len()).into_par_iter().map(|i| {
+        let ri = radii[i];
+        let neighbours: Vec<usize> = grid.candidates(&centers[i], ri + max_radius)
+            .into_iter()
+            .filter(|&j| j != i && centers[i].distance(&centers[j]) < ri + radii[j])
+            .collect();
+
+        let accessible = sphere.iter().filter(|u| {
+            let p = Point::new(centers[i].x + u.x * ri, centers[i].y + u.y * ri, centers[i].z + u.z * ri);
+            neighbours.iter().all(|&j| p.distance(&centers[j]) >= radii[j])
+        }).count();
+
+        4.0 * PI * ri * ri * accessible as f64 / sphere.len() as f64
+    }).collect();
+
+    let mut out = vec![0.0; atoms.len()];
+    for (k, &i) in heavy.iter().enumerate() {
+        out[i] = areas[k];
+    }
+    out
+}
+
+/// Per-residue SASA in A^2, in order of first appearance.
+pub fn sasa(atoms: &[Atom], probe_radius: f64, n_points: usize) -> Vec<(ResidueId, f64)> {
+    let per_atom = atom_sasa(atoms, probe_radius, n_points);
+    let mut order = Vec::new();
+    let mut totals: HashMap<ResidueId, f64> = HashMap::new();
+    for (atom, area) in atoms.iter().zip(per_atom) {
+        let id = ResidueId { chain_id: atom.chain_id, res_seq: atom.res_seq, i_code: atom.i_code };
+        let total = totals.entry(id).or_insert_with(|| {
+            order.push(id);
+            0.0
+        });
+        *total += area;
+    }
+    order.into_iter().map(|id| (id, totals[&id])).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn atom(element: &str, res_seq: i32, x: f64) -> Atom {
+        Atom {
+            serial: res_seq, name: element.into(), alt_loc: ' ', res_name: "ALA".into(),
+            chain_id: 'A', res_seq, i_code: ' ',
+            pos: Point::new(x, 0.0, 0.0), occupancy: 1.0, temp_factor: 0.0, element: element.into()
+        }
+    }
+
+    #[test]
+    fn test_isolated_atom() {
+        let res = sasa(&[atom("C", 1, 0.0)], 1.4, 960);
+        let expected = 4.0 * PI * (1.7f64 + 1.4).powi(2);
+        assert!((res[0].1 - expected).abs() / expected < 0.01);
+    }
+
+    #[test]
+    fn test_two_overlapping_atoms_match_analytic_caps() {
+        // Exposed area of sphere i overlapping sphere j: 4 pi R^2 - 2 pi R h,
+        // with cap height h = R - (d^2 + R^2 - Rj^2) / (2d).
+        let d = 3.0;
+        let atoms = [atom("C", 1, 0.0), atom("O", 2, d)];
+        let (r1, r2) = (1.7 + 1.4, 1.52 + 1.4);
+        let cap = |r: f64, other: f64| r - (d * d + r * r - other * other) / (2.0 * d);
+        let expected = [
+            4.0 * PI * r1 * r1 - 2.0 * PI * r1 * cap(r1, r2),
+            4.0 * PI * r2 * r2 - 2.0 * PI * r2 * cap(r2, r1),
+        ];
+
+        let res = sasa(&atoms, 1.4, 2000);
+        for (got, want) in res.iter().map(|r| r.1).zip(expected) {
+            assert!((got - want).abs() / want < 0.03, "{} vs {}", got, want);
+        }
+    }
+
+    // Places an atom `bond` from `c`, at `angle` B-C-D and `torsion` A-B-C-D
+    // (degrees), by the natural extension reference frame
+    fn place(a: Point, b: Point, c: Point, bond: f64, angle: f64, torsion: f64) -> Point {
+        let (angle, torsion) = (angle.to_radians(), torsion.to_radians());
+        let bc = c.sub(&b).normalize();
+        let n = b.sub(&a).cross(&bc).normalize();
+        let m = n.cross(&bc);
+        let d = [-bond * angle.cos(), bond * angle.sin() * torsion.cos(), bond * angle.sin() * torsion.sin()];
+        c.add(&Point::new(
+            bc.x * d[0] + m.x * d[1] + n.x * d[2],
+            bc.y * d[0] + m.y * d[1] + n.y * d[2],
+            bc.z * d[0] + m.z * d[1] + n.z * d[2],
+        ))
+    }
+
+    // Gly-Ala-Gly with phi = psi = omega = 180, Engh & Huber bond lengths and angles
+    fn extended_tripeptide() -> Vec<Atom> {
+        let mut atoms = Vec::new();
+        let mut push = |name: &str, res_name: &str, res_seq: i32, pos: Point| {
+            let mut a = atom(&name[..1], res_seq, 0.0);
+            a.name = name.into();
+            a.res_name = res_name.into();
+            a.pos = pos;
+            atoms.push(a);
+        };
+        let mut n = Point::new(0.0, 0.0, 0.0);
+        let mut ca = Point::new(1.458, 0.0, 0.0);
+        let mut c = place(Point::new(0.0, 1.0, 0.0), n, ca, 1.525, 111.2, 180.0);
+        for (res_seq, res_name) in [(1, "GLY"), (2, "ALA"), (3, "GLY")] {
+            if res_seq > 1 {
+                let next_n = place(n, ca, c, 1.329, 116.2, 180.0);
+                let next_ca = place(ca, c, next_n, 1.458, 121.7, 180.0);
+                let next_c = place(c, next_n, next_ca, 1.525, 111.2, 180.0);
+                (n, ca, c) = (next_n, next_ca, next_c);
+            }
+            let next_n = place(n, ca, c, 1.329, 116.2, 180.0);
+            push("N", res_name, res_seq, n);
+            push("CA", res_name, res_seq, ca);
+            push("C", res_name, res_seq, c);
+            push("O", res_name, res_seq, place(next_n, ca, c, 1.231, 120.1, 180.0));
+            if res_name == "ALA" {
+                push("CB", res_name, res_seq, place(c, n, ca, 1.521, 110.4, -122.6));
+            }
+        }
+        atoms
+    }
+
+    #[test]
+    fn test_gly_ala_gly() {
+        let atoms = extended_tripeptide();
+        let residues = crate::pdb::group_residues(&atoms);
+        assert!(residues.windows(2).all(|w| w[0].is_bonded_to(&w[1])));
+        // Ala in extended Gly-X-Gly: 113 A^2 (Miller et al., J. Mol. Biol. 196:641, 1987)
+        let ala = sasa(&atoms, 1.4, 2000)[1].1;
+        assert!((ala - 113.0).abs() / 113.0 < 0.05, "{}", ala);
+    }
+
+    #[test]
+    fn test_buried_atom_has_no_area() {
+        let mut atoms = vec![atom("C", 1, 0.0)];
+        for (k, (x, y, z)) in [(1.5, 0.0, 0.0), (-1.5, 0.0, 0.0), (0.0, 1.5, 0.0), (0.0, -1.5, 0.0), (0.0, 0.0, 1.5), (0.0, 0.0, -1.5)].into_iter().enumerate() {
+            let mut a = atom("C", k as i32 + 2, 0.0);
+            a.pos = Point::new(x, y, z);
+            atoms.push(a);
+        }
+        let per_atom = atom_sasa(&atoms, 1.4, 500);
+        assert!(per_atom[0] < 1.0);
+    }
+}
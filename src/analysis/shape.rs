@@ -0,0 +1,75 @@
+//! Global shape from the gyration tensor.
+use super::linalg::symmetric_eigen;
+use crate::pdb::Point;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct Shape {
+    /// Radius of gyration, in Angstrom
+    pub rg: f64,
+    /// l1 - (l2 + l3) / 2; zero for spherically symmetric point sets
+    pub asphericity: f64,
+    /// l2 - l3; zero for cylindrically symmetric point sets
+    pub acylindricity: f64,
+    /// Gyration tensor eigenvalues l1 >= l2 >= l3, in A^2
+    pub principal_moments: [f64; 3],
+}
+
+/// Shape descriptors of a point cloud (typically the Fv CA atoms).
+/// All zero for an empty input.
+pub fn shape_descriptors(points: &[Point]) -> Shape {
+    if points.is_empty() {
+        return Shape::default();
+    }
+    let n = points.len() as f64;
+    let sum = points.iter().fold(Point::new(0.0, 0.0, 0.0), |acc, p| acc.add(p));
+    let center = Point::new(sum.x / n, sum.y / n, sum.z / n);
+
+    let mut tensor = [[0.0; 3]; 3];
+    for p in points {
+        let d = p.sub(&center);
+        let d = [d.x, d.y, d.z];
+        for i in 0..3 {
+            for j in 0..3 {
+                tensor[i][j] += d[i] * d[j] / n;
+            }
+        }
+    }
+
+    let (moments, _) = symmetric_eigen(tensor);
+    let [l1, l2, l3] = moments.map(|l| l.max(0.0));
+    Shape {
+        rg: (l1 + l2 + l3).sqrt(),
+        asphericity: l1 - (l2 + l3) / 2.0,
+        acylindricity: l2 - l3,
+        principal_moments: [l1, l2, l3],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    #[test]
+    fn test_rod_vs_sphere() {
+        let rod: Vec<Point> = (0..50).map(|i| Point::new(i as f64, 0.0, 0.0)).collect();
+        let rod_shape = shape_descriptors(&rod);
+        assert!(rod_shape.principal_moments[1].abs() < 1e-9);
+        assert!((rod_shape.asphericity - rod_shape.principal_moments[0]).abs() < 1e-9);
+        assert!(rod_shape.acylindricity.abs() < 1e-9);
+
+        // Golden-spiral points on a sphere of radius 10
+        let n = 500;
+        let sphere: Vec<Point> = (0..n).map(|i| {
+            let z = 1.0 - 2.0 * (i as f64 + 0.5) / n as f64;
+            let r = (1.0 - z * z).sqrt();
+            let phi = i as f64 * PI * (3.0 - 5f64.sqrt());
+            Point::new(10.0 * r * phi.cos(), 10.0 * r * phi.sin(), 10.0 * z)
+        }).collect();
+        let sphere_shape = shape_descriptors(&sphere);
+        assert!((sphere_shape.rg - 10.0).abs() < 1e-6);
+        assert!(sphere_shape.asphericity < 0.01 * sphere_shape.rg.powi(2));
+        assert!(sphere_shape.acylindricity < 0.01 * sphere_shape.rg.powi(2));
+    }
+}
@@ -0,0 +1,233 @@
+//! Global structural similarity scores over sequence-aligned CA pairs.
+use super::{ca_trace, chain_residues, kabsch, pair_atoms, qcp_rmsd, RmsdError, Superposition};
+use crate::pdb::{Pdb, Point, ResidueId};
+use serde::Serialize;
+
+/// GDT_TS distance cutoffs, in Angstrom
+const GDT_CUTOFFS: [f64; 4] = [1.0, 2.0, 4.0, 8.0];
+/// Refinement rounds per seed superposition
+const MAX_REFINE: usize = 20;
+/// Shortest fragment used to seed a superposition
+const MIN_SEED: usize = 4;
+/// lDDT distance-difference thresholds, in Angstrom
+const LDDT_THRESHOLDS: [f64; 4] = [0.5, 1.0, 2.0, 4.0];
+/// Conventional lDDT inclusion radius, in Angstrom
+pub const LDDT_INCLUSION_RADIUS: f64 = 15.0;
+
+fn paired_ca(reference: &Pdb, model: &Pdb, chain_map: &[(String, String)]) -> (Vec<Point>, Vec<Point>) {
+    pair_atoms(reference, model, chain_map, &["CA"]).into_iter()
+        .map(|(r, m)| (r.pos, m.pos))
+        .unzip()
+}
+
+/// Number of reference residues with a CA in the mapped chains; TM-score and
+/// GDT are normalized by it so that unaligned residues count against the model.
+fn reference_length(reference: &Pdb, chain_map: &[(String, String)]) -> usize {
+    chain_map.iter()
+        .map(|(chain, _)| chain_residues(reference, chain).iter().filter(|r| r.atom("CA").is_some()).count())
+        .sum()
+}
+
+/// CA RMSD after optimal superposition of the aligned chains.
+pub fn ca_rmsd(reference: &Pdb, model: &Pdb, chain_map: &[(String, String)]) -> Result<f64, RmsdError> {
+    let (r, m) = paired_ca(reference, model, chain_map);
+    if r.is_empty() {
+        return Err(RmsdError::EmptyInput);
+    }
+    qcp_rmsd(&r, &m).ok_or(RmsdError::EmptyInput)
+}
+
+/// Best value of `score` over a family of superpositions: the global fit and
+/// fits on sliding fragments, each refined by refitting on the pairs that lie
+/// within `cutoff` (as in the TM-score and LGA programs).
+fn best_over_superpositions(r: &[Point], m: &[Point], cutoff: f64, score: impl Fn(&[f64]) -> f64) -> f64 {
+    let n = r.len();
+    let mut seeds: Vec<(usize, usize)> = vec![(0, n)];
+    let mut len = n / 2;
+    while len >= MIN_SEED {
+        let step = (len / 2).max(1);
+        let mut start = 0;
+        while start + len <= n {
+            seeds.push((start, start + len));
+            start += step;
+        }
+        len /= 2;
+    }
+
+    let distances = |sup: &Superposition| -> Vec<f64> {
+        r.iter().zip(m).map(|(a, b)| sup.apply(b).distance(a)).collect()
+    };
+
+    let mut best = 0.0_f64;
+    for (lo, hi) in seeds {
+        let Some(mut sup) = kabsch(&r[lo..hi], &m[lo..hi]) else { continue };
+        let mut previous: Vec<usize> = Vec::new();
+        for _ in 0..MAX_REFINE {
+            let d = distances(&sup);
+            best = best.max(score(&d));
+            let close: Vec<usize> = (0..n).filter(|&k| d[k] <= cutoff).collect();
+            if close.len() < 3 || close == previous {
+                break;
+            }
+            let rs: Vec<Point> = close.iter().map(|&k| r[k]).collect();
+            let ms: Vec<Point> = close.iter().map(|&k| m[k]).collect();
+            let Some(next) = kabsch(&rs, &ms) else { break };
+            sup = next;
+            previous = close;
+        }
+    }
+    best
+}
+
+/// TM-score of `model` against `reference`, normalized by the reference length.
+/// 1.0 for identical structures, about 0.17 for unrelated ones.
+pub fn tm_score(reference: &Pdb, model: &Pdb, chain_map: &[(String, String)]) -> f64 {
+    let len = reference_length(reference, chain_map);
+    let (r, m) = paired_ca(reference, model, chain_map);
+    if len == 0 || r.is_empty() {
+        return 0.0;
+    }
+    let d0 = if len > 21 { (1.24 * ((len - 15) as f64).cbrt() - 1.8).max(0.5) } else { 0.5 };
+    best_over_superpositions(&r, &m, d0, |d| {
+        d.iter().map(|x| 1.0 / (1.0 + (x / d0).powi(2))).sum::<f64>() / len as f64
+    })
+}
+
+/// GDT_TS as a fraction: mean over 1, 2, 4 and 8 A of the largest share of
+/// reference residues placed within the cutoff by some superposition.
+pub fn gdt_ts(reference: &Pdb, model: &Pdb, chain_map: &[(String, String)]) -> f64 {
+    let len = reference_length(reference, chain_map);
+    let (r, m) = paired_ca(reference, model, chain_map);
+    if len == 0 || r.is_empty() {
+        return 0.0;
+    }
+    let total: f64 = GDT_CUTOFFS.iter().map(|&c| {
+        best_over_superpositions(&r, &m, c, |d| d.iter().filter(|&&x| x <= c).count() as f64 / len as f64)
+    }).sum();
+    total / GDT_CUTOFFS.len() as f64
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LddtScore {
+    pub global: f64,
+    /// Keyed by reference residue; residues without neighbours are left out
+    pub per_residue: Vec<(ResidueId, f64)>,
+}
+
+/// CA-only lDDT of `model` against `reference`. Every reference CA pair closer
+/// than `inclusion_radius` is checked for being preserved in the model within
+/// 0.5, 1, 2 and 4 A; pairs involving residues missing from `pairing` count as
+/// not preserved. `pairing` indexes into the CA traces of both structures.
+pub fn lddt(reference: &Pdb, model: &Pdb, pairing: &[(usize, usize)], inclusion_radius: f64) -> LddtScore {
+    let r_ca = ca_trace(&reference.atoms);
+    let m_ca = ca_trace(&model.atoms);
+    let mut to_model = vec![None; r_ca.len()];
+    for &(i, j) in pairing {
+        if i < r_ca.len() && j < m_ca.len() {
+            to_model[i] = Some(j);
+        }
+    }
+
+    let mut preserved_total = 0.0;
+    let mut checked_total = 0;
+    let mut per_residue = Vec::new();
+    for i in 0..r_ca.len() {
+        let mut preserved = 0.0;
+        let mut checked = 0;
+        for k in 0..r_ca.len() {
+            let d_ref = r_ca[i].pos.distance(&r_ca[k].pos);
+            if k == i || d_ref >= inclusion_radius {
+                continue;
+            }
+            checked += 1;
+            if let (Some(mi), Some(mk)) = (to_model[i], to_model[k]) {
+                let diff = (m_ca[mi].pos.distance(&m_ca[mk].pos) - d_ref).abs();
+                preserved += LDDT_THRESHOLDS.iter().filter(|&&t| diff < t).count() as f64 / LDDT_THRESHOLDS.len() as f64;
+            }
+        }
+        if checked > 0 {
+            let a = &r_ca[i];
+            let id = ResidueId { chain_id: a.chain_id, res_seq: a.res_seq, i_code: a.i_code };
+            per_residue.push((id, preserved / checked as f64));
+            preserved_total += preserved;
+            checked_total += checked;
+        }
+    }
+
+    let global = if checked_total > 0 { preserved_total / checked_total as f64 } else { 0.0 };
+    LddtScore { global, per_residue }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdb::Atom;
+
+    /// CA-only alpha helix, 3.6 residues per turn.
+    fn helix(chain_id: char, n: usize) -> Vec<Atom> {
+        (0..n).map(|i| {
+            let t = i as f64 * 100f64.to_radians();
+            Atom {
+                serial: i as i32 + 1, name: "CA".into(), alt_loc: ' ', res_name: "ALA".into(),
+                chain_id, res_seq: i as i32 + 1, i_code: ' ',
+                pos: Point::new(2.3 * t.cos(), 2.3 * t.sin(), 1.5 * i as f64),
+                occupancy: 1.0, temp_factor: 0.0, element: "C".into()
+            }
+        }).collect()
+    }
+
+    fn map(a: &str, b: &str) -> Vec<(String, String)> {
+        vec![(a.to_string(), b.to_string())]
+    }
+
+    #[test]
+    fn test_identical_structures() {
+        let a = Pdb { atoms: helix('H', 30) };
+        let mut moved = helix('A', 30);
+        for atom in moved.iter_mut() {
+            atom.pos = Point::new(-atom.pos.y + 5.0, atom.pos.x, atom.pos.z - 3.0);
+        }
+        let b = Pdb { atoms: moved };
+        let chains = map("H", "A");
+        assert!(ca_rmsd(&a, &b, &chains).unwrap() < 1e-6);
+        assert!((tm_score(&a, &b, &chains) - 1.0).abs() < 1e-9);
+        assert!((gdt_ts(&a, &b, &chains) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_lddt() {
+        let a = Pdb { atoms: helix('H', 30) };
+        let pairing: Vec<(usize, usize)> = (0..30).map(|i| (i, i)).collect();
+        let same = lddt(&a, &a, &pairing, LDDT_INCLUSION_RADIUS);
+        assert!((same.global - 1.0).abs() < 1e-12);
+        assert_eq!(same.per_residue.len(), 30);
+
+        // Pull residue 10 out by 6 A: only distances involving it suffer
+        let mut perturbed = helix('H', 30);
+        perturbed[10].pos.x += 6.0;
+        let b = Pdb { atoms: perturbed };
+        let score = lddt(&a, &b, &pairing, LDDT_INCLUSION_RADIUS);
+        assert!(score.global < 1.0 && score.global > 0.9);
+        let worst = score.per_residue.iter().min_by(|x, y| x.1.total_cmp(&y.1)).unwrap();
+        assert_eq!(worst.0.res_seq, 11);
+        assert!(worst.1 < 0.5, "worst = {}", worst.1);
+        assert_eq!(score.per_residue[25].1, 1.0);
+    }
+
+    #[test]
+    fn test_partial_match_is_penalized() {
+        let a = Pdb { atoms: helix('H', 30) };
+        let mut bent = helix('H', 30);
+        for atom in bent.iter_mut().skip(15) {
+            atom.pos.x += 10.0;
+        }
+        let b = Pdb { atoms: bent };
+        let chains = map("H", "H");
+        let tm = tm_score(&a, &b, &chains);
+        let gdt = gdt_ts(&a, &b, &chains);
+        // The better-fitting half still superposes exactly
+        assert!((0.45..0.9).contains(&tm), "tm = {}", tm);
+        assert!((0.5..1.0).contains(&gdt), "gdt = {}", gdt);
+        assert_eq!(ca_rmsd(&a, &Pdb { atoms: Vec::new() }, &chains), Err(RmsdError::EmptyInput));
+    }
+}
@@ -0,0 +1,269 @@
+//! Optimal rigid-body superposition of paired point sets.
+use super::linalg::symmetric_eigen;
+use crate::pdb::Point;
+
+/// Rigid transform mapping mobile coordinates onto the reference frame.
+#[derive(Debug, Clone, Copy)]
+pub struct Superposition {
+    pub rotation: [[f64; 3]; 3],
+    pub mobile_center: Point,
+    pub reference_center: Point,
+    /// RMSD after superposition
+    pub rmsd: f64,
+}
+
+impl Superposition {
+    pub fn apply(&self, p: &Point) -> Point {
+        let q = p.sub(&self.mobile_center);
+        let r = &self.rotation;
+        Point::new(
+            r[0][0] * q.x + r[0][1] * q.y + r[0][2] * q.z,
+            r[1][0] * q.x + r[1][1] * q.y + r[1][2] * q.z,
+            r[2][0] * q.x + r[2][1] * q.y + r[2][2] * q.z,
+        ).add(&self.reference_center)
+    }
+}
+
+fn centroid(points: &[Point], weights: &[f64], total: f64) -> Point {
+    let sum = points.iter().zip(weights).fold(Point::new(0.0, 0.0, 0.0), |acc, (p, w)| {
+        acc.add(&Point::new(p.x * w, p.y * w, p.z * w))
+    });
+    Point::new(sum.x / total, sum.y / total, sum.z / total)
+}
+
+/// Least-squares superposition of `mobile` onto `reference` (Kabsch problem,
+/// solved through the quaternion eigen-formulation so reflections never occur).
+/// None for empty or mismatched inputs.
+pub fn kabsch(reference: &[Point], mobile: &[Point]) -> Option<Superposition> {
+    kabsch_weighted(reference, mobile, &vec![1.0; reference.len()])
+}
+
+/// Weighted Kabsch: minimizes sum w_i |R m_i + t - r_i|^2. The reported RMSD is
+/// normalized by the weight sum. None for mismatched inputs or zero total weight.
+pub fn kabsch_weighted(reference: &[Point], mobile: &[Point], weights: &[f64]) -> Option<Superposition> {
+    if reference.is_empty() || reference.len() != mobile.len() || weights.len() != reference.len() {
+        return None;
+    }
+    let total: f64 = weights.iter().sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let rc = centroid(reference, weights, total);
+    let mc = centroid(mobile, weights, total);
+
+    // Cross-covariance S = sum w (m - mc)(r - rc)^T
+    let mut s = [[0.0; 3]; 3];
+    for ((r, m), w) in reference.iter().zip(mobile).zip(weights) {
+        let a = m.sub(&mc);
+        let b = r.sub(&rc);
+        let (a, b) = ([a.x, a.y, a.z], [b.x, b.y, b.z]);
+        for i in 0..3 {
+            for j in 0..3 {
+                s[i][j] += w * a[i] * b[j];
+            }
+        }
+    }
+
+    let (sxx, sxy, sxz) = (s[0][0], s[0][1], s[0][2]);
+    let (syx, syy, syz) = (s[1][0], s[1][1], s[1][2]);
+    let (szx, szy, szz) = (s[2][0], s[2][1], s[2][2]);
+    let key = [
+        [sxx + syy + szz, syz - szy, szx - sxz, sxy - syx],
+        [syz - szy, sxx - syy - szz, sxy + syx, szx + sxz],
+        [szx - sxz, sxy + syx, -sxx + syy - szz, syz + szy],
+        [sxy - syx, szx + sxz, syz + szy, -sxx - syy + szz],
+    ];
+    let (_, vectors) = symmetric_eigen(key);
+    let (q0, q1, q2, q3) = (vectors[0][0], vectors[1][0], vectors[2][0], vectors[3][0]);
+
+    let rotation = [
+        [q0 * q0 + q1 * q1 - q2 * q2 - q3 * q3, 2.0 * (q1 * q2 - q0 * q3), 2.0 * (q1 * q3 + q0 * q2)],
+        [2.0 * (q1 * q2 + q0 * q3), q0 * q0 - q1 * q1 + q2 * q2 - q3 * q3, 2.0 * (q2 * q3 - q0 * q1)],
+        [2.0 * (q1 * q3 - q0 * q2), 2.0 * (q2 * q3 + q0 * q1), q0 * q0 - q1 * q1 - q2 * q2 + q3 * q3],
+    ];
+
+    let mut sup = Superposition { rotation, mobile_center: mc, reference_center: rc, rmsd: 0.0 };
+    let sum_sq: f64 = reference.iter().zip(mobile).zip(weights)
+        .map(|((r, m), w)| w * sup.apply(m).distance(r).powi(2))
+        .sum();
+    sup.rmsd = (sum_sq / total).sqrt();
+    Some(sup)
+}
+
+/// Newton iterations for the largest key-matrix eigenvalue in `qcp_rmsd`
+const QCP_MAX_ITER: usize = 50;
+const QCP_PRECISION: f64 = 1e-11;
+
+/// Optimal superposition RMSD by Theobald's QCP method: the largest eigenvalue
+/// of the key matrix is found by Newton iteration on its characteristic
+/// polynomial, so no rotation is built. Use `kabsch` when the transform itself
+/// is needed. None for empty or mismatched inputs.
+pub fn qcp_rmsd(reference: &[Point], mobile: &[Point]) -> Option<f64> {
+    if reference.is_empty() || reference.len() != mobile.len() {
+        return None;
+    }
+    let n = reference.len() as f64;
+    let ones = vec![1.0; reference.len()];
+    let rc = centroid(reference, &ones, n);
+    let mc = centroid(mobile, &ones, n);
+
+    let mut g = 0.0;
+    let mut s = [[0.0; 3]; 3];
+    for (r, m) in reference.iter().zip(mobile) {
+        let a = m.sub(&mc);
+        let b = r.sub(&rc);
+        g += a.dot(&a) + b.dot(&b);
+        let (a, b) = ([a.x, a.y, a.z], [b.x, b.y, b.z]);
+        for i in 0..3 {
+            for j in 0..3 {
+                s[i][j] += a[i] * b[j];
+            }
+        }
+    }
+    let e0 = g / 2.0;
+
+    let (sxx, sxy, sxz) = (s[0][0], s[0][1], s[0][2]);
+    let (syx, syy, syz) = (s[1][0], s[1][1], s[1][2]);
+    let (szx, szy, szz) = (s[2][0], s[2][1], s[2][2]);
+    let (sxx2, syy2, szz2) = (sxx * sxx, syy * syy, szz * szz);
+    let (sxy2, syz2, sxz2) = (sxy * sxy, syz * syz, sxz * sxz);
+    let (syx2, szy2, szx2) = (syx * syx, szy * szy, szx * szx);
+    let syzszymsyyszz2 = 2.0 * (syz * szy - syy * szz);
+    let sxx2syy2szz2syz2szy2 = syy2 + szz2 - sxx2 + syz2 + szy2;
+    let (sxzpszx, syzpszy, sxypsyx) = (sxz + szx, syz + szy, sxy + syx);
+    let (syzmszy, sxzmszx, sxymsyx) = (syz - szy, sxz - szx, sxy - syx);
+    let (sxxpsyy, sxxmsyy) = (sxx + syy, sxx - syy);
+    let sxy2sxz2syx2szx2 = sxy2 + sxz2 - syx2 - szx2;
+
+    // Characteristic polynomial x^4 + c2 x^2 + c1 x + c0 of the key matrix
+    let c2 = -2.0 * (sxx2 + syy2 + szz2 + sxy2 + syx2 + sxz2 + szx2 + syz2 + szy2);
+    let c1 = 8.0 * (sxx * syz * szy + syy * szx * sxz + szz * sxy * syx
+        - sxx * syy * szz - syz * szx * sxy - szy * syx * sxz);
+    let c0 = sxy2sxz2syx2szx2 * sxy2sxz2syx2szx2
+        + (sxx2syy2szz2syz2szy2 + syzszymsyyszz2) * (sxx2syy2szz2syz2szy2 - syzszymsyyszz2)
+        + (-sxzpszx * syzmszy + sxymsyx * (sxxmsyy - szz)) * (-sxzmszx * syzpszy + sxymsyx * (sxxmsyy + szz))
+        + (-sxzpszx * syzpszy - sxypsyx * (sxxpsyy - szz)) * (-sxzmszx * syzmszy - sxypsyx * (sxxpsyy + szz))
+        + (sxypsyx * syzpszy + sxzpszx * (sxxmsyy + szz)) * (-sxymsyx * syzmszy + sxzpszx * (sxxpsyy + szz))
+        + (sxypsyx * syzmszy + sxzmszx * (sxxmsyy - szz)) * (-sxymsyx * syzpszy + sxzmszx * (sxxpsyy - szz));
+
+    // E0 bounds the largest eigenvalue from above, so Newton converges onto it
+    let mut lambda = e0;
+    for _ in 0..QCP_MAX_ITER {
+        let previous = lambda;
+        let x2 = lambda * lambda;
+        let b = (x2 + c2) * lambda;
+        let a = b + c1;
+        let denom = 2.0 * x2 * lambda + b + a;
+        if denom == 0.0 {
+            break;
+        }
+        lambda -= (a * lambda + c0) / denom;
+        if (lambda - previous).abs() < (QCP_PRECISION * lambda).abs() {
+            break;
+        }
+    }
+
+    Some((2.0 * (e0 - lambda) / n).max(0.0).sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn rotate_z(p: &Point, angle: f64) -> Point {
+        Point::new(p.x * angle.cos() - p.y * angle.sin(), p.x * angle.sin() + p.y * angle.cos(), p.z)
+    }
+
+    #[test]
+    fn test_kabsch_recovers_rigid_motion() {
+        let reference = vec![
+            Point::new(0.0, 0.0, 0.0), Point::new(1.5, 0.0, 0.0), Point::new(1.5, 2.0, 0.3),
+            Point::new(-0.7, 1.1, 2.2), Point::new(3.0, -1.0, 1.0),
+        ];
+        let shift = Point::new(5.0, -2.0, 7.0);
+        let mobile: Vec<Point> = reference.iter().map(|p| rotate_z(p, 1.1).add(&shift)).collect();
+
+        let sup = kabsch(&reference, &mobile).unwrap();
+        assert!(sup.rmsd < 1e-9);
+        for (r, m) in reference.iter().zip(&mobile) {
+            assert!(sup.apply(m).distance(r) < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_weighted_kabsch_ignores_zero_weight_outlier() {
+        let reference = vec![
+            Point::new(0.0, 0.0, 0.0), Point::new(1.5, 0.0, 0.0), Point::new(1.5, 2.0, 0.3),
+            Point::new(-0.7, 1.1, 2.2), Point::new(3.0, -1.0, 1.0),
+        ];
+        let mut mobile: Vec<Point> = reference.iter().map(|p| rotate_z(p, 0.4)).collect();
+        mobile[4] = mobile[4].add(&Point::new(15.0, 0.0, 0.0));
+
+        let weights = [1.0, 1.0, 1.0, 1.0, 0.0];
+        let sup = kabsch_weighted(&reference, &mobile, &weights).unwrap();
+        assert!(sup.rmsd < 1e-9);
+        assert!(kabsch(&reference, &mobile).unwrap().rmsd > 1.0);
+        assert!(kabsch_weighted(&reference, &mobile, &[0.0; 5]).is_none());
+    }
+
+    fn random_cloud(rng: &mut StdRng, n: usize, spread: f64) -> Vec<Point> {
+        (0..n).map(|_| Point::new(
+            rng.random_range(-spread..spread),
+            rng.random_range(-spread..spread),
+            rng.random_range(-spread..spread),
+        )).collect()
+    }
+
+    #[test]
+    fn test_qcp_matches_kabsch() {
+        let mut rng = StdRng::seed_from_u64(17);
+        for n in [3, 10, 230] {
+            let reference = random_cloud(&mut rng, n, 20.0);
+            let noise = random_cloud(&mut rng, n, 1.5);
+            let mobile: Vec<Point> = reference.iter().zip(&noise)
+                .map(|(p, e)| rotate_z(p, 2.3).add(e).add(&Point::new(4.0, 1.0, -9.0)))
+                .collect();
+            let expected = kabsch(&reference, &mobile).unwrap().rmsd;
+            assert!((qcp_rmsd(&reference, &mobile).unwrap() - expected).abs() < 1e-6);
+
+            // Unrelated clouds exercise the large-RMSD end
+            let other = random_cloud(&mut rng, n, 20.0);
+            let expected = kabsch(&reference, &other).unwrap().rmsd;
+            assert!((qcp_rmsd(&reference, &other).unwrap() - expected).abs() < 1e-6);
+        }
+        let line: Vec<Point> = (0..5).map(|i| Point::new(i as f64, 0.0, 0.0)).collect();
+        assert!(qcp_rmsd(&line, &line).unwrap() < 1e-6);
+        assert!(qcp_rmsd(&[], &[]).is_none());
+    }
+
+    /// Timing of `qcp_rmsd` against `kabsch`, which builds the rotation from
+    /// a Jacobi eigendecomposition of the same key matrix (there is no SVD
+    /// path), on antibody-sized CA sets:
+    /// `cargo test --release bench_qcp -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_qcp_vs_kabsch() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let sets: Vec<(Vec<Point>, Vec<Point>)> = (0..2000)
+            .map(|_| (random_cloud(&mut rng, 230, 20.0), random_cloud(&mut rng, 230, 20.0)))
+            .collect();
+
+        let start = std::time::Instant::now();
+        let kabsch_sum: f64 = sets.iter().map(|(a, b)| kabsch(a, b).unwrap().rmsd).sum();
+        let kabsch_time = start.elapsed();
+        let start = std::time::Instant::now();
+        let qcp_sum: f64 = sets.iter().map(|(a, b)| qcp_rmsd(a, b).unwrap()).sum();
+        let qcp_time = start.elapsed();
+
+        println!("kabsch: {:?}, qcp: {:?} for {} superpositions", kabsch_time, qcp_time, sets.len());
+        assert!((kabsch_sum - qcp_sum).abs() < 1e-6 * sets.len() as f64);
+    }
+
+    #[test]
+    fn test_kabsch_rejects_bad_input() {
+        assert!(kabsch(&[], &[]).is_none());
+        assert!(kabsch(&[Point::new(0.0, 0.0, 0.0)], &[]).is_none());
+    }
+}
@@ -1,8 +1,9 @@
 use plotters::prelude::*;
-use scaffolding_lna_rs::{analysis, pdb::Pdb};
+use rayon::prelude::*;
+use scaffolding_lna_rs::{analysis, analysis::RamaPoint, db::{self, AntibodyRecord, Db, DbFilter, DbStats}, download, pdb::{self, Pdb}};
 use std::f64::consts::PI;
 use std::path::Path;
-use std::io::Read;
+use download::Fetcher;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let out_dir = Path::new("pics");
@@ -12,29 +13,90 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     draw_ramachandran("1t66", "pics/ramachandran.png")?;
     draw_score_distribution("pics/scores.png")?;
-    draw_cdr_length_distribution("pics/cdr_lengths.png")?;
     draw_top_n_decay("pics/top_n_decay.png")?;
-    draw_gap_analysis("pics/gap_analysis.png")?;
+    draw_resolution_vs_score("pics/resolution_vs_score.png")?;
+
+    // Statistics of the database; reading is all the plots need. Its path
+    // may be given as the only argument.
+    let db_path = db::default_path(std::env::args_os().nth(1).map(std::path::PathBuf::from).as_deref());
+    let db_path = db_path.as_path();
+    let db = match db_path.exists().then(|| Db::open_read_only(db_path)) {
+        Some(Ok(db)) => db,
+        Some(Err(e)) => {
+            println!("Cannot read {:?} ({}), skipping the database plots", db_path, e);
+            return Ok(());
+        }
+        None => {
+            println!("{:?} not found, skipping the database plots", db_path);
+            return Ok(());
+        }
+    };
+    let stats = db.stats()?;
+    let rama = processed_rama(&db)?;
+    draw_cdr_length_distribution(&stats, "pics/cdr_lengths.png")?;
+    draw_gap_analysis(&db, "pics/gap_analysis.png")?;
+    draw_cleaning_stats(&db, "pics/cleaning_stats.png")?;
+    draw_species_bar_chart(&stats, "pics/species_dist.png")?;
+    draw_ramachandran_heatmap(&rama, "pics/ramachandran_heatmap.png")?;
+    draw_ramachandran_by_class(&rama, "pics/ramachandran_classes.png")?;
 
     println!("Plots generated in pics/");
     Ok(())
 }
 
-fn draw_gap_analysis(out_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    use rand::prelude::*;
-    let mut rng = rand::rng();
-    
-    let normal_bond = rand_distr::Normal::new(1.33, 0.05)?;
-    let outlier_dist = rand_distr::Uniform::new(2.0, 5.0)?;
-    
-    let mut distances: Vec<f64> = Vec::new();
-    for _ in 0..5000 {
-        distances.push(normal_bond.sample(&mut rng));
+/// Torsions persisted by processing, over every processed Fab.
+fn processed_rama(db: &Db) -> Result<Vec<RamaPoint>, Box<dyn std::error::Error>> {
+    let conn = db.get_conn();
+    let mut stmt = conn.prepare("SELECT json_blob FROM antibodies WHERE processed = TRUE AND json_blob IS NOT NULL")?;
+    let blobs = stmt.query_map([], |row| row.get::<_, String>(0))?;
+
+    let mut points: Vec<RamaPoint> = Vec::new();
+    for blob in blobs {
+        let meta: serde_json::Value = serde_json::from_str(&blob?)?;
+        if let Some(rama) = meta.get("rama") {
+            points.extend(serde_json::from_value::<Vec<RamaPoint>>(rama.clone())?);
+        }
     }
-    for _ in 0..100 {
-        distances.push(outlier_dist.sample(&mut rng));
+    Ok(points)
+}
+
+/// The Fab's chains of its stored structure, None without one.
+fn load_fab(db: &Db, record: &AntibodyRecord) -> anyhow::Result<Option<Pdb>> {
+    let Some(structure) = db.load_structure(&record.pdb_id)? else { return Ok(None) };
+    let chains: Vec<char> = [&record.h_chain, &record.l_chain].iter().filter_map(|c| c.chars().next()).collect();
+    Ok(Some(Pdb::parse(&structure.text()?, structure.format).select_chains(&chains)))
+}
+
+/// C(i)–N(i+1) distances of consecutive residues in the stored structures of
+/// the current Fabs, whose structures are read in parallel.
+fn draw_gap_analysis(db: &Db, out_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let records = db.list_antibodies(&DbFilter { processed: Some(true), current_only: true, with_structure: true, ..Default::default() })?;
+    let distances: Vec<f64> = records.par_iter().flat_map_iter(|record| {
+        let fab = match load_fab(db, record) {
+            Ok(fab) => fab,
+            Err(e) => {
+                eprintln!("Skipping {}: {}", record.pdb_id, e);
+                None
+            }
+        };
+        let Some(fab) = fab else { return Vec::new() };
+        let residues = pdb::group_residues(&fab.atoms);
+        residues.windows(2)
+            .filter(|w| w[0].id.chain_id == w[1].id.chain_id)
+            .filter_map(|w| Some(w[0].atom("C")?.pos.distance(&w[1].atom("N")?.pos)))
+            .collect::<Vec<_>>()
+    }).collect();
+
+    let mut buckets = [0u32; 60];
+    for &d in &distances {
+        if (1.0..4.0).contains(&d) {
+            let idx = ((d - 1.0) / 0.05) as usize;
+            if idx < buckets.len() { buckets[idx] += 1; }
+        }
     }
 
+    let max_count = buckets.iter().copied().max().unwrap_or(0).max(1);
+
     let root = BitMapBackend::new(out_path, (800, 600)).into_drawing_area();
     root.fill(&WHITE)?;
 
@@ -43,21 +105,13 @@ fn draw_gap_analysis(out_path: &str) -> Result<(), Box<dyn std::error::Error>> {
         .margin(20)
         .x_label_area_size(40)
         .y_label_area_size(50)
-        .build_cartesian_2d(1.0f64..4.0f64, 0u32..600u32)?;
+        .build_cartesian_2d(1.0f64..4.0f64, 0u32..max_count)?;
 
     chart.configure_mesh()
         .x_desc("Расстояние (Ангстрем)")
         .y_desc("Количество")
         .draw()?;
 
-    let mut buckets = [0u32; 60];
-    for &d in &distances {
-        if d >= 1.0 && d < 4.0 {
-            let idx = ((d - 1.0) / 0.05) as usize;
-            if idx < buckets.len() { buckets[idx] += 1; }
-        }
-    }
-
     chart.draw_series(
         buckets.iter().enumerate().map(|(i, &c)| {
             let x0 = 1.0 + (i as f64) * 0.05;
@@ -66,18 +120,18 @@ fn draw_gap_analysis(out_path: &str) -> Result<(), Box<dyn std::error::Error>> {
         })
     )?;
 
-    // Threshold line at 2.0A
-    let threshold_x = 2.0;
+    // Bonds longer than the threshold count as gaps
+    let threshold_x = pdb::MAX_PEPTIDE_BOND;
     chart.draw_series(LineSeries::new(
-        vec![(threshold_x, 0), (threshold_x, 600)],
+        vec![(threshold_x, 0), (threshold_x, max_count)],
         RED.stroke_width(2),
     ))?
-    .label("Порог отсечения (2.0A)")
+    .label(format!("Порог отсечения ({:.1}A)", threshold_x))
     .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], RED.stroke_width(2)));
 
     chart.configure_series_labels()
-        .background_style(&WHITE.mix(0.8))
-        .border_style(&BLACK)
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
         .draw()?;
 
     Ok(())
@@ -85,11 +139,10 @@ fn draw_gap_analysis(out_path: &str) -> Result<(), Box<dyn std::error::Error>> {
 
 fn draw_ramachandran(pdb_id: &str, out_path: &str) -> Result<(), Box<dyn std::error::Error>> {
     let url = format!("https://files.rcsb.org/download/{}.pdb", pdb_id);
-    let mut content = String::new();
-    ureq::get(&url).call()?.into_body().into_reader().read_to_string(&mut content)?;
+    let content = download::HttpFetcher::default().get_text(&url)?;
     
     let pdb = Pdb::from_str(&content);
-    let angles = analysis::ramachandran(&pdb.atoms);
+    let angles = analysis::ramachandran_angles(&pdb.atoms);
 
     let root = BitMapBackend::new(out_path, (800, 800)).into_drawing_area();
     root.fill(&WHITE)?;
@@ -118,7 +171,7 @@ fn draw_ramachandran(pdb_id: &str, out_path: &str) -> Result<(), Box<dyn std::er
         PathElement::new(vec![(-2.5, 2.0), (-1.5, 2.0), (-1.5, 3.0), (-2.5, 3.0), (-2.5, 2.0)], GREEN.stroke_width(2))
     ))?.label("Бета-лист").legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], GREEN));
 
-    chart.configure_series_labels().border_style(&BLACK).draw()?;
+    chart.configure_series_labels().border_style(BLACK).draw()?;
     Ok(())
 }
 
@@ -161,24 +214,16 @@ fn draw_score_distribution(out_path: &str) -> Result<(), Box<dyn std::error::Err
     Ok(())
 }
 
-fn draw_cdr_length_distribution(out_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    use rand::prelude::*;
-    let mut rng = rand::rng();
-    let normal = rand_distr::Normal::new(13.0, 3.5)?;
-    
-    let lengths: Vec<u32> = (0..5000).map(|_| {
-        let l: f64 = normal.sample(&mut rng);
-        l.round().clamp(5.0, 30.0) as u32
-    }).collect();
-
+/// CDR-H3 lengths (Chothia boundaries, H95-H102) of the numbered heavy chains.
+fn draw_cdr_length_distribution(stats: &DbStats, out_path: &str) -> Result<(), Box<dyn std::error::Error>> {
     let root = BitMapBackend::new(out_path, (800, 600)).into_drawing_area();
     root.fill(&WHITE)?;
 
     let mut counts = [0u32; 35];
-    for &l in &lengths {
-        if (l as usize) < counts.len() { counts[l as usize] += 1; }
+    for (&length, &n) in &stats.cdr_h3_lengths {
+        if length < counts.len() { counts[length] += n as u32; }
     }
-    let max_count = *counts.iter().max().unwrap();
+    let max_count = (*counts.iter().max().unwrap()).max(1);
 
     let mut chart = ChartBuilder::on(&root)
         .caption("Распределение длины CDR H3", ("sans-serif", 40).into_font())
@@ -218,8 +263,8 @@ fn draw_top_n_decay(out_path: &str) -> Result<(), Box<dyn std::error::Error>> {
         .draw()?;
 
     let ranks: Vec<u32> = (1..=5).collect();
-    let scores = vec![0.92, 0.45, 0.42, 0.40, 0.38];
-    let pdb_ids = vec!["1t66 (Цель)", "3h42", "1gig", "4k12", "2x9a"];
+    let scores = [0.92, 0.45, 0.42, 0.40, 0.38];
+    let pdb_ids = ["1t66 (Цель)", "3h42", "1gig", "4k12", "2x9a"];
 
     chart.draw_series(
         LineSeries::new(
@@ -272,24 +317,32 @@ fn draw_resolution_vs_score(out_path: &str) -> Result<(), Box<dyn std::error::Er
     Ok(())
 }
 
-fn draw_species_bar_chart(out_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+/// Share of the current Fabs per heavy chain species, the three most common
+/// by name and the rest as "Other".
+fn draw_species_bar_chart(stats: &DbStats, out_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut ranked: Vec<(String, usize)> = stats.species.clone().into_iter().collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let other: usize = ranked.iter().skip(3).map(|(_, n)| n).sum();
+    ranked.truncate(3);
+    ranked.push(("Other".to_string(), other));
+    let total = stats.current_fabs.max(1) as f64;
+    let sizes: Vec<f64> = ranked.iter().map(|(_, n)| *n as f64 * 100.0 / total).collect();
+    let labels: Vec<&str> = ranked.iter().map(|(name, _)| name.as_str()).collect();
+    let colors = [BLUE, RED, GREEN, YELLOW];
+
     let root = BitMapBackend::new(out_path, (800, 600)).into_drawing_area();
     root.fill(&WHITE)?;
 
-    let sizes = [60.0, 30.0, 5.0, 5.0];
-    let labels = ["Human", "Mouse", "Rabbit", "Other"];
-    let colors = [BLUE, RED, GREEN, YELLOW];
-
     // Use usize for Y axis (0..4)
     let mut chart = ChartBuilder::on(&root)
         .caption("Видовой состав базы данных", ("sans-serif", 40).into_font())
         .margin(20)
         .x_label_area_size(40)
         .y_label_area_size(100)
-        .build_cartesian_2d(0u32..100u32, (0usize..4usize).into_segmented())?;
+        .build_cartesian_2d(0u32..100u32, (0usize..labels.len()).into_segmented())?;
 
     chart.configure_mesh()
-        .y_labels(4)
+        .y_labels(labels.len())
         .y_label_formatter(&|v| {
             match v {
                 SegmentValue::Exact(i) | SegmentValue::CenterOf(i) => {
@@ -302,8 +355,8 @@ fn draw_species_bar_chart(out_path: &str) -> Result<(), Box<dyn std::error::Erro
         .draw()?;
 
     chart.draw_series(
-        (0..4).map(|i| {
-            let val = sizes[i] as u32;
+        (0..labels.len()).map(|i| {
+            let val = sizes[i].round() as u32;
             let style = colors[i].filled();
             Rectangle::new([(0, SegmentValue::Exact(i)), (val, SegmentValue::Exact(i))], style)
         })
@@ -311,31 +364,55 @@ fn draw_species_bar_chart(out_path: &str) -> Result<(), Box<dyn std::error::Erro
     Ok(())
 }
 
-fn draw_ramachandran_heatmap(out_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    use rand::prelude::*;
-    let mut rng = rand::rng();
-    
-    let normal_beta_x = rand_distr::Normal::new(-2.2, 0.4)?;
-    let normal_beta_y = rand_distr::Normal::new(2.5, 0.4)?;
-    let normal_alpha_x = rand_distr::Normal::new(-1.0, 0.3)?;
-    let normal_alpha_y = rand_distr::Normal::new(-0.7, 0.3)?;
-
-    let mut points: Vec<(f64, f64)> = Vec::new();
-    for _ in 0..3000 {
-        points.push((normal_beta_x.sample(&mut rng), normal_beta_y.sample(&mut rng)));
-    }
-    for _ in 0..3000 {
-        points.push((normal_alpha_x.sample(&mut rng), normal_alpha_y.sample(&mut rng)));
+/// Fabs kept and rejected by quality control, numbered or not.
+fn draw_cleaning_stats(db: &Db, out_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = db.get_conn();
+    let mut stmt = conn.prepare("SELECT passed_qc IS TRUE, COUNT(*) FROM antibodies WHERE qc_report IS NOT NULL GROUP BY 1")?;
+    let mut counts = [0u32; 2];
+    for row in stmt.query_map([], |row| Ok((row.get::<_, bool>(0)?, row.get::<_, u32>(1)?)))? {
+        let (passed, count) = row?;
+        counts[usize::from(passed)] = count;
     }
+    let labels = ["Отклонены", "Прошли QC"];
+    let max_count = counts.iter().copied().max().unwrap_or(0).max(1);
+
+    let root = BitMapBackend::new(out_path, (800, 600)).into_drawing_area();
+    root.fill(&WHITE)?;
+
+    let mut chart = ChartBuilder::on(&root)
+        .caption("Результаты контроля качества", ("sans-serif", 40).into_font())
+        .margin(20)
+        .x_label_area_size(40)
+        .y_label_area_size(50)
+        .build_cartesian_2d((0usize..labels.len()).into_segmented(), 0u32..max_count + max_count / 10 + 1)?;
+
+    chart.configure_mesh()
+        .disable_x_mesh()
+        .x_labels(labels.len())
+        .x_label_formatter(&|v| match v {
+            SegmentValue::Exact(i) | SegmentValue::CenterOf(i) if *i < labels.len() => labels[*i].to_string(),
+            _ => "".to_string(),
+        })
+        .y_desc("Количество Fab")
+        .draw()?;
 
+    chart.draw_series(counts.iter().enumerate().map(|(i, &count)| {
+        let color = if i == 0 { RED } else { GREEN };
+        Rectangle::new([(SegmentValue::Exact(i), 0), (SegmentValue::Exact(i + 1), count)], color.filled())
+    }))?;
+    Ok(())
+}
+
+/// Density of the persisted torsions of all processed Fabs.
+fn draw_ramachandran_heatmap(rama: &[RamaPoint], out_path: &str) -> Result<(), Box<dyn std::error::Error>> {
     let root = BitMapBackend::new(out_path, (800, 800)).into_drawing_area();
     root.fill(&WHITE)?;
 
     let bins = 50;
     let mut heatmap = vec![0u32; bins * bins];
     
-    for (x, y) in points {
-        if x >= -PI && x <= PI && y >= -PI && y <= PI {
+    for &RamaPoint { phi: x, psi: y, .. } in rama {
+        if (-PI..=PI).contains(&x) && (-PI..=PI).contains(&y) {
             let xi = ((x + PI) / (2.0 * PI) * bins as f64) as usize;
             let yi = ((y + PI) / (2.0 * PI) * bins as f64) as usize;
             if xi < bins && yi < bins {
@@ -343,7 +420,7 @@ fn draw_ramachandran_heatmap(out_path: &str) -> Result<(), Box<dyn std::error::E
             }
         }
     }
-    let max_val = *heatmap.iter().max().unwrap_or(&1) as f64;
+    let max_val = (*heatmap.iter().max().unwrap_or(&1)).max(1) as f64;
 
     let mut chart = ChartBuilder::on(&root)
         .caption("Плотность карты Рамачандрана", ("sans-serif", 50).into_font())
@@ -377,3 +454,31 @@ fn draw_ramachandran_heatmap(out_path: &str) -> Result<(), Box<dyn std::error::E
     )?;
     Ok(())
 }
+
+/// Glycine, proline and all other residues from the torsions persisted by processing.
+fn draw_ramachandran_by_class(points: &[RamaPoint], out_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let root = BitMapBackend::new(out_path, (1500, 500)).into_drawing_area();
+    root.fill(&WHITE)?;
+    let panels = root.split_evenly((1, 3));
+    // None collects every residue that is neither glycine nor proline
+    let classes = [("Глицин", GREEN, Some("GLY")), ("Пролин", RED, Some("PRO")), ("Остальные", BLUE, None)];
+
+    for (panel, (title, color, res_name)) in panels.iter().zip(classes) {
+        let mut chart = ChartBuilder::on(panel)
+            .caption(title, ("sans-serif", 30).into_font())
+            .margin(10)
+            .x_label_area_size(40)
+            .y_label_area_size(40)
+            .build_cartesian_2d(-PI..PI, -PI..PI)?;
+        chart.configure_mesh().x_desc("Фи (радианы)").y_desc("Пси (радианы)").draw()?;
+        chart.draw_series(
+            points.iter()
+                .filter(|p| match res_name {
+                    Some(name) => p.res_name == name,
+                    None => p.res_name != "GLY" && p.res_name != "PRO",
+                })
+                .map(|p| Circle::new((p.phi, p.psi), 2, color.mix(0.5).filled()))
+        )?;
+    }
+    Ok(())
+}
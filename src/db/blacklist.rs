@@ -0,0 +1,69 @@
+//! Entries excluded by hand, e.g. for mis-annotated chains or chimeric
+//! constructs. Their rows stay, so the next update does not add them again,
+//! but processing and matching pass them over.
+use super::Db;
+use chrono::Utc;
+use rusqlite::Result;
+use serde::Serialize;
+
+/// An entry on the blacklist.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Exclusion {
+    pub pdb_id: String,
+    pub reason: String,
+    /// When it was added, RFC 3339
+    pub added_at: String,
+}
+
+impl Db {
+    /// Excludes an entry, replacing the reason if it already is.
+    pub fn blacklist(&self, pdb_id: &str, reason: &str) -> Result<()> {
+        self.get_conn().execute(
+            "INSERT OR REPLACE INTO blacklist (pdb_id, reason, added_at) VALUES (?1, ?2, ?3)",
+            [&pdb_id.to_lowercase(), reason, &Utc::now().to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    /// Takes an entry off the blacklist; false if it was not on it.
+    pub fn unblacklist(&self, pdb_id: &str) -> Result<bool> {
+        Ok(self.get_conn().execute("DELETE FROM blacklist WHERE pdb_id = ?1", [pdb_id.to_lowercase()])? > 0)
+    }
+
+    /// The excluded entries by PDB ID.
+    pub fn blacklisted(&self) -> Result<Vec<Exclusion>> {
+        self.read(|conn| {
+            let mut stmt = conn.prepare("SELECT pdb_id, reason, added_at FROM blacklist ORDER BY pdb_id")?;
+            let rows = stmt.query_map([], |row| Ok(Exclusion { pdb_id: row.get(0)?, reason: row.get(1)?, added_at: row.get(2)? }))?;
+            rows.collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::DbFilter;
+
+    #[test]
+    fn test_blacklist() {
+        let db = Db::open_in_memory().unwrap();
+        for id in ["1abc", "2abc"] {
+            db.insert_raw(id, "H", "L", None, "human", "x-ray", false).unwrap();
+        }
+        db.blacklist("1ABC", "chimeric construct").unwrap();
+        db.blacklist("1abc", "mis-annotated chains").unwrap();
+        let listed = db.blacklisted().unwrap();
+        assert_eq!(listed.iter().map(|e| (e.pdb_id.as_str(), e.reason.as_str())).collect::<Vec<_>>(), [("1abc", "mis-annotated chains")]);
+
+        let filter = DbFilter { skip_blacklisted: true, ..Default::default() };
+        let ids = |db: &Db| db.list_antibodies(&filter).unwrap().into_iter().map(|r| r.pdb_id).collect::<Vec<_>>();
+        assert_eq!(ids(&db), ["2abc"]);
+        // Still there for everything else
+        assert!(db.get_antibody("1abc").unwrap().is_some());
+
+        assert!(db.unblacklist("1abc").unwrap());
+        assert!(!db.unblacklist("1abc").unwrap());
+        assert_eq!(ids(&db), ["1abc", "2abc"]);
+    }
+}
@@ -0,0 +1,176 @@
+//! Per-Fab embedding vectors and the nearest-neighbour index over them, so
+//! matching can retrieve candidates without scanning every Fab.
+use super::Db;
+use crate::analysis::{self, BallTree, EMBEDDING_DIM};
+use rusqlite::{params, Connection, Result};
+use std::sync::Arc;
+
+/// Index over the stored embeddings, built on the first `Db::knn`
+pub(super) struct EmbeddingIndex {
+    tree: BallTree,
+    fab_ids: Vec<i64>,
+}
+
+impl EmbeddingIndex {
+    fn load(conn: &Connection) -> Result<Self> {
+        let mut stmt = conn.prepare("SELECT fab_id, vector FROM embeddings ORDER BY fab_id")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?)))?;
+        let mut fab_ids = Vec::new();
+        let mut vectors = Vec::new();
+        for row in rows {
+            let (fab_id, bytes) = row?;
+            // Vectors of an older layout are left out until processed again
+            if let Some(vector) = analysis::fingerprint_from_bytes(&bytes).filter(|v| v.len() == EMBEDDING_DIM) {
+                fab_ids.push(fab_id);
+                vectors.push(vector);
+            }
+        }
+        Ok(Self { tree: BallTree::new(EMBEDDING_DIM, &vectors), fab_ids })
+    }
+}
+
+// Embeddings of Fabs processed before they were kept, from their stored
+// fingerprint and CDR lengths
+pub(super) fn backfill(conn: &Connection) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "SELECT fab_id, pdb_id, rama_fingerprint, cdr_h1_length, cdr_h2_length, cdr_h3_length FROM antibodies
+         WHERE rama_fingerprint IS NOT NULL AND cdr_h1_length IS NOT NULL AND cdr_h2_length IS NOT NULL AND cdr_h3_length IS NOT NULL"
+    )?;
+    let rows = stmt.query_map([], |row| {
+        let length = |i| row.get::<_, i64>(i).map(|l| l as usize);
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, Vec<u8>>(2)?, [length(3)?, length(4)?, length(5)?]))
+    })?;
+    let mut insert = conn.prepare("INSERT OR REPLACE INTO embeddings (fab_id, pdb_id, vector) VALUES (?1, ?2, ?3)")?;
+    for row in rows {
+        let (fab_id, pdb_id, fingerprint, lengths) = row?;
+        let embedding = analysis::fingerprint_from_bytes(&fingerprint).and_then(|f| analysis::fab_embedding(&f, lengths));
+        if let Some(embedding) = embedding {
+            insert.execute(params![fab_id, pdb_id, analysis::fingerprint_to_bytes(&embedding)])?;
+        }
+    }
+    Ok(())
+}
+
+impl Db {
+    /// Replaces the embedding of a Fab, or drops it for None.
+    pub fn store_embedding(&self, fab_id: i64, embedding: Option<&[f32]>) -> Result<()> {
+        let conn = self.get_conn();
+        match embedding {
+            Some(embedding) => conn.execute(
+                "INSERT OR REPLACE INTO embeddings (fab_id, pdb_id, vector)
+                 SELECT fab_id, pdb_id, ?2 FROM antibodies WHERE fab_id = ?1",
+                params![fab_id, analysis::fingerprint_to_bytes(embedding)],
+            )?,
+            None => conn.execute("DELETE FROM embeddings WHERE fab_id = ?1", [fab_id])?,
+        };
+        self.invalidate_embedding_index();
+        Ok(())
+    }
+
+    /// The `k` stored Fabs passing `keep` whose embeddings are closest to
+    /// `query`, as Fab ID and Euclidean distance, nearest first. The index is
+    /// built on the first call and kept until embeddings change through this
+    /// handle.
+    pub fn knn(&self, query: &[f32], k: usize, keep: impl Fn(i64) -> bool) -> Result<Vec<(i64, f32)>> {
+        let index = self.embedding_index()?;
+        if query.len() != EMBEDDING_DIM {
+            return Ok(Vec::new());
+        }
+        let found = index.tree.nearest_where(query, k, |i| keep(index.fab_ids[i]));
+        Ok(found.into_iter().map(|(i, d)| (index.fab_ids[i], d)).collect())
+    }
+
+    fn embedding_index(&self) -> Result<Arc<EmbeddingIndex>> {
+        if let Some(index) = self.embedding_index.lock().as_ref() {
+            return Ok(index.clone());
+        }
+        // Built outside the lock, a concurrent first call may build it twice
+        let index = Arc::new(self.read(EmbeddingIndex::load)?);
+        *self.embedding_index.lock() = Some(index.clone());
+        Ok(index)
+    }
+
+    pub(super) fn invalidate_embedding_index(&self) {
+        *self.embedding_index.lock() = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_knn_recall() {
+        let db = Db::open_in_memory().unwrap();
+        let mut rng = StdRng::seed_from_u64(11);
+        // Fingerprints concentrated in a few cells, as real ones are, and
+        // CDR lengths around the usual ones
+        let embedding = |rng: &mut StdRng| {
+            let mut fingerprint = vec![0.0f32; analysis::FINGERPRINT_BINS * analysis::FINGERPRINT_BINS];
+            for _ in 0..60 {
+                fingerprint[rng.random_range(0..40)] += 1.0 / 60.0;
+            }
+            analysis::fab_embedding(&fingerprint, [rng.random_range(8..12), rng.random_range(6..10), rng.random_range(5..20)]).unwrap()
+        };
+        let mut stored = Vec::new();
+        for i in 0..600 {
+            let id = format!("{}abc", i);
+            db.insert_raw(&id, "H", "L", None, "human", "x-ray", false).unwrap();
+            let vector = embedding(&mut rng);
+            let fab_id = db.get_antibody(&id).unwrap().unwrap().fab_id;
+            db.store_embedding(fab_id, Some(&vector)).unwrap();
+            stored.push((fab_id, vector));
+        }
+
+        let k = 20;
+        let mut hits = 0;
+        for _ in 0..25 {
+            let query = embedding(&mut rng);
+            let mut brute: Vec<(i64, f32)> = stored.iter().map(|(id, v)| {
+                (*id, v.iter().zip(&query).map(|(a, b)| (a - b) * (a - b)).sum::<f32>().sqrt())
+            }).collect();
+            brute.sort_by(|a, b| a.1.total_cmp(&b.1));
+            let found = db.knn(&query, k, |_| true).unwrap();
+            assert_eq!(found.len(), k);
+            assert!(found.windows(2).all(|w| w[0].1 <= w[1].1));
+            hits += found.iter().filter(|(id, _)| brute[..k].iter().any(|(b, _)| b == id)).count();
+        }
+        let recall = hits as f64 / (25 * k) as f64;
+        assert!(recall >= 0.95, "recall {}", recall);
+
+        // A new embedding is found once stored, a dropped one no more
+        let fab_id = db.get_antibody("0abc").unwrap().unwrap().fab_id;
+        let query = embedding(&mut rng);
+        db.store_embedding(fab_id, Some(&query)).unwrap();
+        assert_eq!(db.knn(&query, 1, |_| true).unwrap()[0], (fab_id, 0.0));
+        // Filtered out Fabs are skipped, the k nearest of the rest are returned
+        let found = db.knn(&query, k, |id| id != fab_id && id % 10 == 0).unwrap();
+        assert_eq!(found.len(), k);
+        assert!(found.iter().all(|&(id, _)| id != fab_id && id % 10 == 0));
+        db.store_embedding(fab_id, None).unwrap();
+        assert_ne!(db.knn(&query, 1, |_| true).unwrap()[0].0, fab_id);
+        assert!(db.knn(&[0.0; 3], 1, |_| true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_embedding_backfill() {
+        let db = Db::open_in_memory().unwrap();
+        db.insert_raw("1abc", "H", "L", None, "human", "x-ray", false).unwrap();
+        db.insert_raw("2abc", "H", "L", None, "human", "x-ray", false).unwrap();
+        let fingerprint = vec![1.0 / 256.0; 256];
+        let conn = db.get_conn();
+        conn.execute(
+            "UPDATE antibodies SET rama_fingerprint = ?1, cdr_h1_length = 8, cdr_h2_length = 7, cdr_h3_length = 12",
+            [analysis::fingerprint_to_bytes(&fingerprint)],
+        ).unwrap();
+        // 2abc was never numbered
+        conn.execute("UPDATE antibodies SET cdr_h3_length = NULL WHERE pdb_id = '2abc'", []).unwrap();
+        backfill(&conn).unwrap();
+        drop(conn);
+        let query = analysis::fab_embedding(&fingerprint, [8, 7, 12]).unwrap();
+        let fab_id = db.get_antibody("1abc").unwrap().unwrap().fab_id;
+        assert_eq!(db.knn(&query, 5, |_| true).unwrap(), [(fab_id, 0.0)]);
+    }
+}
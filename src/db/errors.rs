@@ -0,0 +1,123 @@
+//! Why processing failed for a Fab, per stage, so failures can be told
+//! apart from Fabs never attempted and retried selectively.
+use super::{AntibodyRecord, Db, DbFilter, RECORD_COLUMNS};
+use chrono::Utc;
+use rusqlite::{params, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Step of processing a Fab that can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProcessingStage {
+    /// Reading the stored structure
+    Parse,
+    /// Quality control rejected the structure
+    Qc,
+    NumberingH,
+    NumberingL,
+}
+
+impl ProcessingStage {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Parse => "parse",
+            Self::Qc => "qc",
+            Self::NumberingH => "numbering_h",
+            Self::NumberingL => "numbering_l",
+        }
+    }
+}
+
+/// A recorded failure of one stage for one Fab.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ProcessingError {
+    pub fab_id: i64,
+    pub pdb_id: String,
+    pub stage: ProcessingStage,
+    pub error: String,
+    /// When it happened, RFC 3339
+    pub failed_at: String,
+}
+
+impl Db {
+    /// Replaces the recorded failures of a Fab; an empty list clears them.
+    pub fn record_processing_errors(&self, fab_id: i64, errors: &[(ProcessingStage, String)]) -> Result<()> {
+        let conn = self.get_conn();
+        conn.execute("DELETE FROM processing_errors WHERE fab_id = ?1", [fab_id])?;
+        let mut insert = conn.prepare_cached(
+            "INSERT INTO processing_errors (fab_id, pdb_id, stage, error, failed_at)
+             SELECT fab_id, pdb_id, ?2, ?3, ?4 FROM antibodies WHERE fab_id = ?1",
+        )?;
+        let now = Utc::now().to_rfc3339();
+        for (stage, error) in errors {
+            insert.execute(params![fab_id, stage.as_str(), error, now])?;
+        }
+        Ok(())
+    }
+
+    /// Records a failure of one stage of a Fab, keeping those of the others.
+    pub fn record_processing_error(&self, fab_id: i64, stage: ProcessingStage, error: &str) -> Result<()> {
+        self.get_conn().execute(
+            "INSERT OR REPLACE INTO processing_errors (fab_id, pdb_id, stage, error, failed_at)
+             SELECT fab_id, pdb_id, ?2, ?3, ?4 FROM antibodies WHERE fab_id = ?1",
+            params![fab_id, stage.as_str(), error, Utc::now().to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    /// Forgets a failure of one stage of a Fab, keeping those of the others.
+    pub fn clear_processing_error(&self, fab_id: i64, stage: ProcessingStage) -> Result<()> {
+        self.get_conn().execute("DELETE FROM processing_errors WHERE fab_id = ?1 AND stage = ?2", params![fab_id, stage.as_str()])?;
+        Ok(())
+    }
+
+    /// Fabs whose last processing failed at `stage`, in fab_id order.
+    pub fn failed_entries(&self, stage: ProcessingStage) -> Result<Vec<ProcessingError>> {
+        self.read(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT fab_id, pdb_id, error, failed_at FROM processing_errors WHERE stage = ?1 ORDER BY fab_id",
+            )?;
+            let rows = stmt.query_map([stage.as_str()], |row| Ok(ProcessingError {
+                fab_id: row.get(0)?,
+                pdb_id: row.get(1)?,
+                stage,
+                error: row.get(2)?,
+                failed_at: row.get(3)?,
+            }))?;
+            rows.collect()
+        })
+    }
+
+    /// Processed Fabs to number again: those whose numbering failed, and
+    /// those passing QC with a chain left unnumbered although it has a
+    /// sequence. Blacklisted entries, those without a structure and those QC
+    /// has not checked are left out.
+    pub fn fabs_with_failed_numbering(&self) -> Result<Vec<AntibodyRecord>> {
+        let filter = DbFilter { processed: Some(true), qc_checked: Some(true), with_structure: true, skip_blacklisted: true, ..Default::default() };
+        let (clause, values) = filter.sql();
+        self.read(|conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM antibodies
+                 WHERE {} AND (
+                     EXISTS (SELECT 1 FROM processing_errors e WHERE e.fab_id = antibodies.fab_id AND e.stage IN ('{}', '{}'))
+                     OR (passed_qc IS TRUE AND EXISTS (
+                         SELECT 1 FROM features f WHERE f.fab_id = antibodies.fab_id AND f.seq <> ''
+                             AND (CASE f.chain_type WHEN 'H' THEN antibodies.numbered_h ELSE antibodies.numbered_l END) IS NOT TRUE
+                     ))
+                 )
+                 ORDER BY fab_id",
+                RECORD_COLUMNS, clause, ProcessingStage::NumberingH.as_str(), ProcessingStage::NumberingL.as_str()
+            ))?;
+            let rows = stmt.query_map(rusqlite::params_from_iter(values), AntibodyRecord::from_row)?;
+            rows.collect()
+        })
+    }
+}
+
+// Recorded failures per stage name, for `Db::stats`
+pub(super) fn error_counts(conn: &rusqlite::Connection) -> Result<BTreeMap<String, usize>> {
+    let mut stmt = conn.prepare("SELECT stage, COUNT(*) FROM processing_errors GROUP BY stage")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as usize)))?;
+    rows.collect()
+}
@@ -0,0 +1,177 @@
+//! Storage of the per-chain matching features.
+use super::{chain_column, AntibodyRecord, Db, DbFilter, RECORD_COLUMNS};
+use crate::analysis;
+use crate::features::{ChainFeatures, FEATURES_VERSION};
+use crate::numbering::ChainType;
+use rusqlite::{params, Connection, Result};
+use std::collections::BTreeMap;
+
+/// Columns read by `features_from_row`, after the fab_id
+const FEATURE_COLUMNS: &str = "chain_id, chain_type, ca_coords, rama_angles, rama_seq, rg, seq, fingerprint";
+
+// Features of one row; None if its blobs are unreadable
+fn features_from_row(row: &rusqlite::Row, first: usize) -> Result<Option<ChainFeatures>> {
+    let chain_id: String = row.get(first)?;
+    let chain_type: String = row.get(first + 1)?;
+    let chain_type = if chain_type == chain_column(ChainType::Heavy) { ChainType::Heavy } else { ChainType::Kappa };
+    let fingerprint: Option<Vec<u8>> = row.get(first + 7)?;
+    Ok(ChainFeatures::from_stored(
+        chain_id.chars().next().unwrap_or(' '),
+        chain_type,
+        &row.get::<_, Vec<u8>>(first + 2)?,
+        &row.get::<_, Vec<u8>>(first + 3)?,
+        &row.get::<_, String>(first + 4)?,
+        row.get::<_, Option<f64>>(first + 5)?.unwrap_or_default(),
+        row.get(first + 6)?,
+        fingerprint.as_deref().and_then(analysis::fingerprint_from_bytes).unwrap_or_default(),
+    ))
+}
+
+// Features per Fab of a query whose rows start with the fab_id, dropping
+// Fabs with an unreadable chain
+fn features_by_fab(conn: &Connection, sql: &str, values: Vec<Box<dyn rusqlite::ToSql>>) -> Result<Vec<(i64, Vec<ChainFeatures>)>> {
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map(rusqlite::params_from_iter(values), |row| Ok((row.get::<_, i64>(0)?, features_from_row(row, 1)?)))?;
+    let mut fabs: BTreeMap<i64, Option<Vec<ChainFeatures>>> = BTreeMap::new();
+    for row in rows {
+        let (fab_id, features) = row?;
+        let chains = fabs.entry(fab_id).or_insert_with(|| Some(Vec::new()));
+        match features {
+            Some(features) => if let Some(chains) = chains {
+                chains.push(features);
+            },
+            None => *chains = None,
+        }
+    }
+    Ok(fabs.into_iter().filter_map(|(fab_id, chains)| Some((fab_id, chains?))).collect())
+}
+
+impl Db {
+    /// Replaces the features of a Fab, kept in the given chain order.
+    pub fn store_features(&self, fab_id: i64, chains: &[ChainFeatures]) -> Result<()> {
+        let conn = self.get_conn();
+        conn.execute("DELETE FROM features WHERE fab_id = ?1", [fab_id])?;
+        let mut insert = conn.prepare_cached(&format!(
+            "INSERT INTO features (fab_id, pdb_id, ordinal, version, {})
+             SELECT fab_id, pdb_id, ?2, {}, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10 FROM antibodies WHERE fab_id = ?1",
+            FEATURE_COLUMNS, FEATURES_VERSION
+        ))?;
+        for (ordinal, chain) in chains.iter().enumerate() {
+            insert.execute(params![
+                fab_id,
+                ordinal as i64,
+                chain.chain_id.to_string(),
+                chain_column(chain.chain_type),
+                chain.ca_bytes(),
+                chain.rama_bytes(),
+                chain.rama_seq(),
+                chain.rg,
+                chain.seq,
+                analysis::fingerprint_to_bytes(&chain.fingerprint)
+            ])?;
+        }
+        Ok(())
+    }
+
+    /// Features of the first Fab of an entry that has any, in chain order.
+    pub fn get_features(&self, pdb_id: &str) -> Result<Vec<ChainFeatures>> {
+        let sql = format!(
+            "SELECT fab_id, {} FROM features
+             WHERE fab_id = (SELECT MIN(fab_id) FROM features WHERE pdb_id = ?1)
+             ORDER BY ordinal",
+            FEATURE_COLUMNS
+        );
+        let fabs = self.read(|conn| features_by_fab(conn, &sql, vec![Box::new(pdb_id.to_string())]))?;
+        Ok(fabs.into_iter().next().map(|(_, chains)| chains).unwrap_or_default())
+    }
+
+    /// Features of each Fab selected by `filter` that has readable ones, in
+    /// fab_id order.
+    pub fn features_iter(&self, filter: &DbFilter) -> Result<impl Iterator<Item = (i64, Vec<ChainFeatures>)> + use<>> {
+        let (clause, values) = filter.sql();
+        let sql = format!(
+            "SELECT fab_id, {} FROM features WHERE fab_id IN (SELECT fab_id FROM antibodies WHERE {})
+             ORDER BY fab_id, ordinal",
+            FEATURE_COLUMNS, clause
+        );
+        Ok(self.read(|conn| features_by_fab(conn, &sql, values))?.into_iter())
+    }
+
+    /// Processed Fabs with a stored structure but no features, as left by
+    /// processing before features were kept, or features of an older
+    /// `FEATURES_VERSION`. Blacklisted entries are left out.
+    pub fn fabs_needing_features(&self) -> Result<Vec<AntibodyRecord>> {
+        let (clause, values) = DbFilter { processed: Some(true), with_structure: true, skip_blacklisted: true, ..Default::default() }.sql();
+        self.read(|conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM antibodies
+                 WHERE {} AND NOT EXISTS (SELECT 1 FROM features f WHERE f.fab_id = antibodies.fab_id AND f.version >= {})
+                 ORDER BY fab_id",
+                RECORD_COLUMNS, clause, FEATURES_VERSION
+            ))?;
+            let rows = stmt.query_map(rusqlite::params_from_iter(values), AntibodyRecord::from_row)?;
+            rows.collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::features::fab_features;
+    use crate::pdb::{Atom, Pdb, Point, StructureFormat};
+
+    fn chain(chain_id: char, n: i32) -> Vec<Atom> {
+        (0..n * 3).map(|k| {
+            let name = ["N", "CA", "C"][k as usize % 3];
+            Atom {
+                serial: k + 1, name: name.into(), alt_loc: ' ', res_name: "TYR".into(), chain_id, res_seq: k / 3 + 1,
+                i_code: ' ', pos: Point::new(k as f64 * 1.2, (k % 2) as f64 * 0.8, (k % 5) as f64 * 0.1),
+                occupancy: 1.0, temp_factor: 15.0, element: name.into(),
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn test_features_storage() {
+        let db = Db::open_in_memory().unwrap();
+        db.insert_raw("1abc", "H", "L", None, "human", "x-ray", false).unwrap();
+        db.insert_raw("1abc", "A", "B", None, "human", "x-ray", false).unwrap();
+        db.insert_raw("2abc", "H", "L", None, "human", "x-ray", false).unwrap();
+        db.get_conn().execute("UPDATE antibodies SET processed = TRUE", []).unwrap();
+        for id in ["1abc", "2abc"] {
+            db.put_structure(id, "ATOM", StructureFormat::Pdb).unwrap();
+        }
+        let fabs: Vec<i64> = db.list_antibodies(&DbFilter::default()).unwrap().iter().map(|r| r.fab_id).collect();
+        assert!(db.get_features("1abc").unwrap().is_empty());
+        assert_eq!(db.fabs_needing_features().unwrap().len(), 3);
+
+        let mut atoms = chain('L', 5);
+        atoms.extend(chain('H', 7));
+        let chains = fab_features(&Pdb { atoms }, 'H', 'L');
+        db.store_features(fabs[0], &chains).unwrap();
+        // Stored again, replaced
+        db.store_features(fabs[0], &chains).unwrap();
+        db.store_features(fabs[1], &chains[1..]).unwrap();
+
+        let stored = db.get_features("1abc").unwrap();
+        assert_eq!(stored.iter().map(|c| (c.chain_id, c.chain_type, c.seq.len())).collect::<Vec<_>>(), [('L', ChainType::Kappa, 5), ('H', ChainType::Heavy, 7)]);
+        assert_eq!(stored[1].ca_bytes(), chains[1].ca_bytes());
+        assert_eq!(stored[1].fingerprint, chains[1].fingerprint);
+        assert_eq!(db.fabs_needing_features().unwrap().iter().map(|r| r.fab_id).collect::<Vec<_>>(), [fabs[2]]);
+        // Features of an older version are computed again
+        db.get_conn().execute("UPDATE features SET version = ?2 WHERE fab_id = ?1", params![fabs[1], FEATURES_VERSION - 1]).unwrap();
+        assert_eq!(db.fabs_needing_features().unwrap().iter().map(|r| r.fab_id).collect::<Vec<_>>(), [fabs[1], fabs[2]]);
+        db.store_features(fabs[1], &chains[1..]).unwrap();
+
+        let all: Vec<(i64, usize)> = db.features_iter(&DbFilter::default()).unwrap().map(|(fab, c)| (fab, c.len())).collect();
+        assert_eq!(all, [(fabs[0], 2), (fabs[1], 1)]);
+        let filter = DbFilter { pdb_id: Some("1abc".to_string()), ..Default::default() };
+        assert_eq!(db.features_iter(&filter).unwrap().count(), 2);
+
+        // A Fab with an unreadable chain has none
+        db.get_conn().execute("UPDATE features SET ca_coords = x'00' WHERE fab_id = ?1 AND ordinal = 1", [fabs[0]]).unwrap();
+        assert_eq!(db.get_features("1abc").unwrap().len(), 0);
+        assert_eq!(db.features_iter(&DbFilter::default()).unwrap().map(|(fab, _)| fab).collect::<Vec<_>>(), [fabs[1]]);
+    }
+}
@@ -0,0 +1,50 @@
+//! The variable domains of each numbered Fab as trimmed PDB text, all of a
+//! structure matching compares; entries also hold constant domains, the
+//! antigen or at times a whole spike.
+use super::{Db, STRUCTURE_ZSTD_LEVEL};
+use crate::pdb::{Pdb, ResidueId};
+use rusqlite::{params, OptionalExtension, Result};
+use std::collections::HashSet;
+
+impl Db {
+    /// Replaces the Fv atoms of a Fab, zstd-compressed, or drops them for
+    /// None.
+    pub fn store_fv(&self, fab_id: i64, fv: Option<&Pdb>) -> anyhow::Result<()> {
+        let conn = self.get_conn();
+        match fv {
+            Some(fv) => conn.execute(
+                "INSERT OR REPLACE INTO fv_structures (fab_id, pdb_id, atoms, fv_blob)
+                 SELECT fab_id, pdb_id, ?2, ?3 FROM antibodies WHERE fab_id = ?1",
+                params![fab_id, fv.atoms.len() as i64, zstd::encode_all(fv.to_pdb_string().as_bytes(), STRUCTURE_ZSTD_LEVEL)?],
+            )?,
+            None => conn.execute("DELETE FROM fv_structures WHERE fab_id = ?1", [fab_id])?,
+        };
+        Ok(())
+    }
+
+    /// The stored Fv atoms of a Fab.
+    pub fn load_fv(&self, fab_id: i64) -> anyhow::Result<Option<Pdb>> {
+        let blob: Option<Vec<u8>> = self.read(|conn| conn.query_row(
+            "SELECT fv_blob FROM fv_structures WHERE fab_id = ?1",
+            [fab_id],
+            |row| row.get(0),
+        ).optional())?;
+        let Some(blob) = blob else { return Ok(None) };
+        Ok(Some(Pdb::from_str(&String::from_utf8(zstd::decode_all(blob.as_slice())?)?)))
+    }
+
+    /// Structure residues of the stored numbering of a Fab, which
+    /// `Pdb::extract_fv` takes; empty if it was stored without them.
+    pub fn numbered_residues(&self, fab_id: i64) -> Result<HashSet<ResidueId>> {
+        self.read(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT res_chain, res_seq, i_code FROM numbering WHERE fab_id = ?1 AND res_seq IS NOT NULL",
+            )?;
+            let rows = stmt.query_map([fab_id], |row| {
+                let code = |i: usize| -> Result<char> { Ok(row.get::<_, String>(i)?.chars().next().unwrap_or(' ')) };
+                Ok(ResidueId { chain_id: code(0)?, res_seq: row.get(1)?, i_code: code(2)? })
+            })?;
+            rows.collect()
+        })
+    }
+}
@@ -0,0 +1,161 @@
+//! Consistency checks between the tables, for databases left behind by
+//! interrupted runs, and resetting the affected Fabs for another pass.
+use super::Db;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use std::fmt;
+
+/// Problems found by `Db::check_integrity`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct IntegrityReport {
+    /// Messages of SQLite's own integrity check, empty when it passes
+    pub sqlite: Vec<String>,
+    /// Processed Fabs without a stored result
+    pub processed_without_result: Vec<i64>,
+    /// Fabs that passed quality control but whose entry has no structure,
+    /// nor a pruned one with features of the Fab kept
+    pub passed_without_structure: Vec<i64>,
+    /// Entries whose stored structure is empty
+    pub empty_structures: Vec<String>,
+    /// Numbering rows of Fabs that no longer exist, left by writers without
+    /// foreign key enforcement
+    pub orphaned_numbering: usize,
+}
+
+impl IntegrityReport {
+    pub fn is_clean(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl fmt::Display for IntegrityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sqlite = if self.sqlite.is_empty() { "ok".to_string() } else { self.sqlite.join("; ") };
+        writeln!(f, "SQLite check:               {}", sqlite)?;
+        writeln!(f, "Processed without result:   {}", self.processed_without_result.len())?;
+        writeln!(f, "Passed QC without structure: {}", self.passed_without_structure.len())?;
+        writeln!(f, "Empty structures:           {}", self.empty_structures.len())?;
+        write!(f, "Orphaned numbering rows:    {}", self.orphaned_numbering)
+    }
+}
+
+fn column<T: rusqlite::types::FromSql>(conn: &Connection, sql: &str) -> rusqlite::Result<Vec<T>> {
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map([], |row| row.get(0))?;
+    rows.collect()
+}
+
+impl Db {
+    /// Looks for rows an interrupted run can leave inconsistent, and runs
+    /// SQLite's integrity check.
+    pub fn check_integrity(&self) -> anyhow::Result<IntegrityReport> {
+        Ok(self.read(|conn| {
+            let sqlite: Vec<String> = column(conn, "PRAGMA integrity_check")?;
+            Ok(IntegrityReport {
+                sqlite: sqlite.into_iter().filter(|message| message != "ok").collect(),
+                processed_without_result: column(
+                    conn,
+                    "SELECT fab_id FROM antibodies
+                     WHERE processed = TRUE AND (json_blob IS NULL OR length(json_blob) = 0)
+                     ORDER BY fab_id",
+                )?,
+                passed_without_structure: column(
+                    conn,
+                    "SELECT fab_id FROM antibodies a
+                     WHERE passed_qc = TRUE AND NOT EXISTS (
+                         SELECT 1 FROM structures s WHERE s.pdb_id = a.pdb_id
+                             AND (pdb_blob IS NOT NULL
+                                 OR EXISTS (SELECT 1 FROM features f WHERE f.fab_id = a.fab_id)
+                                 OR EXISTS (SELECT 1 FROM fv_structures v WHERE v.fab_id = a.fab_id))
+                     )
+                     ORDER BY fab_id",
+                )?,
+                empty_structures: column(
+                    conn,
+                    "SELECT pdb_id FROM structures WHERE pdb_blob IS NOT NULL AND length(pdb_blob) = 0 ORDER BY pdb_id",
+                )?,
+                orphaned_numbering: conn.query_row(
+                    "SELECT COUNT(*) FROM numbering WHERE fab_id NOT IN (SELECT fab_id FROM antibodies)",
+                    [],
+                    |row| row.get::<_, i64>(0),
+                )? as usize,
+            })
+        })?)
+    }
+
+    /// Resets the Fabs named in `report` to unprocessed, deletes empty
+    /// structures so they are downloaded again, and drops orphaned numbering,
+    /// in one transaction. Returns the Fabs reset. Corruption reported by
+    /// SQLite itself is left alone.
+    pub fn repair(&self, report: &IntegrityReport) -> anyhow::Result<usize> {
+        self.with_transaction(|tx| {
+            let mut reset = 0;
+            let mut reset_fab = tx.prepare(
+                "UPDATE antibodies SET processed = FALSE, passed_qc = FALSE, json_blob = NULL, qc_report = NULL
+                 WHERE fab_id = ?1 AND (processed IS NOT FALSE OR passed_qc IS NOT FALSE OR qc_report IS NOT NULL)",
+            )?;
+            for fab_id in report.processed_without_result.iter().chain(&report.passed_without_structure) {
+                reset += reset_fab.execute([fab_id])?;
+            }
+            for pdb_id in &report.empty_structures {
+                tx.execute("DELETE FROM structures WHERE pdb_id = ?1", [pdb_id])?;
+                reset += tx.execute(
+                    "UPDATE antibodies SET processed = FALSE, passed_qc = FALSE, json_blob = NULL, qc_report = NULL
+                     WHERE pdb_id = ?1 AND (processed IS NOT FALSE OR passed_qc IS NOT FALSE OR qc_report IS NOT NULL)",
+                    params![pdb_id],
+                )?;
+            }
+            if report.orphaned_numbering > 0 {
+                tx.execute("DELETE FROM numbering WHERE fab_id NOT IN (SELECT fab_id FROM antibodies)", [])?;
+            }
+            Ok(reset)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::StructureFormat;
+    use crate::numbering::{self, ChainType, Position};
+
+    #[test]
+    fn test_check_and_repair() {
+        let db = Db::open_in_memory().unwrap();
+        for id in ["1abc", "2abc", "3abc", "4abc", "6abc"] {
+            db.insert_raw(id, "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+            db.put_structure(id, "ATOM", StructureFormat::Pdb).unwrap();
+        }
+        db.get_conn().execute("UPDATE antibodies SET processed = TRUE, passed_qc = TRUE, json_blob = '{}'", []).unwrap();
+        let fab = |id: &str| db.get_antibody(id).unwrap().unwrap().fab_id;
+        let numbered = [(Position::new(1, None), 'E')];
+        db.store_numbering(fab("6abc"), ChainType::Heavy, numbering::SCHEME, &numbered).unwrap();
+        assert!(db.check_integrity().unwrap().is_clean());
+
+        let conn = db.get_conn();
+        conn.execute("UPDATE antibodies SET json_blob = NULL WHERE pdb_id = '1abc'", []).unwrap();
+        conn.execute("DELETE FROM structures WHERE pdb_id = '2abc'", []).unwrap();
+        conn.execute("UPDATE structures SET pdb_blob = x'' WHERE pdb_id = '3abc'", []).unwrap();
+        conn.execute("INSERT INTO antibodies (pdb_id, h_chain, l_chain, processed, json_blob) VALUES ('5abc', 'H', 'L', TRUE, '')", []).unwrap();
+        // As written by a connection without foreign key enforcement
+        conn.execute_batch("PRAGMA foreign_keys = OFF; DELETE FROM antibodies WHERE pdb_id = '6abc'; PRAGMA foreign_keys = ON;").unwrap();
+        drop(conn);
+
+        let report = db.check_integrity().unwrap();
+        assert_eq!(report, IntegrityReport {
+            sqlite: Vec::new(),
+            processed_without_result: vec![fab("1abc"), fab("5abc")],
+            passed_without_structure: vec![fab("2abc")],
+            empty_structures: vec!["3abc".to_string()],
+            orphaned_numbering: 1,
+        });
+
+        assert_eq!(db.repair(&report).unwrap(), 4);
+        assert!(db.check_integrity().unwrap().is_clean());
+        for id in ["1abc", "2abc", "3abc", "5abc"] {
+            assert!(!db.get_antibody(id).unwrap().unwrap().processed, "{}", id);
+        }
+        assert!(db.get_antibody("4abc").unwrap().unwrap().processed);
+        assert!(db.load_structure("3abc").unwrap().is_none());
+    }
+}
@@ -0,0 +1,78 @@
+//! Where the database file lives when no path is given, so a shared copy
+//! can be used from any working directory.
+use super::Db;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Environment variable naming the database file, below the command line flag
+pub const DB_PATH_ENV: &str = "SCAFFOLDING_DB";
+
+/// File name of the database in the data directories
+const DB_FILE: &str = "antibodies.db";
+
+/// The database file to use: `flag` if given, else `$SCAFFOLDING_DB`, else
+/// under `$XDG_DATA_HOME` if that is set, else `data/` in the working
+/// directory.
+pub fn default_path(flag: Option<&Path>) -> PathBuf {
+    resolve_path(flag, &|key| std::env::var_os(key))
+}
+
+// `default_path` with the environment looked up by `env`
+fn resolve_path(flag: Option<&Path>, env: &dyn Fn(&str) -> Option<OsString>) -> PathBuf {
+    let set = |key: &str| env(key).filter(|value| !value.is_empty()).map(PathBuf::from);
+    if let Some(path) = flag {
+        path.to_path_buf()
+    } else if let Some(path) = set(DB_PATH_ENV) {
+        path
+    } else if let Some(data_home) = set("XDG_DATA_HOME") {
+        data_home.join(env!("CARGO_PKG_NAME")).join(DB_FILE)
+    } else {
+        Path::new("data").join(DB_FILE)
+    }
+}
+
+impl Db {
+    /// Opens or creates the database at `default_path(flag)`, creating its
+    /// directory if needed.
+    pub fn open_default(flag: Option<&Path>) -> anyhow::Result<Self> {
+        let path = default_path(flag);
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        Self::open(path)
+    }
+
+    /// File of the database, None in memory.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_path() {
+        let env = |vars: &'static [(&'static str, &'static str)]| move |key: &str| {
+            vars.iter().find(|(k, _)| *k == key).map(|(_, v)| OsString::from(v))
+        };
+        let both = env(&[(DB_PATH_ENV, "/shared/ab.db"), ("XDG_DATA_HOME", "/home/me/.local/share")]);
+        assert_eq!(resolve_path(Some(Path::new("my.db")), &both), Path::new("my.db"));
+        assert_eq!(resolve_path(None, &both), Path::new("/shared/ab.db"));
+        assert_eq!(
+            resolve_path(None, &env(&[(DB_PATH_ENV, ""), ("XDG_DATA_HOME", "/home/me/.local/share")])),
+            Path::new("/home/me/.local/share/scaffolding-lna-rs/antibodies.db")
+        );
+        assert_eq!(resolve_path(None, &env(&[])), Path::new("data/antibodies.db"));
+    }
+
+    #[test]
+    fn test_open_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join("ab.db");
+        let db = Db::open_default(Some(&path)).unwrap();
+        assert_eq!(db.path(), Some(path.as_path()));
+        assert!(path.exists());
+    }
+}
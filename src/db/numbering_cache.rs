@@ -0,0 +1,31 @@
+//! Numbering results by sequence, so chains seen before, such as re-deposits
+//! and other copies in the asymmetric unit, are not numbered again.
+use super::Db;
+use crate::numbering::NumberingResult;
+use rusqlite::{OptionalExtension, Result};
+
+impl Db {
+    /// The cached domains under `key`; None if there are none or they no
+    /// longer read as such.
+    pub fn cached_numbering(&self, key: &str) -> Result<Option<Vec<NumberingResult>>> {
+        let json: Option<String> = self.read(|conn| {
+            conn.query_row("SELECT result FROM numbering_cache WHERE key = ?1", [key], |row| row.get(0)).optional()
+        })?;
+        let mut cached: Option<Vec<NumberingResult>> = json.and_then(|json| serde_json::from_str(&json).ok());
+        for result in cached.iter_mut().flatten() {
+            if result.regions.len() != result.positions.len() {
+                result.fill_regions();
+            }
+        }
+        Ok(cached)
+    }
+
+    /// Caches the domains of a sequence under `key`, replacing any there.
+    pub fn cache_numbering(&self, key: &str, result: &[NumberingResult]) -> anyhow::Result<()> {
+        self.get_conn().execute(
+            "INSERT OR REPLACE INTO numbering_cache (key, result) VALUES (?1, ?2)",
+            [key, &serde_json::to_string(result)?],
+        )?;
+        Ok(())
+    }
+}
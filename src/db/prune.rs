@@ -0,0 +1,141 @@
+//! Dropping the stored structure files of processed entries, by far the
+//! largest part of the database, once matching can do without them.
+use super::Db;
+use crate::features::FEATURES_VERSION;
+use chrono::Utc;
+use rusqlite::Connection;
+use std::fmt;
+
+/// Meta key of the time structures were last pruned, RFC 3339; unset once
+/// they are all downloaded again
+pub const BLOBS_PRUNED_KEY: &str = "blobs_pruned";
+
+/// Processed entries whose structure `Db::prune_blobs` keeps. Entries with
+/// an unprocessed Fab, or a passed one without current features, always keep
+/// theirs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PruneKeep {
+    None,
+    /// Entries with a Fab that failed quality control, to reinspect them
+    QcFailed,
+    /// Every structure; the file is only compacted
+    All,
+}
+
+/// Outcome of `Db::prune_blobs`; sizes in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PruneReport {
+    pub pruned: usize,
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+impl fmt::Display for PruneReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mb = |bytes: u64| bytes as f64 / 1e6;
+        writeln!(f, "Pruned structures: {}", self.pruned)?;
+        write!(f, "Size:              {:.1} MB -> {:.1} MB", mb(self.size_before), mb(self.size_after))
+    }
+}
+
+// Bytes in use by the database, whether in a file or in memory
+fn database_size(conn: &Connection) -> rusqlite::Result<u64> {
+    let pages: i64 = conn.query_row("PRAGMA page_count", [], |row| row.get(0))?;
+    let page_size: i64 = conn.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+    Ok((pages * page_size) as u64)
+}
+
+impl Db {
+    /// Drops the structure files of processed entries, except those `keep`
+    /// names, and compacts the file. The structures rows stay, so downloads
+    /// skip pruned entries until one needs processing again or an update
+    /// asks for them back.
+    pub fn prune_blobs(&self, keep: PruneKeep) -> anyhow::Result<PruneReport> {
+        let conn = self.get_conn();
+        let size_before = database_size(&conn)?;
+        let kept = match keep {
+            PruneKeep::None => "FALSE",
+            PruneKeep::QcFailed => "a.passed_qc IS NOT TRUE",
+            PruneKeep::All => "TRUE",
+        };
+        let pruned = conn.execute(
+            &format!(
+                "UPDATE structures SET pdb_blob = NULL
+                 WHERE pdb_blob IS NOT NULL AND NOT EXISTS (
+                     SELECT 1 FROM antibodies a WHERE a.pdb_id = structures.pdb_id AND (
+                         a.processed IS NOT TRUE
+                         OR (a.passed_qc IS TRUE AND NOT EXISTS (
+                             SELECT 1 FROM features f WHERE f.fab_id = a.fab_id AND f.version >= {}
+                         ))
+                         OR {}
+                     )
+                 )",
+                FEATURES_VERSION, kept
+            ),
+            [],
+        )?;
+        if pruned > 0 {
+            self.meta_set(BLOBS_PRUNED_KEY, &Utc::now().to_rfc3339())?;
+        }
+        conn.execute("VACUUM", [])?;
+        // Shrinks the file itself rather than leaving the pages in the WAL
+        self.checkpoint()?;
+        Ok(PruneReport { pruned, size_before, size_after: database_size(&conn)? })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{DbFilter, StructureFormat};
+    use crate::features::ChainFeatures;
+    use crate::numbering::ChainType;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_prune_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let db = Db::open(&path).unwrap();
+        // Random text, which compression barely shrinks
+        let mut rng = StdRng::seed_from_u64(7);
+        let ids: Vec<String> = (0..40).map(|i| format!("{}abc", i)).collect();
+        for id in &ids {
+            db.insert_raw(id, "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+            let content: String = (0..50_000).map(|_| rng.random_range('a'..='z')).collect();
+            db.put_structure(id, &content, StructureFormat::Pdb).unwrap();
+        }
+        let features = [ChainFeatures {
+            chain_id: 'H', chain_type: ChainType::Heavy, ca: Vec::new(), rama: Vec::new(), rg: 10.0, seq: String::new(), fingerprint: Vec::new(),
+        }];
+        for record in db.list_antibodies(&DbFilter::default()).unwrap() {
+            db.store_features(record.fab_id, &features).unwrap();
+        }
+        // 0abc is unprocessed, 1abc failed QC, 2abc passed without features
+        let conn = db.get_conn();
+        conn.execute("UPDATE antibodies SET processed = TRUE, passed_qc = TRUE, json_blob = '{}' WHERE pdb_id <> '0abc'", []).unwrap();
+        conn.execute("UPDATE antibodies SET passed_qc = FALSE WHERE pdb_id = '1abc'", []).unwrap();
+        conn.execute("DELETE FROM features WHERE pdb_id = '2abc'", []).unwrap();
+        drop(conn);
+
+        assert_eq!(db.prune_blobs(PruneKeep::All).unwrap().pruned, 0);
+        assert_eq!(db.meta_get(BLOBS_PRUNED_KEY).unwrap(), None);
+        assert_eq!(db.prune_blobs(PruneKeep::QcFailed).unwrap().pruned, 37);
+        assert!(db.load_structure("1abc").unwrap().is_some());
+
+        let file_size = std::fs::metadata(&path).unwrap().len();
+        let report = db.prune_blobs(PruneKeep::None).unwrap();
+        assert_eq!(report.pruned, 1);
+        assert!(report.size_after < report.size_before);
+        assert!(std::fs::metadata(&path).unwrap().len() < file_size);
+        assert!(db.meta_get(BLOBS_PRUNED_KEY).unwrap().is_some());
+        for (id, stored) in [("0abc", true), ("1abc", false), ("2abc", true), ("3abc", false)] {
+            assert_eq!(db.load_structure(id).unwrap().is_some(), stored, "{}", id);
+        }
+        // Pruned entries still count as matchable, their rows are kept
+        let scorable = DbFilter { scorable: true, ..Default::default() };
+        assert_eq!(db.list_antibodies(&scorable).unwrap().len(), 40);
+        assert!(db.check_integrity().unwrap().is_clean());
+    }
+}
@@ -0,0 +1,440 @@
+//! Portable subsets of the database: export of the processed Fabs to a pruned
+//! SQLite file or a zstd-compressed JSONL dump, and merging such an export
+//! into another database.
+use super::{Db, MIGRATIONS};
+use anyhow::{bail, Context};
+use rusqlite::types::Value;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// First bytes of every SQLite database file
+const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+
+/// zstd level of JSONL exports
+const EXPORT_ZSTD_LEVEL: i32 = 9;
+
+/// Container of an export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportFormat {
+    /// A database file that opens like any other
+    #[default]
+    Sqlite,
+    /// One JSON object per row, zstd-compressed
+    Jsonl,
+}
+
+/// What `Db::export` writes.
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    /// Include the stored structures. Matching scores against them, so an
+    /// export without them serves filtering and statistics only.
+    pub structures: bool,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self { format: ExportFormat::Sqlite, structures: true }
+    }
+}
+
+/// Outcome of `Db::import`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Fabs that were not in the database
+    pub added: usize,
+    /// Unprocessed Fabs that took the imported processing results
+    pub updated: usize,
+    /// Processed Fabs left as they were
+    pub kept: usize,
+    /// Structures of entries that had none
+    pub structures: usize,
+}
+
+impl fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Added Fabs:      {}", self.added)?;
+        writeln!(f, "Updated Fabs:    {}", self.updated)?;
+        writeln!(f, "Kept Fabs:       {}", self.kept)?;
+        write!(f, "Structures:      {}", self.structures)
+    }
+}
+
+// Rows of the exported subset, per table, in import order: numbering,
+// feature, embedding and Fv rows refer to the Fabs before them
+fn export_queries(structures: bool) -> Vec<(&'static str, &'static str)> {
+    let mut queries = vec![
+        ("antibodies", "SELECT * FROM antibodies WHERE processed = TRUE ORDER BY fab_id"),
+        (
+            "numbering",
+            "SELECT * FROM numbering WHERE fab_id IN (SELECT fab_id FROM antibodies WHERE processed = TRUE)
+             ORDER BY fab_id",
+        ),
+        (
+            "features",
+            "SELECT * FROM features WHERE fab_id IN (SELECT fab_id FROM antibodies WHERE processed = TRUE)
+             ORDER BY fab_id, ordinal",
+        ),
+        (
+            "embeddings",
+            "SELECT * FROM embeddings WHERE fab_id IN (SELECT fab_id FROM antibodies WHERE processed = TRUE)
+             ORDER BY fab_id",
+        ),
+        (
+            "fv_structures",
+            "SELECT * FROM fv_structures WHERE fab_id IN (SELECT fab_id FROM antibodies WHERE processed = TRUE)
+             ORDER BY fab_id",
+        ),
+    ];
+    if structures {
+        queries.push((
+            "structures",
+            "SELECT * FROM structures
+             WHERE pdb_blob IS NOT NULL AND pdb_id IN (SELECT pdb_id FROM antibodies WHERE processed = TRUE)
+             ORDER BY pdb_id",
+        ));
+    }
+    queries
+}
+
+fn value_to_json(value: Value) -> serde_json::Value {
+    match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Integer(i) => json!(i),
+        Value::Real(r) => json!(r),
+        Value::Text(t) => json!(t),
+        Value::Blob(b) => json!({ "hex": b.iter().map(|b| format!("{:02x}", b)).collect::<String>() }),
+    }
+}
+
+fn value_from_json(value: &serde_json::Value) -> anyhow::Result<Value> {
+    Ok(match value {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Integer(*b as i64),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Real(n.as_f64().context("Unrepresentable number")?),
+        },
+        serde_json::Value::String(s) => Value::Text(s.clone()),
+        serde_json::Value::Object(o) => {
+            let hex = o.get("hex").and_then(|h| h.as_str()).context("Object value without hex blob")?;
+            if !hex.len().is_multiple_of(2) {
+                bail!("Odd-length hex blob");
+            }
+            let bytes = (0..hex.len()).step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                .collect::<Result<Vec<u8>, _>>()?;
+            Value::Blob(bytes)
+        }
+        serde_json::Value::Array(_) => bail!("Unexpected array value"),
+    })
+}
+
+// Merges rows into the database, remapping the fab_ids of imported Fabs
+struct Merger<'a> {
+    conn: &'a Connection,
+    // Imported fab_id to local fab_id, for the Fabs taken over
+    fab_ids: HashMap<i64, i64>,
+    // Tables and local Fabs whose old rows in them have been cleared
+    cleared: HashSet<(&'static str, i64)>,
+    report: ImportReport,
+}
+
+impl<'a> Merger<'a> {
+    fn new(conn: &'a Connection) -> Self {
+        Self { conn, fab_ids: HashMap::new(), cleared: HashSet::new(), report: ImportReport::default() }
+    }
+
+    fn row(&mut self, table: &str, columns: &[String], values: Vec<Value>) -> anyhow::Result<()> {
+        let row: HashMap<&str, Value> = columns.iter().map(String::as_str).zip(values).collect();
+        match table {
+            "antibodies" => self.antibody(row),
+            "numbering" => self.fab_row("numbering", row),
+            "features" => self.fab_row("features", row),
+            "embeddings" => self.fab_row("embeddings", row),
+            "fv_structures" => self.fab_row("fv_structures", row),
+            "structures" => self.structure(row),
+            _ => Ok(()),
+        }
+    }
+
+    // New Fabs are added and unprocessed ones overwritten; processed local
+    // Fabs win. Clusters refer to fab_ids of the other database and are
+    // recomputed instead.
+    fn antibody(&mut self, mut row: HashMap<&str, Value>) -> anyhow::Result<()> {
+        let Some(Value::Integer(imported_id)) = row.remove("fab_id") else { bail!("Antibody row without fab_id") };
+        row.remove("cluster_id");
+        row.remove("cluster_representative");
+        let key = (row.get("pdb_id").cloned(), row.get("h_chain").cloned(), row.get("l_chain").cloned());
+        let existing: Option<(i64, bool)> = self.conn.query_row(
+            "SELECT fab_id, processed IS TRUE FROM antibodies WHERE pdb_id = ?1 AND h_chain = ?2 AND l_chain = ?3",
+            params![key.0, key.1, key.2],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        ).optional()?;
+
+        let (columns, values): (Vec<&str>, Vec<Value>) = row.into_iter().unzip();
+        let local_id = match existing {
+            Some((_, true)) => {
+                self.report.kept += 1;
+                return Ok(());
+            }
+            Some((fab_id, false)) => {
+                let assignments: Vec<String> = columns.iter().enumerate().map(|(i, c)| format!("{} = ?{}", c, i + 1)).collect();
+                self.conn.execute(
+                    &format!(
+                        "UPDATE antibodies SET {}, cluster_id = NULL, cluster_representative = NULL WHERE fab_id = {}",
+                        assignments.join(", "), fab_id
+                    ),
+                    rusqlite::params_from_iter(values),
+                )?;
+                self.report.updated += 1;
+                fab_id
+            }
+            None => {
+                self.insert("INSERT", "antibodies", &columns, values)?;
+                self.report.added += 1;
+                self.conn.last_insert_rowid()
+            }
+        };
+        self.fab_ids.insert(imported_id, local_id);
+        Ok(())
+    }
+
+    // A row of a per-Fab table; the first one of a Fab taken over replaces
+    // what the Fab had in that table
+    fn fab_row(&mut self, table: &'static str, mut row: HashMap<&str, Value>) -> anyhow::Result<()> {
+        let Some(Value::Integer(imported_id)) = row.remove("fab_id") else { bail!("Row of {} without fab_id", table) };
+        let Some(&fab_id) = self.fab_ids.get(&imported_id) else { return Ok(()) };
+        if self.cleared.insert((table, fab_id)) {
+            self.conn.execute(&format!("DELETE FROM {} WHERE fab_id = ?1", table), [fab_id])?;
+        }
+        row.insert("fab_id", Value::Integer(fab_id));
+        let (columns, values): (Vec<&str>, Vec<Value>) = row.into_iter().unzip();
+        self.insert("INSERT", table, &columns, values)?;
+        Ok(())
+    }
+
+    // Stored structures are never replaced
+    fn structure(&mut self, row: HashMap<&str, Value>) -> anyhow::Result<()> {
+        let (columns, values): (Vec<&str>, Vec<Value>) = row.into_iter().unzip();
+        self.report.structures += self.insert("INSERT OR IGNORE", "structures", &columns, values)?;
+        Ok(())
+    }
+
+    // Inserts one row with the given verb; returns the rows changed
+    fn insert(&self, verb: &str, table: &str, columns: &[&str], values: Vec<Value>) -> rusqlite::Result<usize> {
+        let placeholders: Vec<String> = (1..=columns.len()).map(|i| format!("?{}", i)).collect();
+        self.conn.execute(
+            &format!("{} INTO {} ({}) VALUES ({})", verb, table, columns.join(", "), placeholders.join(", ")),
+            rusqlite::params_from_iter(values),
+        )
+    }
+}
+
+// Column names and rows of a query, with every value as stored
+fn query_rows(conn: &Connection, sql: &str, mut f: impl FnMut(&[String], Vec<Value>) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    let mut stmt = conn.prepare(sql)?;
+    let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let values = (0..columns.len()).map(|i| row.get::<_, Value>(i)).collect::<rusqlite::Result<_>>()?;
+        f(&columns, values)?;
+    }
+    Ok(())
+}
+
+impl Db {
+    /// Writes the processed Fabs with their numbering and, if selected, their
+    /// structures to a new file at `path`, for `import` elsewhere. Download
+    /// bookkeeping and archived summaries are left out.
+    pub fn export(&self, path: &Path, options: &ExportOptions) -> anyhow::Result<()> {
+        if path.exists() {
+            bail!("{:?} already exists", path);
+        }
+        match options.format {
+            ExportFormat::Sqlite => {
+                self.read(|conn| conn.execute("VACUUM INTO ?1", [path.to_string_lossy()]))?;
+                let out = Connection::open(path)?;
+                out.execute_batch(
+                    "DELETE FROM numbering WHERE fab_id IN (SELECT fab_id FROM antibodies WHERE processed IS NOT TRUE);
+                     DELETE FROM features WHERE fab_id IN (SELECT fab_id FROM antibodies WHERE processed IS NOT TRUE);
+                     DELETE FROM embeddings WHERE fab_id IN (SELECT fab_id FROM antibodies WHERE processed IS NOT TRUE);
+                     DELETE FROM fv_structures WHERE fab_id IN (SELECT fab_id FROM antibodies WHERE processed IS NOT TRUE);
+                     DELETE FROM antibodies WHERE processed IS NOT TRUE;
+                     DELETE FROM structures WHERE pdb_id NOT IN (SELECT pdb_id FROM antibodies);
+                     DELETE FROM processing_errors;
+                     DELETE FROM download_failures;
+                     DELETE FROM meta WHERE key <> 'schema_version';",
+                )?;
+                if !options.structures {
+                    out.execute("DELETE FROM structures", [])?;
+                }
+                out.execute("VACUUM", [])?;
+            }
+            ExportFormat::Jsonl => {
+                let mut encoder = zstd::Encoder::new(BufWriter::new(File::create(path)?), EXPORT_ZSTD_LEVEL)?;
+                writeln!(encoder, "{}", json!({ "schema_version": MIGRATIONS.len() }))?;
+                let conn = self.get_conn();
+                for (table, sql) in export_queries(options.structures) {
+                    query_rows(&conn, sql, |columns, values| {
+                        let row: serde_json::Map<String, serde_json::Value> = columns.iter().cloned()
+                            .zip(values.into_iter().map(value_to_json))
+                            .collect();
+                        writeln!(encoder, "{}", json!({ "table": table, "row": row }))?;
+                        Ok(())
+                    })?;
+                }
+                encoder.finish()?.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Merges an export of either format into this database in one
+    /// transaction. Fabs that are new or still unprocessed here take the
+    /// imported rows, processed ones are kept; structures are only added.
+    /// The export must be at this build's schema version.
+    pub fn import(&self, path: &Path) -> anyhow::Result<ImportReport> {
+        let mut magic = [0u8; 16];
+        let is_sqlite = File::open(path)?.read_exact(&mut magic).is_ok() && magic == SQLITE_MAGIC;
+
+        let report = self.with_transaction(|tx| {
+            let mut merger = Merger::new(tx);
+            if is_sqlite {
+                let source = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+                let version = Db::schema_version(&source)?;
+                if version != MIGRATIONS.len() {
+                    bail!("{:?} is at schema version {}, this build needs {}", path, version, MIGRATIONS.len());
+                }
+                for (table, sql) in export_queries(true) {
+                    query_rows(&source, sql, |columns, values| merger.row(table, columns, values))?;
+                }
+            } else {
+                let mut lines = BufReader::new(zstd::Decoder::new(File::open(path)?)?).lines();
+                let header: serde_json::Value = serde_json::from_str(&lines.next().context("Empty export")??)?;
+                let version = header.get("schema_version").and_then(|v| v.as_u64());
+                if version != Some(MIGRATIONS.len() as u64) {
+                    bail!("{:?} is at schema version {:?}, this build needs {}", path, version, MIGRATIONS.len());
+                }
+                for line in lines {
+                    let line: serde_json::Value = serde_json::from_str(&line?)?;
+                    let table = line.get("table").and_then(|t| t.as_str()).context("Line without table")?;
+                    let row = line.get("row").and_then(|r| r.as_object()).context("Line without row")?;
+                    let columns: Vec<String> = row.keys().cloned().collect();
+                    let values = row.values().map(value_from_json).collect::<anyhow::Result<_>>()?;
+                    merger.row(table, &columns, values)?;
+                }
+            }
+            Ok(merger.report)
+        })?;
+        self.invalidate_embedding_index();
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::StructureFormat;
+    use crate::features::ChainFeatures;
+    use crate::numbering::{self, ChainType, Position};
+
+    // Two processed Fabs with numbering, features and a structure, one unprocessed
+    fn source() -> Db {
+        let db = Db::open_in_memory().unwrap();
+        for id in ["1abc", "2abc", "3abc"] {
+            db.insert_raw(id, "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+        }
+        db.get_conn().execute(
+            "UPDATE antibodies SET processed = TRUE, passed_qc = TRUE, kmer_profile = x'0102', rg = 21.5,
+             rama_fingerprint = x'ff', cluster_id = fab_id WHERE pdb_id IN ('1abc', '2abc')",
+            [],
+        ).unwrap();
+        let h3: Vec<(Position, char)> = (95..=102).map(|n| (Position::new(n, None), 'Y')).collect();
+        let features = [ChainFeatures {
+            chain_id: 'H',
+            chain_type: ChainType::Heavy,
+            ca: Vec::new(),
+            rama: Vec::new(),
+            rg: 12.0,
+            seq: String::new(),
+            fingerprint: vec![0.5; 4],
+        }];
+        for id in ["1abc", "2abc"] {
+            let fab = db.get_antibody(id).unwrap().unwrap().fab_id;
+            db.store_numbering(fab, ChainType::Heavy, numbering::SCHEME, &h3).unwrap();
+            db.store_features(fab, &features).unwrap();
+            db.put_structure(id, "ATOM", StructureFormat::Pdb).unwrap();
+        }
+        db.put_structure("3abc", "ATOM", StructureFormat::Pdb).unwrap();
+        db
+    }
+
+    // Holds 1abc processed with other results and 2abc still unprocessed
+    fn target() -> Db {
+        let db = Db::open_in_memory().unwrap();
+        db.insert_raw("0abc", "H", "L", None, "mus musculus", "X-RAY DIFFRACTION", false).unwrap();
+        db.insert_raw("1abc", "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+        db.insert_raw("2abc", "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+        db.get_conn().execute("UPDATE antibodies SET processed = TRUE, rg = 30.0 WHERE pdb_id = '1abc'", []).unwrap();
+        db
+    }
+
+    #[test]
+    fn test_export_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = source();
+        for (name, format) in [("export.db", ExportFormat::Sqlite), ("export.jsonl.zst", ExportFormat::Jsonl)] {
+            let path = dir.path().join(name);
+            db.export(&path, &ExportOptions { format, structures: true }).unwrap();
+            assert!(db.export(&path, &ExportOptions::default()).is_err());
+
+            let local = target();
+            let report = local.import(&path).unwrap();
+            assert_eq!(report, ImportReport { added: 0, updated: 1, kept: 1, structures: 2 }, "{}", name);
+
+            let kept = local.get_antibody("1abc").unwrap().unwrap();
+            assert_eq!(kept.rg, Some(30.0));
+            let updated = local.get_antibody("2abc").unwrap().unwrap();
+            assert!(updated.processed && updated.passed_qc);
+            assert_eq!(updated.kmer_profile, Some(vec![1, 2]));
+            assert_eq!(updated.rg, Some(21.5));
+            assert_eq!(updated.rama_fingerprint, Some(vec![0xff]));
+            assert_eq!(updated.cdr_h3.as_deref(), Some("YYYYYYYY"));
+            assert_eq!(updated.cluster_id, None);
+            assert_eq!(local.get_numbering("2abc", ChainType::Heavy).unwrap().len(), 8);
+            assert!(local.get_numbering("1abc", ChainType::Heavy).unwrap().is_empty());
+            assert_eq!(local.get_features("2abc").unwrap()[0].rg, 12.0);
+            assert!(local.get_features("1abc").unwrap().is_empty());
+            assert!(local.load_structure("2abc").unwrap().is_some());
+            // Unprocessed entries are not exported
+            assert!(local.get_antibody("3abc").unwrap().is_none());
+            assert!(local.load_structure("3abc").unwrap().is_none());
+
+            // Into an empty database every Fab is new
+            let empty = Db::open_in_memory().unwrap();
+            assert_eq!(empty.import(&path).unwrap(), ImportReport { added: 2, updated: 0, kept: 0, structures: 2 });
+            assert_eq!(empty.get_antibody("1abc").unwrap().unwrap().rg, Some(21.5));
+            assert_eq!(empty.get_numbering("1abc", ChainType::Heavy).unwrap().len(), 8);
+        }
+    }
+
+    #[test]
+    fn test_export_without_structures() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = source();
+        for (name, format) in [("export.db", ExportFormat::Sqlite), ("export.jsonl.zst", ExportFormat::Jsonl)] {
+            let path = dir.path().join(name);
+            db.export(&path, &ExportOptions { format, structures: false }).unwrap();
+            let local = Db::open_in_memory().unwrap();
+            assert_eq!(local.import(&path).unwrap().structures, 0);
+            assert!(local.get_antibody("1abc").unwrap().unwrap().processed);
+            assert!(local.load_structure("1abc").unwrap().is_none());
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! Writing summary records into the antibodies table, refreshing the
+//! metadata of Fabs already there.
+use super::Db;
+use crate::download::{FabKey, Record};
+use rusqlite::params;
+use std::borrow::Borrow;
+use std::collections::HashSet;
+
+/// Outcome of `Db::upsert_records`, in Fabs.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UpsertStats {
+    pub inserted: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+}
+
+impl Db {
+    /// Inserts the Fabs of `records` that are new and updates the metadata
+    /// of the others where it changed, e.g. a revised resolution, in one
+    /// transaction. Stored structures and processing results are left alone;
+    /// Fabs flagged 'removed' become current again.
+    pub fn upsert_records<R: Borrow<Record>>(&self, records: impl IntoIterator<Item = R>) -> anyhow::Result<UpsertStats> {
+        let existing: HashSet<FabKey> = self.read(|conn| {
+            let mut stmt = conn.prepare("SELECT pdb_id, h_chain, l_chain FROM antibodies")?;
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+            rows.collect()
+        })?;
+
+        self.with_transaction(|tx| {
+            let mut stats = UpsertStats::default();
+            let mut upsert = tx.prepare(
+                "INSERT INTO antibodies (pdb_id, h_chain, l_chain, resolution, species, method, scfv,
+                    antigen_chain, antigen_type, antigen_name, light_type, deposition_date)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+                 ON CONFLICT (pdb_id, h_chain, l_chain) DO UPDATE SET
+                    resolution = excluded.resolution, species = excluded.species, method = excluded.method,
+                    scfv = excluded.scfv, antigen_chain = excluded.antigen_chain, antigen_type = excluded.antigen_type,
+                    antigen_name = excluded.antigen_name, light_type = COALESCE(excluded.light_type, light_type),
+                    deposition_date = excluded.deposition_date,
+                    status = CASE WHEN status = 'removed' THEN 'current' ELSE status END
+                 WHERE resolution IS NOT excluded.resolution OR species IS NOT excluded.species
+                    OR method IS NOT excluded.method OR scfv IS NOT excluded.scfv
+                    OR antigen_chain IS NOT excluded.antigen_chain OR antigen_type IS NOT excluded.antigen_type
+                    OR antigen_name IS NOT excluded.antigen_name
+                    OR (excluded.light_type IS NOT NULL AND light_type IS NOT excluded.light_type)
+                    OR deposition_date IS NOT excluded.deposition_date OR status = 'removed'"
+            )?;
+            for rec in records {
+                let rec = rec.borrow();
+                let changed = upsert.execute(params![
+                    rec.pdb, rec.h_chain, rec.l_chain, rec.resolution, rec.species, rec.method, rec.scfv,
+                    rec.antigen_chain, rec.antigen_type, rec.antigen_name, rec.light_type,
+                    rec.date.map(|d| d.to_string())
+                ])? > 0;
+                match (changed, existing.contains(&rec.fab_key())) {
+                    (false, _) => stats.unchanged += 1,
+                    (true, false) => stats.inserted += 1,
+                    (true, true) => stats.updated += 1,
+                }
+            }
+            Ok(stats)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::LightType;
+
+    #[test]
+    fn test_upsert_records() {
+        let db = Db::open_in_memory().unwrap();
+        let record = Record {
+            pdb: "1abc".to_string(), h_chain: "H".to_string(), l_chain: "L".to_string(), resolution: Some(2.5),
+            species: "homo sapiens".to_string(), method: "X-RAY DIFFRACTION".to_string(), scfv: false,
+            antigen_chain: None, antigen_type: None, antigen_name: None, light_type: Some("kappa".to_string()), date: None,
+        };
+        assert_eq!(db.upsert_records([&record]).unwrap(), UpsertStats { inserted: 1, updated: 0, unchanged: 0 });
+        assert_eq!(db.upsert_records([&record]).unwrap(), UpsertStats { inserted: 0, updated: 0, unchanged: 1 });
+
+        let fab_id = db.get_antibody("1abc").unwrap().unwrap().fab_id;
+        db.get_conn().execute("UPDATE antibodies SET processed = TRUE, json_blob = '{}' WHERE fab_id = ?1", [fab_id]).unwrap();
+        let revised = Record { resolution: Some(2.1), light_type: None, ..record.clone() };
+        assert_eq!(db.upsert_records(std::slice::from_ref(&revised)).unwrap(), UpsertStats { inserted: 0, updated: 1, unchanged: 0 });
+
+        let stored = db.get_antibody("1abc").unwrap().unwrap();
+        assert_eq!((stored.fab_id, stored.resolution, stored.processed), (fab_id, Some(2.1), true));
+        // A blank light chain type keeps the known one
+        assert_eq!(stored.light_type, Some(LightType::Kappa));
+    }
+}
@@ -1,27 +1,75 @@
-use crate::db::Db;
-use anyhow::{Context, Result};
+use crate::db::{Db, BLOBS_PRUNED_KEY, LAST_SUMMARY_DOWNLOAD_KEY};
+use crate::pdb::{Pdb, StructureFormat};
+use crate::progress::ProgressSink;
+use anyhow::{bail, Context, Result};
+use chrono::{Datelike, NaiveDate, Utc};
+use flate2::read::GzDecoder;
 use log::{info, warn, debug};
 use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
-use std::io::{Read, Write};
-use std::path::Path;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
 use std::time::Duration;
 use rusqlite::params;
 
 const SUMMARY_URL: &str = "https://opig.stats.ox.ac.uk/webapps/sabdab-sabpred/sabdab/summary/all/";
 
-pub fn download_summary(path: &Path) -> Result<()> {
-    if path.exists() {
-        info!("Summary file already exists at {:?}", path);
-        return Ok(());
+/// Meta keys of the HTTP validators of the last summary download
+const SUMMARY_ETAG_KEY: &str = "sabdab_etag";
+const SUMMARY_LAST_MODIFIED_KEY: &str = "sabdab_last_modified";
+
+/// Makes sure an up-to-date summary is at `path`. A copy fetched earlier is
+/// revalidated with the recorded ETag / Last-Modified and kept on a 304; a
+/// file without recorded validators (placed by hand or by an older version)
+/// is used as is. `refresh` always downloads.
+pub fn download_summary(fetcher: &dyn Fetcher, db: &Db, path: &Path, refresh: bool) -> Result<()> {
+    fetch_summary(fetcher, db, SUMMARY_URL, path, refresh)
+}
+
+fn fetch_summary(fetcher: &dyn Fetcher, db: &Db, url: &str, path: &Path, refresh: bool) -> Result<()> {
+    let mut known = Validators::default();
+    if path.exists() && !refresh {
+        known = Validators { etag: db.meta_get(SUMMARY_ETAG_KEY)?, last_modified: db.meta_get(SUMMARY_LAST_MODIFIED_KEY)? };
+        if known == Validators::default() {
+            info!("Using summary file at {:?}", path);
+            return Ok(());
+        }
+    }
+
+    info!("Downloading summary from {}", url);
+    // Written next to the cached copy first, which stays intact on errors
+    let partial = path.with_extension("part");
+    let mut file = fs::File::create(&partial)?;
+    let fetched = fetcher.get_if_changed(url, &known, &mut file);
+    drop(file);
+    let validators = match fetched {
+        Ok(Some(validators)) => validators,
+        Ok(None) => {
+            fs::remove_file(&partial)?;
+            info!("Summary at {:?} is up to date", path);
+            return Ok(());
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+    };
+    fs::rename(&partial, path)?;
+    for (key, value) in [(SUMMARY_ETAG_KEY, validators.etag), (SUMMARY_LAST_MODIFIED_KEY, validators.last_modified)] {
+        match value {
+            Some(value) => db.meta_set(key, &value)?,
+            None => db.meta_remove(key)?,
+        }
     }
-    info!("Downloading summary from {}", SUMMARY_URL);
-    let mut response = ureq::get(SUMMARY_URL).call()?.into_body().into_reader();
-    let mut file = fs::File::create(path)?;
-    std::io::copy(&mut response, &mut file)?;
     Ok(())
 }
 
+#[derive(Debug, Clone)]
 pub struct Record {
     pub pdb: String,
     pub h_chain: String,
@@ -30,141 +78,1777 @@ pub struct Record {
     pub species: String,
     pub method: String,
     pub scfv: bool,
+    /// Antigen chain IDs, e.g. "A | B"; None for apo entries
+    pub antigen_chain: Option<String>,
+    /// Lowercase antigen type, e.g. "protein" or "peptide | peptide"
+    pub antigen_type: Option<String>,
+    pub antigen_name: Option<String>,
+    /// "kappa" or "lambda"; None when the summary leaves it blank
+    pub light_type: Option<String>,
+    /// Deposition date; None when missing or malformed
+    pub date: Option<NaiveDate>,
+}
+
+/// (pdb_id, h_chain, l_chain): identifies one Fab of an entry.
+pub type FabKey = (String, String, String);
+
+impl Record {
+    pub fn fab_key(&self) -> FabKey {
+        (self.pdb.clone(), self.h_chain.clone(), self.l_chain.clone())
+    }
+}
+
+// Column positions of the fields `parse_summary` reads
+struct SummaryColumns {
+    pdb: usize,
+    h_chain: usize,
+    l_chain: usize,
+    species: usize,
+    resolution: usize,
+    method: usize,
+    scfv: usize,
+    date: Option<usize>,
+    antigen_chain: Option<usize>,
+    antigen_type: Option<usize>,
+    antigen_name: Option<usize>,
+    light_type: Option<usize>,
 }
 
-pub fn parse_summary(path: &Path) -> Result<Vec<Record>> {
-    let content = fs::read_to_string(path)?;
-    let mut records = Vec::new();
-    let mut reader = csv::ReaderBuilder::new()
-        .delimiter(b'\t')
-        .from_reader(content.as_bytes());
+impl SummaryColumns {
+    // Layout of summary files cached by earlier versions
+    const POSITIONAL: Self = Self { pdb: 0, h_chain: 1, l_chain: 2, species: 12, resolution: 13, method: 14, scfv: 17, date: Some(9),
+        antigen_chain: Some(4), antigen_type: Some(5), antigen_name: Some(7), light_type: None };
 
-    for result in reader.records() {
-        let record = match result {
-            Ok(r) => r,
-            Err(_) => continue, 
+    /// Looks the columns up by name, failing with the list of missing ones.
+    fn from_header(header: &csv::StringRecord) -> Result<Self> {
+        let optional = |name: &str| header.iter().position(|h| h.trim() == name);
+        let mut missing = Vec::new();
+        let mut find = |name: &'static str| {
+            header.iter().position(|h| h.trim() == name).unwrap_or_else(|| {
+                missing.push(name);
+                0
+            })
+        };
+        let columns = Self {
+            pdb: find("pdb"),
+            h_chain: find("Hchain"),
+            l_chain: find("Lchain"),
+            species: find("heavy_species"),
+            resolution: find("resolution"),
+            method: find("method"),
+            scfv: find("scfv"),
+            date: optional("date"),
+            antigen_chain: optional("antigen_chain"),
+            antigen_type: optional("antigen_type"),
+            antigen_name: optional("antigen_name"),
+            light_type: optional("light_ctype"),
         };
-        
-        let pdb = record.get(0).unwrap_or("").to_string();
-        let h_chain = record.get(1).unwrap_or("").to_string();
-        let l_chain = record.get(2).unwrap_or("").to_string();
-        let species = record.get(12).unwrap_or("").to_lowercase();
-        let resolution = record.get(13).and_then(|s| s.parse::<f64>().ok());
-        let method = record.get(14).unwrap_or("").to_uppercase();
-        let scfv = record.get(17).map(|s| s == "True").unwrap_or(false); 
-
-        if species == "homo sapiens" 
-           && resolution.map(|r| r <= 3.0).unwrap_or(false)
-           && (method.contains("X-RAY") || method.contains("ELECTRON MICROSCOPY"))
-           && !scfv 
+        if !missing.is_empty() {
+            bail!("Summary file is missing columns: {}", missing.join(", "));
+        }
+        Ok(columns)
+    }
+}
+
+/// Which summary rows make it into the database. Empty `species` or `methods`
+/// lists accept any value.
+#[derive(Debug, Clone)]
+pub struct FilterConfig {
+    /// Heavy chain species, compared case-insensitively
+    pub species: Vec<String>,
+    /// Worst accepted resolution in Angstrom; entries without one are rejected when set
+    pub max_resolution: Option<f64>,
+    /// Accepted experimental methods, matched as case-insensitive substrings
+    pub methods: Vec<String>,
+    pub allow_scfv: bool,
+    /// Earliest accepted deposition date, inclusive; entries without a date are rejected when set
+    pub min_date: Option<NaiveDate>,
+    /// Latest accepted deposition date, inclusive; entries without a date are rejected when set
+    pub max_date: Option<NaiveDate>,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            species: vec!["homo sapiens".into()],
+            max_resolution: Some(3.0),
+            methods: vec!["X-RAY".into(), "ELECTRON MICROSCOPY".into()],
+            allow_scfv: false,
+            min_date: None,
+            max_date: None,
+        }
+    }
+}
+
+impl FilterConfig {
+    /// The first filter rejecting `record`, or None if it is accepted.
+    fn rejection(&self, record: &Record) -> Option<&'static str> {
+        if !self.species.is_empty() && !self.species.iter().any(|s| s.eq_ignore_ascii_case(&record.species)) {
+            return Some("species");
+        }
+        if let Some(max) = self.max_resolution
+            && record.resolution.is_none_or(|r| r > max)
         {
-             records.push(Record {
-                pdb,
-                h_chain,
-                l_chain,
-                resolution,
-                species,
-                method,
-                scfv,
-            });
-        }
-    }
-    Ok(records)
-}
-
-pub fn fetch_pdb(pdb_id: &str) -> Result<String> {
-    let url = format!("https://files.rcsb.org/download/{}.pdb", pdb_id);
-    let mut body = String::new();
-    ureq::get(&url)
-        .call()
-        .context("Failed to fetch PDB")?
-        .into_body()
-        .into_reader()
-        .read_to_string(&mut body)?;
-    Ok(body)
-}
-
-pub fn populate_db(db: &mut Db, summary_path: &Path) -> Result<()> {
-    download_summary(summary_path)?;
-    let records = parse_summary(summary_path)?;
-    info!("Found {} valid records after filtering.", records.len());
-
-    // Insert metadata first
-    {
-        let conn = db.get_conn();
-        let mut stmt = conn.prepare(
-            "INSERT OR IGNORE INTO antibodies (pdb_id, h_chain, l_chain, resolution, species, method, scfv)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"
+            return Some("resolution");
+        }
+        if !self.methods.is_empty() && !self.methods.iter().any(|m| record.method.contains(&m.to_uppercase())) {
+            return Some("method");
+        }
+        if record.scfv && !self.allow_scfv {
+            return Some("scfv");
+        }
+        if let Some(min) = self.min_date
+            && record.date.is_none_or(|d| d < min)
+        {
+            return Some("date");
+        }
+        if let Some(max) = self.max_date
+            && record.date.is_none_or(|d| d > max)
+        {
+            return Some("date");
+        }
+        None
+    }
+}
+
+// Optional column value, None when absent, empty or SAbDab's "NA"
+fn summary_value(record: &csv::StringRecord, column: Option<usize>) -> Option<String> {
+    let value = record.get(column?)?.trim();
+    (!value.is_empty() && !value.eq_ignore_ascii_case("NA")).then(|| value.to_string())
+}
+
+// SAbDab writes MM/DD/YY; MM/DD/YYYY and ISO dates are accepted too. A
+// two-digit year is the latest such year not in the future, so "99" is 1999
+// and "24" is 2024 (chrono's fixed 1969 pivot would put "70" in 1970 but
+// "30" in 2030).
+fn parse_summary_date(value: &str) -> Option<NaiveDate> {
+    let value = value.trim();
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Some(date);
+    }
+    let parts: Vec<&str> = value.split('/').collect();
+    let [month, day, year] = parts[..] else { return None };
+    if !year.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let year: i32 = match year.len() {
+        2 => {
+            let this_year = Utc::now().year();
+            let year = this_year / 100 * 100 + year.parse::<i32>().ok()?;
+            if year > this_year { year - 100 } else { year }
+        }
+        4 => year.parse().ok()?,
+        _ => return None,
+    };
+    NaiveDate::from_ymd_opt(year, month.parse().ok()?, day.parse().ok()?)
+}
+
+/// Parses the SAbDab summary and keeps the rows accepted by `filter`,
+/// locating columns by their header names. `positional_columns` uses fixed
+/// column positions instead, for old cached files whose header does not match.
+pub fn parse_summary(path: &Path, positional_columns: bool, filter: &FilterConfig) -> Result<Vec<Record>> {
+    Ok(parse_summary_iter(path, positional_columns, filter)?.collect())
+}
+
+/// Streaming form of `parse_summary`: reads the file one row at a time and
+/// yields the accepted records. Only the header is read up front.
+pub fn parse_summary_iter<'a>(path: &Path, positional_columns: bool, filter: &'a FilterConfig) -> Result<SummaryRecords<'a>> {
+    let mut reader = csv::ReaderBuilder::new().delimiter(b'\t').from_path(path)?;
+    let columns = if positional_columns {
+        SummaryColumns::POSITIONAL
+    } else {
+        SummaryColumns::from_header(reader.headers()?)?
+    };
+    if (filter.min_date.is_some() || filter.max_date.is_some()) && columns.date.is_none() {
+        bail!("Summary file has no date column to filter on");
+    }
+    Ok(SummaryRecords { rows: reader.into_records(), columns, filter, excluded: BTreeMap::new() })
+}
+
+/// Iterator over the accepted records of a summary, see `parse_summary_iter`.
+/// Malformed rows are skipped; the per-filter exclusion counts are logged
+/// once the file is exhausted.
+pub struct SummaryRecords<'a> {
+    rows: csv::StringRecordsIntoIter<fs::File>,
+    columns: SummaryColumns,
+    filter: &'a FilterConfig,
+    excluded: BTreeMap<&'static str, usize>,
+}
+
+impl Iterator for SummaryRecords<'_> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        let columns = &self.columns;
+        for result in self.rows.by_ref() {
+            let Ok(record) = result else { continue };
+            let record = Record {
+                pdb: record.get(columns.pdb).unwrap_or("").to_string(),
+                h_chain: record.get(columns.h_chain).unwrap_or("").to_string(),
+                l_chain: record.get(columns.l_chain).unwrap_or("").to_string(),
+                species: record.get(columns.species).unwrap_or("").to_lowercase(),
+                resolution: record.get(columns.resolution).and_then(|s| s.parse::<f64>().ok()),
+                method: record.get(columns.method).unwrap_or("").to_uppercase(),
+                scfv: record.get(columns.scfv).map(|s| s == "True").unwrap_or(false),
+                antigen_chain: summary_value(&record, columns.antigen_chain),
+                antigen_type: summary_value(&record, columns.antigen_type).map(|t| t.to_lowercase()),
+                antigen_name: summary_value(&record, columns.antigen_name),
+                light_type: summary_value(&record, columns.light_type)
+                    .map(|t| t.to_lowercase())
+                    .filter(|t| t == "kappa" || t == "lambda"),
+                date: columns.date.and_then(|i| record.get(i)).and_then(parse_summary_date),
+            };
+
+            match self.filter.rejection(&record) {
+                Some(reason) => *self.excluded.entry(reason).or_default() += 1,
+                None => return Some(record),
+            }
+        }
+        for (reason, count) in std::mem::take(&mut self.excluded) {
+            info!("Excluded {} summary rows by the {} filter", count, reason);
+        }
+        None
+    }
+}
+
+const RCSB_DOWNLOAD_URL: &str = "https://files.rcsb.org/download";
+const RCSB_REMOVED_URL: &str = "https://data.rcsb.org/rest/v1/holdings/removed";
+const PDBE_DOWNLOAD_URL: &str = "https://www.ebi.ac.uk/pdbe/entry-files/download";
+const PDBJ_DOWNLOAD_URL: &str = "https://pdbj.org/rest/newweb/fetch/file";
+
+/// HTTP cache validators of a downloaded resource.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Source of downloaded bytes, so the download logic can be exercised without network.
+pub trait Fetcher: Sync {
+    /// Body of `url`, or `Ok(None)` when the server reports it does not exist (404).
+    fn get_bytes(&self, url: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Body of `url` as UTF-8 text; a missing resource is an error.
+    fn get_text(&self, url: &str) -> Result<String> {
+        let Some(bytes) = self.get_bytes(url)? else { bail!("{} not found", url) };
+        String::from_utf8(bytes).with_context(|| format!("{} is not UTF-8", url))
+    }
+
+    /// Conditional GET: writes the body of `url` to `out` and returns its
+    /// validators, or None without writing when the server confirms `known`
+    /// is still current. The default ignores `known` and always downloads.
+    fn get_if_changed(&self, url: &str, _known: &Validators, out: &mut dyn std::io::Write) -> Result<Option<Validators>> {
+        let Some(bytes) = self.get_bytes(url)? else { bail!("{} not found", url) };
+        out.write_all(&bytes)?;
+        Ok(Some(Validators::default()))
+    }
+}
+
+/// Default connect and response timeout of HTTP requests
+pub const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The HTTP agent every request goes through. Honors `HTTPS_PROXY` /
+/// `HTTP_PROXY` (and `ALL_PROXY`, `NO_PROXY`), gives up on connections that
+/// stall for `timeout` before the response arrives and identifies the tool in
+/// the User-Agent.
+pub fn http_agent(timeout: Duration) -> ureq::Agent {
+    ureq::Agent::config_builder()
+        .proxy(ureq::Proxy::try_from_env())
+        .timeout_connect(Some(timeout))
+        .timeout_send_request(Some(timeout))
+        .timeout_recv_response(Some(timeout))
+        .user_agent(format!("scaffolding-lna-rs/{} (antibody scaffold matcher)", env!("CARGO_PKG_VERSION")))
+        .build()
+        .into()
+}
+
+/// Plain HTTP fetches through a configured ureq agent.
+pub struct HttpFetcher {
+    agent: ureq::Agent,
+}
+
+impl HttpFetcher {
+    pub fn new(agent: ureq::Agent) -> Self {
+        Self { agent }
+    }
+}
+
+impl Default for HttpFetcher {
+    fn default() -> Self {
+        Self::new(http_agent(DEFAULT_HTTP_TIMEOUT))
+    }
+}
+
+impl Fetcher for HttpFetcher {
+    fn get_bytes(&self, url: &str) -> Result<Option<Vec<u8>>> {
+        match self.agent.get(url).call() {
+            Ok(response) => {
+                let mut body = Vec::new();
+                response.into_body().into_reader().read_to_end(&mut body)?;
+                Ok(Some(body))
+            }
+            Err(ureq::Error::StatusCode(404)) => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to fetch {}", url)),
+        }
+    }
+
+    fn get_if_changed(&self, url: &str, known: &Validators, out: &mut dyn std::io::Write) -> Result<Option<Validators>> {
+        let mut request = self.agent.get(url);
+        if let Some(etag) = &known.etag {
+            request = request.header("If-None-Match", etag);
+        }
+        if let Some(last_modified) = &known.last_modified {
+            request = request.header("If-Modified-Since", last_modified);
+        }
+        let response = request.call().with_context(|| format!("Failed to fetch {}", url))?;
+        if response.status() == 304 {
+            return Ok(None);
+        }
+        let header = |name: &str| response.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+        let validators = Validators { etag: header("etag"), last_modified: header("last-modified") };
+        std::io::copy(&mut response.into_body().into_reader(), out)?;
+        Ok(Some(validators))
+    }
+}
+
+/// Serves fixed bodies by URL suffix and records every requested URL, for
+/// exercising the download and population logic without network. URLs
+/// starting with a failing prefix error, everything else is a 404.
+#[derive(Default)]
+pub struct MockFetcher {
+    bodies: HashMap<String, Vec<u8>>,
+    failing: Vec<String>,
+    requested: std::sync::Mutex<Vec<String>>,
+}
+
+impl MockFetcher {
+    /// Serves each (URL suffix, text) gzip-compressed, as the archive does.
+    pub fn new(bodies: &[(&str, &str)]) -> Self {
+        let gzip = |text: &str| {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            std::io::Write::write_all(&mut encoder, text.as_bytes()).expect("in-memory write");
+            encoder.finish().expect("in-memory write")
+        };
+        let bodies = bodies.iter().map(|(suffix, text)| (suffix.to_string(), gzip(text))).collect();
+        Self { bodies, ..Default::default() }
+    }
+
+    /// Fails every request whose URL starts with `prefix`.
+    pub fn failing(mut self, prefix: &str) -> Self {
+        self.failing.push(prefix.to_string());
+        self
+    }
+
+    /// Serves `body` as is, without compression.
+    pub fn with_raw(mut self, suffix: &str, body: &str) -> Self {
+        self.bodies.insert(suffix.to_string(), body.as_bytes().to_vec());
+        self
+    }
+
+    /// Requested URLs so far, in order.
+    pub fn requested(&self) -> Vec<String> {
+        self.requested.lock().expect("not poisoned").clone()
+    }
+}
+
+impl Fetcher for MockFetcher {
+    fn get_bytes(&self, url: &str) -> Result<Option<Vec<u8>>> {
+        self.requested.lock().expect("not poisoned").push(url.to_string());
+        if self.failing.iter().any(|prefix| url.starts_with(prefix)) {
+            bail!("connection refused");
+        }
+        Ok(self.bodies.iter().find(|(suffix, _)| url.ends_with(suffix.as_str())).map(|(_, b)| b.clone()))
+    }
+}
+
+// Mirrors serve a mix of compressed and plain files
+fn decode(bytes: &[u8]) -> Result<String> {
+    if bytes.starts_with(&[0x1f, 0x8b]) {
+        let mut text = String::new();
+        GzDecoder::new(bytes).read_to_string(&mut text).context("Failed to decompress")?;
+        Ok(text)
+    } else {
+        Ok(String::from_utf8(bytes.to_vec()).context("Structure file is not UTF-8")?)
+    }
+}
+
+/// Archive site structures are downloaded from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirror {
+    Rcsb,
+    Pdbe,
+    Pdbj,
+}
+
+impl Mirror {
+    /// URL of the `format` file of `pdb_id` on this mirror.
+    pub fn url(&self, pdb_id: &str, format: StructureFormat) -> String {
+        let id = pdb_id.to_lowercase();
+        match (self, format) {
+            (Mirror::Rcsb, StructureFormat::Pdb) => format!("{}/{}.pdb.gz", RCSB_DOWNLOAD_URL, id),
+            (Mirror::Rcsb, StructureFormat::Mmcif) => format!("{}/{}.cif.gz", RCSB_DOWNLOAD_URL, id),
+            (Mirror::Pdbe, StructureFormat::Pdb) => format!("{}/pdb{}.ent", PDBE_DOWNLOAD_URL, id),
+            (Mirror::Pdbe, StructureFormat::Mmcif) => format!("{}/{}.cif", PDBE_DOWNLOAD_URL, id),
+            (Mirror::Pdbj, StructureFormat::Pdb) => format!("{}?cat=pdb&type=pdb&id={}", PDBJ_DOWNLOAD_URL, id),
+            (Mirror::Pdbj, StructureFormat::Mmcif) => format!("{}?cat=pdb&type=mmcif&id={}", PDBJ_DOWNLOAD_URL, id),
+        }
+    }
+}
+
+/// Ordered mirror list for one download session. The mirror that last
+/// answered is remembered, so later fetches start there.
+pub struct MirrorSession {
+    mirrors: Vec<Mirror>,
+    preferred: AtomicUsize,
+}
+
+impl MirrorSession {
+    pub fn new(mirrors: &[Mirror]) -> Self {
+        let mirrors = if mirrors.is_empty() { vec![Mirror::Rcsb] } else { mirrors.to_vec() };
+        Self { mirrors, preferred: AtomicUsize::new(0) }
+    }
+
+    // Mirror indices starting at the preferred one
+    fn order(&self) -> impl Iterator<Item = usize> + '_ {
+        let start = self.preferred.load(Ordering::Relaxed);
+        (0..self.mirrors.len()).map(move |i| (start + i) % self.mirrors.len())
+    }
+}
+
+/// Downloads the PDB file of `pdb_id`, falling back to mmCIF for entries only
+/// distributed in that format. Mirrors are tried in session order when one
+/// fails to answer; a mirror answering that neither file exists is trusted.
+/// Returns the decompressed text and its format, or None if the entry has no files.
+pub fn fetch_structure(fetcher: &dyn Fetcher, mirrors: &MirrorSession, pdb_id: &str) -> Result<Option<(String, StructureFormat)>> {
+    let mut last_error = None;
+    for index in mirrors.order() {
+        let mirror = mirrors.mirrors[index];
+        match fetch_from(fetcher, mirror, pdb_id) {
+            Ok(found) => {
+                mirrors.preferred.store(index, Ordering::Relaxed);
+                return Ok(found);
+            }
+            Err(e) => {
+                debug!("{:?} failed for {}: {}", mirror, pdb_id, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.expect("at least one mirror"))
+}
+
+fn fetch_from(fetcher: &dyn Fetcher, mirror: Mirror, pdb_id: &str) -> Result<Option<(String, StructureFormat)>> {
+    for format in [StructureFormat::Pdb, StructureFormat::Mmcif] {
+        if let Some(bytes) = fetcher.get_bytes(&mirror.url(pdb_id, format))? {
+            return Ok(Some((decode(&bytes)?, format)));
+        }
+        debug!("{} not available as {} on {:?}", pdb_id, format.as_str(), mirror);
+    }
+    Ok(None)
+}
+
+/// `fetch_structure` over the default mirrors.
+pub fn fetch_pdb(fetcher: &dyn Fetcher, pdb_id: &str) -> Result<Option<(String, StructureFormat)>> {
+    fetch_structure(fetcher, &MirrorSession::new(&DownloadOptions::default().mirrors), pdb_id)
+}
+
+/// Asks RCSB whether `pdb_id` was removed from the archive. Returns None if it
+/// was not, otherwise the ID superseding it, if any.
+pub fn removed_entry(fetcher: &dyn Fetcher, pdb_id: &str) -> Result<Option<Option<String>>> {
+    let url = format!("{}/{}", RCSB_REMOVED_URL, pdb_id.to_uppercase());
+    let Some(body) = fetcher.get_bytes(&url)? else { return Ok(None) };
+    let entry: serde_json::Value = serde_json::from_slice(&body).context("Malformed holdings response")?;
+    let superseded_by = entry["rcsb_repository_holdings_removed"]["id_codes_replaced_by"]
+        .as_array()
+        .and_then(|ids| ids.first())
+        .and_then(|id| id.as_str())
+        .map(|id| id.to_lowercase());
+    Ok(Some(superseded_by))
+}
+
+/// Settings of the structure download stage.
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Maximum number of simultaneous downloads, independent of the rayon pool
+    pub jobs: usize,
+    /// Read the summary by fixed column positions instead of header names
+    pub positional_columns: bool,
+    pub filter: FilterConfig,
+    /// Also download the entries superseding obsolete ones
+    pub fetch_replacements: bool,
+    /// Mirrors in the order they are tried
+    pub mirrors: Vec<Mirror>,
+    /// Delete the summary file once it is archived in the database
+    pub clean: bool,
+    /// Download the summary even if the cached copy is current
+    pub refresh_summary: bool,
+    /// Local PDB mirror consulted before any network download
+    pub mirror_dir: Option<PathBuf>,
+    /// Only report what would be downloaded, without network access or writes
+    pub dry_run: bool,
+    /// Entries whose download failed more often than this are skipped until
+    /// `retry_failed`
+    pub max_failures: u32,
+    /// Fetch stored structures again once their last check is this old, to
+    /// pick up revised coordinates; None never does
+    pub revalidate_after: Option<chrono::Duration>,
+    /// Download the structures dropped by `Db::prune_blobs` again; pruned
+    /// entries that need processing are downloaded regardless
+    pub restore_pruned: bool,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            jobs: 8,
+            positional_columns: false,
+            filter: FilterConfig::default(),
+            fetch_replacements: false,
+            mirrors: vec![Mirror::Rcsb, Mirror::Pdbe, Mirror::Pdbj],
+            clean: false,
+            refresh_summary: false,
+            mirror_dir: None,
+            dry_run: false,
+            max_failures: 3,
+            revalidate_after: Some(chrono::Duration::days(180)),
+            restore_pruned: false,
+        }
+    }
+}
+
+// Result of downloading one entry
+#[derive(Debug)]
+enum FetchOutcome {
+    Fetched(String, StructureFormat),
+    /// Removed from the archive, possibly superseded by another ID
+    Obsolete(Option<String>),
+    /// Downloaded but not a usable structure (e.g. a proxy error page)
+    Invalid(String),
+    Failed(String),
+}
+
+// Chain IDs listed for an entry, skipping SAbDab's "NA" for absent chains
+fn listed_chains(h_chain: &str, l_chain: &str) -> Vec<char> {
+    [h_chain, l_chain].iter()
+        .filter(|c| !c.eq_ignore_ascii_case("NA"))
+        .flat_map(|c| c.chars().filter(char::is_ascii_alphanumeric))
+        .collect()
+}
+
+/// Checks a downloaded file before it is stored: it has to contain atoms and,
+/// when `expected_chains` is not empty, at least one of those chains.
+pub fn validate_structure(content: &str, format: StructureFormat, expected_chains: &[char]) -> Result<(), String> {
+    let pdb = Pdb::parse(content, format);
+    if pdb.atoms.is_empty() {
+        return Err("no atom records".into());
+    }
+    if !expected_chains.is_empty() && !pdb.atoms.iter().any(|a| expected_chains.contains(&a.chain_id)) {
+        let expected: String = expected_chains.iter().collect();
+        return Err(format!("none of chains {} present", expected));
+    }
+    Ok(())
+}
+
+// Fetches one entry with three attempts, reporting it to `progress`. The
+// download is checked against `expected`, a missing file is looked up in the
+// removed holdings
+fn fetch_one(
+    fetcher: &dyn Fetcher,
+    mirrors: &MirrorSession,
+    pdb_id: &str,
+    expected: &[char],
+    progress: &dyn ProgressSink,
+) -> FetchOutcome {
+    let mut last_error = String::new();
+    for _ in 0..3 {
+        let attempt = fetch_structure(fetcher, mirrors, pdb_id).and_then(|content| match content {
+            Some((text, format)) => Ok(match validate_structure(&text, format, expected) {
+                Ok(()) => FetchOutcome::Fetched(text, format),
+                Err(reason) => FetchOutcome::Invalid(reason),
+            }),
+            None => Ok(match removed_entry(fetcher, pdb_id)? {
+                Some(superseded_by) => FetchOutcome::Obsolete(superseded_by),
+                None => FetchOutcome::Failed("not found".into()),
+            }),
+        });
+        match attempt {
+            Ok(outcome) => {
+                match &outcome {
+                    FetchOutcome::Fetched(..) => progress.succeeded(pdb_id),
+                    FetchOutcome::Obsolete(_) => progress.failed(pdb_id, "obsolete"),
+                    FetchOutcome::Invalid(reason) => progress.failed(pdb_id, reason),
+                    FetchOutcome::Failed(error) => progress.failed(pdb_id, error),
+                }
+                return outcome;
+            }
+            Err(e) => {
+                debug!("Error fetching {}: {}", pdb_id, e);
+                last_error = e.to_string();
+                std::thread::sleep(Duration::from_millis(500));
+            }
+        }
+    }
+    progress.failed(pdb_id, &last_error);
+    FetchOutcome::Failed(last_error)
+}
+
+// Fetches `ids` on `pool`, sending each outcome to `tx` as soon as it is
+// complete. With a bounded channel at most pool size + channel capacity
+// bodies are held at once. Stops early once the receiver is gone.
+fn fetch_into(
+    fetcher: &dyn Fetcher,
+    mirrors: &MirrorSession,
+    ids: &[String],
+    expected_chains: &HashMap<String, Vec<char>>,
+    pool: &ThreadPool,
+    progress: &dyn ProgressSink,
+    tx: SyncSender<(String, FetchOutcome)>,
+) {
+    let _ = pool.install(|| ids.par_iter().try_for_each_with(tx, |tx, pdb_id| {
+        let expected = expected_chains.get(pdb_id).map(Vec::as_slice).unwrap_or_default();
+        tx.send((pdb_id.clone(), fetch_one(fetcher, mirrors, pdb_id, expected, progress)))
+    }));
+}
+
+// Chains listed for every entry over all of its Fabs, for `validate_structure`
+fn expected_chains(db: &Db) -> Result<HashMap<String, Vec<char>>> {
+    let conn = db.get_conn();
+    let mut stmt = conn.prepare("SELECT pdb_id, h_chain, l_chain FROM antibodies")?;
+    let rows = stmt.query_map([], |row| {
+        let (h, l): (Option<String>, Option<String>) = (row.get(1)?, row.get(2)?);
+        Ok((row.get::<_, String>(0)?, listed_chains(&h.unwrap_or_default(), &l.unwrap_or_default())))
+    })?;
+    let mut chains: HashMap<String, Vec<char>> = HashMap::new();
+    for row in rows {
+        let (id, listed) = row?;
+        chains.entry(id).or_default().extend(listed);
+    }
+    Ok(chains)
+}
+
+// Outcome of `download_missing`
+#[derive(Debug, Default)]
+struct DownloadReport {
+    failed: Vec<String>,
+    /// (obsolete ID, superseding ID)
+    obsolete: Vec<(String, Option<String>)>,
+    /// (ID, reason) of downloads rejected by `validate_structure`
+    rejected: Vec<(String, String)>,
+    /// Entries whose stored structure was replaced by different content
+    revised: Vec<String>,
+}
+
+/// Stored downloads between WAL checkpoints, so long runs do not grow the
+/// WAL without bound
+const CHECKPOINT_EVERY: usize = 200;
+
+// Downloads `ids`, storing valid blobs, marking obsolete entries and
+// recording why payloads were rejected. Entries fetched again with different
+// content are queued for reprocessing. Entries that fail every attempt are
+// counted in download_failures, any other outcome clears them from it. The
+// calling thread is the only writer and commits every entry as it arrives, so
+// memory stays bounded by the pool size rather than by the number of entries.
+fn download_missing(
+    db: &Db,
+    fetcher: &dyn Fetcher,
+    mirrors: &MirrorSession,
+    ids: &[String],
+    pool: &ThreadPool,
+    progress: &dyn ProgressSink,
+) -> Result<DownloadReport> {
+    let conn = db.get_conn();
+    let expected_chains = expected_chains(db)?;
+    let mut stored = conn.prepare("UPDATE antibodies SET download_error = NULL WHERE pdb_id = ?1")?;
+    let mut obsolete = conn.prepare("UPDATE antibodies SET status = 'obsolete', superseded_by = ?1 WHERE pdb_id = ?2")?;
+    let mut invalid = conn.prepare("UPDATE antibodies SET download_error = ?1 WHERE pdb_id = ?2")?;
+    let mut failed = conn.prepare(
+        "INSERT INTO download_failures (pdb_id, attempts, last_error, last_attempt_at) VALUES (?1, 1, ?2, ?3)
+         ON CONFLICT (pdb_id) DO UPDATE SET attempts = attempts + 1, last_error = ?2, last_attempt_at = ?3"
+    )?;
+    let mut resolved = conn.prepare("DELETE FROM download_failures WHERE pdb_id = ?1")?;
+    let mut reprocess = conn.prepare("UPDATE antibodies SET processed = FALSE, qc_report = NULL WHERE pdb_id = ?1")?;
+
+    let mut report = DownloadReport::default();
+    progress.start("Downloading", ids.len());
+    let (tx, rx) = sync_channel(pool.current_num_threads());
+    std::thread::scope(|scope| -> Result<()> {
+        scope.spawn(|| fetch_into(fetcher, mirrors, ids, &expected_chains, pool, progress, tx));
+        for (i, (pdb_id, outcome)) in rx.into_iter().enumerate() {
+            if i > 0 && i % CHECKPOINT_EVERY == 0 {
+                db.checkpoint()?;
+            }
+            if let FetchOutcome::Failed(error) = &outcome {
+                failed.execute(params![pdb_id, error, Utc::now().to_rfc3339()])?;
+            } else {
+                resolved.execute([&pdb_id])?;
+            }
+            match outcome {
+                FetchOutcome::Fetched(c, format) => {
+                    stored.execute([&pdb_id])?;
+                    if db.put_structure(&pdb_id, &c, format)? {
+                        reprocess.execute([&pdb_id])?;
+                        report.revised.push(pdb_id);
+                    }
+                }
+                FetchOutcome::Obsolete(superseded_by) => {
+                    obsolete.execute(params![superseded_by, pdb_id])?;
+                    report.obsolete.push((pdb_id, superseded_by));
+                }
+                FetchOutcome::Invalid(reason) => {
+                    invalid.execute(params![format!("content_invalid: {}", reason), pdb_id])?;
+                    report.rejected.push((pdb_id, reason));
+                }
+                FetchOutcome::Failed(_) => report.failed.push(pdb_id),
+            }
+        }
+        Ok(())
+    })?;
+    db.checkpoint()?;
+    progress.finish();
+    Ok(report)
+}
+
+// Adds rows for the entries superseding obsolete ones, inheriting the
+// metadata (and so the filter decisions) of every Fab of the entry they
+// replace, except the deposition date, which is left unknown. Skips
+// replacements already in the database or present in the summary, whose own
+// rows have been through the filters. Returns the added IDs.
+fn add_replacements(db: &Db, obsolete: &[(String, Option<String>)], summary_fabs: &HashSet<FabKey>) -> Result<Vec<String>> {
+    let conn = db.get_conn();
+    let summary_ids: HashSet<&str> = summary_fabs.iter().map(|(id, _, _)| id.as_str()).collect();
+    let mut added = Vec::new();
+    let mut insert = conn.prepare(
+        "INSERT OR IGNORE INTO antibodies (pdb_id, h_chain, l_chain, resolution, species, method, scfv,
+             antigen_chain, antigen_type, antigen_name, light_type)
+         SELECT ?1, h_chain, l_chain, resolution, species, method, scfv, antigen_chain, antigen_type, antigen_name, light_type
+         FROM antibodies WHERE pdb_id = ?2"
+    )?;
+    for (old, new) in obsolete {
+        let Some(new) = new else { continue };
+        if !summary_ids.contains(new.as_str()) && insert.execute(params![new, old])? > 0 {
+            added.push(new.clone());
+        }
+    }
+    Ok(added)
+}
+
+/// Every Fab in the summary, regardless of filters.
+pub fn summary_fabs(path: &Path, positional_columns: bool) -> Result<HashSet<FabKey>> {
+    let mut reader = csv::ReaderBuilder::new().delimiter(b'\t').from_path(path)?;
+    let columns = if positional_columns {
+        SummaryColumns::POSITIONAL
+    } else {
+        SummaryColumns::from_header(reader.headers()?)?
+    };
+    Ok(reader.records()
+        .filter_map(|r| {
+            let r = r.ok()?;
+            Some((r.get(columns.pdb)?.to_string(), r.get(columns.h_chain)?.to_string(), r.get(columns.l_chain)?.to_string()))
+        })
+        .collect())
+}
+
+/// Outcome of `sync_records`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    pub inserted: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+}
+
+/// Brings the antibodies table in line with a fresh summary: upserts its
+/// Fabs with `Db::upsert_records` and flags Fabs that left the summary with
+/// status 'removed', restoring them if they come back. A Fab is identified by
+/// its PDB ID and chains, so changed chains read as a new Fab replacing a
+/// removed one. Rows are never deleted; entries RCSB reports as obsolete
+/// keep that status. Records the time of the update under
+/// `LAST_SUMMARY_DOWNLOAD_KEY` in the meta table.
+pub fn sync_records<R: Borrow<Record>>(
+    db: &Db,
+    records: impl IntoIterator<Item = R>,
+    summary_fabs: &HashSet<FabKey>,
+) -> Result<SyncReport> {
+    let upserted = db.upsert_records(records)?;
+    db.with_transaction(|tx| {
+        let mut removed = tx.prepare(
+            "UPDATE antibodies SET status = 'removed' WHERE pdb_id = ?1 AND h_chain = ?2 AND l_chain = ?3 AND status = 'current'"
         )?;
-        
-        conn.execute("BEGIN TRANSACTION", [])?;
-        for rec in &records {
-            stmt.execute(params![
-                rec.pdb,
-                rec.h_chain,
-                rec.l_chain,
-                rec.resolution,
-                rec.species,
-                rec.method,
-                rec.scfv
-            ])?;
-        }
-        conn.execute("COMMIT", [])?;
-    }
-
-    // Cleanup: Remove the large summary file as it's now in the DB
-    if summary_path.exists() {
-        info!("Removing temporary summary file...");
+        let mut report = SyncReport { inserted: upserted.inserted, updated: upserted.updated, unchanged: upserted.unchanged, removed: 0 };
+        let mut stmt = tx.prepare("SELECT pdb_id, h_chain, l_chain FROM antibodies")?;
+        let existing = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?.collect::<rusqlite::Result<Vec<FabKey>>>()?;
+        for (id, h, l) in existing.iter().filter(|key| !summary_fabs.contains(*key)) {
+            report.removed += removed.execute([id, h, l])?;
+        }
+
+        db.meta_set(LAST_SUMMARY_DOWNLOAD_KEY, &Utc::now().to_rfc3339())?;
+        Ok(report)
+    })
+}
+
+// Stores the summary in the meta table under today's date, unless the latest
+// archived copy is identical
+fn archive_summary(db: &Db, summary_path: &Path) -> Result<()> {
+    let content = fs::read_to_string(summary_path)?;
+    if db.get_last_summary()?.is_some_and(|(_, last)| last == content) {
+        return Ok(());
+    }
+    db.put_summary(Utc::now().date_naive(), &content)
+}
+
+// Parses the summary, syncs the antibodies table with it and archives it.
+// Returns every Fab in the summary.
+fn load_summary(db: &Db, summary_path: &Path, options: &DownloadOptions) -> Result<HashSet<FabKey>> {
+    // Insert new metadata, refresh changed rows, as the summary is parsed
+    let summary_fabs = summary_fabs(summary_path, options.positional_columns)?;
+    let mut accepted = 0;
+    let records = parse_summary_iter(summary_path, options.positional_columns, &options.filter)?.inspect(|_| accepted += 1);
+    let sync = sync_records(db, records, &summary_fabs)?;
+    info!("Found {} valid records after filtering.", accepted);
+    info!("{} new entries, {} updated, {} unchanged, {} no longer in the summary", sync.inserted, sync.updated, sync.unchanged, sync.removed);
+
+    // Keep the full summary, the antibodies table only holds a few of its columns
+    archive_summary(db, summary_path)?;
+    if options.clean {
+        info!("Removing summary file...");
         fs::remove_file(summary_path)?;
     }
+    Ok(summary_fabs)
+}
 
-    // Identify what needs downloading
-    let mut to_download = Vec::new();
-    {
-        let conn = db.get_conn();
-        let mut stmt = conn.prepare("SELECT pdb_id FROM antibodies WHERE pdb_blob IS NULL")?;
-        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
-        for r in rows {
-            to_download.push(r?);
+// Entries with a current Fab but no stored structure, without those whose
+// download already failed more than `max_failures` times. Pruned entries
+// count only if they are restored or have a Fab to process.
+fn missing_structures(db: &Db, max_failures: Option<u32>, restore_pruned: bool) -> Result<Vec<String>> {
+    let conn = db.get_conn();
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT pdb_id FROM antibodies a LEFT JOIN structures s USING (pdb_id)
+         WHERE pdb_blob IS NULL AND status = 'current'
+             AND (s.pdb_id IS NULL OR ?2 OR a.processed IS NOT TRUE)
+             AND pdb_id NOT IN (SELECT pdb_id FROM download_failures WHERE attempts > ?1)"
+    )?;
+    let rows = stmt.query_map(params![max_failures.map_or(i64::MAX, i64::from), restore_pruned], |row| row.get::<_, String>(0))?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+/// File names a local PDB mirror may hold `pdb_id` under, flat or in the
+/// archive's two-letter subdirectories.
+fn local_candidates(mirror_dir: &Path, pdb_id: &str) -> Vec<(PathBuf, StructureFormat)> {
+    let id = pdb_id.to_lowercase();
+    let names = [
+        (format!("pdb{}.ent.gz", id), StructureFormat::Pdb),
+        (format!("{}.pdb", id), StructureFormat::Pdb),
+        (format!("{}.cif.gz", id), StructureFormat::Mmcif),
+    ];
+    let divided = id.get(1..3).map(|mid| mirror_dir.join(mid));
+    std::iter::once(mirror_dir.to_path_buf()).chain(divided)
+        .flat_map(|dir| names.iter().map(move |(name, format)| (dir.join(name), *format)))
+        .collect()
+}
+
+// Stores the structures of `ids` found under `mirror_dir`; returns the IDs
+// that were not found or did not validate
+fn load_local(db: &Db, mirror_dir: &Path, ids: &[String]) -> Result<Vec<String>> {
+    let expected = expected_chains(db)?;
+    let not_found = db.with_transaction(|tx| {
+        let mut not_found = Vec::new();
+        let mut stored = tx.prepare("UPDATE antibodies SET download_error = NULL WHERE pdb_id = ?1")?;
+        for pdb_id in ids {
+            let local = local_candidates(mirror_dir, pdb_id).into_iter().find(|(path, _)| path.is_file());
+            let Some((path, format)) = local else {
+                not_found.push(pdb_id.clone());
+                continue;
+            };
+            let content = decode(&fs::read(&path)?).with_context(|| format!("Failed to read {:?}", path))?;
+            let chains = expected.get(pdb_id).map(Vec::as_slice).unwrap_or_default();
+            match validate_structure(&content, format, chains) {
+                Ok(()) => {
+                    db.put_structure(pdb_id, &content, format)?;
+                    stored.execute([pdb_id])?;
+                }
+                Err(reason) => {
+                    warn!("Skipping {:?}: {}", path, reason);
+                    not_found.push(pdb_id.clone());
+                }
+            }
+        }
+        Ok(not_found)
+    })?;
+    info!("Loaded {} of {} structures from {:?}", ids.len() - not_found.len(), ids.len(), mirror_dir);
+    Ok(not_found)
+}
+
+/// Rough size of one gzipped structure download, for dry-run estimates
+const AVERAGE_DOWNLOAD_BYTES: u64 = 250_000;
+
+/// What `populate_db` would do, as reported by a dry run.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DryRunReport {
+    /// Filtered summary Fabs not yet in the database
+    pub new_entries: usize,
+    /// Current Fabs that are no longer in the summary
+    pub removed_entries: usize,
+    /// Entries left without a structure after the sync, i.e. downloads
+    pub missing_blobs: usize,
+    pub estimated_bytes: u64,
+}
+
+impl std::fmt::Display for DryRunReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "New entries:         {}", self.new_entries)?;
+        writeln!(f, "Removed entries:     {}", self.removed_entries)?;
+        writeln!(f, "Structures to fetch: {}", self.missing_blobs)?;
+        write!(f, "Estimated download:  {:.1} MB", self.estimated_bytes as f64 / 1e6)
+    }
+}
+
+// Diffs the filtered summary against the database without writing anything
+fn plan_population(db: &Db, summary_path: &Path, options: &DownloadOptions) -> Result<DryRunReport> {
+    if !summary_path.exists() {
+        bail!("No summary at {:?}; a dry run does not download it", summary_path);
+    }
+    let records = parse_summary(summary_path, options.positional_columns, &options.filter)?;
+    let summary_fabs = summary_fabs(summary_path, options.positional_columns)?;
+    let record_keys: HashSet<FabKey> = records.iter().map(Record::fab_key).collect();
+
+    let conn = db.get_conn();
+    let existing: HashMap<FabKey, String> = {
+        let mut stmt = conn.prepare("SELECT pdb_id, h_chain, l_chain, status FROM antibodies")?;
+        stmt.query_map([], |row| Ok(((row.get(0)?, row.get(1)?, row.get(2)?), row.get(3)?)))?
+            .collect::<rusqlite::Result<_>>()?
+    };
+    // Pruned entries count as stored unless they are restored
+    let stored: HashSet<String> = {
+        let mut stmt = conn.prepare("SELECT pdb_id FROM structures WHERE pdb_blob IS NOT NULL OR NOT ?1")?;
+        stmt.query_map([options.restore_pruned], |row| row.get(0))?.collect::<rusqlite::Result<_>>()?
+    };
+
+    let mut report = DryRunReport::default();
+    // Entries with a current Fab once the sync is done
+    let mut current: HashSet<&str> = HashSet::new();
+    for key in &record_keys {
+        if !existing.contains_key(key) {
+            report.new_entries += 1;
+            current.insert(&key.0);
+        }
+    }
+    for (key, status) in &existing {
+        let in_summary = summary_fabs.contains(key);
+        if status == "current" && !in_summary {
+            report.removed_entries += 1;
         }
+        // Removed Fabs listed again come back as current
+        if (status == "current" && in_summary) || (status == "removed" && record_keys.contains(key)) {
+            current.insert(&key.0);
+        }
+    }
+    report.missing_blobs = current.iter().filter(|id| !stored.contains(**id)).count();
+    report.estimated_bytes = report.missing_blobs as u64 * AVERAGE_DOWNLOAD_BYTES;
+    Ok(report)
+}
+
+/// Populates the database from a local PDB mirror without any network
+/// access: the summary must already be at `summary_path`. Returns the IDs
+/// that are not available locally.
+pub fn populate_from_dir(db: &Db, summary_path: &Path, mirror_dir: &Path) -> Result<Vec<String>> {
+    load_summary(db, summary_path, &DownloadOptions::default())?;
+    let not_found = load_local(db, mirror_dir, &missing_structures(db, None, false)?)?;
+    if !not_found.is_empty() {
+        warn!("{} entries not found in {:?}: {}", not_found.len(), mirror_dir, not_found.join(", "));
+    }
+    Ok(not_found)
+}
+
+/// Downloads the summary, syncs the database with it and fetches the missing
+/// structures. A dry run (`options.dry_run`) only reads the cached summary and
+/// the database and returns what would happen.
+pub fn populate_db(db: &Db, fetcher: &dyn Fetcher, summary_path: &Path, options: &DownloadOptions, progress: &dyn ProgressSink) -> Result<Option<DryRunReport>> {
+    if options.dry_run {
+        return plan_population(db, summary_path, options).map(Some);
+    }
+    download_summary(fetcher, db, summary_path, options.refresh_summary)?;
+    let summary_fabs = load_summary(db, summary_path, options)?;
+
+    // Identify what needs downloading, taking what a local mirror has first
+    let mut to_download = missing_structures(db, Some(options.max_failures), options.restore_pruned)?;
+    if let Some(mirror_dir) = &options.mirror_dir {
+        to_download = load_local(db, mirror_dir, &to_download)?;
     }
 
-    if to_download.is_empty() {
+    // Stored structures not checked for a while are fetched again, and
+    // reprocessed if upstream revised them
+    let stale = match options.revalidate_after {
+        Some(age) => db.entries_with_stale_hash(Utc::now() - age)?,
+        None => Vec::new(),
+    };
+
+    if to_download.is_empty() && stale.is_empty() {
         info!("All PDBs are already downloaded.");
-        return Ok(());
+        if options.restore_pruned {
+            db.meta_remove(BLOBS_PRUNED_KEY)?;
+        }
+        return Ok(None);
     }
 
-    info!("Downloading {} PDBs...", to_download.len());
-    
-    let chunk_size = 50;
-    for chunk in to_download.chunks(chunk_size) {
-        let fetched: Vec<(String, Option<String>)> = chunk.par_iter().map(|pdb_id| {
-            for _ in 0..3 {
-                match fetch_pdb(pdb_id) {
-                    Ok(content) => return (pdb_id.clone(), Some(content)),
-                    Err(e) => {
-                        debug!("Error fetching {}: {}", pdb_id, e);
-                        std::thread::sleep(Duration::from_millis(500));
-                    }
-                }
+    info!("Downloading {} PDBs with {} parallel jobs...", to_download.len(), options.jobs);
+
+    // Downloads get their own small pool so the connection count does not
+    // follow the core count
+    let pool = ThreadPoolBuilder::new().num_threads(options.jobs.max(1)).build()?;
+    let mirrors = MirrorSession::new(&options.mirrors);
+    let mut report = download_missing(db, fetcher, &mirrors, &to_download, &pool, progress)?;
+
+    if !stale.is_empty() {
+        info!("Revalidating {} stored structures...", stale.len());
+        let revalidated = download_missing(db, fetcher, &mirrors, &stale, &pool, progress)?;
+        if !revalidated.revised.is_empty() {
+            info!("{} entries were revised upstream and will be reprocessed: {}", revalidated.revised.len(), revalidated.revised.join(", "));
+        }
+        report.failed.extend(revalidated.failed);
+        report.obsolete.extend(revalidated.obsolete);
+        report.rejected.extend(revalidated.rejected);
+    }
+
+    if !report.obsolete.is_empty() {
+        info!("{} entries are obsolete", report.obsolete.len());
+        if options.fetch_replacements {
+            let replacements = add_replacements(db, &report.obsolete, &summary_fabs)?;
+            info!("Downloading {} superseding entries...", replacements.len());
+            let replaced = download_missing(db, fetcher, &mirrors, &replacements, &pool, progress)?;
+            report.failed.extend(replaced.failed);
+            report.rejected.extend(replaced.rejected);
+        }
+    }
+
+    if !report.rejected.is_empty() {
+        let rejected: Vec<String> = report.rejected.iter().map(|(id, reason)| format!("{} ({})", id, reason)).collect();
+        warn!("Rejected {} downloaded payloads: {}", rejected.len(), rejected.join(", "));
+    }
+
+    if !report.failed.is_empty() {
+        warn!("{} of {} downloads failed: {}", report.failed.len(), to_download.len(), report.failed.join(", "));
+    } else if options.restore_pruned {
+        db.meta_remove(BLOBS_PRUNED_KEY)?;
+    }
+
+    Ok(None)
+}
+
+// Entries in download_failures that still lack a structure
+fn failed_downloads(db: &Db) -> Result<Vec<String>> {
+    let conn = db.get_conn();
+    let mut stmt = conn.prepare(
+        "SELECT pdb_id FROM download_failures WHERE pdb_id IN (
+             SELECT pdb_id FROM antibodies LEFT JOIN structures USING (pdb_id)
+             WHERE pdb_blob IS NULL AND status = 'current'
+         ) ORDER BY pdb_id"
+    )?;
+    let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+/// Another download pass over just the entries recorded in
+/// download_failures, however often they failed before. Returns the IDs that
+/// still fail.
+pub fn retry_failed(db: &Db, fetcher: &dyn Fetcher, options: &DownloadOptions, progress: &dyn ProgressSink) -> Result<Vec<String>> {
+    let ids = failed_downloads(db)?;
+    if ids.is_empty() {
+        info!("No failed downloads to retry.");
+        return Ok(Vec::new());
+    }
+    info!("Retrying {} failed downloads...", ids.len());
+    let pool = ThreadPoolBuilder::new().num_threads(options.jobs.max(1)).build()?;
+    let report = download_missing(db, fetcher, &MirrorSession::new(&options.mirrors), &ids, &pool, progress)?;
+    if !report.failed.is_empty() {
+        warn!("{} of {} downloads still fail: {}", report.failed.len(), ids.len(), report.failed.join(", "));
+    }
+    Ok(report.failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{antigen_condition, deposited_before_condition, AntigenType, LightType};
+    use crate::progress::tests::RecordingProgress;
+    use crate::progress::NoProgress;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn rcsb() -> MirrorSession {
+        MirrorSession::new(&[Mirror::Rcsb])
+    }
+
+    #[test]
+    fn test_prefers_gzipped_pdb() {
+        let fetcher = MockFetcher::new(&[("1abc.pdb.gz", "ATOM"), ("1abc.cif.gz", "data_1ABC")]);
+        let (text, format) = fetch_structure(&fetcher, &rcsb(), "1abc").unwrap().unwrap();
+        assert_eq!((text.as_str(), format), ("ATOM", StructureFormat::Pdb));
+        assert_eq!(fetcher.requested().len(), 1);
+    }
+
+    #[test]
+    fn test_falls_back_to_mmcif() {
+        let fetcher = MockFetcher::new(&[("7big.cif.gz", "data_7BIG")]);
+        let (text, format) = fetch_structure(&fetcher, &rcsb(), "7big").unwrap().unwrap();
+        assert_eq!((text.as_str(), format), ("data_7BIG", StructureFormat::Mmcif));
+        let requested = fetcher.requested();
+        assert!(requested[0].ends_with("7big.pdb.gz"));
+        assert!(requested[1].ends_with("7big.cif.gz"));
+
+        assert!(fetch_structure(&MockFetcher::new(&[]), &rcsb(), "0000").unwrap().is_none());
+    }
+
+    // Counts concurrent get_bytes calls
+    #[derive(Default)]
+    struct SlowFetcher {
+        in_flight: AtomicUsize,
+        peak: AtomicUsize,
+    }
+
+    impl Fetcher for SlowFetcher {
+        fn get_bytes(&self, _url: &str) -> Result<Option<Vec<u8>>> {
+            let now = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(now, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(5));
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(ATOM_H.as_bytes())?;
+            Ok(Some(encoder.finish()?))
+        }
+    }
+
+    const ATOM_H: &str = "ATOM      1  CA  GLY H   1      10.000  10.000  10.000  1.00  0.00           C";
+
+    // Runs `fetch_into` and collects every outcome
+    fn fetch_all(fetcher: &dyn Fetcher, ids: &[String], pool: &ThreadPool, progress: &dyn ProgressSink) -> Vec<(String, FetchOutcome)> {
+        let (tx, rx) = sync_channel(1);
+        std::thread::scope(|scope| {
+            scope.spawn(|| fetch_into(fetcher, &rcsb(), ids, &HashMap::new(), pool, progress, tx));
+            rx.into_iter().collect()
+        })
+    }
+
+    #[test]
+    fn test_download_concurrency_limit() {
+        let fetcher = SlowFetcher::default();
+        let ids: Vec<String> = (0..40).map(|i| format!("{:04}", i)).collect();
+        let pool = ThreadPoolBuilder::new().num_threads(3).build().unwrap();
+        let fetched = fetch_all(&fetcher, &ids, &pool, &NoProgress);
+        assert_eq!(fetched.len(), 40);
+        assert!(fetched.iter().all(|(_, outcome)| matches!(outcome, FetchOutcome::Fetched(..))));
+        assert!(fetcher.peak.load(Ordering::SeqCst) <= 3);
+    }
+
+    #[test]
+    fn test_progress_per_entry() {
+        let fetcher = MockFetcher::new(&[("1abc.pdb.gz", ATOM_H), ("2abc.pdb.gz", ATOM_H)]);
+        let ids = vec!["1abc".to_string(), "2abc".to_string(), "gone".to_string()];
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let progress = RecordingProgress::default();
+        let fetched = fetch_all(&fetcher, &ids, &pool, &progress);
+        assert_eq!(fetched.iter().filter(|(_, o)| matches!(o, FetchOutcome::Failed(_))).count(), 1);
+
+        let mut events = progress.events.into_inner().unwrap();
+        events.sort();
+        assert_eq!(events, ["failed gone", "ok 1abc", "ok 2abc"]);
+    }
+
+    // Serves 1 MB structures and counts how many it has handed out
+    #[derive(Default)]
+    struct LargeFetcher {
+        served: AtomicUsize,
+    }
+
+    impl LargeFetcher {
+        fn body() -> String {
+            format!("{}\n{}", ATOM_H, "REMARK 999 PADDING\n".repeat(1 << 16))
+        }
+    }
+
+    impl Fetcher for LargeFetcher {
+        fn get_bytes(&self, _url: &str) -> Result<Option<Vec<u8>>> {
+            self.served.fetch_add(1, Ordering::SeqCst);
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+            encoder.write_all(Self::body().as_bytes())?;
+            Ok(Some(encoder.finish()?))
+        }
+    }
+
+    #[test]
+    fn test_streaming_bounds_bodies_in_flight() {
+        let fetcher = LargeFetcher::default();
+        let ids: Vec<String> = (0..24).map(|i| format!("{:04}", i)).collect();
+        let pool = ThreadPoolBuilder::new().num_threads(3).build().unwrap();
+
+        // A slow consumer: bodies fetched but not yet consumed never exceed
+        // the workers plus the channel capacity
+        let (tx, rx) = sync_channel(2);
+        let peak = std::thread::scope(|scope| {
+            scope.spawn(|| fetch_into(&fetcher, &rcsb(), &ids, &HashMap::new(), &pool, &NoProgress, tx));
+            let mut peak = 0;
+            for (consumed, _) in rx.into_iter().enumerate() {
+                std::thread::sleep(Duration::from_millis(5));
+                peak = peak.max(fetcher.served.load(Ordering::SeqCst) - consumed);
             }
-            warn!("Failed to download {}", pdb_id);
-            (pdb_id.clone(), None)
-        }).collect();
+            peak
+        });
+        assert!(peak <= 3 + 2 + 1, "{} bodies in flight", peak);
+
+        // Every large body ends up in the database
+        let db = Db::open_in_memory().unwrap();
+        for id in &ids {
+            db.insert_raw(id, "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+        }
+        let report = download_missing(&db, &fetcher, &rcsb(), &ids, &pool, &NoProgress).unwrap();
+        assert!(report.failed.is_empty());
+        let sizes: Vec<usize> = ids.iter().map(|id| db.get_structure(id).unwrap().unwrap().0.len()).collect();
+        assert_eq!(sizes, vec![LargeFetcher::body().len(); 24]);
+    }
+
+    const SUMMARY: &str = "pdb\tHchain\tLchain\tmodel\tdate\theavy_species\tlight_species\tresolution\tmethod\tscfv\n\
+1abc\tH\tL\t0\t01/01/20\thomo sapiens\thomo sapiens\t2.1\tX-RAY DIFFRACTION\tFalse\n\
+2abc\tH\tL\t0\t01/01/20\tmus musculus\tmus musculus\t2.1\tX-RAY DIFFRACTION\tFalse\n\
+3abc\tA\tB\t0\t01/01/20\thomo sapiens\thomo sapiens\t3.5\tX-RAY DIFFRACTION\tFalse\n";
+
+    fn summary_file(content: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_summary_by_header() {
+        let file = summary_file(SUMMARY);
+        let records = parse_summary(file.path(), false, &FilterConfig::default()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!((records[0].pdb.as_str(), records[0].resolution), ("1abc", Some(2.1)));
+
+        let reordered = summary_file("method\tscfv\tresolution\theavy_species\tLchain\tHchain\tpdb\n\
+X-RAY DIFFRACTION\tFalse\t2.4\thomo sapiens\tL\tH\t4abc\n");
+        let records = parse_summary(reordered.path(), false, &FilterConfig::default()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!((records[0].pdb.as_str(), records[0].h_chain.as_str()), ("4abc", "H"));
+    }
+
+    #[test]
+    fn test_summary_iter_matches_vec() {
+        let file = summary_file(&format!("{}not\tenough\n", SUMMARY));
+        let filter = FilterConfig { max_resolution: None, ..Default::default() };
+        let streamed: Vec<Record> = parse_summary_iter(file.path(), false, &filter).unwrap().collect();
+        let collected = parse_summary(file.path(), false, &filter).unwrap();
+        assert_eq!(format!("{:?}", streamed), format!("{:?}", collected));
+        assert_eq!(streamed.iter().map(|r| r.pdb.as_str()).collect::<Vec<_>>(), ["1abc", "3abc"]);
+
+        // Syncing straight from the stream gives the same rows
+        let db = Db::open_in_memory().unwrap();
+        let fabs = summary_fabs(file.path(), false).unwrap();
+        let report = sync_records(&db, parse_summary_iter(file.path(), false, &filter).unwrap(), &fabs).unwrap();
+        assert_eq!(report.inserted, 2);
+        assert_eq!(sync_records(&db, &collected, &fabs).unwrap(), SyncReport { unchanged: 2, ..Default::default() });
+    }
+
+    #[test]
+    fn test_summary_missing_columns() {
+        let file = summary_file("pdb\tHchain\tLchain\tspecies\n1abc\tH\tL\thomo sapiens\n");
+        let error = parse_summary(file.path(), false, &FilterConfig::default()).unwrap_err().to_string();
+        assert!(error.contains("heavy_species, resolution, method, scfv"), "{}", error);
+    }
+
+    #[test]
+    fn test_summary_filters() {
+        let file = summary_file("pdb\tHchain\tLchain\tdate\theavy_species\tresolution\tmethod\tscfv\n\
+base\tH\tL\t06/15/18\thomo sapiens\t2.0\tX-RAY DIFFRACTION\tFalse\n\
+mouse\tH\tL\t06/15/18\tmus musculus\t2.0\tX-RAY DIFFRACTION\tFalse\n\
+lowres\tH\tL\t06/15/18\thomo sapiens\t3.8\tX-RAY DIFFRACTION\tFalse\n\
+cryoem\tH\tL\t06/15/18\thomo sapiens\t2.0\tELECTRON MICROSCOPY\tFalse\n\
+scfv\tH\tL\t06/15/18\thomo sapiens\t2.0\tX-RAY DIFFRACTION\tTrue\n\
+old\tH\tL\t03/02/99\thomo sapiens\t2.0\tX-RAY DIFFRACTION\tFalse\n");
+        let ids = |filter: &FilterConfig| -> Vec<String> {
+            parse_summary(file.path(), false, filter).unwrap().into_iter().map(|r| r.pdb).collect()
+        };
+        let default = FilterConfig::default();
+        assert_eq!(ids(&default), ["base", "cryoem", "old"]);
+
+        let species = FilterConfig { species: vec!["Mus musculus".into()], ..default.clone() };
+        assert_eq!(ids(&species), ["mouse"]);
+        let resolution = FilterConfig { max_resolution: None, ..default.clone() };
+        assert_eq!(ids(&resolution), ["base", "lowres", "cryoem", "old"]);
+        let methods = FilterConfig { methods: vec!["electron microscopy".into()], ..default.clone() };
+        assert_eq!(ids(&methods), ["cryoem"]);
+        let scfv = FilterConfig { allow_scfv: true, ..default.clone() };
+        assert_eq!(ids(&scfv), ["base", "cryoem", "scfv", "old"]);
+        let date = FilterConfig { min_date: NaiveDate::from_ymd_opt(2000, 1, 1), ..default.clone() };
+        assert_eq!(ids(&date), ["base", "cryoem"]);
+    }
+
+    #[test]
+    fn test_summary_dates() {
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d);
+        assert_eq!(parse_summary_date("06/15/18"), date(2018, 6, 15));
+        assert_eq!(parse_summary_date("03/02/99"), date(1999, 3, 2));
+        assert_eq!(parse_summary_date("03/02/1999"), date(1999, 3, 2));
+        assert_eq!(parse_summary_date(" 2020-01-01 "), date(2020, 1, 1));
+        // Two-digit years never land in the future
+        let next_year = Utc::now().year() + 1;
+        assert_eq!(parse_summary_date(&format!("01/01/{:02}", next_year % 100)), date(next_year - 100, 1, 1));
+        for malformed in ["", "NA", "13/01/20", "02/30/20", "1/1", "01/01/020", "01/01/20x", "01/01/+2"] {
+            assert_eq!(parse_summary_date(malformed), None, "{:?}", malformed);
+        }
+
+        // Both bounds are inclusive, entries without a date are rejected
+        let file = summary_file("pdb\tHchain\tLchain\tdate\theavy_species\tresolution\tmethod\tscfv\n\
+first\tH\tL\t01/01/20\thomo sapiens\t2.0\tX-RAY DIFFRACTION\tFalse\n\
+last\tH\tL\t12/31/20\thomo sapiens\t2.0\tX-RAY DIFFRACTION\tFalse\n\
+after\tH\tL\t01/01/21\thomo sapiens\t2.0\tX-RAY DIFFRACTION\tFalse\n\
+bad\tH\tL\t31/12/20\thomo sapiens\t2.0\tX-RAY DIFFRACTION\tFalse\n");
+        let filter = FilterConfig { min_date: date(2020, 1, 1), max_date: date(2020, 12, 31), ..Default::default() };
+        let records = parse_summary(file.path(), false, &filter).unwrap();
+        let ids: Vec<&str> = records.iter().map(|r| r.pdb.as_str()).collect();
+        assert_eq!(ids, ["first", "last"]);
+
+        // Stored as ISO dates, compared as such by the match filter
+        let db = Db::open_in_memory().unwrap();
+        let all = parse_summary(file.path(), false, &FilterConfig::default()).unwrap();
+        sync_records(&db, &all, &summary_fabs(file.path(), false).unwrap()).unwrap();
+        let before = |d: Option<NaiveDate>| -> Vec<String> {
+            let sql = format!("SELECT pdb_id FROM antibodies WHERE {} ORDER BY deposition_date", deposited_before_condition(d));
+            db.get_conn().prepare(&sql).unwrap().query_map([], |r| r.get(0)).unwrap().map(|r| r.unwrap()).collect()
+        };
+        assert_eq!(before(date(2020, 12, 31)), ["first"]);
+        assert_eq!(before(date(2021, 1, 2)), ["first", "last", "after"]);
+        assert_eq!(before(None).len(), 4);
+    }
+
+    #[test]
+    fn test_incremental_sync() {
+        let db = Db::open_in_memory().unwrap();
+        let header = "pdb\tHchain\tLchain\theavy_species\tresolution\tmethod\tscfv\n";
+        let row = |id: &str, h: &str, resolution: &str| format!("{}\t{}\tL\thomo sapiens\t{}\tX-RAY DIFFRACTION\tFalse\n", id, h, resolution);
+        let sync = |content: String| {
+            let file = summary_file(&content);
+            let records = parse_summary(file.path(), false, &FilterConfig::default()).unwrap();
+            sync_records(&db, &records, &summary_fabs(file.path(), false).unwrap()).unwrap()
+        };
+
+        let old = format!("{}{}{}{}", header, row("1abc", "H", "2.0"), row("2abc", "H", "2.5"), row("3abc", "H", "2.0"));
+        assert_eq!(sync(old.clone()), SyncReport { inserted: 3, updated: 0, unchanged: 0, removed: 0 });
+        assert_eq!(sync(old), SyncReport { unchanged: 3, ..Default::default() });
+        db.get_conn().execute("UPDATE antibodies SET processed = TRUE", []).unwrap();
+
+        // 2abc gets a corrected resolution, 3abc a different heavy chain (a
+        // new Fab replacing the old one), 1abc is dropped
+        let new = format!("{}{}{}{}", header, row("2abc", "H", "2.2"), row("3abc", "A", "2.0"), row("4abc", "H", "1.8"));
+        assert_eq!(sync(new), SyncReport { inserted: 2, updated: 1, unchanged: 0, removed: 2 });
+
+        let conn = db.get_conn();
+        let get = |id: &str, h: &str| conn.query_row(
+            "SELECT resolution, status, processed FROM antibodies WHERE pdb_id = ?1 AND h_chain = ?2", [id, h],
+            |r| Ok((r.get::<_, f64>(0)?, r.get::<_, String>(1)?, r.get::<_, bool>(2)?)),
+        ).unwrap();
+        assert_eq!(get("1abc", "H"), (2.0, "removed".to_string(), true));
+        assert_eq!(get("2abc", "H"), (2.2, "current".to_string(), true));
+        assert_eq!(get("3abc", "H"), (2.0, "removed".to_string(), true));
+        assert_eq!(get("3abc", "A"), (2.0, "current".to_string(), false));
+        assert!(db.meta_get(LAST_SUMMARY_DOWNLOAD_KEY).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_obsolete_entry_superseded() {
+        let db = Db::open_in_memory().unwrap();
+        db.insert_raw("1old", "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+        db.insert_raw("2gne", "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+        let fetcher = MockFetcher::new(&[("5new.pdb.gz", ATOM_H)])
+            .with_raw("removed/1OLD", r#"{"rcsb_id": "1OLD", "rcsb_repository_holdings_removed": {"id_codes_replaced_by": ["5NEW"]}}"#);
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+
+        let ids = vec!["1old".to_string(), "2gne".to_string()];
+        let report = download_missing(&db, &fetcher, &rcsb(), &ids, &pool, &NoProgress).unwrap();
+        assert_eq!(report.obsolete, [("1old".to_string(), Some("5new".to_string()))]);
+        assert_eq!(report.failed, ["2gne"]);
+
+        let added = add_replacements(&db, &report.obsolete, &HashSet::new()).unwrap();
+        assert_eq!(added, ["5new"]);
+        let replaced = download_missing(&db, &fetcher, &rcsb(), &added, &pool, &NoProgress).unwrap();
+        assert!(replaced.failed.is_empty());
 
         let conn = db.get_conn();
-        conn.execute("BEGIN TRANSACTION", [])?;
-        let mut stmt = conn.prepare("UPDATE antibodies SET pdb_blob = ?1 WHERE pdb_id = ?2")?;
-        for (pdb_id, content) in fetched {
-            if let Some(c) = content {
-                stmt.execute(params![c.as_bytes(), pdb_id])?;
+        let (status, superseded_by): (String, String) = conn.query_row(
+            "SELECT status, superseded_by FROM antibodies WHERE pdb_id = '1old'", [], |r| Ok((r.get(0)?, r.get(1)?)),
+        ).unwrap();
+        assert_eq!((status.as_str(), superseded_by.as_str()), ("obsolete", "5new"));
+        let (h_chain, has_blob): (String, bool) = conn.query_row(
+            "SELECT h_chain, pdb_blob IS NOT NULL FROM antibodies LEFT JOIN structures USING (pdb_id) WHERE pdb_id = '5new'", [], |r| Ok((r.get(0)?, r.get(1)?)),
+        ).unwrap();
+        assert_eq!((h_chain.as_str(), has_blob), ("H", true));
+
+        // A replacement listed in the summary went through the filters on its own
+        assert!(add_replacements(&db, &report.obsolete, &HashSet::from([("5new".to_string(), "H".to_string(), "L".to_string())])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_mirror_fallback() {
+        let fetcher = MockFetcher::new(&[])
+            .failing(RCSB_DOWNLOAD_URL)
+            .with_raw("pdb1abc.ent", "ATOM 1abc")
+            .with_raw("pdb2abc.ent", "ATOM 2abc");
+        let mirrors = MirrorSession::new(&[Mirror::Rcsb, Mirror::Pdbe, Mirror::Pdbj]);
+
+        let (text, _) = fetch_structure(&fetcher, &mirrors, "1abc").unwrap().unwrap();
+        assert_eq!(text, "ATOM 1abc");
+        // The second fetch starts at the mirror that worked
+        let (text, _) = fetch_structure(&fetcher, &mirrors, "2abc").unwrap().unwrap();
+        assert_eq!(text, "ATOM 2abc");
+        let requested = fetcher.requested();
+        assert!(requested[0].starts_with(RCSB_DOWNLOAD_URL));
+        assert!(requested[1].starts_with(PDBE_DOWNLOAD_URL));
+        assert_eq!(requested.len(), 3);
+        assert!(requested[2].starts_with(PDBE_DOWNLOAD_URL));
+    }
+
+    #[test]
+    fn test_summary_kept_and_archived() {
+        let db = Db::open_in_memory().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("summary.tsv");
+        // Nothing passes the filters, so nothing is requested
+        let content = SUMMARY.replace("homo sapiens", "mus musculus");
+        fs::write(&path, &content).unwrap();
+        let fetcher = MockFetcher::default();
+
+        populate_db(&db, &fetcher, &path, &DownloadOptions::default(), &NoProgress).unwrap();
+        assert!(path.exists());
+        assert_eq!(db.get_last_summary().unwrap().map(|(_, c)| c), Some(content.clone()));
+
+        // The second run reads the file on disk and does not archive it again
+        populate_db(&db, &fetcher, &path, &DownloadOptions { clean: true, ..Default::default() }, &NoProgress).unwrap();
+        assert!(fetcher.requested().is_empty());
+        assert!(!path.exists());
+        let archived: i64 = db.get_conn()
+            .query_row("SELECT COUNT(*) FROM meta WHERE key LIKE 'summary:%'", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(archived, 1);
+    }
+
+    // Answers `n` HTTP requests with `respond(request)` and returns the requests
+    fn mock_server(n: usize, respond: fn(&str) -> String) -> (String, std::thread::JoinHandle<Vec<String>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/summary", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for stream in listener.incoming().take(n) {
+                let mut stream = stream.unwrap();
+                let mut buffer = [0u8; 4096];
+                let len = stream.read(&mut buffer).unwrap();
+                let request = String::from_utf8_lossy(&buffer[..len]).to_lowercase();
+                stream.write_all(respond(&request).as_bytes()).unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_populate_offline() {
+        let db = Db::open_in_memory().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("summary.tsv");
+        let summary = format!("{}4abc\tH\tL\t0\t01/01/20\thomo sapiens\thomo sapiens\t2.0\tX-RAY DIFFRACTION\tFalse\n", SUMMARY);
+        let fetcher = MockFetcher::new(&[("1abc.pdb.gz", ATOM_H), ("4abc.pdb.gz", ATOM_H)])
+            .with_raw("summary/all/", &summary);
+
+        populate_db(&db, &fetcher, &path, &DownloadOptions::default(), &NoProgress).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), summary);
+        let mut stored: Vec<String> = db.get_conn().prepare("SELECT pdb_id FROM structures").unwrap()
+            .query_map([], |r| r.get(0)).unwrap().map(|r| r.unwrap()).collect();
+        stored.sort();
+        assert_eq!(stored, ["1abc", "4abc"]);
+        assert_eq!(fetcher.requested().len(), 3);
+
+        // Everything is stored and the summary has no validators to check
+        populate_db(&db, &fetcher, &path, &DownloadOptions::default(), &NoProgress).unwrap();
+        assert_eq!(fetcher.requested().len(), 3);
+    }
+
+    #[test]
+    fn test_conditional_summary_download() {
+        let (url, server) = mock_server(3, |request| {
+            if request.contains("if-none-match: \"v1\"") {
+                "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n".to_string()
+            } else {
+                let body = "pdb\tHchain\n1abc\tH\n";
+                format!(
+                    "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nLast-Modified: Wed, 01 Jan 2025 00:00:00 GMT\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(), body
+                )
             }
+        });
+        let db = Db::open_in_memory().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("summary.tsv");
+        let fetcher = HttpFetcher::default();
+
+        fetch_summary(&fetcher, &db, &url, &path, false).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "pdb\tHchain\n1abc\tH\n");
+
+        // Revalidation keeps the local copy on a 304
+        fs::write(&path, "cached").unwrap();
+        fetch_summary(&fetcher, &db, &url, &path, false).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "cached");
+
+        fetch_summary(&fetcher, &db, &url, &path, true).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "pdb\tHchain\n1abc\tH\n");
+
+        let requests = server.join().unwrap();
+        assert!(!requests[0].contains("if-none-match"));
+        assert!(requests[1].contains("if-none-match: \"v1\""));
+        assert!(requests[1].contains("if-modified-since: wed, 01 jan 2025"));
+        assert!(!requests[2].contains("if-none-match"));
+    }
+
+    #[test]
+    fn test_invalid_payloads_rejected() {
+        let db = Db::open_in_memory().unwrap();
+        for id in ["html", "trnc", "chns", "good"] {
+            db.insert_raw(id, "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
         }
-        conn.execute("COMMIT", [])?;
-        print!(".");
-        std::io::stdout().flush()?;
+        let fetcher = MockFetcher::new(&[
+            ("html.pdb.gz", "<html><body>502 Bad Gateway</body></html>"),
+            ("trnc.pdb.gz", "HEADER    IMMUNE SYSTEM\nCOMPND    MOL_ID: 1;\nATOM      1  N   GL"),
+            ("chns.pdb.gz", &ATOM_H.replace(" H ", " A ")),
+            ("good.pdb.gz", ATOM_H),
+        ]);
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let ids: Vec<String> = ["html", "trnc", "chns", "good"].map(String::from).to_vec();
+        let mut report = download_missing(&db, &fetcher, &rcsb(), &ids, &pool, &NoProgress).unwrap();
+        report.rejected.sort();
+        assert_eq!(report.rejected, [
+            ("chns".to_string(), "none of chains HL present".to_string()),
+            ("html".to_string(), "no atom records".to_string()),
+            ("trnc".to_string(), "no atom records".to_string()),
+        ]);
+
+        let conn = db.get_conn();
+        let stored: Vec<(String, Option<String>)> = conn.prepare("SELECT pdb_id, download_error FROM antibodies LEFT JOIN structures USING (pdb_id) WHERE pdb_blob IS NULL ORDER BY pdb_id").unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?))).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(stored.len(), 3);
+        assert!(stored.iter().all(|(_, e)| e.as_deref().unwrap().starts_with("content_invalid")));
+        assert_eq!(listed_chains("H", "NA"), ['H']);
     }
-    println!();
-    
-    Ok(())
-}
\ No newline at end of file
+
+    #[test]
+    fn test_antigen_columns() {
+        let file = summary_file("pdb\tHchain\tLchain\tantigen_chain\tantigen_type\tantigen_name\theavy_species\tresolution\tmethod\tscfv\n\
+1bnd\tH\tL\tA\tprotein\themagglutinin\thomo sapiens\t2.0\tX-RAY DIFFRACTION\tFalse\n\
+2pep\tH\tL\tP\tpeptide\tgp41 peptide\thomo sapiens\t2.0\tX-RAY DIFFRACTION\tFalse\n\
+3apo\tH\tL\tNA\tNA\tNA\thomo sapiens\t2.0\tX-RAY DIFFRACTION\tFalse\n");
+        let records = parse_summary(file.path(), false, &FilterConfig::default()).unwrap();
+        assert_eq!(records[0].antigen_type.as_deref(), Some("protein"));
+        assert_eq!(records[0].antigen_name.as_deref(), Some("hemagglutinin"));
+        assert_eq!((records[2].antigen_chain.as_ref(), records[2].antigen_type.as_ref()), (None, None));
+
+        let db = Db::open_in_memory().unwrap();
+        sync_records(&db, &records, &summary_fabs(file.path(), false).unwrap()).unwrap();
+        let select = |types: &[AntigenType]| -> Vec<String> {
+            let sql = format!("SELECT pdb_id FROM antibodies WHERE {} ORDER BY pdb_id", antigen_condition(types));
+            db.get_conn().prepare(&sql).unwrap().query_map([], |r| r.get(0)).unwrap().map(|r| r.unwrap()).collect()
+        };
+        assert_eq!(select(&[AntigenType::Protein]), ["1bnd"]);
+        assert_eq!(select(&[AntigenType::Peptide, AntigenType::None]), ["2pep", "3apo"]);
+        assert_eq!(select(&[]).len(), 3);
+    }
+
+    #[test]
+    fn test_light_type_column() {
+        let file = summary_file("pdb\tHchain\tLchain\tlight_ctype\theavy_species\tresolution\tmethod\tscfv\n\
+1kap\tH\tL\tKappa\thomo sapiens\t2.0\tX-RAY DIFFRACTION\tFalse\n\
+2lam\tH\tL\tLambda\thomo sapiens\t2.0\tX-RAY DIFFRACTION\tFalse\n\
+3unk\tH\tL\tNA\thomo sapiens\t2.0\tX-RAY DIFFRACTION\tFalse\n");
+        let records = parse_summary(file.path(), false, &FilterConfig::default()).unwrap();
+        let types: Vec<Option<&str>> = records.iter().map(|r| r.light_type.as_deref()).collect();
+        assert_eq!(types, [Some("kappa"), Some("lambda"), None]);
+
+        let db = Db::open_in_memory().unwrap();
+        let fabs = summary_fabs(file.path(), false).unwrap();
+        sync_records(&db, &records, &fabs).unwrap();
+        let select = |condition: &str| -> Vec<String> {
+            let sql = format!("SELECT pdb_id FROM antibodies WHERE {} ORDER BY pdb_id", condition);
+            db.get_conn().prepare(&sql).unwrap().query_map([], |r| r.get(0)).unwrap().map(|r| r.unwrap()).collect()
+        };
+        assert_eq!(select(LightType::Kappa.sql_condition()), ["1kap"]);
+        assert_eq!(select(LightType::Lambda.sql_condition()), ["2lam"]);
+
+        // A blank summary value does not erase a type inferred from the numbering
+        db.get_conn().execute("UPDATE antibodies SET light_type = 'lambda', resolution = 1.0 WHERE pdb_id = '3unk'", []).unwrap();
+        sync_records(&db, &records, &fabs).unwrap();
+        assert_eq!(select(LightType::Lambda.sql_condition()), ["2lam", "3unk"]);
+    }
+
+    #[test]
+    fn test_populate_from_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let summary = dir.path().join("summary.tsv");
+        let rows = format!("{}4abc\tH\tL\t0\t01/01/20\thomo sapiens\thomo sapiens\t2.0\tX-RAY DIFFRACTION\tFalse\n", SUMMARY.replace("3.5", "2.5"));
+        fs::write(&summary, rows).unwrap();
+
+        // 1abc as a gzipped entry file in the divided layout, 3abc as plain PDB
+        let mirror = dir.path().join("mirror");
+        fs::create_dir_all(mirror.join("ab")).unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(ATOM_H.as_bytes()).unwrap();
+        fs::write(mirror.join("ab/pdb1abc.ent.gz"), encoder.finish().unwrap()).unwrap();
+        fs::write(mirror.join("3abc.pdb"), ATOM_H.replace(" H ", " A ")).unwrap();
+
+        let db = Db::open_in_memory().unwrap();
+        let not_found = populate_from_dir(&db, &summary, &mirror).unwrap();
+        assert_eq!(not_found, ["4abc"]);
+
+        let stored: Vec<(String, String)> = db.get_conn()
+            .prepare("SELECT pdb_id, format FROM structures WHERE pdb_blob IS NOT NULL ORDER BY pdb_id").unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?))).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(stored, [("1abc".to_string(), "pdb".to_string()), ("3abc".to_string(), "pdb".to_string())]);
+    }
+
+    #[test]
+    fn test_http_timeout_and_user_agent() {
+        // Accepts connections but never answers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/1abc.pdb.gz", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let _held: Vec<_> = listener.incoming().take(3).collect();
+            std::thread::sleep(Duration::from_secs(10));
+        });
+        let fetcher = HttpFetcher::new(http_agent(Duration::from_millis(200)));
+        let start = std::time::Instant::now();
+        assert!(fetcher.get_bytes(&url).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let (url, server) = mock_server(1, |_| "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string());
+        assert!(HttpFetcher::new(http_agent(DEFAULT_HTTP_TIMEOUT)).get_bytes(&url).unwrap().is_none());
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains(&format!("user-agent: scaffolding-lna-rs/{}", env!("CARGO_PKG_VERSION"))));
+    }
+
+    #[test]
+    fn test_dry_run_report() {
+        let db = Db::open_in_memory().unwrap();
+        // 1abc is stored, 5old left the summary; 3abc is excluded by resolution
+        db.insert_raw("1abc", "H", "L", Some(2.1), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+        db.insert_raw("5old", "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+        db.put_structure("1abc", "ATOM", StructureFormat::Pdb).unwrap();
+        let summary = summary_file(&format!("{}4abc\tH\tL\t0\t01/01/20\thomo sapiens\thomo sapiens\t2.0\tX-RAY DIFFRACTION\tFalse\n", SUMMARY));
+
+        let options = DownloadOptions { dry_run: true, ..Default::default() };
+        let report = populate_db(&db, &MockFetcher::default(), summary.path(), &options, &NoProgress).unwrap().unwrap();
+        assert_eq!(report, DryRunReport { new_entries: 1, removed_entries: 1, missing_blobs: 1, estimated_bytes: AVERAGE_DOWNLOAD_BYTES });
+
+        // Nothing was written
+        let rows: Vec<(String, String)> = db.get_conn().prepare("SELECT pdb_id, status FROM antibodies ORDER BY pdb_id").unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?))).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(rows, [("1abc".to_string(), "current".to_string()), ("5old".to_string(), "current".to_string())]);
+    }
+
+    #[test]
+    fn test_multiple_fabs_per_entry() {
+        let db = Db::open_in_memory().unwrap();
+        let summary = summary_file("pdb\tHchain\tLchain\theavy_species\tresolution\tmethod\tscfv\n\
+            1t66\tH\tL\thomo sapiens\t2.8\tX-RAY DIFFRACTION\tFalse\n\
+            1t66\tA\tB\thomo sapiens\t2.8\tX-RAY DIFFRACTION\tFalse\n");
+        let records = parse_summary(summary.path(), false, &FilterConfig::default()).unwrap();
+        let sync = sync_records(&db, &records, &summary_fabs(summary.path(), false).unwrap()).unwrap();
+        assert_eq!(sync.inserted, 2);
+        let mut chains = expected_chains(&db).unwrap().remove("1t66").unwrap();
+        chains.sort();
+        assert_eq!(chains, ['A', 'B', 'H', 'L']);
+
+        // One download serves both Fabs
+        assert_eq!(missing_structures(&db, None, false).unwrap(), ["1t66"]);
+        let fetcher = MockFetcher::new(&[("1t66.pdb.gz", &ATOM_H.replace(" H ", " A "))]);
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        download_missing(&db, &fetcher, &rcsb(), &missing_structures(&db, None, false).unwrap(), &pool, &NoProgress).unwrap();
+        assert_eq!(fetcher.requested().len(), 1);
+        assert!(missing_structures(&db, None, false).unwrap().is_empty());
+
+        // Pruned, the entry is only downloaded again when asked or when a Fab
+        // needs processing
+        db.get_conn().execute("UPDATE antibodies SET processed = TRUE", []).unwrap();
+        db.get_conn().execute("UPDATE structures SET pdb_blob = NULL", []).unwrap();
+        assert!(missing_structures(&db, None, false).unwrap().is_empty());
+        assert_eq!(missing_structures(&db, None, true).unwrap(), ["1t66"]);
+        db.get_conn().execute("UPDATE antibodies SET processed = FALSE WHERE h_chain = 'A'", []).unwrap();
+        assert_eq!(missing_structures(&db, None, false).unwrap(), ["1t66"]);
+        download_missing(&db, &fetcher, &rcsb(), &["1t66".to_string()], &pool, &NoProgress).unwrap();
+
+        let fabs: Vec<(String, String)> = db.get_conn()
+            .prepare("SELECT h_chain, l_chain FROM antibodies JOIN structures USING (pdb_id) ORDER BY fab_id").unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?))).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(fabs, [("H".to_string(), "L".to_string()), ("A".to_string(), "B".to_string())]);
+    }
+
+    #[test]
+    fn test_download_failures_tracked() {
+        let db = Db::open_in_memory().unwrap();
+        for id in ["good", "gone", "flky"] {
+            db.insert_raw(id, "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+        }
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let failures = || -> Vec<(String, i64, String)> {
+            db.get_conn().prepare("SELECT pdb_id, attempts, last_error FROM download_failures ORDER BY pdb_id").unwrap()
+                .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?))).unwrap().map(|r| r.unwrap()).collect()
+        };
+
+        // "gone" 404s everywhere, "flky" has a bad night
+        let down = MockFetcher::new(&[("good.pdb.gz", ATOM_H), ("flky.pdb.gz", ATOM_H)])
+            .failing("https://files.rcsb.org/download/flky");
+        let ids = missing_structures(&db, Some(1), false).unwrap();
+        download_missing(&db, &down, &rcsb(), &ids, &pool, &NoProgress).unwrap();
+        let recorded = failures();
+        assert_eq!(recorded.iter().map(|(id, n, _)| (id.as_str(), *n)).collect::<Vec<_>>(), [("flky", 1), ("gone", 1)]);
+        assert!(recorded[0].2.contains("connection refused"));
+        assert_eq!(recorded[1].2, "not found");
+
+        // A second failure puts "gone" over the limit of normal runs
+        download_missing(&db, &down, &rcsb(), &["gone".to_string()], &pool, &NoProgress).unwrap();
+        assert_eq!(missing_structures(&db, Some(1), false).unwrap(), ["flky"]);
+        assert_eq!(failed_downloads(&db).unwrap(), ["flky", "gone"]);
+
+        // The retry pass covers both; the recovered entry leaves the table
+        let up = MockFetcher::new(&[("flky.pdb.gz", ATOM_H)]);
+        let report = download_missing(&db, &up, &rcsb(), &failed_downloads(&db).unwrap(), &pool, &NoProgress).unwrap();
+        assert_eq!(report.failed, ["gone"]);
+        assert_eq!(failures().iter().map(|(id, n, _)| (id.as_str(), *n)).collect::<Vec<_>>(), [("gone", 3)]);
+        assert_eq!(failed_downloads(&db).unwrap(), ["gone"]);
+    }
+
+    #[test]
+    fn test_revised_structures_reprocessed() {
+        let db = Db::open_in_memory().unwrap();
+        for id in ["same", "edit"] {
+            db.insert_raw(id, "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+        }
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let v1 = MockFetcher::new(&[("same.pdb.gz", ATOM_H), ("edit.pdb.gz", ATOM_H)]);
+        let report = download_missing(&db, &v1, &rcsb(), &missing_structures(&db, None, false).unwrap(), &pool, &NoProgress).unwrap();
+        assert!(report.revised.is_empty());
+        db.get_conn().execute("UPDATE antibodies SET processed = TRUE", []).unwrap();
+
+        // Nothing is stale yet; with a cutoff in the future both are
+        let cutoff = Utc::now() + chrono::Duration::days(1);
+        assert!(db.entries_with_stale_hash(Utc::now() - chrono::Duration::days(1)).unwrap().is_empty());
+        let mut stale = db.entries_with_stale_hash(cutoff).unwrap();
+        stale.sort();
+        assert_eq!(stale, ["edit", "same"]);
+
+        let edited = ATOM_H.replace("10.000  10.000  10.000", "10.500  10.000  10.000");
+        let v2 = MockFetcher::new(&[("same.pdb.gz", ATOM_H), ("edit.pdb.gz", &edited)]);
+        let report = download_missing(&db, &v2, &rcsb(), &stale, &pool, &NoProgress).unwrap();
+        assert_eq!(report.revised, ["edit"]);
+        let processed = |id: &str| -> bool {
+            db.get_conn().query_row("SELECT processed FROM antibodies WHERE pdb_id = ?1", [id], |r| r.get(0)).unwrap()
+        };
+        assert!(!processed("edit"));
+        assert!(processed("same"));
+        assert!(db.entries_with_stale_hash(Utc::now() - chrono::Duration::days(1)).unwrap().is_empty());
+    }
+}
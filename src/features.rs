@@ -0,0 +1,212 @@
+//! Per-chain descriptors of the stored Fabs that matching scores against,
+//! kept in the `features` table so matching need not parse structures.
+use crate::analysis::{self, RamaPoint};
+use crate::numbering::ChainType;
+use crate::pdb::{self, Atom, Pdb, Point};
+
+/// Version of how the features are computed and encoded, stored with them.
+/// Raising it has processing compute the stored ones again. Since 2 they
+/// cover the Fv only.
+pub const FEATURES_VERSION: i64 = 2;
+
+/// f32 values per CA: x, y, z, occupancy, B-factor
+const CA_VALUES: usize = 5;
+
+/// f32 values per residue with torsions: phi, psi
+const RAMA_VALUES: usize = 2;
+
+/// What matching needs of one chain of a Fab. Residues are numbered from 1
+/// in chain order, the deposited numbering is not kept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainFeatures {
+    pub chain_id: char,
+    /// Role in the Fab; light chains are `Kappa` whatever their type
+    pub chain_type: ChainType,
+    /// CA atoms of the amino acids, primary conformer
+    pub ca: Vec<Atom>,
+    /// Residues with both backbone torsions defined
+    pub rama: Vec<RamaPoint>,
+    pub rg: f64,
+    /// One-letter sequence of `ca`
+    pub seq: String,
+    pub fingerprint: Vec<f32>,
+}
+
+fn f32s_to_bytes(values: impl IntoIterator<Item = f64>) -> Vec<u8> {
+    values.into_iter().flat_map(|v| (v as f32).to_le_bytes()).collect()
+}
+
+// Groups of `n` values, None unless the blob holds exactly `count` of them
+fn f32_groups(bytes: &[u8], n: usize, count: usize) -> Option<Vec<Vec<f64>>> {
+    if bytes.len() != count * n * 4 {
+        return None;
+    }
+    let values: Vec<f64> = bytes.chunks_exact(4).map(|c| f32::from_le_bytes(c.try_into().unwrap()) as f64).collect();
+    Some(values.chunks(n).map(<[f64]>::to_vec).collect())
+}
+
+impl ChainFeatures {
+    /// CA coordinates with occupancy and B-factor, little-endian f32.
+    pub fn ca_bytes(&self) -> Vec<u8> {
+        f32s_to_bytes(self.ca.iter().flat_map(|a| [a.pos.x, a.pos.y, a.pos.z, a.occupancy, a.temp_factor]))
+    }
+
+    /// Phi and psi of each `rama` residue, little-endian f32.
+    pub fn rama_bytes(&self) -> Vec<u8> {
+        f32s_to_bytes(self.rama.iter().flat_map(|p| [p.phi, p.psi]))
+    }
+
+    /// One-letter codes of the `rama` residues.
+    pub fn rama_seq(&self) -> String {
+        self.rama.iter().map(|p| pdb::three_to_one(&p.res_name)).collect()
+    }
+
+    /// Rebuilds the features from their stored form; None if the blobs do
+    /// not fit the sequences.
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_stored(
+        chain_id: char,
+        chain_type: ChainType,
+        ca_bytes: &[u8],
+        rama_bytes: &[u8],
+        rama_seq: &str,
+        rg: f64,
+        seq: String,
+        fingerprint: Vec<f32>,
+    ) -> Option<Self> {
+        let ca = f32_groups(ca_bytes, CA_VALUES, seq.chars().count())?.into_iter().zip(seq.chars()).enumerate()
+            .map(|(i, (v, code))| Atom {
+                serial: i as i32 + 1,
+                name: "CA".to_string(),
+                alt_loc: ' ',
+                res_name: pdb::one_to_three(code).to_string(),
+                chain_id,
+                res_seq: i as i32 + 1,
+                i_code: ' ',
+                pos: Point::new(v[0], v[1], v[2]),
+                occupancy: v[3],
+                temp_factor: v[4],
+                element: "C".to_string(),
+            })
+            .collect();
+        let rama = f32_groups(rama_bytes, RAMA_VALUES, rama_seq.chars().count())?.into_iter().zip(rama_seq.chars()).enumerate()
+            .map(|(i, (v, code))| RamaPoint {
+                chain_id,
+                res_seq: i as i32 + 1,
+                i_code: ' ',
+                res_name: pdb::one_to_three(code).to_string(),
+                phi: v[0],
+                psi: v[1],
+            })
+            .collect();
+        Some(Self { chain_id, chain_type, ca, rama, rg, seq, fingerprint })
+    }
+}
+
+/// Features of the heavy and light chain of a Fab, in the order of `pdb`,
+/// which holds the Fab's chains or their Fv. A chain missing from `pdb` has
+/// none.
+pub fn fab_features(pdb: &Pdb, h_chain: char, l_chain: char) -> Vec<ChainFeatures> {
+    let trace = FabTrace::from_pdb(pdb);
+    let mut chains: Vec<char> = Vec::new();
+    for a in &trace.ca {
+        if (a.chain_id == h_chain || a.chain_id == l_chain) && !chains.contains(&a.chain_id) {
+            chains.push(a.chain_id);
+        }
+    }
+    chains.into_iter().map(|chain_id| {
+        let ca: Vec<Atom> = trace.ca.iter().filter(|a| a.chain_id == chain_id).cloned().collect();
+        let rama: Vec<RamaPoint> = trace.rama.iter().filter(|p| p.chain_id == chain_id).cloned().collect();
+        let points: Vec<Point> = ca.iter().map(|a| a.pos).collect();
+        let angles: Vec<(f64, f64)> = rama.iter().map(|p| (p.phi, p.psi)).collect();
+        ChainFeatures {
+            chain_id,
+            chain_type: if chain_id == h_chain { ChainType::Heavy } else { ChainType::Kappa },
+            seq: ca.iter().map(|a| pdb::three_to_one(&a.res_name)).collect(),
+            rg: analysis::shape_descriptors(&points).rg,
+            fingerprint: analysis::rama_fingerprint(&angles, analysis::FINGERPRINT_BINS),
+            ca,
+            rama,
+        }
+    }).collect()
+}
+
+/// CA trace and backbone torsions of a whole Fab, the chains in file order,
+/// as the structural score components compare them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FabTrace {
+    pub ca: Vec<Atom>,
+    pub rama: Vec<RamaPoint>,
+}
+
+impl FabTrace {
+    pub fn from_pdb(pdb: &Pdb) -> Self {
+        Self { ca: analysis::ca_trace(&pdb.atoms), rama: analysis::ramachandran(&pdb.atoms) }
+    }
+
+    /// Joins stored chain features, in their stored order.
+    pub fn from_features(chains: &[ChainFeatures]) -> Self {
+        Self {
+            ca: chains.iter().flat_map(|c| c.ca.iter().cloned()).collect(),
+            rama: chains.iter().flat_map(|c| c.rama.iter().cloned()).collect(),
+        }
+    }
+
+    /// The CA atoms as a structure, for the functions taking one.
+    pub fn ca_pdb(&self) -> Pdb {
+        Pdb { atoms: self.ca.clone() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_features_round_trip() {
+        // Zig-zag backbone, k counting the atoms of a chain
+        let atom = |chain_id: char, res_seq: i32, name: &str, res_name: &str, offset: f64, k: i32| Atom {
+            serial: k + 1, name: name.into(), alt_loc: ' ', res_name: res_name.into(), chain_id, res_seq, i_code: ' ',
+            pos: Point::new(offset + k as f64 * 1.2, (k % 2) as f64 * 0.8, (k % 5) as f64 * 0.1),
+            occupancy: 0.5, temp_factor: 20.0, element: "C".into(),
+        };
+        // Light chain first in the file, a water and an unrelated chain after
+        let mut atoms = Vec::new();
+        for (chain_id, res_name, offset) in [('L', "SER", 0.0), ('H', "GLY", 40.0), ('A', "ALA", 80.0)] {
+            for i in 0..6 {
+                for (j, name) in ["N", "CA", "C"].iter().enumerate() {
+                    atoms.push(atom(chain_id, i + 1, name, res_name, offset, i * 3 + j as i32));
+                }
+            }
+        }
+        atoms.push(atom('H', 200, "O", "HOH", 0.0, 0));
+        let pdb = Pdb { atoms };
+
+        let chains = fab_features(&pdb, 'H', 'L');
+        assert_eq!(chains.iter().map(|c| (c.chain_id, c.chain_type)).collect::<Vec<_>>(), [('L', ChainType::Kappa), ('H', ChainType::Heavy)]);
+        assert_eq!((chains[1].seq.as_str(), chains[1].rama.len()), ("GGGGGG", 4));
+
+        for chain in &chains {
+            let stored = ChainFeatures::from_stored(
+                chain.chain_id, chain.chain_type, &chain.ca_bytes(), &chain.rama_bytes(), &chain.rama_seq(),
+                chain.rg, chain.seq.clone(), chain.fingerprint.clone(),
+            ).unwrap();
+            assert_eq!(stored.seq, chain.seq);
+            assert_eq!(stored.ca_bytes(), chain.ca_bytes());
+            assert_eq!(stored.rama_bytes(), chain.rama_bytes());
+            assert_eq!(stored.rama_seq(), chain.rama_seq());
+            for (a, b) in stored.rama.iter().zip(&chain.rama) {
+                assert!((a.phi - b.phi).abs() < 1e-6 && (a.psi - b.psi).abs() < 1e-6);
+            }
+            assert!(ChainFeatures::from_stored(chain.chain_id, chain.chain_type, &[0; 4], &[], "", 0.0, chain.seq.clone(), Vec::new()).is_none());
+        }
+
+        // Joined, the chains give the trace of the Fab
+        let fab = Pdb { atoms: pdb.atoms.iter().filter(|a| a.chain_id != 'A').cloned().collect() };
+        let whole = FabTrace::from_pdb(&fab);
+        let joined = FabTrace::from_features(&chains);
+        assert_eq!(joined.ca, whole.ca);
+        assert_eq!(joined.rama, whole.rama);
+        assert_eq!(analysis::ca_trace(&joined.ca_pdb().atoms), joined.ca);
+    }
+}
@@ -4,4 +4,8 @@ pub mod process;
 pub mod pdb;
 pub mod numbering;
 pub mod analysis;
+pub mod features;
 pub mod match_ab;
+pub mod progress;
+#[cfg(test)]
+mod testing;
@@ -1,15 +1,112 @@
 use anyhow::Result;
-use clap::Parser;
+use chrono::NaiveDate;
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use log::info;
-use scaffolding_lna_rs::{db, download, process, match_ab};
+use scaffolding_lna_rs::{db, download, numbering, process, match_ab};
+use scaffolding_lna_rs::analysis::{RegionWeights, Weighting};
+use scaffolding_lna_rs::pdb::QcThresholds;
+use scaffolding_lna_rs::progress::BarProgress;
+
+#[derive(Clone, Copy, ValueEnum)]
+enum WeightingArg {
+    Uniform,
+    Occupancy,
+    InverseB,
+}
+
+impl From<WeightingArg> for Weighting {
+    fn from(arg: WeightingArg) -> Self {
+        match arg {
+            WeightingArg::Uniform => Weighting::Uniform,
+            WeightingArg::Occupancy => Weighting::Occupancy,
+            WeightingArg::InverseB => Weighting::InverseB,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum MirrorArg {
+    Rcsb,
+    Pdbe,
+    Pdbj,
+}
+
+impl From<MirrorArg> for download::Mirror {
+    fn from(arg: MirrorArg) -> Self {
+        match arg {
+            MirrorArg::Rcsb => download::Mirror::Rcsb,
+            MirrorArg::Pdbe => download::Mirror::Pdbe,
+            MirrorArg::Pdbj => download::Mirror::Pdbj,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum AntigenArg {
+    Protein,
+    Peptide,
+    Hapten,
+    None,
+}
+
+impl From<AntigenArg> for db::AntigenType {
+    fn from(arg: AntigenArg) -> Self {
+        match arg {
+            AntigenArg::Protein => db::AntigenType::Protein,
+            AntigenArg::Peptide => db::AntigenType::Peptide,
+            AntigenArg::Hapten => db::AntigenType::Hapten,
+            AntigenArg::None => db::AntigenType::None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LightArg {
+    Kappa,
+    Lambda,
+}
+
+impl From<LightArg> for db::LightType {
+    fn from(arg: LightArg) -> Self {
+        match arg {
+            LightArg::Kappa => db::LightType::Kappa,
+            LightArg::Lambda => db::LightType::Lambda,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum KeepArg {
+    None,
+    QcFailed,
+    All,
+}
+
+impl From<KeepArg> for db::PruneKeep {
+    fn from(arg: KeepArg) -> Self {
+        match arg {
+            KeepArg::None => db::PruneKeep::None,
+            KeepArg::QcFailed => db::PruneKeep::QcFailed,
+            KeepArg::All => db::PruneKeep::All,
+        }
+    }
+}
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Path to the PDB file to match
     #[arg(required = true)]
-    input: PathBuf,
+    input: Option<PathBuf>,
+
+    /// Database file [default: $SCAFFOLDING_DB, else under $XDG_DATA_HOME, else data/antibodies.db]
+    #[arg(long, global = true)]
+    db_path: Option<PathBuf>,
 
         /// Force update of the database
         #[arg(short, long)]
@@ -18,31 +115,515 @@ struct Cli {
         /// Number of top matches to return
         #[arg(short = 'n', long, default_value_t = 5)]
         top_n: usize,
+
+        /// Weight of the contact map overlap component in the match score
+        #[arg(long, default_value_t = 0.0)]
+        contact_weight: f64,
+
+        /// Weight of the distance-matrix RMSD component in the match score
+        #[arg(long, default_value_t = 0.0)]
+        drmsd_weight: f64,
+
+        /// Weight of the CA lDDT component in the match score
+        #[arg(long, default_value_t = 0.0)]
+        lddt_weight: f64,
+
+        /// Weight of the CDR-H3 loop descriptor likeness in the match score
+        #[arg(long, default_value_t = 0.0)]
+        h3_descriptor_weight: f64,
+
+        /// Report the worst-deviating region of each match after superposition
+        #[arg(long)]
+        annotate: bool,
+
+        /// Per-atom weighting of the RMSD component
+        #[arg(long, value_enum, default_value_t = WeightingArg::Uniform)]
+        weighting: WeightingArg,
+
+        /// Fraction of candidates, pre-ranked by k-mer and Ramachandran fingerprint similarity, that get full scoring
+        #[arg(long, default_value_t = 0.2)]
+        prefilter_fraction: f64,
+
+        /// Discard candidates whose radius of gyration differs by more than this fraction
+        #[arg(long)]
+        rg_tolerance: Option<f64>,
+
+        /// Band width of the sequence alignments, 0 for the full alignment
+        #[arg(long, default_value_t = 15)]
+        band_width: usize,
+
+        /// Weight of the numbered heavy chain identity in the match score
+        #[arg(long, default_value_t = 0.0)]
+        sequence_weight: f64,
+
+        /// Weight of CDR positions within the sequence component
+        #[arg(long, default_value_t = 1.0)]
+        weight_cdr: f64,
+
+        /// Weight of framework positions within the sequence component
+        #[arg(long, default_value_t = 1.0)]
+        weight_framework: f64,
+
+        /// Heavy chain of the input structure
+        #[arg(long, default_value_t = 'H')]
+        heavy_chain: char,
+
+        /// Light chain of the input structure
+        #[arg(long, default_value_t = 'L')]
+        light_chain: char,
+
+        /// Only match entries bound to this antigen type, repeatable
+        #[arg(long = "antigen-type", value_enum)]
+        antigen_types: Vec<AntigenArg>,
+
+        /// Only match Fabs with this light chain type
+        #[arg(long, value_enum)]
+        light_type: Option<LightArg>,
+
+        /// Only match entries deposited before this date (YYYY-MM-DD)
+        #[arg(long)]
+        deposited_before: Option<NaiveDate>,
+
+        /// Return one representative per clone instead of every re-deposit
+        #[arg(long)]
+        unique_clones: bool,
+
+        /// Only match Fabs whose CDR-H3 length is within this many residues of the target's
+        #[arg(long)]
+        h3_tolerance: Option<usize>,
+
+        /// Only match Fabs with the target's canonical classes of CDR-L1-L3, H1 and H2
+        #[arg(long)]
+        same_canonical: bool,
+
+        /// Score only the nearest neighbours of the target by embedding, for large databases
+        #[arg(long)]
+        fast: bool,
     }
-    
+
+#[derive(Subcommand)]
+enum Command {
+    /// Download and process the SAbDab entries selected by the filters
+    Update(UpdateArgs),
+    /// Print counts and distributions of the database as JSON
+    Stats,
+    /// Write the processed Fabs to a new file for import-db elsewhere
+    ExportDb(ExportArgs),
+    /// Merge a file written by export-db into the database
+    ImportDb {
+        /// Exported file, in either format
+        path: PathBuf,
+    },
+    /// Report the numbering backend in use and check the database for rows
+    /// left inconsistent by interrupted runs
+    Doctor {
+        /// Reset the affected Fabs so the next update processes them again
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Drop the stored structures of processed entries, which matching does
+    /// not need once their features are stored, and compact the database
+    Clean {
+        /// Processed structures to keep anyway
+        #[arg(long, value_enum, default_value_t = KeepArg::QcFailed)]
+        keep: KeepArg,
+    },
+    /// Apply new QC thresholds to the processed Fabs, processing those that
+    /// pass now without numbering the others again
+    ReprocessQc(QcArgs),
+    /// Manage the entries left out of processing and matching
+    Exclude {
+        #[command(subcommand)]
+        action: ExcludeAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum ExcludeAction {
+    /// Exclude an entry, replacing the reason if it already is
+    Add {
+        pdb_id: String,
+        /// Why the entry is excluded, e.g. "chimeric construct"
+        #[arg(long)]
+        reason: String,
+    },
+    /// Include an excluded entry again
+    Remove { pdb_id: String },
+    /// Print the excluded entries as JSON
+    List,
+}
+
+// QC thresholds; those not given keep the values in use, initially 0 gaps,
+// 4 residues missing backbone atoms and the rest unchecked
+#[derive(Args)]
+struct QcArgs {
+    /// Most geometric chain breaks a Fab passing QC may have
+    #[arg(long)]
+    max_geometric_gaps: Option<usize>,
+
+    /// Most residues missing backbone atoms a Fab passing QC may have
+    #[arg(long)]
+    max_missing_backbone: Option<usize>,
+
+    /// Most breaks in the deposited numbering a Fab passing QC may have
+    #[arg(long)]
+    max_numbering_gaps: Option<usize>,
+
+    /// Largest Ramachandran outlier fraction a Fab passing QC may have
+    #[arg(long)]
+    max_rama_outliers: Option<f64>,
+}
+
+impl QcArgs {
+    // The thresholds in use with the given ones replaced; None if none are
+    fn thresholds(&self, db: &db::Db) -> Result<Option<QcThresholds>> {
+        if self.max_geometric_gaps.is_none() && self.max_missing_backbone.is_none()
+            && self.max_numbering_gaps.is_none() && self.max_rama_outliers.is_none()
+        {
+            return Ok(None);
+        }
+        let current = process::qc_thresholds(db)?;
+        Ok(Some(QcThresholds {
+            max_geometric_gaps: self.max_geometric_gaps.unwrap_or(current.max_geometric_gaps),
+            max_missing_backbone: self.max_missing_backbone.unwrap_or(current.max_missing_backbone),
+            max_numbering_gaps: self.max_numbering_gaps.or(current.max_numbering_gaps),
+            max_rama_outlier_fraction: self.max_rama_outliers.or(current.max_rama_outlier_fraction),
+        }))
+    }
+}
+
+#[derive(Args)]
+struct ExportArgs {
+    /// File to create
+    path: PathBuf,
+
+    /// Write zstd-compressed JSONL instead of a SQLite database
+    #[arg(long)]
+    jsonl: bool,
+
+    /// Leave out the stored structures, which matching needs
+    #[arg(long)]
+    without_structures: bool,
+}
+
+impl ExportArgs {
+    fn export_options(&self) -> db::ExportOptions {
+        db::ExportOptions {
+            format: if self.jsonl { db::ExportFormat::Jsonl } else { db::ExportFormat::Sqlite },
+            structures: !self.without_structures,
+        }
+    }
+}
+
+#[derive(Args)]
+struct UpdateArgs {
+    /// Maximum number of simultaneous structure downloads
+    #[arg(long, default_value_t = 8)]
+    download_jobs: usize,
+
+    /// Read the SAbDab summary by fixed column positions (old cached files)
+    #[arg(long)]
+    positional_summary: bool,
+
+    /// Accepted heavy chain species, repeatable; "any" disables the filter
+    #[arg(long = "species", default_values_t = ["homo sapiens".to_string()])]
+    species: Vec<String>,
+
+    /// Worst accepted resolution in Angstrom, 0 to accept any
+    #[arg(long, default_value_t = 3.0)]
+    max_resolution: f64,
+
+    /// Accepted experimental methods (substring match), repeatable; "any" disables the filter
+    #[arg(long = "method", default_values_t = ["X-RAY".to_string(), "ELECTRON MICROSCOPY".to_string()])]
+    methods: Vec<String>,
+
+    /// Keep single-chain Fv entries
+    #[arg(long)]
+    allow_scfv: bool,
+
+    /// Earliest accepted deposition date (YYYY-MM-DD)
+    #[arg(long)]
+    min_date: Option<NaiveDate>,
+
+    /// Latest accepted deposition date (YYYY-MM-DD)
+    #[arg(long)]
+    max_date: Option<NaiveDate>,
+
+    /// Also download the entries superseding obsolete ones
+    #[arg(long)]
+    fetch_replacements: bool,
+
+    /// Archive mirrors in the order they are tried, repeatable
+    #[arg(long = "mirror", value_enum, default_values_t = [MirrorArg::Rcsb, MirrorArg::Pdbe, MirrorArg::Pdbj])]
+    mirrors: Vec<MirrorArg>,
+
+    /// Delete the downloaded summary file once it is archived in the database
+    #[arg(long)]
+    clean: bool,
+
+    /// Download the summary even if the cached copy is current
+    #[arg(long)]
+    refresh_summary: bool,
+
+    /// Local PDB mirror to take structures from before downloading
+    #[arg(long)]
+    mirror_dir: Option<PathBuf>,
+
+    /// Connect and response timeout of HTTP requests, in seconds
+    #[arg(long, default_value_t = 30)]
+    timeout: u64,
+
+    /// Report what would be downloaded from the cached summary, without fetching or writing
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Skip entries whose download failed more often than this
+    #[arg(long, default_value_t = 3)]
+    max_failures: u32,
+
+    /// Only retry the downloads and the numbering that failed before, however often
+    #[arg(long, conflicts_with = "dry_run")]
+    retry_failed: bool,
+
+    /// Fetch stored structures again after this many days to detect revisions, 0 never does
+    #[arg(long, default_value_t = 180)]
+    revalidate_days: i64,
+
+    /// Download the structures dropped by clean again
+    #[arg(long)]
+    restore_pruned: bool,
+
+    /// Sequence identity over both chains at which Fabs count as the same clone
+    #[arg(long, default_value_t = scaffolding_lna_rs::analysis::DEFAULT_CLUSTER_IDENTITY)]
+    cluster_identity: f64,
+
+    /// Maximum number of simultaneous numbering tool processes, by default one per core up to 4
+    #[arg(long)]
+    numbering_jobs: Option<usize>,
+
+    /// Fabs processed and saved together; an interrupted run keeps the chunks it finished
+    #[arg(long, default_value_t = process::DEFAULT_CHUNK_SIZE)]
+    chunk_size: usize,
+
+    /// Fabs of a chunk whose structures are held in memory at once
+    #[arg(long, default_value_t = process::DEFAULT_MAX_IN_FLIGHT)]
+    max_in_flight: usize,
+
+    #[command(flatten)]
+    qc: QcArgs,
+}
+
+impl UpdateArgs {
+    fn download_options(&self) -> download::DownloadOptions {
+        let any = |values: &[String]| values.iter().any(|v| v.eq_ignore_ascii_case("any"));
+        download::DownloadOptions {
+            jobs: self.download_jobs,
+            positional_columns: self.positional_summary,
+            filter: download::FilterConfig {
+                species: if any(&self.species) { Vec::new() } else { self.species.clone() },
+                max_resolution: (self.max_resolution > 0.0).then_some(self.max_resolution),
+                methods: if any(&self.methods) { Vec::new() } else { self.methods.clone() },
+                allow_scfv: self.allow_scfv,
+                min_date: self.min_date,
+                max_date: self.max_date,
+            },
+            fetch_replacements: self.fetch_replacements,
+            mirrors: self.mirrors.iter().map(|&m| m.into()).collect(),
+            clean: self.clean,
+            refresh_summary: self.refresh_summary,
+            mirror_dir: self.mirror_dir.clone(),
+            dry_run: self.dry_run,
+            max_failures: self.max_failures,
+            revalidate_after: (self.revalidate_days > 0).then(|| chrono::Duration::days(self.revalidate_days)),
+            restore_pruned: self.restore_pruned,
+        }
+    }
+
+    fn fetcher(&self) -> download::HttpFetcher {
+        download::HttpFetcher::new(download::http_agent(Duration::from_secs(self.timeout)))
+    }
+
+    fn process_options(&self, db: &db::Db) -> Result<process::ProcessOptions> {
+        Ok(process::ProcessOptions {
+            cluster_identity: self.cluster_identity,
+            numbering_concurrency: self.numbering_jobs.unwrap_or_else(numbering::default_concurrency),
+            chunk_size: self.chunk_size,
+            max_in_flight: self.max_in_flight,
+            qc: self.qc.thresholds(db)?,
+        })
+    }
+}
+
+fn update(db: &db::Db, fetcher: &dyn download::Fetcher, options: &download::DownloadOptions, process_options: &process::ProcessOptions) -> Result<()> {
+    // The summary is cached next to the database
+    let summary_path = db.path().unwrap_or(Path::new("data/antibodies.db")).with_file_name("sabdab_summary_all.tsv");
+    if let Some(report) = download::populate_db(db, fetcher, &summary_path, options, &BarProgress::new())? {
+        println!("{}", report);
+        return Ok(());
+    }
+    process::process_all_default(db, process_options, &BarProgress::new())
+}
+
     fn main() -> Result<()> {
         env_logger::init();
         let cli = Cli::parse();
         
-        let db_path = Path::new("data/antibodies.db");
-        if let Some(parent) = db_path.parent() {
-            std::fs::create_dir_all(parent)?;
+        let db_path = db::default_path(cli.db_path.as_deref());
+        let db_path = db_path.as_path();
+
+        // Matching a populated database and reporting on it need no write
+        // access, so they also work on read-only volumes
+        if !cli.force_update && db_path.exists()
+            && let Ok(db) = db::Db::open_read_only(db_path)
+        {
+            let read_only = match &cli.command {
+                Some(Command::Stats | Command::ExportDb(_) | Command::Doctor { repair: false }) => true,
+                Some(Command::Exclude { action: ExcludeAction::List }) => true,
+                None => db.is_populated()?,
+                _ => false,
+            };
+            if read_only {
+                warn_if_newer(&db, db_path)?;
+                return match cli.command {
+                    Some(Command::Stats) => print_stats(&db),
+                    Some(Command::ExportDb(args)) => db.export(&args.path, &args.export_options()),
+                    Some(Command::Doctor { .. }) => doctor(&db, false),
+                    Some(Command::Exclude { action }) => exclude(&db, &action),
+                    _ => run_match(&db, cli),
+                };
+            }
+        }
+
+        let db = db::Db::open_default(Some(db_path))?;
+        warn_if_newer(&db, db_path)?;
+
+        match &cli.command {
+            Some(Command::Stats) => return print_stats(&db),
+            Some(Command::ExportDb(args)) => return db.export(&args.path, &args.export_options()),
+            Some(Command::ImportDb { path }) => return import(&db, path),
+            Some(Command::Doctor { repair }) => return doctor(&db, *repair),
+            Some(Command::Exclude { action }) => return exclude(&db, action),
+            Some(Command::Clean { keep }) => {
+                println!("{}", db.prune_blobs((*keep).into())?);
+                return Ok(());
+            }
+            Some(Command::ReprocessQc(args)) => {
+                let Some(thresholds) = args.thresholds(&db)? else {
+                    anyhow::bail!("No QC thresholds given");
+                };
+                println!("{}", process::run_qc(&db, &thresholds, process::DEFAULT_CHUNK_SIZE, &BarProgress::new())?);
+                return process::process_all_default(&db, &process::ProcessOptions::default(), &BarProgress::new());
+            }
+            _ => {}
+        }
+        if let Some(Command::Update(args)) = &cli.command {
+            if args.retry_failed {
+                download::retry_failed(&db, &args.fetcher(), &args.download_options(), &BarProgress::new())?;
+                let options = args.process_options(&db)?;
+                process::process_all_default(&db, &options, &BarProgress::new())?;
+                println!("{}", process::process_failed_default(&db, &options, &BarProgress::new())?);
+                return Ok(());
+            }
+            return update(&db, &args.fetcher(), &args.download_options(), &args.process_options(&db)?);
         }
-        
-        let mut db = db::Db::open(db_path)?;
     
         // Auto-initialization
         let needs_init = !db.is_populated()? || cli.force_update;
         if needs_init {
             info!("Database needs initialization or update...");
-            let summary_path = Path::new("data/sabdab_summary_all.tsv");
-            download::populate_db(&mut db, summary_path)?;
-            process::process_all(&mut db)?;
+            // Structures dropped by clean only come back on a forced update
+            let restore_pruned = cli.force_update && db.meta_get(db::BLOBS_PRUNED_KEY)?.is_some();
+            let options = download::DownloadOptions { restore_pruned, ..Default::default() };
+            update(&db, &download::HttpFetcher::default(), &options, &process::ProcessOptions::default())?;
         }
     
-        // Default mode: Match
-        let matches = match_ab::find_matches(&mut db, &cli.input, cli.top_n)?;
+        run_match(&db, cli)
+    }
+
+    // Same schema version, but a newer build may store values this one misreads
+    fn warn_if_newer(db: &db::Db, path: &Path) -> Result<()> {
+        if let Some(version) = db.created_by_newer()? {
+            eprintln!(
+                "Warning: {:?} was created by scaffolding-lna-rs {}, newer than this build ({})",
+                path, version, env!("CARGO_PKG_VERSION")
+            );
+        }
+        Ok(())
+    }
+
+    // Default mode: Match
+    fn run_match(db: &db::Db, cli: Cli) -> Result<()> {
+        let input = cli.input.expect("required without a subcommand");
+        let mut options = match_ab::MatchOptions { top_n: cli.top_n, ..Default::default() };
+        options.weights.contact = cli.contact_weight;
+        options.weights.drmsd = cli.drmsd_weight;
+        options.weights.lddt = cli.lddt_weight;
+        options.weights.h3_descriptor = cli.h3_descriptor_weight;
+        options.annotate_deviation = cli.annotate;
+        options.weighting = cli.weighting.into();
+        options.prefilter_fraction = cli.prefilter_fraction;
+        options.rg_tolerance = cli.rg_tolerance;
+        options.band_width = (cli.band_width > 0).then_some(cli.band_width);
+        options.weights.sequence = cli.sequence_weight;
+        options.region_weights = RegionWeights::new(cli.weight_framework, cli.weight_cdr);
+        options.target_heavy_chain = cli.heavy_chain;
+        options.target_light_chain = cli.light_chain;
+        options.antigen_types = cli.antigen_types.iter().map(|&t| t.into()).collect();
+        options.light_type = cli.light_type.map(Into::into);
+        options.deposited_before = cli.deposited_before;
+        options.unique_clones = cli.unique_clones;
+        options.h3_length_tolerance = cli.h3_tolerance;
+        options.same_canonical = cli.same_canonical;
+        options.ann_candidates = cli.fast.then_some(match_ab::FAST_CANDIDATES);
+        let matches = match_ab::find_matches(db, &input, &options)?;
         println!("{}", serde_json::to_string_pretty(&matches)?);
     
         Ok(())
     }
+
+    // Clusters refer to fab_ids, so imported Fabs are regrouped with the rest
+    fn import(db: &db::Db, path: &Path) -> Result<()> {
+        let report = db.import(path)?;
+        println!("{}", report);
+        if report.added + report.updated > 0 {
+            process::cluster_clones(db, scaffolding_lna_rs::analysis::DEFAULT_CLUSTER_IDENTITY)?;
+        }
+        Ok(())
+    }
+
+    fn doctor(db: &db::Db, repair: bool) -> Result<()> {
+        println!("Numbering: {}", numbering::detected_backend());
+        let report = db.check_integrity()?;
+        println!("{}", report);
+        if report.is_clean() {
+            return Ok(());
+        }
+        if repair {
+            println!("Reset {} Fabs for processing", db.repair(&report)?);
+        }
+        if !report.sqlite.is_empty() {
+            anyhow::bail!("SQLite reports corruption, restore the database from a backup or export");
+        }
+        if !repair {
+            anyhow::bail!("Inconsistent rows found, run doctor --repair to reset them");
+        }
+        Ok(())
+    }
+
+    fn exclude(db: &db::Db, action: &ExcludeAction) -> Result<()> {
+        match action {
+            ExcludeAction::Add { pdb_id, reason } => db.blacklist(pdb_id, reason)?,
+            ExcludeAction::Remove { pdb_id } => {
+                if !db.unblacklist(pdb_id)? {
+                    anyhow::bail!("{} is not excluded", pdb_id);
+                }
+            }
+            ExcludeAction::List => println!("{}", serde_json::to_string_pretty(&db.blacklisted()?)?),
+        }
+        Ok(())
+    }
+
+    fn print_stats(db: &db::Db) -> Result<()> {
+        println!("{}", serde_json::to_string_pretty(&db.stats()?)?);
+        Ok(())
+    }
@@ -1,20 +1,184 @@
-use crate::db::Db;
-use crate::pdb::Pdb;
-use crate::analysis;
+use crate::db::{AntibodyRecord, AntigenType, CdrLength, Db, DbFilter, LightType, StoredStructure};
+use crate::features::{ChainFeatures, FabTrace};
+use crate::pdb::{Atom, Pdb, Point};
+use crate::analysis::{self, KmerProfile, LoopDescriptors, RegionWeights, RmsdError, Weighting};
+use crate::numbering::{self, CachedStrategy, ChainNumbering, ChainType, Numbered, NumberingStrategy, Region};
+use crate::process::canonical::{self, Loop};
+use crate::process::KMER_K;
 use anyhow::Result;
+use chrono::NaiveDate;
 use rayon::prelude::*;
 use serde::Serialize;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
-use log::info;
+use log::{info, warn};
 
 #[derive(Serialize)]
 pub struct MatchResult {
     pub pdb_id: String,
+    /// Chains of the matched Fab within the entry
+    pub h_chain: String,
+    pub l_chain: String,
+    /// "kappa" or "lambda" when known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub light_type: Option<String>,
     pub score: f64,
     pub method: String,
+    /// Where the superposed candidate deviates most, e.g. "max deviation 6.2 Å at H99–H103"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub worst_region: Option<String>,
 }
 
-pub fn find_matches(db: &mut Db, target_path: &Path, top_n: usize) -> Result<Vec<MatchResult>> {
+/// Relative weights of the score components; zero disables a component.
+#[derive(Debug, Clone)]
+pub struct ScoreWeights {
+    pub rmsd: f64,
+    pub rama: f64,
+    /// Contact map overlap, robust to VH/VL hinge motion
+    pub contact: f64,
+    /// Distance-matrix RMSD, superposition free
+    pub drmsd: f64,
+    /// CA lDDT, superposition free and robust to domain motion
+    pub lddt: f64,
+    /// Region-weighted identity of the numbered heavy chains
+    pub sequence: f64,
+    /// Likeness of the CDR-H3 loop descriptors; needs the target numbered
+    pub h3_descriptor: f64,
+}
+
+impl Default for ScoreWeights {
+    fn default() -> Self {
+        Self { rmsd: 0.5, rama: 0.5, contact: 0.0, drmsd: 0.0, lddt: 0.0, sequence: 0.0, h3_descriptor: 0.0 }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchOptions {
+    /// Number of top matches to return
+    pub top_n: usize,
+    pub weights: ScoreWeights,
+    /// CA-CA distance cutoff for contact maps, in Angstrom
+    pub contact_cutoff: f64,
+    /// Annotate each returned match with its worst-deviating region
+    pub annotate_deviation: bool,
+    /// Per-atom weighting of the RMSD component and superpositions
+    pub weighting: Weighting,
+    /// Fraction of candidates, pre-ranked by k-mer and Ramachandran
+    /// fingerprint similarity, that get full scoring; 1.0 scores everything
+    pub prefilter_fraction: f64,
+    /// Discard candidates whose radius of gyration differs from the target's
+    /// by more than this fraction; None keeps all
+    pub rg_tolerance: Option<f64>,
+    /// Band width of the sequence alignments; None runs the full DP
+    pub band_width: Option<usize>,
+    /// Framework/CDR weights of the sequence component
+    pub region_weights: RegionWeights,
+    /// Heavy chain of the target, numbered for the sequence and H3
+    /// descriptor components
+    pub target_heavy_chain: char,
+    /// Light chain of the target, numbered for the canonical class filter
+    pub target_light_chain: char,
+    /// Only consider entries bound to one of these antigen types; empty for all
+    pub antigen_types: Vec<AntigenType>,
+    /// Only consider Fabs with this light chain type
+    pub light_type: Option<LightType>,
+    /// Only consider entries deposited before this date, e.g. for benchmarks
+    /// against targets solved later
+    pub deposited_before: Option<NaiveDate>,
+    /// Only consider clone representatives, so re-deposits of one antibody
+    /// do not fill the top matches
+    pub unique_clones: bool,
+    /// Only consider Fabs whose CDR-H3 length is within this many residues
+    /// of the target's; needs the target numbered, ignored if that fails
+    pub h3_length_tolerance: Option<usize>,
+    /// Only consider Fabs with the target's canonical class on each non-H3
+    /// loop whose class is known; needs the target numbered, loops of a chain
+    /// that fails are not filtered
+    pub same_canonical: bool,
+    /// Score only the Fabs among this many nearest neighbours of the target
+    /// by embedding, looked up in the index instead of pre-ranking every
+    /// candidate; needs the target numbered, falls back to the prefilter if
+    /// that fails
+    pub ann_candidates: Option<usize>,
+}
+
+/// Nearest neighbours retrieved for scoring in fast mode
+pub const FAST_CANDIDATES: usize = 200;
+
+/// The k-mer prefilter never narrows the field below this many candidates
+const PREFILTER_MIN_KEEP: usize = 100;
+
+/// Leading CAs of target and candidate paired for the RMSD component
+const RMSD_RESIDUES: usize = 50;
+
+impl Default for MatchOptions {
+    fn default() -> Self {
+        Self {
+            top_n: 5,
+            weights: ScoreWeights::default(),
+            contact_cutoff: 8.0,
+            annotate_deviation: false,
+            weighting: Weighting::Uniform,
+            prefilter_fraction: 0.2,
+            rg_tolerance: None,
+            band_width: Some(15),
+            region_weights: RegionWeights::default(),
+            target_heavy_chain: 'H',
+            target_light_chain: 'L',
+            antigen_types: Vec::new(),
+            light_type: None,
+            deposited_before: None,
+            unique_clones: false,
+            h3_length_tolerance: None,
+            same_canonical: false,
+            ann_candidates: None,
+        }
+    }
+}
+
+// One Fab of a stored entry, with its Fv atoms or else the entry's structure
+struct Fab {
+    record: AntibodyRecord,
+    fv: Option<Pdb>,
+    structure: Option<StoredStructure>,
+}
+
+impl Fab {
+    // Loads the Fv or the structure of a candidate; runs on the scoring
+    // workers, which query the database concurrently. None if both are gone
+    // or unreadable.
+    fn load(db: &Db, record: &AntibodyRecord) -> Option<Self> {
+        match db.load_fv(record.fab_id) {
+            Ok(Some(fv)) => return Some(Self { record: record.clone(), fv: Some(fv), structure: None }),
+            Ok(None) => {}
+            Err(e) => warn!("Could not load the Fv of {}: {}", record.pdb_id, e),
+        }
+        match db.load_structure(&record.pdb_id) {
+            Ok(structure) => Some(Self { record: record.clone(), fv: None, structure: Some(structure?) }),
+            Err(e) => {
+                warn!("Could not load the structure of {}: {}", record.pdb_id, e);
+                None
+            }
+        }
+    }
+
+    // The Fv, or the Fab's chains of the stored structure, the whole
+    // structure if none of them are present. An undecodable blob parses as
+    // an empty structure.
+    fn parse(&self) -> Pdb {
+        let Some(structure) = &self.structure else { return self.fv.clone().unwrap_or(Pdb { atoms: Vec::new() }) };
+        let content = structure.text().unwrap_or_else(|e| {
+            warn!("Stored structure of {} is unreadable: {}", self.record.pdb_id, e);
+            String::new()
+        });
+        let entry = Pdb::parse(&content, structure.format);
+        let (h_id, l_id) = self.record.chain_ids();
+        let fab = entry.select_chains(&[h_id, l_id]);
+        if fab.atoms.is_empty() { entry } else { fab }
+    }
+}
+
+pub fn find_matches(db: &Db, target_path: &Path, options: &MatchOptions) -> Result<Vec<MatchResult>> {
     let target_content = std::fs::read_to_string(target_path)?;
     let target_pdb = Pdb::from_str(&target_content);
     // Extract target sequence (naive extraction from atoms for MVP)
@@ -26,56 +190,557 @@ pub fn find_matches(db: &mut Db, target_path: &Path, top_n: usize) -> Result<Vec
     // We'll use RMSD on first 100 atoms as a dummy metric if counts match, 
     // or just return 0.0 to show the pipeline works.
     
-    // Fetch candidates
-    let candidates = {
-        let conn = db.get_conn();
-        // Only select those that passed QC
-        let mut stmt = conn.prepare("SELECT pdb_id, pdb_blob, method FROM antibodies WHERE processed = TRUE AND passed_qc = TRUE AND pdb_blob IS NOT NULL")?;
-        let rows = stmt.query_map([], |row| {
-            Ok((
-                row.get::<_, String>(0)?,
-                row.get::<_, Vec<u8>>(1)?,
-                row.get::<_, String>(2)?,
-            ))
-        })?;
-        
-        let mut res = Vec::new();
-        for r in rows { res.push(r?); }
-        res
+    let target_ca_points: Vec<_> = analysis::ca_trace(&target_pdb.atoms).iter().map(|a| a.pos).collect();
+    let target_rg = analysis::shape_descriptors(&target_ca_points).rg;
+
+    let weights = &options.weights;
+    let target_chain_numbering = if weights.sequence > 0.0 || weights.h3_descriptor > 0.0 || options.h3_length_tolerance.is_some() || options.ann_candidates.is_some() || options.same_canonical {
+        number_target(db, &target_pdb, options.target_heavy_chain)
+    } else {
+        None
+    };
+    let target_numbering = target_chain_numbering.as_ref().map(ChainNumbering::numbered);
+    let target_h3_loop = target_chain_numbering.as_ref().filter(|_| weights.h3_descriptor > 0.0)
+        .and_then(|numbering| analysis::residue_loop_descriptors(&target_pdb, &numbering.h3_loop_residues()));
+    // H3 length window, checked in SQL on the stored CDR lengths
+    let cdr_length = options.h3_length_tolerance.zip(target_numbering.as_ref()).map(|(tolerance, numbered)| CdrLength {
+        chain: ChainType::Heavy,
+        cdr: Region::Cdr3,
+        length: numbering::cdr_sequences(numbered, ChainType::Heavy)[2].len(),
+        tolerance,
+    });
+    let canonical_classes = if options.same_canonical {
+        target_canonical_classes(db, &target_pdb, target_numbering.as_ref(), options.target_light_chain)
+    } else {
+        Vec::new()
     };
 
+    // Fetch candidates that passed QC
+    let filter = DbFilter {
+        processed: Some(true),
+        passed_qc: Some(true),
+        current_only: true,
+        scorable: true,
+        antigen_types: options.antigen_types.clone(),
+        light_type: options.light_type,
+        deposited_before: options.deposited_before,
+        representatives_only: options.unique_clones,
+        cdr_length,
+        canonical_classes,
+        skip_blacklisted: true,
+        ..Default::default()
+    };
+    let mut rg_rejected = 0;
+    let mut candidates = Vec::new();
+    for record in db.list_antibodies(&filter)? {
+        // Shape pre-filter on the stored Rg, before any parsing
+        if let (Some(tolerance), Some(rg)) = (options.rg_tolerance, record.rg)
+            && (rg - target_rg).abs() > tolerance * target_rg
+        {
+            rg_rejected += 1;
+            continue;
+        }
+        candidates.push(record);
+    }
+
+    if rg_rejected > 0 {
+        info!("Rg filter discarded {} candidates", rg_rejected);
+    }
     info!("Matching against {} candidates...", candidates.len());
 
-    let mut results: Vec<MatchResult> = candidates.par_iter().map(|(id, blob, method)| {
-        let content = String::from_utf8_lossy(blob);
-        let candidate_pdb = Pdb::from_str(&content);
-        
+    // Cheap first pass: rank by k-mer profile and Ramachandran fingerprint,
+    // only the closest candidates are aligned. In fast mode the embedding
+    // index picks them instead.
+    let target_kmers = analysis::kmer_profile(&analysis::structure_sequence(&target_pdb.atoms), KMER_K);
+    let target_fingerprint = analysis::rama_fingerprint(&analysis::ramachandran_angles(&target_pdb.atoms), analysis::FINGERPRINT_BINS);
+    let target_embedding = options.ann_candidates.zip(target_numbering.as_ref()).and_then(|(k, numbered)| {
+        let lengths = numbering::cdr_sequences(numbered, ChainType::Heavy).map(|s| s.len());
+        Some((k, analysis::fab_embedding(&target_fingerprint, lengths)?))
+    });
+    if options.ann_candidates.is_some() && target_embedding.is_none() {
+        warn!("Target has no embedding, fast mode falls back to the prefilter");
+    }
+    let keep = ((candidates.len() as f64 * options.prefilter_fraction).ceil() as usize).max(PREFILTER_MIN_KEEP);
+    let candidates: Vec<_> = if let Some((k, embedding)) = target_embedding {
+        // Only Fabs passing the filter count towards the k nearest
+        let allowed: HashSet<i64> = candidates.iter().map(|r| r.fab_id).collect();
+        let nearest: HashSet<i64> = db.knn(&embedding, k, |fab_id| allowed.contains(&fab_id))?.into_iter().map(|(fab_id, _)| fab_id).collect();
+        let total = candidates.len();
+        let candidates: Vec<_> = candidates.into_iter().filter(|r| nearest.contains(&r.fab_id)).collect();
+        info!("Fast mode kept {} of {} candidates", candidates.len(), total);
+        candidates
+    } else if keep < candidates.len() {
+        let mut ranked: Vec<(f64, AntibodyRecord)> = candidates.into_par_iter().filter_map(|record| {
+            let stored = (
+                record.kmer_profile.as_deref().and_then(KmerProfile::from_bytes),
+                record.rama_fingerprint.as_deref().and_then(analysis::fingerprint_from_bytes),
+            );
+            let (profile, fingerprint) = match stored {
+                (Some(profile), Some(fingerprint)) => (profile, fingerprint),
+                _ => {
+                    let pdb = Fab::load(db, &record)?.parse();
+                    (
+                        analysis::kmer_profile(&analysis::structure_sequence(&pdb.atoms), KMER_K),
+                        analysis::rama_fingerprint(&analysis::ramachandran_angles(&pdb.atoms), analysis::FINGERPRINT_BINS),
+                    )
+                }
+            };
+            let similarity = (analysis::kmer_similarity(&target_kmers, &profile)
+                + analysis::fingerprint_similarity(&target_fingerprint, &fingerprint)) / 2.0;
+            Some((similarity, record))
+        }).collect();
+        ranked.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+        info!("Prefilter kept {} of {} candidates", keep, ranked.len());
+        ranked.into_iter().take(keep).map(|(_, c)| c).collect()
+    } else {
+        candidates
+    };
+
+    let candidate_numbering = if weights.sequence > 0.0 && target_numbering.is_some() {
+        db.numbering_by_fab(ChainType::Heavy)?
+    } else {
+        HashMap::new()
+    };
+    let target = FabTrace::from_pdb(&target_pdb);
+    let target_ca = &target.ca;
+    let target_contacts = analysis::contact_map(target_ca, options.contact_cutoff);
+    // Candidates processed before features were kept are parsed instead
+    let stored_features: HashMap<i64, Vec<ChainFeatures>> = db.features_iter(&filter)?.collect();
+
+    let scored: Vec<(MatchResult, Option<RmsdError>)> = candidates.par_iter().filter_map(|record| {
+        let candidate = match stored_features.get(&record.fab_id) {
+            Some(chains) => FabTrace::from_features(chains),
+            None => FabTrace::from_pdb(&Fab::load(db, record)?.parse()),
+        };
+
         // Metric: RMSD + Ramachandran
-        // RMSD
-        let limit = target_pdb.atoms.len().min(candidate_pdb.atoms.len()).min(50);
-        let rmsd_score = if limit > 0 {
-             1.0 / (1.0 + analysis::rmsd(&target_pdb.atoms[0..limit], &candidate_pdb.atoms[0..limit]))
-        } else {
-            0.0
+        // RMSD over the leading CAs
+        let limit = target_ca.len().min(candidate.ca.len()).min(RMSD_RESIDUES);
+        let pairs: Vec<_> = target_ca[0..limit].iter().cloned()
+            .zip(candidate.ca[0..limit].iter().cloned())
+            .collect();
+        let (rmsd_score, rmsd_error) = match fitted_rmsd(&pairs, options.weighting) {
+            Ok(value) => (1.0 / (1.0 + value), None),
+            Err(e) => {
+                info!("Skipping RMSD for {} {}/{}: {}", record.pdb_id, record.h_chain, record.l_chain, e);
+                (0.0, Some(e))
+            }
         };
 
         // Ramachandran
-        let target_rama = analysis::ramachandran(&target_pdb.atoms);
-        let cand_rama = analysis::ramachandran(&candidate_pdb.atoms);
-        let rama_score = analysis::ramachandran_score(&target_rama, &cand_rama);
+        let rama_score = analysis::ramachandran_score(&target.rama, &candidate.rama, options.band_width).score;
+
+        // Superposition-free components over sequence-aligned CAs
+        let mut contact_score = 0.0;
+        let mut drmsd_score = 0.0;
+        let mut lddt_score = 0.0;
+        if weights.contact > 0.0 || weights.drmsd > 0.0 || weights.lddt > 0.0 {
+            let cand_ca = &candidate.ca;
+            let pairing = analysis::sequence_pairing(target_ca, cand_ca, options.band_width);
+            if weights.contact > 0.0 {
+                let cand_contacts = analysis::contact_map(cand_ca, options.contact_cutoff);
+                contact_score = analysis::contact_map_overlap(&target_contacts, &cand_contacts, &pairing);
+            }
+            if weights.drmsd > 0.0 && !pairing.is_empty() {
+                let paired: Vec<_> = pairing.iter().map(|&(i, j)| (target_ca[i].pos, cand_ca[j].pos)).collect();
+                drmsd_score = 1.0 / (1.0 + analysis::drmsd(&paired));
+            }
+            if weights.lddt > 0.0 {
+                lddt_score = analysis::lddt(&target_pdb, &candidate.ca_pdb(), &pairing, analysis::LDDT_INCLUSION_RADIUS).global;
+            }
+        }
 
-        // Weighted sum (50/50 for now)
-        let score = 0.5 * rmsd_score + 0.5 * rama_score;
+        let h3_descriptor_score = match (&target_h3_loop, record.h3_loop.as_deref().and_then(|json| serde_json::from_str::<LoopDescriptors>(json).ok())) {
+            (Some(t), Some(c)) => 1.0 / (1.0 + t.distance(&c)),
+            _ => 0.0,
+        };
+
+        // Weighted mean of the enabled components
+        // Numbered heavy chain identity, weighted by region
+        let sequence_score = match (&target_numbering, candidate_numbering.get(&record.fab_id)) {
+            (Some(t), Some(c)) => analysis::align_weighted(t, c, ChainType::Heavy, &options.region_weights),
+            _ => 0.0,
+        };
 
-        MatchResult {
-            pdb_id: id.clone(),
+        let total = weights.rmsd + weights.rama + weights.contact + weights.drmsd + weights.lddt + weights.sequence + weights.h3_descriptor;
+        let score = if total > 0.0 {
+            (weights.rmsd * rmsd_score
+                + weights.rama * rama_score
+                + weights.contact * contact_score
+                + weights.drmsd * drmsd_score
+                + weights.lddt * lddt_score
+                + weights.sequence * sequence_score
+                + weights.h3_descriptor * h3_descriptor_score) / total
+        } else {
+            0.0
+        };
+
+        let result = MatchResult {
+            pdb_id: record.pdb_id.clone(),
+            h_chain: record.h_chain.clone(),
+            l_chain: record.l_chain.clone(),
+            light_type: record.light_type.map(|t| t.as_str().to_string()),
             score,
-            method: method.clone(),
-        }
+            method: record.method.clone().unwrap_or_default(),
+            worst_region: None,
+        };
+        Some((result, rmsd_error))
     }).collect();
 
+    let skipped: Vec<RmsdError> = scored.iter().filter_map(|(_, e)| *e).collect();
+    if skipped.is_empty() {
+        info!("Scored {} candidates", scored.len());
+    } else {
+        let empty = skipped.iter().filter(|e| **e == RmsdError::EmptyInput).count();
+        info!(
+            "Scored {} candidates, {} without an RMSD component ({} empty, {} other)",
+            scored.len(), skipped.len(), empty, skipped.len() - empty
+        );
+    }
+    let mut results: Vec<MatchResult> = scored.into_iter().map(|(r, _)| r).collect();
+
     // Sort by score descending
     results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
     
-    Ok(results.into_iter().take(top_n).collect())
+    results.truncate(options.top_n);
+
+    // Deviation profiles are only worth computing for the reported matches
+    if options.annotate_deviation {
+        for result in results.iter_mut() {
+            let candidate = candidates.iter().find(|r| {
+                r.pdb_id == result.pdb_id && r.h_chain == result.h_chain && r.l_chain == result.l_chain
+            });
+            let Some(fab) = candidate.and_then(|r| Fab::load(db, r)) else { continue };
+            let candidate_pdb = fab.parse();
+            let pairing = analysis::sequence_pairing(target_ca, &analysis::ca_trace(&candidate_pdb.atoms), options.band_width);
+            let profile = analysis::per_residue_deviation(&target_pdb, &candidate_pdb, &pairing);
+            result.worst_region = analysis::deviation_summary(&profile).map(|s| s.annotation());
+        }
+    }
+
+    Ok(results)
+}
+
+// Weighted RMSD of the pairs once the candidate is superposed onto the
+// target with the same weights, so the score does not depend on where
+// either file places the molecule. Uniform weights need no rotation, QCP
+// gives the RMSD directly.
+fn fitted_rmsd(pairs: &[(Atom, Atom)], weighting: Weighting) -> std::result::Result<f64, RmsdError> {
+    if weighting == Weighting::Uniform {
+        let (target, candidate): (Vec<Point>, Vec<Point>) = pairs.iter().map(|(t, c)| (t.pos, c.pos)).unzip();
+        return analysis::qcp_rmsd(&target, &candidate).ok_or(RmsdError::EmptyInput);
+    }
+    // Empty input or zero weight, which `weighted_rmsd` reports
+    let Some(superposition) = analysis::weighted_superposition(pairs, weighting) else {
+        return analysis::weighted_rmsd(pairs, weighting);
+    };
+    let fitted: Vec<(Atom, Atom)> = pairs.iter()
+        .map(|(target, candidate)| (target.clone(), Atom { pos: superposition.apply(&candidate.pos), ..candidate.clone() }))
+        .collect();
+    analysis::weighted_rmsd(&fitted, weighting)
+}
+
+// Martin numbering of the target heavy chain, cached in `db`; None disables
+// the components that need it
+fn number_target(db: &Db, target: &Pdb, heavy_chain: char) -> Option<ChainNumbering> {
+    let residues = target.numbered_sequence(heavy_chain);
+    let sequence: String = residues.iter().map(|&(_, code)| code).collect();
+    match CachedStrategy::new(numbering::detect(), db).number(&sequence, "antibody") {
+        Ok(domains) => match domains.into_iter().find(|d| d.chain_type == ChainType::Heavy) {
+            Some(domain) => ChainNumbering::map(&residues, &[domain])
+                .inspect_err(|e| warn!("Numbering of target chain {} does not fit its residues, sequence component and H3 filter disabled: {}", heavy_chain, e))
+                .ok(),
+            None => {
+                warn!("Target chain {} has no heavy domain, sequence component and H3 filter disabled", heavy_chain);
+                None
+            }
+        },
+        Err(e) => {
+            warn!("Could not number target chain {}, sequence component and H3 filter disabled: {}", heavy_chain, e);
+            None
+        }
+    }
+}
+
+// Known canonical classes of the target's loops, from the heavy chain's
+// numbering and the light chain's
+fn target_canonical_classes(db: &Db, target: &Pdb, heavy: Option<&Numbered>, light_chain: char) -> Vec<(Loop, String)> {
+    let mut classes = heavy.map(|numbered| canonical::chain_classes(numbered, ChainType::Heavy)).unwrap_or_default();
+    let sequence = target.get_sequence(light_chain);
+    match CachedStrategy::new(numbering::detect(), db).number(&sequence, "antibody") {
+        Ok(domains) => match domains.into_iter().find(|d| d.chain_type != ChainType::Heavy) {
+            Some(domain) => classes.extend(canonical::chain_classes(&domain.positions, domain.chain_type)),
+            None => warn!("Target chain {} has no light domain, light chain canonical filter disabled", light_chain),
+        },
+        Err(e) => warn!("Could not number target chain {}, light chain canonical filter disabled: {}", light_chain, e),
+    }
+    classes.into_iter().filter(|&(_, class)| class != canonical::UNKNOWN).map(|(cdr, class)| (cdr, class.to_string())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::features;
+    use crate::pdb::StructureFormat;
+    use crate::process;
+    use crate::progress::NoProgress;
+    use crate::testing::{backbone, paired_fab, synthetic_fab, MockStrategy};
+
+    // Processed Fabs with structures and, if asked, their features
+    fn seeded_db(fabs: u64, length: usize, with_features: bool) -> Db {
+        let db = Db::open_in_memory().unwrap();
+        for seed in 0..fabs {
+            let pdb_id = format!("{}abc", seed);
+            let content = synthetic_fab(seed, length);
+            db.insert_raw(&pdb_id, "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+            db.put_structure(&pdb_id, &content, StructureFormat::Pdb).unwrap();
+            if with_features {
+                let fab_id = db.get_antibody(&pdb_id).unwrap().unwrap().fab_id;
+                db.store_features(fab_id, &features::fab_features(&Pdb::from_str(&content), 'H', 'L')).unwrap();
+            }
+        }
+        db.get_conn().execute("UPDATE antibodies SET processed = TRUE, passed_qc = TRUE", []).unwrap();
+        db
+    }
+
+    fn scores(db: &Db, target: &Path, options: &MatchOptions) -> Vec<(String, f64)> {
+        let mut scores: Vec<(String, f64)> = find_matches(db, target, options).unwrap().into_iter().map(|m| (m.pdb_id, m.score)).collect();
+        scores.sort_by(|a, b| a.0.cmp(&b.0));
+        scores
+    }
+
+    #[test]
+    fn test_features_score_like_structures() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target.pdb");
+        std::fs::write(&target, synthetic_fab(100, 40)).unwrap();
+        let db = seeded_db(6, 40, true);
+
+        let mut options = MatchOptions { top_n: 10, weighting: Weighting::InverseB, ..Default::default() };
+        options.weights = ScoreWeights { rmsd: 1.0, rama: 1.0, contact: 1.0, drmsd: 1.0, lddt: 1.0, ..Default::default() };
+        let from_features = scores(&db, &target, &options);
+        db.get_conn().execute("DELETE FROM features", []).unwrap();
+        let from_structures = scores(&db, &target, &options);
+
+        assert_eq!(from_features.len(), 6);
+        for ((id_f, score_f), (id_s, score_s)) in from_features.iter().zip(&from_structures) {
+            assert_eq!(id_f, id_s);
+            assert!((score_f - score_s).abs() < 1e-5, "{}: {} vs {}", id_f, score_f, score_s);
+        }
+    }
+
+    #[test]
+    fn test_rmsd_after_superposition() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target.pdb");
+        // 1abc turned a quarter about z and shifted, exact in PDB precision
+        let mut moved = Pdb::from_str(&synthetic_fab(1, 40));
+        for atom in &mut moved.atoms {
+            let p = atom.pos;
+            atom.pos = Point::new(10.0 - p.y, p.x - 5.0, p.z + 3.0);
+        }
+        std::fs::write(&target, moved.to_pdb_string()).unwrap();
+        let db = seeded_db(3, 40, false);
+
+        for weighting in [Weighting::Uniform, Weighting::InverseB] {
+            let mut options = MatchOptions { top_n: 10, weighting, ..Default::default() };
+            options.weights = ScoreWeights { rmsd: 1.0, rama: 0.0, ..Default::default() };
+            let matches = find_matches(&db, &target, &options).unwrap();
+            assert_eq!(matches[0].pdb_id, "1abc");
+            assert!((matches[0].score - 1.0).abs() < 1e-9, "{:?}: {}", weighting, matches[0].score);
+            assert!(matches[1].score < 0.9);
+        }
+    }
+
+    #[test]
+    fn test_fitted_rmsd_paths() {
+        let pdb = Pdb::from_str(&synthetic_fab(1, 20));
+        let other = Pdb::from_str(&synthetic_fab(2, 20));
+        let pairs: Vec<(Atom, Atom)> = analysis::ca_trace(&pdb.atoms).into_iter().zip(analysis::ca_trace(&other.atoms)).collect();
+        // QCP for uniform weights agrees with the Kabsch fit
+        let kabsch = analysis::weighted_superposition(&pairs, Weighting::Uniform).unwrap().rmsd;
+        assert!((fitted_rmsd(&pairs, Weighting::Uniform).unwrap() - kabsch).abs() < 1e-6);
+        assert!(fitted_rmsd(&pairs, Weighting::InverseB).unwrap() > 0.0);
+        assert_eq!(fitted_rmsd(&[], Weighting::Uniform), Err(RmsdError::EmptyInput));
+        assert_eq!(fitted_rmsd(&[], Weighting::Occupancy), Err(RmsdError::EmptyInput));
+    }
+
+    #[test]
+    fn test_two_chain_rama_score() {
+        // Two-chain fixture of `analysis::test_ramachandran_two_chains`: C(H4)-N(L5)
+        // looks like a peptide bond, which grouping residues by chain does not
+        // read across
+        let h: Vec<(i32, char)> = (1..=4).map(|i| (i, ' ')).collect();
+        let l: Vec<(i32, char)> = (5..=8).map(|i| (i, ' ')).collect();
+        let mut target = backbone('H', &h, Point::new(0.0, 0.0, 0.0));
+        target.extend(backbone('L', &l, Point::new(12.0 * 1.2, 0.0, 0.0)));
+        let perturbed = |moved: fn(&Atom) -> Option<Point>| -> Vec<Atom> {
+            target.iter().cloned().map(|a| Atom { pos: moved(&a).unwrap_or(a.pos), ..a }).collect()
+        };
+        // L turned 90 degrees about the junction, changing only the torsions
+        // across it
+        let turned = perturbed(|a| (a.chain_id == 'L').then(|| Point::new(a.pos.x, -a.pos.z, a.pos.y)));
+        // L6's CA lifted out of the plane, changing torsions within L
+        let bent = perturbed(|a| (a.chain_id == 'L' && a.res_seq == 6 && a.name == "CA").then(|| Point::new(a.pos.x, a.pos.y, a.pos.z + 1.0)));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("target.pdb");
+        std::fs::write(&path, Pdb { atoms: target.clone() }.to_pdb_string()).unwrap();
+        let mut options = MatchOptions { top_n: 10, ..Default::default() };
+        options.weights = ScoreWeights { rmsd: 0.0, rama: 1.0, ..Default::default() };
+        let score = |candidate: Vec<Atom>| {
+            let db = Db::open_in_memory().unwrap();
+            db.insert_raw("1abc", "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+            db.put_structure("1abc", &Pdb { atoms: candidate }.to_pdb_string(), StructureFormat::Pdb).unwrap();
+            db.get_conn().execute("UPDATE antibodies SET processed = TRUE, passed_qc = TRUE", []).unwrap();
+            scores(&db, &path, &options)[0].1
+        };
+        let paired = score(target.clone());
+        assert_eq!(paired, 1.0);
+        assert_eq!(score(turned), 1.0);
+        let bent = score(bent);
+        assert!(bent < paired, "{}", bent);
+    }
+
+    #[test]
+    fn test_h3_descriptor_component() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target.pdb");
+        std::fs::write(&target, paired_fab(1, 115)).unwrap();
+        // Processed through the numbering cache, which then numbers the
+        // target too
+        let db = Db::open_in_memory().unwrap();
+        let mut kappa = Vec::new();
+        for seed in 0..3 {
+            let content = paired_fab(seed, 115);
+            kappa.push(Pdb::from_str(&content).get_sequence('L'));
+            db.insert_raw(&format!("{}abc", seed), "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+            db.put_structure(&format!("{}abc", seed), &content, StructureFormat::Pdb).unwrap();
+        }
+        let strategy = CachedStrategy::new(MockStrategy { kappa, ..Default::default() }, &db);
+        process::process_all(&db, &strategy, &process::ProcessOptions::default(), &NoProgress).unwrap();
+        assert!(db.list_antibodies(&DbFilter::default()).unwrap().iter().all(|r| r.h3_loop.is_some()));
+
+        let mut options = MatchOptions { top_n: 10, ..Default::default() };
+        options.weights = ScoreWeights { rmsd: 0.0, rama: 0.0, h3_descriptor: 1.0, ..Default::default() };
+        let matches = find_matches(&db, &target, &options).unwrap();
+        assert_eq!(matches.len(), 3);
+        assert_eq!(matches[0].pdb_id, "1abc");
+        assert!((matches[0].score - 1.0).abs() < 1e-9);
+        assert!(matches[1..].iter().all(|m| m.score > 0.0 && m.score < 1.0));
+    }
+
+    #[test]
+    fn test_fast_mode_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target.pdb");
+        std::fs::write(&target, paired_fab(1, 115)).unwrap();
+        // Five copies of the target, nearest in embedding space, and two others
+        let db = Db::open_in_memory().unwrap();
+        let mut kappa = Vec::new();
+        for (i, seed) in [1, 1, 1, 1, 1, 2, 3].into_iter().enumerate() {
+            let content = paired_fab(seed, 115);
+            kappa.push(Pdb::from_str(&content).get_sequence('L'));
+            db.insert_raw(&format!("{}abc", i), "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+            db.put_structure(&format!("{}abc", i), &content, StructureFormat::Pdb).unwrap();
+        }
+        let strategy = CachedStrategy::new(MockStrategy { kappa, ..Default::default() }, &db);
+        process::process_all(&db, &strategy, &process::ProcessOptions::default(), &NoProgress).unwrap();
+        // The copies fail QC, all but one excluded from matching
+        db.get_conn().execute("UPDATE antibodies SET passed_qc = FALSE WHERE pdb_id IN ('0abc', '1abc', '2abc', '3abc')", []).unwrap();
+
+        let options = MatchOptions { top_n: 10, ann_candidates: Some(3), ..Default::default() };
+        let mut found: Vec<String> = find_matches(&db, &target, &options).unwrap().into_iter().map(|m| m.pdb_id).collect();
+        found.sort();
+        assert_eq!(found, ["4abc", "5abc", "6abc"]);
+    }
+
+    #[test]
+    fn test_match_after_prune() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target.pdb");
+        std::fs::write(&target, synthetic_fab(100, 40)).unwrap();
+        let db = seeded_db(4, 40, true);
+        let options = MatchOptions { top_n: 10, annotate_deviation: true, ..Default::default() };
+        let before = scores(&db, &target, &options);
+
+        assert_eq!(db.prune_blobs(crate::db::PruneKeep::None).unwrap().pruned, 4);
+        assert!(db.load_structure("0abc").unwrap().is_none());
+        assert_eq!(scores(&db, &target, &options), before);
+    }
+
+    #[test]
+    fn test_match_on_fv() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target.pdb");
+        std::fs::write(&target, synthetic_fab(100, 40)).unwrap();
+        // Fabs bound to an antigen, their Fv spanning the chains
+        let db = Db::open_in_memory().unwrap();
+        for seed in 0..4 {
+            let pdb_id = format!("{}abc", seed);
+            let fab = synthetic_fab(seed, 40);
+            let antigen: Vec<String> = synthetic_fab(seed + 10, 40).lines().map(|l| format!("{}A{}", &l[..21], &l[22..])).collect();
+            db.insert_raw(&pdb_id, "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+            db.put_structure(&pdb_id, &format!("{}\n{}", fab, antigen.join("\n")), StructureFormat::Pdb).unwrap();
+            let fab_id = db.get_antibody(&pdb_id).unwrap().unwrap().fab_id;
+            let fv = Pdb::from_str(&fab);
+            db.store_fv(fab_id, Some(&fv)).unwrap();
+            db.store_features(fab_id, &features::fab_features(&fv, 'H', 'L')).unwrap();
+        }
+        db.get_conn().execute("UPDATE antibodies SET processed = TRUE, passed_qc = TRUE", []).unwrap();
+        let options = MatchOptions { top_n: 10, annotate_deviation: true, ..Default::default() };
+        let from_features = scores(&db, &target, &options);
+
+        // Without features or structures the Fv is scored
+        db.get_conn().execute_batch("DELETE FROM features; UPDATE structures SET pdb_blob = NULL;").unwrap();
+        let from_fv = scores(&db, &target, &options);
+        assert_eq!(from_fv.len(), 4);
+        for ((id_f, score_f), (id_v, score_v)) in from_features.iter().zip(&from_fv) {
+            assert_eq!(id_f, id_v);
+            assert!((score_f - score_v).abs() < 1e-5, "{}: {} vs {}", id_f, score_f, score_v);
+        }
+        assert!(find_matches(&db, &target, &options).unwrap().iter().all(|m| m.worst_region.is_some()));
+    }
+
+    #[test]
+    fn test_blacklisted_never_match() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target.pdb");
+        // The target itself is stored as 1abc, the best match by far
+        std::fs::write(&target, synthetic_fab(1, 40)).unwrap();
+        let db = seeded_db(4, 40, true);
+        let options = MatchOptions { top_n: 10, ..Default::default() };
+        assert_eq!(scores(&db, &target, &options).len(), 4);
+        assert_eq!(find_matches(&db, &target, &options).unwrap()[0].pdb_id, "1abc");
+
+        db.blacklist("1abc", "chimeric construct").unwrap();
+        let ids: Vec<String> = scores(&db, &target, &options).into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, ["0abc", "2abc", "3abc"]);
+        let representatives = MatchOptions { unique_clones: true, ..options };
+        assert!(find_matches(&db, &target, &representatives).unwrap().iter().all(|m| m.pdb_id != "1abc"));
+    }
+
+    /// Scoring from stored features against parsing every structure:
+    /// `cargo test --release bench_features -- --ignored --nocapture`
+    /// About 0.53 s against 1.44 s for 1000 synthetic Fabs.
+    #[test]
+    #[ignore]
+    fn bench_features_vs_structures() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target.pdb");
+        std::fs::write(&target, synthetic_fab(10_000, 115)).unwrap();
+        let db = seeded_db(1000, 115, true);
+        let options = MatchOptions { prefilter_fraction: 1.0, ..Default::default() };
+
+        let start = std::time::Instant::now();
+        let from_features = scores(&db, &target, &options);
+        let features_time = start.elapsed();
+        db.get_conn().execute("DELETE FROM features", []).unwrap();
+        let start = std::time::Instant::now();
+        let from_structures = scores(&db, &target, &options);
+        let structures_time = start.elapsed();
+
+        println!("features: {:?}, structures: {:?} for 1000 Fabs", features_time, structures_time);
+        assert_eq!(from_features.len(), from_structures.len());
+    }
 }
@@ -1,120 +1,1952 @@
 use anyhow::{Result, bail};
-use log::{warn, debug};
-use std::io::Write;
+use log::{debug, info, warn};
+use parking_lot::{Condvar, Mutex};
+use crate::db::Db;
+use crate::pdb::{Pdb, ResidueId};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::cmp::Ordering;
+use std::fmt;
+use std::io::{Read, Write};
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 use tempfile::NamedTempFile;
 
+/// Scheme position: residue number plus optional insertion code ("100A").
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Position {
+    pub number: u32,
+    pub insertion: Option<char>,
+}
+
+impl Position {
+    pub fn new(number: u32, insertion: Option<char>) -> Self {
+        Self { number, insertion }
+    }
+
+    /// Order along the chain in `scheme`. That is the derived order, number
+    /// then insertion, except in IMGT, whose CDR3 insertions run up from 111
+    /// and then down to 112: 111, 111A, 111B, 112B, 112A, 112.
+    pub fn cmp_in(&self, other: &Self, scheme: Scheme) -> Ordering {
+        if scheme == Scheme::Imgt && self.number == IMGT_REVERSED && other.number == IMGT_REVERSED {
+            // No insertion comes last
+            return match (self.insertion, other.insertion) {
+                (Some(a), Some(b)) => b.cmp(&a),
+                (a, b) => a.is_none().cmp(&b.is_none()),
+            };
+        }
+        self.cmp(other)
+    }
+
+    /// Whether the position lies in `first..=last` in `scheme`, insertions
+    /// included: H100A is within H95-H102.
+    pub fn in_range(&self, first: Position, last: Position, scheme: Scheme) -> bool {
+        self.cmp_in(&first, scheme).is_ge() && self.cmp_in(&last, scheme).is_le()
+    }
+}
+
+/// IMGT position whose insertions are numbered backwards
+const IMGT_REVERSED: u32 = 112;
+
+impl FromStr for Position {
+    type Err = anyhow::Error;
+
+    /// Reads "100", "100A" and ANARCI's tuple form "(100, 'A')", where a
+    /// blank insertion code means none.
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if let Some(tuple) = s.strip_prefix('(').and_then(|t| t.strip_suffix(')')) {
+            let Some((number, insertion)) = tuple.split_once(',') else {
+                bail!("Invalid numbering position: {}", s);
+            };
+            let insertion = insertion.trim();
+            let insertion = insertion.strip_prefix('\'').and_then(|i| i.strip_suffix('\'')).unwrap_or(insertion);
+            return format!("{}{}", number.trim(), insertion.trim()).parse();
+        }
+        let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let number = s[..digits].parse()?;
+        let mut rest = s[digits..].trim().chars();
+        let insertion = rest.next();
+        if rest.next().is_some() || insertion.is_some_and(|c| !c.is_ascii_alphabetic()) {
+            bail!("Invalid numbering position: {}", s);
+        }
+        Ok(Self { number, insertion })
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.number)?;
+        if let Some(c) = self.insertion {
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ChainType {
+    Heavy,
+    Kappa,
+    Lambda,
+}
+
+/// Framework and CDR regions of a variable domain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Region {
+    Fr1,
+    Cdr1,
+    Fr2,
+    Cdr2,
+    Fr3,
+    Cdr3,
+    Fr4,
+}
+
+impl Region {
+    pub const ALL: [Region; 7] = [Region::Fr1, Region::Cdr1, Region::Fr2, Region::Cdr2, Region::Fr3, Region::Cdr3, Region::Fr4];
+
+    /// Region of a position numbered in `scheme`, the CDRs as `cdr_ranges`
+    /// defines them and the frameworks in between.
+    pub fn of(position: Position, scheme: Scheme, chain: ChainType) -> Region {
+        let [cdr1, cdr2, cdr3] = cdr_ranges(scheme, chain);
+        let before = |cdr: &RangeInclusive<Position>| position.cmp_in(cdr.start(), scheme).is_lt();
+        let within = |cdr: &RangeInclusive<Position>| position.cmp_in(cdr.end(), scheme).is_le();
+        if before(&cdr1) { Region::Fr1 }
+        else if within(&cdr1) { Region::Cdr1 }
+        else if before(&cdr2) { Region::Fr2 }
+        else if within(&cdr2) { Region::Cdr2 }
+        else if before(&cdr3) { Region::Fr3 }
+        else if within(&cdr3) { Region::Cdr3 }
+        else { Region::Fr4 }
+    }
+
+    /// Region of a Martin-numbered position, using the Chothia CDR
+    /// boundaries (H26-H32, H52-H56, H95-H102; L24-L34, L50-L56, L89-L97).
+    pub fn martin(position: Position, chain: ChainType) -> Region {
+        Region::of(position, Scheme::Martin, chain)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Region::Fr1 => "fr1",
+            Region::Cdr1 => "cdr1",
+            Region::Fr2 => "fr2",
+            Region::Cdr2 => "cdr2",
+            Region::Fr3 => "fr3",
+            Region::Cdr3 => "cdr3",
+            Region::Fr4 => "fr4",
+        }
+    }
+
+    pub fn is_cdr(&self) -> bool {
+        matches!(self, Region::Cdr1 | Region::Cdr2 | Region::Cdr3)
+    }
+}
+
+/// A residue at its scheme position.
+pub type NumberedResidue = (Position, char);
+
+/// A numbered chain: scheme positions with their residues, in chain order.
+pub type Numbered = Vec<NumberedResidue>;
+
+/// Numbering scheme of a `NumberingResult`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Scheme {
+    Martin,
+    Kabat,
+    Chothia,
+    Imgt,
+}
+
+impl Scheme {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Martin => "martin",
+            Self::Kabat => "kabat",
+            Self::Chothia => "chothia",
+            Self::Imgt => "imgt",
+        }
+    }
+}
+
+impl FromStr for Scheme {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        [Self::Martin, Self::Kabat, Self::Chothia, Self::Imgt].into_iter()
+            .find(|scheme| scheme.as_str() == s)
+            .ok_or_else(|| anyhow::anyhow!("Unknown numbering scheme: {}", s))
+    }
+}
+
+/// Scheme the chains are numbered in.
+pub const SCHEME: &str = Scheme::Martin.as_str();
+
+/// A variable domain as the numbering found it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NumberingResult {
+    /// Type the domain was numbered as, whatever the chain was annotated as
+    pub chain_type: ChainType,
+    /// Alignment score of the numbering tool; 0 for strategies without one
+    pub score: f64,
+    pub scheme: Scheme,
+    pub positions: Numbered,
+    /// First and last residue of the domain in the numbered sequence,
+    /// 0-based and inclusive, as ANARCI reports them
+    pub query_start: usize,
+    pub query_end: usize,
+    /// Region of each of `positions`
+    #[serde(default)]
+    pub regions: Vec<Region>,
+}
+
+impl NumberingResult {
+    pub fn new(chain_type: ChainType, score: f64, scheme: Scheme, positions: Numbered, query_start: usize, query_end: usize) -> Self {
+        let mut result = Self { chain_type, score, scheme, positions, query_start, query_end, regions: Vec::new() };
+        result.fill_regions();
+        result
+    }
+
+    // Sets `regions` from the positions, e.g. for results stored before
+    // they were kept
+    pub(crate) fn fill_regions(&mut self) {
+        self.regions = self.positions.iter().map(|&(position, _)| Region::of(position, self.scheme, self.chain_type)).collect();
+    }
+
+    /// Residues of the domain in `region`.
+    pub fn region_sequence(&self, region: Region) -> String {
+        self.positions.iter().zip(&self.regions).filter(|&(_, &r)| r == region).map(|(&(_, residue), _)| residue).collect()
+    }
+
+    /// Checks that the numbered residues are `sequence` from `query_start`
+    /// to `query_end`, one by one, so positions map back onto residues.
+    pub fn check_sequence(&self, sequence: &str) -> std::result::Result<(), NumberingError> {
+        let expected = sequence.chars().skip(self.query_start).take((self.query_end + 1).saturating_sub(self.query_start));
+        let mut expected = expected.map(Some).chain(std::iter::repeat(None));
+        let mut got = self.positions.iter().map(|&(_, residue)| Some(residue)).chain(std::iter::repeat(None));
+        for position in self.query_start.. {
+            match (expected.next().flatten(), got.next().flatten()) {
+                (None, None) if position > self.query_end => return Ok(()),
+                (e, g) if e == g && e.is_some() => {}
+                (e, g) => return Err(NumberingError::SequenceMismatch { position, expected: e.unwrap_or('-'), got: g.unwrap_or('-') }),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Numbering output to positions and residues, dropping malformed pairs.
+pub fn parse_numbered(pairs: &[(String, String)]) -> Numbered {
+    pairs.iter()
+        .filter_map(|(pos, aa)| Some((pos.parse().ok()?, aa.chars().next()?)))
+        .collect()
+}
+
+/// CDR1-3 of a chain numbered in `scheme`, each scheme with its own
+/// definition: Kabat's by sequence variability, Chothia's by structural
+/// loops (which Martin numbering keeps), IMGT's fixed windows.
+pub fn cdr_ranges(scheme: Scheme, chain: ChainType) -> [RangeInclusive<Position>; 3] {
+    let p = |number: u32| Position::new(number, None);
+    let heavy = chain == ChainType::Heavy;
+    match scheme {
+        Scheme::Kabat if heavy => [p(31)..=Position::new(35, Some('B')), p(50)..=p(65), p(95)..=p(102)],
+        Scheme::Chothia | Scheme::Martin if heavy => [p(26)..=p(32), p(52)..=p(56), p(95)..=p(102)],
+        Scheme::Kabat | Scheme::Chothia | Scheme::Martin => [p(24)..=p(34), p(50)..=p(56), p(89)..=p(97)],
+        Scheme::Imgt => [p(27)..=p(38), p(56)..=p(65), p(105)..=p(117)],
+    }
+}
+
+/// Martin positions of CDR-H3 through the conserved W103 closing it, the
+/// residues `analysis::loop_descriptors` describes.
+pub fn h3_loop_range() -> RangeInclusive<Position> {
+    let h3 = cdr_ranges(Scheme::Martin, ChainType::Heavy)[2].clone();
+    *h3.start()..=Position::new(103, None)
+}
+
+/// Residues of the CDRs of a chain.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Cdrs {
+    pub cdr1: String,
+    pub cdr2: String,
+    pub cdr3: String,
+}
+
+impl Cdrs {
+    /// Residues of the CDRs of a chain numbered in `scheme`, by `cdr_ranges`.
+    pub fn of(numbered: &[NumberedResidue], scheme: Scheme, chain: ChainType) -> Self {
+        let ranges = cdr_ranges(scheme, chain);
+        let mut cdrs: [String; 3] = Default::default();
+        for &(position, residue) in numbered {
+            if let Some(i) = ranges.iter().position(|r| position.in_range(*r.start(), *r.end(), scheme)) {
+                cdrs[i].push(residue);
+            }
+        }
+        let [cdr1, cdr2, cdr3] = cdrs;
+        Self { cdr1, cdr2, cdr3 }
+    }
+
+    pub fn into_array(self) -> [String; 3] {
+        [self.cdr1, self.cdr2, self.cdr3]
+    }
+}
+
+/// The CDRs of a numbered domain, by the definition of its scheme.
+pub fn extract_cdrs(result: &NumberingResult) -> Cdrs {
+    Cdrs::of(&result.positions, result.scheme, result.chain_type)
+}
+
+/// Residues of CDR1-3 of a Martin-numbered chain.
+pub fn cdr_sequences(numbered: &[(Position, char)], chain: ChainType) -> [String; 3] {
+    Cdrs::of(numbered, Scheme::Martin, chain).into_array()
+}
+
+/// Kappa or lambda from a Martin-numbered light chain: lambda chains have no
+/// residue at L10. None when the numbering does not cover L9-L11.
+pub fn infer_light_type(positions: &[Position]) -> Option<ChainType> {
+    let has = |n: u32| positions.iter().any(|p| p.number == n);
+    if !(has(9) && has(11)) {
+        return None;
+    }
+    Some(if has(10) { ChainType::Kappa } else { ChainType::Lambda })
+}
+
+/// A residue of a structure chain and where the numbering put it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainResidue {
+    pub id: ResidueId,
+    pub residue: char,
+    /// None outside the variable domains
+    pub position: Option<Position>,
+    pub region: Option<Region>,
+}
+
+/// Numbering of a structure chain: every residue of it in chain order,
+/// mapped through its index in the numbered sequence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainNumbering {
+    /// Type of the first domain
+    pub chain_type: ChainType,
+    pub scheme: Scheme,
+    pub residues: Vec<ChainResidue>,
+}
+
+impl ChainNumbering {
+    /// Maps `domains`, numbered from the codes of `sequence`, onto its
+    /// residues by their query offsets. Errs without domains or if one
+    /// does not match the sequence.
+    pub fn map(sequence: &[(ResidueId, char)], domains: &[NumberingResult]) -> Result<Self> {
+        let Some(first) = domains.first() else { return Err(NumberingError::NoDomainDetected.into()) };
+        let codes: String = sequence.iter().map(|&(_, code)| code).collect();
+        let mut residues: Vec<ChainResidue> = sequence.iter()
+            .map(|&(id, residue)| ChainResidue { id, residue, position: None, region: None })
+            .collect();
+        for domain in domains {
+            domain.check_sequence(&codes)?;
+            for (k, (&(position, _), &region)) in domain.positions.iter().zip(&domain.regions).enumerate() {
+                let residue = &mut residues[domain.query_start + k];
+                residue.position = Some(position);
+                residue.region = Some(region);
+            }
+        }
+        Ok(Self { chain_type: first.chain_type, scheme: first.scheme, residues })
+    }
+
+    /// The numbered residues with their positions, in chain order.
+    pub fn numbered(&self) -> Numbered {
+        self.residues.iter().filter_map(|r| Some((r.position?, r.residue))).collect()
+    }
+
+    /// The residues numbered within `h3_loop_range`.
+    pub fn h3_loop_residues(&self) -> HashSet<ResidueId> {
+        let range = h3_loop_range();
+        self.residues.iter()
+            .filter(|r| r.position.is_some_and(|p| p.in_range(*range.start(), *range.end(), Scheme::Martin)))
+            .map(|r| r.id)
+            .collect()
+    }
+}
+
+/// Numbers a chain of `pdb` in `scheme`, keeping which residue each
+/// position belongs to.
+pub fn number_chain(pdb: &Pdb, chain_id: char, strategy: &dyn NumberingStrategy, scheme: Scheme) -> Result<ChainNumbering> {
+    let sequence = pdb.numbered_sequence(chain_id);
+    if sequence.is_empty() {
+        bail!("No chain {}", chain_id);
+    }
+    let codes: String = sequence.iter().map(|&(_, code)| code).collect();
+    let domains = strategy.number(&codes, "antibody")?;
+    if let Some(domain) = domains.iter().find(|d| d.scheme != scheme) {
+        bail!("Numbered in {}, not {}", domain.scheme.as_str(), scheme.as_str());
+    }
+    ChainNumbering::map(&sequence, &domains)
+}
+
+/// Numbering of one sequence, or why it failed.
+pub type NumberingOutcome = std::result::Result<Vec<NumberingResult>, NumberingError>;
+
 pub trait NumberingStrategy {
-    fn number(&self, sequence: &str, chain_type: &str) -> Result<Vec<(String, String)>>; // (Number, Residue)
+    /// The variable domains of a sequence in sequence order, one for most
+    /// chains, two for single-chain constructs like scFvs. Errs if there is
+    /// none.
+    fn number(&self, sequence: &str, chain_type: &str) -> NumberingOutcome;
+
+    /// Numbers (name, sequence) pairs, one outcome per pair in their order.
+    /// Errs only if the batch as a whole could not be run.
+    fn number_batch(&self, sequences: &[(String, String)]) -> std::result::Result<Vec<NumberingOutcome>, NumberingError> {
+        Ok(sequences.iter().map(|(_, sequence)| self.number(sequence, "antibody")).collect())
+    }
+
+    /// Whether positions may be off by a residue or so, as for
+    /// `HeuristicStrategy`
+    fn is_approximate(&self) -> bool {
+        false
+    }
+
+    /// Whether numbering runs an external program, which `LimitedStrategy`
+    /// keeps from running too many times at once
+    fn runs_subprocess(&self) -> bool {
+        false
+    }
 }
 
-pub struct AnarciStrategy;
+impl<S: NumberingStrategy + ?Sized> NumberingStrategy for Box<S> {
+    fn number(&self, sequence: &str, chain_type: &str) -> NumberingOutcome {
+        (**self).number(sequence, chain_type)
+    }
 
-impl AnarciStrategy {
-    pub fn new() -> Self {
-        Self
+    fn number_batch(&self, sequences: &[(String, String)]) -> std::result::Result<Vec<NumberingOutcome>, NumberingError> {
+        (**self).number_batch(sequences)
+    }
+
+    fn is_approximate(&self) -> bool {
+        (**self).is_approximate()
+    }
+
+    fn runs_subprocess(&self) -> bool {
+        (**self).runs_subprocess()
+    }
+}
+
+impl<S: NumberingStrategy + ?Sized> NumberingStrategy for &S {
+    fn number(&self, sequence: &str, chain_type: &str) -> NumberingOutcome {
+        (**self).number(sequence, chain_type)
+    }
+
+    fn number_batch(&self, sequences: &[(String, String)]) -> std::result::Result<Vec<NumberingOutcome>, NumberingError> {
+        (**self).number_batch(sequences)
+    }
+
+    fn is_approximate(&self) -> bool {
+        (**self).is_approximate()
+    }
+
+    fn runs_subprocess(&self) -> bool {
+        (**self).runs_subprocess()
+    }
+}
+
+/// Numbering tool runs allowed at once unless configured otherwise: one per
+/// core up to 4, as each loads its own model
+pub fn default_concurrency() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get()).min(4)
+}
+
+// Counting semaphore
+struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+// A unit taken from a `Semaphore`, given back on drop
+struct Permit<'a>(&'a Semaphore);
+
+impl Semaphore {
+    fn new(permits: usize) -> Self {
+        Self { available: Mutex::new(permits), released: Condvar::new() }
     }
 
-    fn find_binary() -> PathBuf {
-        // Check local venv first
-        let venv_bin = Path::new(".venv/bin/anarcii");
-        if venv_bin.exists() {
-            return venv_bin.to_path_buf();
+    fn acquire(&self) -> Permit<'_> {
+        let mut available = self.available.lock();
+        while *available == 0 {
+            self.released.wait(&mut available);
         }
-        
-        // Also check if we are inside a directory that has .venv (e.g. running from root)
-        // This is a bit hacky, normally we expect correct env.
-        if let Ok(cwd) = std::env::current_dir() {
-            let venv_full = cwd.join(".venv/bin/anarcii");
-            if venv_full.exists() {
-                return venv_full;
+        *available -= 1;
+        Permit(self)
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.0.available.lock() += 1;
+        self.0.released.notify_one();
+    }
+}
+
+/// Bounds how many single-sequence `number` calls run `inner` at once when
+/// it runs an external program, so parallel callers do not start a process
+/// per core. Strategies numbering in-process are not limited, and neither
+/// is `number_batch`, a single run however many sequences it holds.
+pub struct LimitedStrategy<S: NumberingStrategy> {
+    inner: S,
+    permits: Semaphore,
+}
+
+impl<S: NumberingStrategy> LimitedStrategy<S> {
+    /// `limit` concurrent runs, at least one.
+    pub fn new(inner: S, limit: usize) -> Self {
+        Self { inner, permits: Semaphore::new(limit.max(1)) }
+    }
+}
+
+impl<S: NumberingStrategy> NumberingStrategy for LimitedStrategy<S> {
+    fn number(&self, sequence: &str, chain_type: &str) -> NumberingOutcome {
+        let _permit = self.inner.runs_subprocess().then(|| self.permits.acquire());
+        self.inner.number(sequence, chain_type)
+    }
+
+    fn number_batch(&self, sequences: &[(String, String)]) -> std::result::Result<Vec<NumberingOutcome>, NumberingError> {
+        self.inner.number_batch(sequences)
+    }
+
+    fn is_approximate(&self) -> bool {
+        self.inner.is_approximate()
+    }
+
+    fn runs_subprocess(&self) -> bool {
+        self.inner.runs_subprocess()
+    }
+}
+
+/// Cache key of a sequence numbered in `scheme`: hex SHA-256 of both.
+pub fn cache_key(sequence: &str, scheme: Scheme) -> String {
+    crate::db::content_hash(format!("{}\n{}", scheme.as_str(), sequence).as_bytes())
+}
+
+/// Looks sequences up in the database's numbering cache before handing them
+/// to `inner`, and caches what it numbers. Approximate numbering is not
+/// cached, so it is redone once ANARCI is installed; nor is it written where
+/// the database is read-only.
+pub struct CachedStrategy<'a, S: NumberingStrategy> {
+    inner: S,
+    db: &'a Db,
+}
+
+impl<'a, S: NumberingStrategy> CachedStrategy<'a, S> {
+    pub fn new(inner: S, db: &'a Db) -> Self {
+        Self { inner, db }
+    }
+
+    fn cached(&self, key: &str) -> Option<Vec<NumberingResult>> {
+        self.db.cached_numbering(key).inspect_err(|e| warn!("Could not read the numbering cache: {}", e)).ok().flatten()
+    }
+}
+
+impl<S: NumberingStrategy> NumberingStrategy for CachedStrategy<'_, S> {
+    fn number(&self, sequence: &str, _chain_type: &str) -> NumberingOutcome {
+        self.number_batch(&[("seq".to_string(), sequence.to_string())])?.pop().unwrap_or_else(|| Err(NumberingError::OutputParseError("no outcome for the sequence".to_string())))
+    }
+
+    // Only the sequences missing from the cache go to `inner`, each once
+    fn number_batch(&self, sequences: &[(String, String)]) -> std::result::Result<Vec<NumberingOutcome>, NumberingError> {
+        let keys: Vec<String> = sequences.iter().map(|(_, sequence)| cache_key(sequence, Scheme::Martin)).collect();
+        let mut found: HashMap<&str, Vec<NumberingResult>> = HashMap::new();
+        let mut missing = Vec::new();
+        for (key, (name, sequence)) in keys.iter().zip(sequences) {
+            if found.contains_key(key.as_str()) || missing.iter().any(|(k, _)| k == key) {
+                continue;
+            }
+            match self.cached(key) {
+                Some(result) => {
+                    found.insert(key, result);
+                }
+                None => missing.push((key.clone(), (name.clone(), sequence.clone()))),
+            }
+        }
+
+        let (missing_keys, missing): (Vec<String>, Vec<(String, String)>) = missing.into_iter().unzip();
+        let mut numbered: HashMap<&str, NumberingOutcome> = HashMap::new();
+        if !missing.is_empty() {
+            for (key, outcome) in missing_keys.iter().zip(self.inner.number_batch(&missing)?) {
+                if let Ok(result) = &outcome
+                    && !self.inner.is_approximate()
+                    && let Err(e) = self.db.cache_numbering(key, result)
+                {
+                    debug!("Could not cache the numbering: {}", e);
+                }
+                numbered.insert(key, outcome);
+            }
+        }
+        Ok(keys.iter().map(|key| match found.get(key.as_str()) {
+            Some(result) => Ok(result.clone()),
+            None => numbered[key.as_str()].clone(),
+        }).collect())
+    }
+
+    fn is_approximate(&self) -> bool {
+        self.inner.is_approximate()
+    }
+
+    fn runs_subprocess(&self) -> bool {
+        self.inner.runs_subprocess()
+    }
+}
+
+/// How long probing a numbering tool with `--help` may take
+const PROBE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Numbering backend settled on by `detected_backend`: a numbering tool, or
+/// `HeuristicStrategy` if none runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backend(Option<(AnarciTool, AnarciCommand)>);
+
+impl Backend {
+    pub fn strategy(&self) -> Box<dyn NumberingStrategy + Sync> {
+        match &self.0 {
+            Some((tool, command)) => {
+                Box::new(AnarciStrategy { tool: *tool, command: Some(command.clone()), ..AnarciStrategy::new() })
+            }
+            None => Box::new(HeuristicStrategy),
+        }
+    }
+
+    pub fn is_approximate(&self) -> bool {
+        self.0.is_none()
+    }
+
+    /// The tool with what its `--version` prints, for diagnostics; probed
+    /// anew on each call.
+    pub fn version(&self) -> String {
+        match &self.0 {
+            Some((tool, command)) => match tool_version(command, PROBE_TIMEOUT) {
+                Some(version) => format!("{} {}", tool.name(), version),
+                None => format!("{}, version unknown", tool.name()),
+            },
+            None => "heuristic".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Some((tool, command)) => write!(f, "{} ({})", tool.name(), command),
+            None => write!(f, "heuristic, approximate"),
+        }
+    }
+}
+
+// Failure to set up the temp files of a run
+fn temp_file_error(e: std::io::Error) -> NumberingError {
+    NumberingError::ExecutionFailed { stderr: format!("Could not create the numbering tool's files: {}", e) }
+}
+
+fn output_error(e: impl fmt::Display) -> NumberingError {
+    NumberingError::OutputParseError(e.to_string())
+}
+
+// The first line `command --version` prints if it succeeds within `timeout`
+fn tool_version(command: &AnarciCommand, timeout: Duration) -> Option<String> {
+    let mut child = Command::new(&command.program)
+        .args(&command.prefix_args)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .ok()?;
+    let (mut stdout, mut stderr) = (child.stdout.take()?, child.stderr.take()?);
+    // A line or two, well within what the pipes hold while waiting
+    if !wait_with_timeout(child, timeout).ok()??.success() {
+        return None;
+    }
+    let mut output = String::new();
+    stdout.read_to_string(&mut output).ok()?;
+    stderr.read_to_string(&mut output).ok()?;
+    output.lines().map(str::trim).find(|line| !line.is_empty()).map(str::to_string)
+}
+
+// Whether `command --help` succeeds within `timeout`
+fn probe(command: &AnarciCommand, timeout: Duration) -> bool {
+    let child = Command::new(&command.program)
+        .args(&command.prefix_args)
+        .arg("--help")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    matches!(child.map(|child| wait_with_timeout(child, timeout)), Ok(Ok(Some(status))) if status.success())
+}
+
+// The first candidate answering `--help` within `timeout`: the
+// `ANARCI_CMD_ENV` value `env_command`, anarcii in the venv of the working
+// directory or on `path`, classic ANARCI on `path`. A configured command
+// named ANARCI is taken for the classic tool.
+fn detect_backend(env_command: Option<&str>, path: Option<&OsStr>, timeout: Duration) -> Backend {
+    let mut candidates = Vec::new();
+    if let Some(command) = env_command.and_then(parse_command) {
+        let tool = if command.program.file_name() == Some(OsStr::new("ANARCI")) { AnarciTool::Anarci } else { AnarciTool::Anarcii };
+        match executable(&command.program, path) {
+            Some(program) => candidates.push((tool, AnarciCommand { program, ..command })),
+            None => warn!("{} from {} is not executable, ignoring it", command, ANARCI_CMD_ENV),
+        }
+    }
+    let venv = std::env::current_dir().unwrap_or_default().join(".venv/bin/anarcii");
+    let found = [
+        (AnarciTool::Anarcii, venv.is_file().then_some(venv)),
+        (AnarciTool::Anarcii, executable(Path::new("anarcii"), path)),
+        (AnarciTool::Anarci, executable(Path::new("ANARCI"), path)),
+    ];
+    candidates.extend(found.into_iter().filter_map(|(tool, program)| Some((tool, AnarciCommand { program: program?, prefix_args: Vec::new() }))));
+
+    let mut failed = Vec::new();
+    for (tool, command) in candidates {
+        if probe(&command, timeout) {
+            return Backend(Some((tool, command)));
+        }
+        debug!("{} does not answer --help, skipping it", command);
+        failed.push(command.to_string());
+    }
+    if failed.is_empty() {
+        warn!(
+            "No numbering tool found. Install ANARCII into .venv (pip install anarcii), put anarcii or ANARCI on the PATH, or set {} to the command running one, e.g. {}=\"python -m anarcii\"",
+            ANARCI_CMD_ENV,
+            ANARCI_CMD_ENV
+        );
+    } else {
+        warn!("No numbering tool runs, {} failed `--help`", failed.join(", "));
+    }
+    Backend(None)
+}
+
+static DETECTED: OnceLock<Backend> = OnceLock::new();
+
+/// The numbering backend of this process, probed on first use: the
+/// `ANARCI_CMD_ENV` command, ANARCII, classic ANARCI, then the heuristic.
+pub fn detected_backend() -> &'static Backend {
+    DETECTED.get_or_init(|| {
+        let backend = detect_backend(std::env::var(ANARCI_CMD_ENV).ok().as_deref(), std::env::var_os("PATH").as_deref(), PROBE_TIMEOUT);
+        match backend.is_approximate() {
+            true => warn!("Numbering approximately from conserved framework residues"),
+            false => info!("Numbering with {}", backend),
+        }
+        backend
+    })
+}
+
+/// A strategy for the `detected_backend`.
+pub fn detect() -> Box<dyn NumberingStrategy + Sync> {
+    detected_backend().strategy()
+}
+
+/// Environment variable with the ANARCII timeout in seconds
+pub const ANARCI_TIMEOUT_ENV: &str = "ANARCI_TIMEOUT";
+
+/// How long one ANARCII run may take unless configured otherwise
+pub const DEFAULT_ANARCI_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Why numbering failed. The tool missing concerns every sequence, the
+/// tool failing or timing out a run, the rest a single sequence.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NumberingError {
+    /// No numbering tool to run, with what was tried
+    BinaryNotFound(String),
+    /// The tool could not be run or exited with an error
+    ExecutionFailed { stderr: String },
+    /// The numbering tool ran longer than this and was killed
+    Timeout(Duration),
+    /// Nothing in the sequence numbers as an antibody variable domain
+    NoDomainDetected,
+    /// The tool's output could not be read
+    OutputParseError(String),
+    /// The numbered residues differ from the input at this 0-based index of
+    /// it; '-' stands for a residue on one side only
+    SequenceMismatch { position: usize, expected: char, got: char },
+}
+
+impl fmt::Display for NumberingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BinaryNotFound(tried) => write!(f, "{}", tried),
+            Self::ExecutionFailed { stderr } => write!(f, "Numbering tool failed: {}", stderr.trim()),
+            Self::Timeout(timeout) => write!(f, "Numbering timed out after {:.1} s", timeout.as_secs_f64()),
+            Self::NoDomainDetected => write!(f, "No antibody variable domain found"),
+            Self::OutputParseError(e) => write!(f, "Unreadable numbering output: {}", e),
+            Self::SequenceMismatch { position, expected, got } => {
+                write!(f, "Numbered residue {} is {}, the sequence has {}", position + 1, got, expected)
             }
         }
+    }
+}
+
+impl std::error::Error for NumberingError {}
+
+/// Environment variable with the command running ANARCII, split on
+/// whitespace, e.g. "python -m anarcii"
+pub const ANARCI_CMD_ENV: &str = "ANARCI_CMD";
+
+// Program and leading arguments running ANARCII
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AnarciCommand {
+    program: PathBuf,
+    prefix_args: Vec<String>,
+}
+
+impl fmt::Display for AnarciCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.program.display())?;
+        self.prefix_args.iter().try_for_each(|arg| write!(f, " {}", arg))
+    }
+}
+
+// Command-line numbering tools: ANARCII, which reads a FASTA and writes one
+// CSV, and classic ANARCI, which writes a CSV per kind of chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AnarciTool {
+    Anarcii,
+    Anarci,
+}
+
+impl AnarciTool {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Anarcii => "ANARCII",
+            Self::Anarci => "ANARCI",
+        }
+    }
+}
+
+// A command line split on whitespace; None if it is blank
+fn parse_command(line: &str) -> Option<AnarciCommand> {
+    let mut words = line.split_whitespace();
+    let program = PathBuf::from(words.next()?);
+    Some(AnarciCommand { program, prefix_args: words.map(str::to_string).collect() })
+}
+
+// `program` if it is an executable file, or looked up in the `path`
+// directories when it is a bare name
+fn executable(program: &Path, path: Option<&OsStr>) -> Option<PathBuf> {
+    if program.components().count() > 1 {
+        return program.is_file().then(|| program.to_path_buf());
+    }
+    std::env::split_paths(path?).map(|dir| dir.join(program)).find(|p| p.is_file())
+}
+
+// The command to run: `explicit` if given, else the `ANARCI_CMD_ENV` value
+// `env_command`, else anarcii in the venv of the working directory or on
+// `path`. The error lists what was tried and how to fix it.
+fn resolve_command(explicit: Option<&AnarciCommand>, env_command: Option<&str>, path: Option<&OsStr>) -> std::result::Result<AnarciCommand, NumberingError> {
+    let configured = match explicit {
+        Some(command) => Some((command.clone(), "given to AnarciStrategy::with_command".to_string())),
+        None => env_command.and_then(parse_command).map(|command| (command, format!("from {}", ANARCI_CMD_ENV))),
+    };
+    if let Some((command, source)) = configured {
+        let Some(program) = executable(&command.program, path) else {
+            return Err(NumberingError::BinaryNotFound(format!(
+                "ANARCII command {:?} ({}) is not executable: {} is neither a file nor on the PATH",
+                command.to_string(),
+                source,
+                command.program.display()
+            )));
+        };
+        return Ok(AnarciCommand { program, ..command });
+    }
+
+    let mut tried = Vec::new();
+    let venv = std::env::current_dir().unwrap_or_default().join(".venv/bin/anarcii");
+    if venv.is_file() {
+        return Ok(AnarciCommand { program: venv, prefix_args: Vec::new() });
+    }
+    tried.push(venv.display().to_string());
+    if let Some(program) = executable(Path::new("anarcii"), path) {
+        return Ok(AnarciCommand { program, prefix_args: Vec::new() });
+    }
+    tried.extend(std::env::split_paths(path.unwrap_or_default()).map(|dir| dir.join("anarcii").display().to_string()));
+    Err(NumberingError::BinaryNotFound(format!(
+        "ANARCII not found, tried:\n  {}\nInstall it into .venv (pip install anarcii), put it on the PATH, or set {} to the command running it, e.g. {}=\"python -m anarcii\"",
+        tried.join("\n  "),
+        ANARCI_CMD_ENV,
+        ANARCI_CMD_ENV
+    )))
+}
+
+pub struct AnarciStrategy {
+    timeout: Duration,
+    tool: AnarciTool,
+    /// Overrides `ANARCI_CMD_ENV` and the lookup
+    command: Option<AnarciCommand>,
+    /// Whether the tool numbers through stdin and stdout, known after the
+    /// first run
+    stdio: OnceLock<bool>,
+}
+
+impl Default for AnarciStrategy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AnarciStrategy {
+    /// Runs time out after `ANARCI_TIMEOUT_ENV` seconds if set, else
+    /// `DEFAULT_ANARCI_TIMEOUT`. ANARCII is run as `ANARCI_CMD_ENV` says, or
+    /// found in `.venv` or on the PATH.
+    pub fn new() -> Self {
+        let timeout = std::env::var(ANARCI_TIMEOUT_ENV).ok().and_then(|s| {
+            let timeout = s.trim().parse::<f64>().ok().filter(|&t| t > 0.0).and_then(|t| Duration::try_from_secs_f64(t).ok());
+            if timeout.is_none() {
+                warn!("Ignoring {}={:?}, not a number of seconds", ANARCI_TIMEOUT_ENV, s);
+            }
+            timeout
+        });
+        Self { timeout: timeout.unwrap_or(DEFAULT_ANARCI_TIMEOUT), tool: AnarciTool::Anarcii, command: None, stdio: OnceLock::new() }
+    }
+
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self { timeout, ..Self::new() }
+    }
+
+    /// Runs `program` with `prefix_args` before the usual arguments, e.g.
+    /// `python` with `-m anarcii`. A bare name is looked up on the PATH.
+    pub fn with_command(program: PathBuf, prefix_args: Vec<String>) -> Self {
+        Self { command: Some(AnarciCommand { program, prefix_args }), ..Self::new() }
+    }
+
+    /// Runs classic ANARCI as `program` with `prefix_args`.
+    pub fn with_classic_command(program: PathBuf, prefix_args: Vec<String>) -> Self {
+        Self { tool: AnarciTool::Anarci, ..Self::with_command(program, prefix_args) }
+    }
+
+    // The command to run, or why there is none
+    fn resolve(&self) -> std::result::Result<AnarciCommand, NumberingError> {
+        resolve_command(self.command.as_ref(), std::env::var(ANARCI_CMD_ENV).ok().as_deref(), std::env::var_os("PATH").as_deref())
+    }
+
+    /// Whether ANARCII can be run.
+    pub fn is_available(&self) -> bool {
+        self.resolve().is_ok()
+    }
+}
+
+// Names of the metadata columns across ANARCII and classic ANARCI versions,
+// compared without case, spaces, underscores and dashes
+const NAME_COLUMNS: [&str; 2] = ["name", "id"];
+const CHAIN_COLUMNS: [&str; 2] = ["chain", "chaintype"];
+const SCORE_COLUMNS: [&str; 3] = ["score", "conf", "confidence"];
+const START_COLUMNS: [&str; 2] = ["querystart", "seqstartindex"];
+const END_COLUMNS: [&str; 2] = ["queryend", "seqendindex"];
 
-        // Default to system PATH
-        PathBuf::from("anarcii")
+fn normalize_header(header: &str) -> String {
+    header.chars().filter(|c| !matches!(c, ' ' | '_' | '-')).flat_map(char::to_lowercase).collect()
+}
+
+// Numbered domains per record name of ANARCII's CSV output, one per row
+// that numbers any residues as a known chain type, in sequence order.
+// ANARCII writes Name,Chain,Score,Query start,Query end and classic ANARCI
+// Id,chain_type,score,seqstart_index,seqend_index among other columns; the
+// metadata columns are found by name wherever they are, and every other
+// column named by a position holds the residue at it. Errs with the header
+// if that finds no name, chain, query span or positions.
+fn parse_anarcii_csv(content: &str) -> std::result::Result<HashMap<String, Vec<NumberingResult>>, NumberingError> {
+    let mut reader = csv::Reader::from_reader(content.as_bytes());
+    let headers = reader.headers().map_err(output_error)?.clone();
+    let normalized: Vec<String> = headers.iter().map(normalize_header).collect();
+    let column = |names: &[&str]| normalized.iter().position(|h| names.contains(&h.as_str()));
+    let (name_column, chain_column, score_column) = (column(&NAME_COLUMNS), column(&CHAIN_COLUMNS), column(&SCORE_COLUMNS));
+    let (start_column, end_column) = (column(&START_COLUMNS), column(&END_COLUMNS));
+    let metadata = [name_column, chain_column, score_column, start_column, end_column];
+    let header_positions: Vec<Option<Position>> = headers.iter().enumerate()
+        .map(|(i, h)| if metadata.contains(&Some(i)) { None } else { h.parse().ok() })
+        .collect();
+    let missing: Vec<&str> = [("name", name_column), ("chain", chain_column), ("query start", start_column), ("query end", end_column)]
+        .into_iter()
+        .filter_map(|(what, column)| column.is_none().then_some(what))
+        .chain(header_positions.iter().all(Option::is_none).then_some("position"))
+        .collect();
+    let (Some(name_column), Some(chain_column), Some(start_column), Some(end_column), []) = (name_column, chain_column, start_column, end_column, missing.as_slice()) else {
+        return Err(NumberingError::OutputParseError(format!(
+            "no {} columns in the CSV header, is the numbering tool a version this does not read? Header: {}",
+            missing.join(", "),
+            headers.iter().collect::<Vec<_>>().join(",")
+        )));
+    };
+    let mut numbered: HashMap<String, Vec<NumberingResult>> = HashMap::new();
+    for record in reader.records() {
+        let record = record.map_err(output_error)?;
+        let name = record.get(name_column).unwrap_or_default();
+        let chain_type = match record.get(chain_column).unwrap_or_default().trim() {
+            "H" => ChainType::Heavy,
+            "K" => ChainType::Kappa,
+            "L" => ChainType::Lambda,
+            _ => continue,
+        };
+        let positions: Numbered = record.iter().enumerate()
+            .filter(|(_, field)| !field.is_empty() && *field != "-")
+            .filter_map(|(i, field)| Some((header_positions.get(i).copied().flatten()?, field.chars().next()?)))
+            .collect();
+        if !positions.is_empty() {
+            let value = |column: usize| record.get(column).and_then(|s| s.trim().parse().ok());
+            numbered.entry(name.to_string()).or_default().push(NumberingResult::new(
+                chain_type,
+                score_column.and_then(value).unwrap_or_default(),
+                Scheme::Martin,
+                positions,
+                value(start_column).map_or(0, |v: f64| v as usize),
+                value(end_column).map_or(0, |v: f64| v as usize),
+            ));
+        }
     }
+    for domains in numbered.values_mut() {
+        domains.sort_by_key(|d| d.query_start);
+    }
+    Ok(numbered)
 }
 
 impl NumberingStrategy for AnarciStrategy {
-    fn number(&self, sequence: &str, _chain_type: &str) -> Result<Vec<(String, String)>> {
-        // Create temp fasta
-        let mut input_file = NamedTempFile::new()?;
-        writeln!(input_file, ">seq\n{}", sequence)?;
-        let input_path = input_file.path();
-        
-        // Output file
-        let output_file = NamedTempFile::new()?;
-        let _output_path = output_file.path().with_extension("csv"); // ANARCII likely needs suffix or implies it?
-        // Actually CLI said -o FILE (must end in .csv)
-        // NamedTempFile path usually doesn't end in .csv.
-        // We need a temp path that ends in .csv.
-        let temp_dir = std::env::temp_dir();
-        let output_csv_path = temp_dir.join(format!("anarcii_{}.csv", uuid::Uuid::new_v4()));
-
-        let binary = Self::find_binary();
-        debug!("Using ANARCII binary at: {:?}", binary);
-
-        let output = Command::new(binary)
-            .arg(input_path)
-            .arg("--scheme")
-            .arg("martin")
-            .arg("-o")
-            .arg(&output_csv_path)
-            .output();
-
-        let result = match output {
-            Ok(o) if o.status.success() => {
-                if !output_csv_path.exists() {
-                    bail!("ANARCII finished successfully but no output file found.");
+    fn number(&self, sequence: &str, _chain_type: &str) -> NumberingOutcome {
+        self.number_batch(&[("seq".to_string(), sequence.to_string())])?.pop().unwrap_or_else(|| Err(NumberingError::OutputParseError("no outcome for the sequence".to_string())))
+    }
+
+    fn runs_subprocess(&self) -> bool {
+        true
+    }
+
+    // One ANARCII run over a FASTA of all sequences, records named by index
+    // as the given names may not survive the round trip. The FASTA goes in
+    // on stdin and the CSV comes back on stdout; if the first such run fails
+    // the tool is taken not to support it and run on temp files instead.
+    fn number_batch(&self, sequences: &[(String, String)]) -> std::result::Result<Vec<NumberingOutcome>, NumberingError> {
+        if sequences.is_empty() {
+            return Ok(Vec::new());
+        }
+        let fasta: String = sequences.iter().enumerate().map(|(i, (_, sequence))| format!(">s{}\n{}\n", i, sequence)).collect();
+        let command = self.resolve()?;
+        debug!("Numbering {} sequences with {} as {}", sequences.len(), self.tool.name(), command);
+
+        let result = match self.stdio.get() {
+            _ if self.tool == AnarciTool::Anarci => self.run_classic(&command, &fasta),
+            Some(false) => self.run_on_files(&command, &fasta),
+            Some(true) => self.run_on_stdio(&command, &fasta),
+            None => match self.run_on_stdio(&command, &fasta) {
+                Ok(content) => {
+                    let _ = self.stdio.set(true);
+                    Ok(content)
                 }
-                
-                let content = std::fs::read_to_string(&output_csv_path)?;
-                // Parse CSV
-                // Headers: Name,Chain,Score,Query start,Query end,1,2,...
-                // Row: seq,H,31.0,0,112,E,V,...
-                
-                let mut reader = csv::Reader::from_reader(content.as_bytes());
-                let headers = reader.headers()?.clone();
-                
-                // We expect only one record (or one relevant chain if we passed one seq)
-                // ANARCII might split chains if it detects multiple domains.
-                // For now, take the first valid chain row.
-                
-                let mut numbered_seq = Vec::new();
-                for result in reader.records() {
-                    let record = result?;
-                    // Iterate columns starting from index 5 (after Name,Chain,Score,Qstart,Qend)
-                    // Check headers to be sure.
-                    
-                    for (i, field) in record.iter().enumerate() {
-                        if i < 5 { continue; } // Skip metadata
-                        if field == "-" { continue; } // Gap/Missing
-                        
-                        let number = &headers[i];
-                        numbered_seq.push((number.to_string(), field.to_string()));
-                    }
-                    if !numbered_seq.is_empty() {
-                         break; // Found our chain
+                Err(e @ NumberingError::Timeout(_)) => Err(e),
+                Err(e) => {
+                    debug!("ANARCII does not number through stdin and stdout, using temp files: {}", e);
+                    let _ = self.stdio.set(false);
+                    self.run_on_files(&command, &fasta)
+                }
+            },
+        };
+        let mut numbered = match result {
+            Ok(numbered) => numbered,
+            Err(e) => {
+                match e {
+                    NumberingError::Timeout(_) => warn!("{} killed after {:?} numbering {} sequences", self.tool.name(), self.timeout, sequences.len()),
+                    _ => warn!("{}: {}", self.tool.name(), e),
+                }
+                return Err(e);
+            }
+        };
+        Ok(sequences.iter().enumerate().map(|(i, (_, sequence))| {
+            let domains = numbered.remove(&format!("s{}", i)).ok_or(NumberingError::NoDomainDetected)?;
+            for domain in &domains {
+                domain.check_sequence(sequence)?;
+            }
+            Ok(domains)
+        }).collect())
+    }
+}
+
+impl AnarciStrategy {
+    // Numbering read from stdout, with `-` for the input and output paths
+    fn run_on_stdio(&self, command: &AnarciCommand, fasta: &str) -> std::result::Result<HashMap<String, Vec<NumberingResult>>, NumberingError> {
+        let content = self.run(command, &[OsStr::new("-"), OsStr::new("--scheme"), OsStr::new(SCHEME), OsStr::new("-o"), OsStr::new("-")], Some(fasta))?;
+        if !content.lines().next().is_some_and(|header| header.split(',').any(|h| h == "Name")) {
+            return Err(NumberingError::OutputParseError("ANARCII wrote no CSV to stdout".to_string()));
+        }
+        parse_anarcii_csv(&content)
+    }
+
+    // Numbering of a FASTA file written to a CSV file, both removed however
+    // the run ends
+    fn run_on_files(&self, command: &AnarciCommand, fasta: &str) -> std::result::Result<HashMap<String, Vec<NumberingResult>>, NumberingError> {
+        let mut input_file = NamedTempFile::new().map_err(temp_file_error)?;
+        input_file.write_all(fasta.as_bytes()).and_then(|_| input_file.flush()).map_err(temp_file_error)?;
+        // ANARCII wants an output path ending in .csv
+        let output_dir = tempfile::tempdir().map_err(temp_file_error)?;
+        let output_csv_path = output_dir.path().join("numbering.csv");
+        self.run(command, &[input_file.path().as_os_str(), OsStr::new("--scheme"), OsStr::new(SCHEME), OsStr::new("-o"), output_csv_path.as_os_str()], None)?;
+        let content = std::fs::read_to_string(&output_csv_path)
+            .map_err(|e| output_error(format!("ANARCII finished successfully but its output is unreadable: {}", e)))?;
+        parse_anarcii_csv(&content)
+    }
+
+    // Numbering by classic ANARCI, which writes the heavy and light domains
+    // to `<prefix>_H.csv` and `<prefix>_KL.csv`, each only if there are any
+    fn run_classic(&self, command: &AnarciCommand, fasta: &str) -> std::result::Result<HashMap<String, Vec<NumberingResult>>, NumberingError> {
+        let mut input_file = NamedTempFile::new().map_err(temp_file_error)?;
+        input_file.write_all(fasta.as_bytes()).and_then(|_| input_file.flush()).map_err(temp_file_error)?;
+        let output_dir = tempfile::tempdir().map_err(temp_file_error)?;
+        let prefix = output_dir.path().join("numbering");
+        let args = [OsStr::new("-i"), input_file.path().as_os_str(), OsStr::new("--scheme"), OsStr::new(SCHEME), OsStr::new("--csv"), OsStr::new("-o"), prefix.as_os_str()];
+        self.run(command, &args, None)?;
+        let mut numbered: HashMap<String, Vec<NumberingResult>> = HashMap::new();
+        for kind in ["H", "KL"] {
+            let path = output_dir.path().join(format!("numbering_{}.csv", kind));
+            if !path.exists() {
+                continue;
+            }
+            for (name, domains) in parse_anarcii_csv(&std::fs::read_to_string(&path).map_err(output_error)?)? {
+                numbered.entry(name).or_default().extend(domains);
+            }
+        }
+        for domains in numbered.values_mut() {
+            domains.sort_by_key(|d| d.query_start);
+        }
+        Ok(numbered)
+    }
+
+    // Stdout of one run of `command` with `args`, fed `stdin` if given.
+    // Fails with `NumberingError::Timeout` if killed for running too long.
+    fn run(&self, command: &AnarciCommand, args: &[&OsStr], stdin: Option<&str>) -> std::result::Result<String, NumberingError> {
+        let failed = |e: std::io::Error| NumberingError::ExecutionFailed { stderr: format!("Failed to execute {}: {}", self.tool.name(), e) };
+        // stderr goes to a file, a pipe nobody reads while waiting could
+        // fill up and stall the run
+        let stderr_file = NamedTempFile::new().map_err(temp_file_error)?;
+        let mut child = Command::new(&command.program)
+            .args(&command.prefix_args)
+            .args(args)
+            .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(stderr_file.reopen().map_err(temp_file_error)?)
+            .spawn()
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => NumberingError::BinaryNotFound(format!("{} is gone: {} not found", self.tool.name(), command)),
+                _ => failed(e),
+            })?;
+        // Fed and drained from threads so neither pipe can fill up. They are
+        // left behind on a timeout, a killed tool's children may keep the
+        // pipes open.
+        if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+            let input = input.to_string();
+            std::thread::spawn(move || pipe.write_all(input.as_bytes()));
+        }
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let reader = std::thread::spawn(move || {
+            let mut content = String::new();
+            stdout.read_to_string(&mut content).map(|_| content)
+        });
+
+        match wait_with_timeout(child, self.timeout) {
+            Ok(Some(status)) if status.success() => reader.join()
+                .map_err(|_| output_error(format!("Reading the output of {} panicked", self.tool.name())))?
+                .map_err(|e| output_error(format!("{} finished successfully but its output is unreadable: {}", self.tool.name(), e))),
+            Ok(Some(status)) => Err(NumberingError::ExecutionFailed {
+                stderr: format!("{} exited with {}: {}", self.tool.name(), status, std::fs::read_to_string(stderr_file.path()).unwrap_or_default()),
+            }),
+            Ok(None) => Err(NumberingError::Timeout(self.timeout)),
+            Err(e) => Err(failed(e)),
+        }
+    }
+}
+
+// Exit status of `child`, or None if it ran past `timeout` and was killed
+fn wait_with_timeout(mut child: Child, timeout: Duration) -> std::io::Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Numbers a variable domain without ANARCI, from the conserved Cys and Trp
+/// of the framework and the J-region motif (WGxG heavy, FGxG light), with
+/// Chothia-style insertions in the CDRs (H31, H52, H100; L30, L95). Enough
+/// to locate the regions, but loops of unusual length can be off by a
+/// residue or so. Kappa and lambda are told apart by the length of FR1
+/// only. Residues past the end of the domain are left unnumbered.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HeuristicStrategy;
+
+/// Residue indices of the conserved anchors of a variable domain
+struct Anchors {
+    cys1: usize,
+    trp: usize,
+    cys2: usize,
+    /// First residue of the J-region motif, W for heavy chains, F for light
+    j_motif: usize,
+}
+
+// The first set of anchors spaced as in a variable domain, preferring the
+// framework Trp followed by a Gln three residues on (WVRQ, WYQQ, ...)
+fn find_anchors(seq: &[u8]) -> Option<Anchors> {
+    let is = |i: usize, residues: &[u8]| seq.get(i).is_some_and(|r| residues.contains(r));
+    for strict in [true, false] {
+        for cys1 in (0..seq.len().min(40)).filter(|&i| seq[i] == b'C') {
+            for trp in (cys1 + 10..=cys1 + 20).filter(|&i| is(i, b"W") && (!strict || is(i + 3, b"Q"))) {
+                for cys2 in (trp + 45..=trp + 70).filter(|&i| is(i, b"C")) {
+                    let j_motif = (cys2 + 5..=cys2 + 35).find(|&i| is(i, b"WF") && is(i + 1, b"G") && is(i + 3, b"G"));
+                    if let Some(j_motif) = j_motif {
+                        return Some(Anchors { cys1, trp, cys2, j_motif });
                     }
                 }
-                Ok(numbered_seq)
             }
-            Ok(o) => {
-                 let stderr = String::from_utf8_lossy(&o.stderr);
-                 warn!("ANARCII failed: {}", stderr);
-                 bail!("ANARCII failed: {}", stderr);
+        }
+    }
+    None
+}
+
+// Positions first..=last, without those in `skip`
+fn slots(first: u32, last: u32, skip: &[u32]) -> Vec<Position> {
+    (first..=last).filter(|n| !skip.contains(n)).map(|n| Position::new(n, None)).collect()
+}
+
+// Numbers `residues` onto `slots`: surplus residues become insertions after
+// `site`, missing ones delete positions from `site` downwards. None if they
+// are too many or too few to fit.
+fn number_onto(residues: &[u8], mut slots: Vec<Position>, site: u32) -> Option<Numbered> {
+    let at = slots.iter().rposition(|p| p.number == site).map_or(slots.len(), |i| i + 1);
+    if residues.len() >= slots.len() {
+        let extra = residues.len() - slots.len();
+        if extra > 26 {
+            debug!("{} residues too many to number around position {}", extra, site);
+            return None;
+        }
+        let insertions = (0..extra as u8).map(|i| Position::new(site, Some((b'A' + i) as char)));
+        slots.splice(at..at, insertions);
+    } else {
+        for _ in 0..slots.len() - residues.len() {
+            let Some(i) = slots.iter().rposition(|p| p.number <= site && p.insertion.is_none()) else {
+                debug!("Too few residues to number around position {}", site);
+                return None;
+            };
+            slots.remove(i);
+        }
+    }
+    Some(slots.into_iter().zip(residues.iter().map(|&r| r as char)).collect())
+}
+
+impl HeuristicStrategy {
+    // The first variable domain of `seq`
+    fn number_domain(seq: &[u8]) -> std::result::Result<NumberingResult, NumberingError> {
+        let Some(Anchors { cys1, trp, cys2, j_motif }) = find_anchors(seq) else {
+            return Err(NumberingError::NoDomainDetected);
+        };
+        let heavy = seq[j_motif] == b'W';
+        // (Cys1, Trp, Cys2, J motif) positions and the insertion sites of the
+        // stretches between them
+        let (anchors, sites, last) = if heavy {
+            ([22, 36, 92, 103], [31, 52, 100], 113)
+        } else {
+            ([23, 35, 88, 98], [30, 66, 95], 107)
+        };
+
+        // FR1 ends at the Cys; lambda chains have no L10
+        let chain_type = if heavy { ChainType::Heavy } else if cys1 == 21 { ChainType::Lambda } else { ChainType::Kappa };
+        let fr1_slots = if chain_type == ChainType::Lambda { slots(1, 22, &[10]) } else { slots(1, anchors[0] - 1, &[]) };
+        let query_start = cys1.saturating_sub(fr1_slots.len());
+        let fr1 = &seq[query_start..cys1];
+        let mut numbered = fr1_slots[fr1_slots.len() - fr1.len()..].iter().copied().zip(fr1.iter().map(|&r| r as char)).collect::<Numbered>();
+
+        let bounds = [cys1, trp, cys2, j_motif];
+        for i in 0..3 {
+            numbered.push((Position::new(anchors[i], None), seq[bounds[i]] as char));
+            let mut between = slots(anchors[i] + 1, anchors[i + 1] - 1, &[]);
+            if heavy && i == 1 {
+                // Every VH has H82A-C
+                let at = between.iter().position(|p| p.number == 83).unwrap();
+                between.splice(at..at, ['A', 'B', 'C'].map(|c| Position::new(82, Some(c))));
             }
-            Err(e) => {
-                warn!("Failed to execute ANARCII: {}", e);
-                bail!("Failed to execute ANARCII: {}", e);
+            numbered.extend(number_onto(&seq[bounds[i] + 1..bounds[i + 1]], between, sites[i]).ok_or(NumberingError::NoDomainDetected)?);
+        }
+        let fr4 = &seq[j_motif..seq.len().min(j_motif + (last - anchors[3]) as usize + 1)];
+        numbered.extend((anchors[3]..).map(|n| Position::new(n, None)).zip(fr4.iter().map(|&r| r as char)));
+
+        Ok(NumberingResult::new(chain_type, 0.0, Scheme::Martin, numbered, query_start, j_motif + fr4.len() - 1))
+    }
+}
+
+impl NumberingStrategy for HeuristicStrategy {
+    // Domains one after the other, as long as another one follows
+    fn number(&self, sequence: &str, _chain_type: &str) -> NumberingOutcome {
+        let seq = sequence.as_bytes();
+        let mut domains = vec![Self::number_domain(seq)?];
+        loop {
+            let offset = domains.last().unwrap().query_end + 1;
+            let Ok(mut domain) = Self::number_domain(&seq[offset..]) else { break };
+            domain.query_start += offset;
+            domain.query_end += offset;
+            domains.push(domain);
+        }
+        Ok(domains)
+    }
+
+    fn is_approximate(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_position_parse_and_order() {
+        let p: Position = "100A".parse().unwrap();
+        assert_eq!(p, Position::new(100, Some('A')));
+        assert_eq!(p.to_string(), "100A");
+        assert_eq!(" 95 ".parse::<Position>().unwrap(), Position::new(95, None));
+        assert!("A100".parse::<Position>().is_err());
+        assert!("100AB".parse::<Position>().is_err());
+
+        assert!(Position::new(100, None) < p);
+        assert!(p < Position::new(100, Some('B')));
+        assert!(Position::new(100, Some('K')) < Position::new(101, None));
+        assert_eq!(Region::martin(p, ChainType::Heavy), Region::Cdr3);
+        assert_eq!(Region::martin(Position::new(103, None), ChainType::Heavy), Region::Fr4);
+    }
+
+    #[test]
+    fn test_position_formats() {
+        for (text, expected) in [
+            ("111A", Position::new(111, Some('A'))),
+            ("1119", Position::new(1119, None)),
+            ("(111, 'A')", Position::new(111, Some('A'))),
+            ("(112, ' ')", Position::new(112, None)),
+            (" ( 52 ,'C' ) ", Position::new(52, Some('C'))),
+        ] {
+            assert_eq!(text.parse::<Position>().unwrap(), expected, "{}", text);
+        }
+        for bad in ["", "(111)", "(111, 'AB')", "(A, ' ')", "111-", "-1"] {
+            assert!(bad.parse::<Position>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_position_order() {
+        let parse = |positions: &[&str]| -> Vec<Position> { positions.iter().map(|p| p.parse().unwrap()).collect() };
+        // Numeric, not string order: 111A comes before 1119
+        let mut positions = parse(&["1119", "111A", "2", "111", "100B", "100A", "100"]);
+        positions.sort();
+        assert_eq!(positions, parse(&["2", "100", "100A", "100B", "111", "111A", "1119"]));
+
+        // IMGT CDR3: up from 111, then down to 112
+        let imgt = parse(&["110", "111", "111A", "111B", "111C", "112C", "112B", "112A", "112", "113"]);
+        let mut shuffled = imgt.clone();
+        shuffled.reverse();
+        shuffled.swap(1, 6);
+        shuffled.sort_by(|a, b| a.cmp_in(b, Scheme::Imgt));
+        assert_eq!(shuffled, imgt);
+        assert!(imgt.windows(2).all(|w| w[0].cmp_in(&w[1], Scheme::Imgt).is_lt()));
+        // Other schemes number insertions forwards everywhere
+        assert!(Position::new(112, Some('A')).cmp_in(&Position::new(112, Some('B')), Scheme::Martin).is_lt());
+        assert!(Position::new(112, None).cmp_in(&Position::new(112, Some('A')), Scheme::Martin).is_lt());
+        assert_eq!(Position::new(112, Some('A')).cmp_in(&Position::new(112, Some('A')), Scheme::Imgt), Ordering::Equal);
+
+        // Ranges include their insertions
+        let (h95, h102) = (Position::new(95, None), Position::new(102, None));
+        assert!(Position::new(100, Some('A')).in_range(h95, h102, Scheme::Martin));
+        assert!(Position::new(95, None).in_range(h95, h102, Scheme::Martin));
+        assert!(!Position::new(94, Some('Z')).in_range(h95, h102, Scheme::Martin));
+        assert!(!Position::new(103, None).in_range(h95, h102, Scheme::Martin));
+        // IMGT CDR3 is 105-117; 112A lies between 111B and 112
+        let (h105, h117) = (Position::new(105, None), Position::new(117, None));
+        assert!(Position::new(112, Some('A')).in_range(h105, h117, Scheme::Imgt));
+        assert!(Position::new(112, Some('A')).in_range(Position::new(111, Some('B')), Position::new(112, None), Scheme::Imgt));
+        assert!(!Position::new(112, Some('A')).in_range(Position::new(112, None), h117, Scheme::Imgt));
+    }
+
+    #[test]
+    fn test_infer_light_type() {
+        let kappa: Vec<Position> = (1..=20).map(|n| Position::new(n, None)).collect();
+        let lambda: Vec<Position> = kappa.iter().copied().filter(|p| p.number != 10).collect();
+        assert_eq!(infer_light_type(&kappa), Some(ChainType::Kappa));
+        assert_eq!(infer_light_type(&lambda), Some(ChainType::Lambda));
+        assert_eq!(infer_light_type(&kappa[12..]), None);
+    }
+
+    // Numbers everything as a one-residue heavy chain, counting the
+    // sequences it is given
+    struct CountingStrategy {
+        calls: std::sync::atomic::AtomicUsize,
+    }
+
+    impl NumberingStrategy for CountingStrategy {
+        fn number(&self, sequence: &str, _chain_type: &str) -> NumberingOutcome {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if sequence.is_empty() {
+                return Err(NumberingError::NoDomainDetected);
             }
+            let positions = vec![(Position::new(1, Some('A')), sequence.chars().next().unwrap())];
+            Ok(vec![NumberingResult::new(ChainType::Heavy, 1.0, Scheme::Martin, positions, 0, 0)])
+        }
+    }
+
+    #[test]
+    fn test_cached_strategy() {
+        let db = Db::open_in_memory().unwrap();
+        let strategy = CachedStrategy::new(CountingStrategy { calls: Default::default() }, &db);
+        let calls = || strategy.inner.calls.load(std::sync::atomic::Ordering::SeqCst);
+        let batch: Vec<(String, String)> = ["EVQ", "DIQ", "EVQ", "", ""].iter().enumerate().map(|(i, s)| (i.to_string(), s.to_string())).collect();
+        let outcomes = strategy.number_batch(&batch).unwrap();
+        assert_eq!(calls(), 3);
+        assert_eq!(outcomes.iter().map(|o| o.as_ref().ok().map(|r| r[0].positions[0].1)).collect::<Vec<_>>(), [Some('E'), Some('D'), Some('E'), None, None]);
+
+        // Cached, including across strategies; failures are not
+        assert_eq!(strategy.number("EVQ", "antibody").unwrap(), outcomes[0].as_ref().unwrap().clone());
+        assert_eq!(calls(), 3);
+        assert!(strategy.number("", "antibody").is_err());
+        assert_eq!(calls(), 4);
+        let other = CachedStrategy::new(CountingStrategy { calls: Default::default() }, &db);
+        assert_eq!(other.number("DIQ", "antibody").unwrap()[0].positions, [(Position::new(1, Some('A')), 'D')]);
+        assert_eq!(other.inner.calls.load(std::sync::atomic::Ordering::SeqCst), 0);
+
+        // Approximate numbering is not kept
+        let heuristic = CachedStrategy::new(HeuristicStrategy, &db);
+        let sequence = "DIQMTQSPSSLSASVGDRVTITCRASQGIRNYLAWYQQKPGKAPKLLIYAASTLQSGVPSRFSGSGSGTDFTLTISSLQPEDVATYYCQRYNRAPYTFGQGTKVEIK";
+        assert!(heuristic.number(sequence, "antibody").is_ok());
+        assert_eq!(db.cached_numbering(&cache_key(sequence, Scheme::Martin)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_anarcii_csv() {
+        // Three records, the second one not an antibody, the third one a
+        // single-chain construct with two domains, listed out of order
+        let csv = "Name,Chain,Score,Query start,Query end,1,2,3,100A,101\n\
+                   s0,H,31.0,0,4,E,V,Q,-,S\n\
+                   s1,F,0.0,,,-,-,-,-,-\n\
+                   s2,K,28.5,120,122,D,I,-,-,K\n\
+                   s2,H,20.1,0,3,Q,-,-,G,A\n";
+        let numbered = parse_anarcii_csv(csv).unwrap();
+        assert_eq!(numbered.len(), 2);
+        let positions = vec![(Position::new(1, None), 'E'), (Position::new(2, None), 'V'), (Position::new(3, None), 'Q'), (Position::new(101, None), 'S')];
+        assert_eq!(numbered["s0"], [NumberingResult::new(ChainType::Heavy, 31.0, Scheme::Martin, positions, 0, 4)]);
+        assert_eq!(numbered["s0"][0].regions, [Region::Fr1, Region::Fr1, Region::Fr1, Region::Cdr3]);
+        assert!(!numbered.contains_key("s1"));
+        let domains: Vec<_> = numbered["s2"].iter()
+            .map(|d| (d.chain_type, d.score, d.query_start, d.query_end, d.positions.iter().map(|&(_, r)| r).collect::<String>()))
+            .collect();
+        assert_eq!(domains, [(ChainType::Heavy, 20.1, 0, 3, "QGA".to_string()), (ChainType::Kappa, 28.5, 120, 122, "DIK".to_string())]);
+    }
+
+    #[test]
+    fn test_csv_header_variants() {
+        let domain = |numbered: HashMap<String, Vec<NumberingResult>>| {
+            let d = &numbered["s0"][0];
+            (d.chain_type, d.score, d.query_start, d.query_end, d.positions.iter().map(|(p, r)| format!("{}{}", p, r)).collect::<Vec<_>>())
         };
+        let expected = (ChainType::Heavy, 31.5, 1, 3, vec!["1E".to_string(), "2V".to_string(), "3Q".to_string()]);
+        for csv in [
+            // ANARCII
+            "Name,Chain,Score,Query start,Query end,1,2,3\ns0,H,31.5,1,3,E,V,Q\n",
+            // Columns added in front and between, the score renamed
+            "Index,Name,Species,Chain,Conf,Query_start,Query_end,1,2,3\n7,s0,human,H,31.5,1,3,E,V,Q\n",
+            // Classic ANARCI
+            "Id,domain_no,hmm_species,chain_type,e-value,score,seqstart_index,seqend_index,identity_species,v_gene,v_identity,j_gene,j_identity,1,2,3\n\
+             s0,0,human,H,1.2e-30,31.5,1,3,human,IGHV3-23*01,0.96,IGHJ4*01,0.93,E,V,Q\n",
+            // Positions as (number, insertion) tuples
+            "Name,Chain,Score,Query start,Query end,\"(1, ' ')\",\"(2, ' ')\",\"(3, ' ')\"\ns0,H,31.5,1,3,E,V,Q\n",
+        ] {
+            assert_eq!(domain(parse_anarcii_csv(csv).unwrap()), expected, "{}", csv);
+        }
 
-        // Cleanup
-        let _ = std::fs::remove_file(&output_csv_path);
-        
-        result
+        // Layouts it cannot place name the missing columns and show the header
+        let error = parse_anarcii_csv("Name,Chain,Score,Start,End,1,2\ns0,H,31.5,1,2,E,V\n").unwrap_err();
+        assert!(matches!(error, NumberingError::OutputParseError(_)));
+        let message = error.to_string();
+        assert!(message.contains("no query start, query end columns"), "{}", message);
+        assert!(message.contains("Header: Name,Chain,Score,Start,End,1,2"), "{}", message);
+        let error = parse_anarcii_csv("Name,Chain,Score,Query start,Query end,Numbering\ns0,H,31.5,1,2,EV\n").unwrap_err();
+        assert!(error.to_string().contains("no position columns"), "{}", error);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_tool_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = fake_anarcii(dir.path(), "[ \"$1\" = --version ] && echo && echo 'anarcii 1.0.2' && exit 0\nexit 1\n").command.unwrap();
+        assert_eq!(tool_version(&script, PROBE_TIMEOUT).as_deref(), Some("anarcii 1.0.2"));
+        assert_eq!(Backend(Some((AnarciTool::Anarcii, script))).version(), "ANARCII anarcii 1.0.2");
+        let failing = fake_anarcii(dir.path(), "exit 1\n").command.unwrap();
+        assert_eq!(Backend(Some((AnarciTool::Anarci, failing))).version(), "ANARCI, version unknown");
+        assert_eq!(Backend(None).version(), "heuristic");
+    }
+
+    #[test]
+    fn test_anarci_command() {
+        assert_eq!(parse_command("  python -m  anarcii "), Some(AnarciCommand { program: "python".into(), prefix_args: vec!["-m".into(), "anarcii".into()] }));
+        assert_eq!(parse_command(" \t"), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let tool = dir.path().join("anarci-tool");
+        std::fs::write(&tool, "").unwrap();
+        let path = std::env::join_paths([dir.path().join("bin"), dir.path().to_path_buf()]).unwrap();
+        // Bare names are looked up on the PATH, a blank variable is ignored
+        let command = resolve_command(None, Some("anarci-tool -m anarci"), Some(&path)).unwrap();
+        assert_eq!(command, AnarciCommand { program: tool.clone(), prefix_args: vec!["-m".into(), "anarci".into()] });
+        let explicit = AnarciCommand { program: tool.clone(), prefix_args: Vec::new() };
+        assert_eq!(resolve_command(Some(&explicit), Some("python -m anarci"), None).unwrap(), explicit);
+
+        let error = resolve_command(None, Some("missing-python -m anarci"), Some(&path)).unwrap_err().to_string();
+        assert!(error.contains("\"missing-python -m anarci\" (from ANARCI_CMD)"), "{}", error);
+        let error = resolve_command(None, Some(" "), Some(&path)).unwrap_err().to_string();
+        for tried in [dir.path().join("bin/anarcii"), dir.path().join("anarcii"), std::env::current_dir().unwrap().join(".venv/bin/anarcii")] {
+            assert!(error.contains(&tried.display().to_string()), "{}", error);
+        }
+        assert!(error.contains("set ANARCI_CMD"), "{}", error);
+    }
+
+    // An executable script standing in for ANARCII
+    #[cfg(unix)]
+    fn fake_anarcii(dir: &Path, body: &str) -> AnarciStrategy {
+        use std::os::unix::fs::PermissionsExt;
+        let script = dir.join("anarcii");
+        std::fs::write(&script, format!("#!/bin/sh\n{}", body)).unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        AnarciStrategy::with_command(script, Vec::new())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_anarci_stdio() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv = "Name,Chain,Score,Query start,Query end,1,2,3\ns0,H,30.5,0,2,E,V,Q\ns1,K,28.0,0,2,D,I,Q\n";
+        std::fs::write(dir.path().join("canned.csv"), csv).unwrap();
+        let sequences = [("a".to_string(), "EVQ".to_string()), ("b".to_string(), "DIQ".to_string()), ("c".to_string(), "XXX".to_string())];
+        let chains = |outcomes: Vec<NumberingOutcome>| -> Vec<Option<ChainType>> {
+            outcomes.into_iter().map(|o| o.ok().map(|domains| domains[0].chain_type)).collect()
+        };
+        let expected = [Some(ChainType::Heavy), Some(ChainType::Kappa), None];
+
+        // Reads the FASTA from stdin, prints the CSV
+        let record = dir.path().join("stdin");
+        let strategy = fake_anarcii(dir.path(), &format!("[ \"$1\" = - ] && [ \"$5\" = - ] || exit 1\ncat > {:?}\ncat {:?}\n", record, dir.path().join("canned.csv")));
+        assert_eq!(chains(strategy.number_batch(&sequences).unwrap()), expected);
+        assert_eq!(std::fs::read_to_string(&record).unwrap(), ">s0\nEVQ\n>s1\nDIQ\n>s2\nXXX\n");
+        assert_eq!(strategy.stdio.get(), Some(&true));
+
+        // Rejects `-`, so the files are used and removed afterwards
+        let record = dir.path().join("paths");
+        let strategy = fake_anarcii(dir.path(), &format!("[ \"$1\" = - ] && exit 1\necho \"$1 $5\" > {:?}\ncp {:?} \"$5\"\n", record, dir.path().join("canned.csv")));
+        assert_eq!(chains(strategy.number_batch(&sequences).unwrap()), expected);
+        assert_eq!(strategy.stdio.get(), Some(&false));
+        let paths = std::fs::read_to_string(&record).unwrap();
+        assert!(paths.split_whitespace().all(|path| !Path::new(path).exists()), "{}", paths);
+        // Failures after that are reported as they are
+        std::fs::remove_file(dir.path().join("canned.csv")).unwrap();
+        assert!(strategy.number_batch(&sequences).is_err());
     }
-}
\ No newline at end of file
+
+    #[cfg(unix)]
+    #[test]
+    fn test_detect_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let (broken, working) = (dir.path().join("broken"), dir.path().join("working"));
+        for (bin, body) in [(&broken, "exit 1\n"), (&working, "exit 0\n")] {
+            std::fs::create_dir(bin).unwrap();
+            // Copies of the fake anarcii, written last
+            for name in ["ANARCI", "custom", "anarcii"] {
+                fake_anarcii(bin, body);
+                std::fs::rename(bin.join("anarcii"), bin.join(name)).unwrap_or_default();
+            }
+        }
+        let backend = |env_command: Option<&str>, dirs: &[&Path]| {
+            let path = std::env::join_paths(dirs).unwrap();
+            detect_backend(env_command, Some(&path), Duration::from_secs(10)).0.map(|(tool, command)| (tool, command.to_string()))
+        };
+        let at = |dir: &Path, name: &str| dir.join(name).display().to_string();
+
+        assert_eq!(backend(None, &[&working]), Some((AnarciTool::Anarcii, at(&working, "anarcii"))));
+        assert_eq!(backend(None, &[&broken]), None);
+        // Classic ANARCI after an anarcii that does not run
+        std::fs::remove_file(broken.join("ANARCI")).unwrap();
+        assert_eq!(backend(None, &[&broken, &working]), Some((AnarciTool::Anarci, at(&working, "ANARCI"))));
+        // The configured command goes first, a missing or broken one is passed over
+        assert_eq!(backend(Some("custom -m anarcii"), &[&working]), Some((AnarciTool::Anarcii, at(&working, "custom") + " -m anarcii")));
+        assert_eq!(backend(Some(&at(&working, "ANARCI")), &[]), Some((AnarciTool::Anarci, at(&working, "ANARCI"))));
+        assert_eq!(backend(Some("missing"), &[&working]), Some((AnarciTool::Anarcii, at(&working, "anarcii"))));
+        assert_eq!(backend(Some(&at(&broken, "custom")), &[&working]), Some((AnarciTool::Anarcii, at(&working, "anarcii"))));
+        assert!(Backend(None).strategy().is_approximate());
+
+        // A tool hanging on --help is given up on
+        let hung = dir.path().join("hung");
+        std::fs::create_dir(&hung).unwrap();
+        fake_anarcii(&hung, "sleep 30\n");
+        let path = std::env::join_paths([&hung]).unwrap();
+        let start = Instant::now();
+        assert_eq!(detect_backend(None, Some(&path), Duration::from_millis(300)), Backend(None));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_classic_anarci() {
+        // Writes the heavy domains only, as ANARCI does when there are no light ones
+        let dir = tempfile::tempdir().unwrap();
+        let csv = "Id,domain_no,hmm_species,chain_type,e-value,score,seqstart_index,seqend_index,identity_species,v_gene,v_identity,j_gene,j_identity,1,2,3\n\
+                   s1,0,human,H,1e-50,160.2,4,6,human,IGHV3-23*01,0.9,IGHJ4*01,1.0,E,V,Q\n\
+                   s1,1,human,H,1e-40,120.0,0,1,human,IGHV3-23*01,0.9,IGHJ4*01,1.0,Q,V,-\n";
+        std::fs::write(dir.path().join("canned.csv"), csv).unwrap();
+        let body = format!("[ \"$1\" = -i ] && [ \"$5\" = --csv ] || exit 1\ncp {:?} \"$7_H.csv\"\n", dir.path().join("canned.csv"));
+        let AnarciStrategy { command, .. } = fake_anarcii(dir.path(), &body);
+        let command = command.unwrap();
+        let strategy = AnarciStrategy::with_classic_command(command.program, command.prefix_args);
+
+        let outcomes = strategy.number_batch(&[("a".to_string(), "XXX".to_string()), ("b".to_string(), "QVXXEVQ".to_string())]).unwrap();
+        assert!(outcomes[0].is_err());
+        let domains = outcomes[1].as_ref().unwrap();
+        assert_eq!(domains.iter().map(|d| (d.query_start, d.query_end, d.positions.len())).collect::<Vec<_>>(), [(0, 1, 2), (4, 6, 3)]);
+        assert_eq!((domains[1].chain_type, domains[1].score), (ChainType::Heavy, 160.2));
+    }
+
+    #[test]
+    fn test_check_sequence() {
+        let domain = |residues: &str, query_start: usize, query_end: usize| {
+            let positions = residues.chars().enumerate().map(|(i, c)| (Position::new(i as u32 + 1, None), c)).collect();
+            NumberingResult::new(ChainType::Heavy, 0.0, Scheme::Martin, positions, query_start, query_end)
+        };
+        let mismatch = |position, expected, got| Err(NumberingError::SequenceMismatch { position, expected, got });
+        assert_eq!(domain("QVQ", 2, 4).check_sequence("MSQVQGG"), Ok(()));
+        assert_eq!(domain("QAQ", 2, 4).check_sequence("MSQVQGG"), mismatch(3, 'V', 'A'));
+        // A residue dropped, or one too many
+        assert_eq!(domain("QV", 2, 4).check_sequence("MSQVQGG"), mismatch(4, 'Q', '-'));
+        assert_eq!(domain("QVQG", 2, 4).check_sequence("MSQVQGG"), mismatch(5, '-', 'G'));
+        assert_eq!(domain("QVQ", 5, 7).check_sequence("MSQVQGG"), mismatch(5, 'G', 'Q'));
+        assert_eq!(mismatch(3, 'V', 'A').unwrap_err().to_string(), "Numbered residue 4 is A, the sequence has V");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_anarci_sequence_mismatch() {
+        // The second sequence comes back with a residue substituted
+        let dir = tempfile::tempdir().unwrap();
+        let csv = "Name,Chain,Score,Query start,Query end,1,2,3\ns0,H,30.5,1,3,E,V,Q\ns1,K,28.0,0,2,D,V,Q\n";
+        std::fs::write(dir.path().join("canned.csv"), csv).unwrap();
+        let strategy = fake_anarcii(dir.path(), &format!("cat > /dev/null\ncat {:?}\n", dir.path().join("canned.csv")));
+        let outcomes = strategy.number_batch(&[("a".to_string(), "MEVQ".to_string()), ("b".to_string(), "DIQ".to_string())]).unwrap();
+        assert_eq!(outcomes[0].as_ref().unwrap()[0].query_start, 1);
+        let error = outcomes[1].as_ref().unwrap_err();
+        assert_eq!(error, &NumberingError::SequenceMismatch { position: 1, expected: 'I', got: 'V' });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_numbering_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let sequences = [("a".to_string(), "EVQ".to_string()), ("b".to_string(), "GSGSG".to_string())];
+        let missing = AnarciStrategy::with_command(dir.path().join("missing/anarcii"), Vec::new());
+        assert!(matches!(missing.number_batch(&sequences), Err(NumberingError::BinaryNotFound(_))));
+
+        // Crashes, on stdin and stdout and on files alike
+        let strategy = fake_anarcii(dir.path(), "echo 'CUDA out of memory' >&2\nexit 3\n");
+        match strategy.number_batch(&sequences) {
+            Err(NumberingError::ExecutionFailed { stderr }) => assert!(stderr.contains("CUDA out of memory"), "{}", stderr),
+            other => panic!("{:?}", other),
+        }
+
+        // Rows cut short
+        let strategy = fake_anarcii(dir.path(), "cat > /dev/null\nprintf 'Name,Chain,Score,Query start,Query end,1\\ns0,H\\n'\n");
+        assert!(matches!(strategy.number_batch(&sequences), Err(NumberingError::OutputParseError(_))));
+
+        // Only the first sequence is an antibody
+        let strategy = fake_anarcii(dir.path(), "cat > /dev/null\nprintf 'Name,Chain,Score,Query start,Query end,1,2,3\\ns0,H,30.0,0,2,E,V,Q\\ns1,F,0.0,,,-,-,-\\n'\n");
+        let outcomes = strategy.number_batch(&sequences).unwrap();
+        assert!(outcomes[0].is_ok());
+        assert_eq!(outcomes[1], Err(NumberingError::NoDomainDetected));
+        assert_eq!(HeuristicStrategy.number("GSGSG", "antibody"), Err(NumberingError::NoDomainDetected));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_limited_strategy() {
+        // Logs how many copies of it are running, then takes its time
+        let dir = tempfile::tempdir().unwrap();
+        let (running, log) = (dir.path().join("running"), dir.path().join("log"));
+        std::fs::create_dir(&running).unwrap();
+        let body = format!(
+            "touch {running:?}/$$\nls {running:?} | wc -l >> {log:?}\ncat > /dev/null\nsleep 0.3\nrm {running:?}/$$\n\
+             printf 'Name,Chain,Score,Query start,Query end,1,2,3\\ns0,H,30.0,0,2,E,V,Q\\n'\n"
+        );
+        let limited = LimitedStrategy::new(fake_anarcii(dir.path(), &body), 2);
+        std::thread::scope(|scope| {
+            for _ in 0..6 {
+                scope.spawn(|| assert_eq!(limited.number("EVQ", "antibody").unwrap()[0].positions.len(), 3));
+            }
+        });
+        let counts: Vec<usize> = std::fs::read_to_string(&log).unwrap().lines().map(|l| l.trim().parse().unwrap()).collect();
+        assert_eq!(counts.len(), 6);
+        assert!(counts.iter().all(|&n| n <= 2), "{:?}", counts);
+
+        // Numbering in-process is not held up
+        let limited = LimitedStrategy::new(HeuristicStrategy, 1);
+        assert!(!limited.runs_subprocess());
+        let permit = limited.permits.acquire();
+        assert!(limited.number("ASTKGPSVFPLAPSSKSTSGG", "antibody").is_err());
+        drop(permit);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_anarci_timeout() {
+        // Stands in for a hung ANARCII: creates its output, then sleeps
+        let dir = tempfile::tempdir().unwrap();
+        let output_record = dir.path().join("output");
+        let timeout = Duration::from_millis(300);
+        let strategy = AnarciStrategy {
+            timeout,
+            ..fake_anarcii(dir.path(), &format!("[ \"$1\" = - ] && exit 1\ntouch \"$5\"\necho \"$5\" > {:?}\nsleep 30\n", output_record))
+        };
+        let start = Instant::now();
+        let error = strategy.number_batch(&[("a".to_string(), "EVQLVESGG".to_string())]).unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(error, NumberingError::Timeout(timeout));
+        // The output it created is cleaned up
+        let output = std::fs::read_to_string(&output_record).unwrap();
+        assert!(!Path::new(output.trim()).exists());
+
+        // Timing out on stdin and stdout is no reason to fall back to files
+        let strategy = AnarciStrategy { timeout, ..fake_anarcii(dir.path(), "sleep 30\n") };
+        let error = strategy.number_batch(&[("a".to_string(), "EVQLVESGG".to_string())]).unwrap_err();
+        assert_eq!(error, NumberingError::Timeout(timeout));
+        assert_eq!(strategy.stdio.get(), None);
+    }
+
+    // Start and end of `cdr` in `sequence`
+    fn span(sequence: &str, cdr: &str) -> (usize, usize) {
+        let start = sequence.find(cdr).unwrap_or_else(|| panic!("{} not in {}", cdr, sequence));
+        (start, start + cdr.len())
+    }
+
+    // IMGT numbering of a domain split into FR1, CDR1, ..., FR4: framework
+    // residues end at the last position of their region, CDR1-2 fill their
+    // windows from both ends, CDR3 insertions alternate 111A, 112A, 111B...
+    fn imgt_numbering(regions: [&str; 7]) -> Numbered {
+        let p = |n: u32| Position::new(n, None);
+        let windows = [(1, 26), (27, 38), (39, 55), (56, 65), (66, 104), (105, 117), (118, 128)];
+        let mut numbered = Vec::new();
+        for (i, (region, (first, last))) in regions.iter().zip(windows).enumerate() {
+            let n = region.len();
+            let positions: Vec<Position> = match i {
+                1 | 3 => (first..first + n.div_ceil(2) as u32).chain(last + 1 - (n / 2) as u32..=last).map(p).collect(),
+                5 => {
+                    let extra = n.saturating_sub(13);
+                    let up = (0..extra.div_ceil(2)).map(|k| Position::new(111, Some((b'A' + k as u8) as char)));
+                    let down = (0..extra / 2).rev().map(|k| Position::new(112, Some((b'A' + k as u8) as char)));
+                    (105..=111).map(p).chain(up).chain(down).chain((112..=117).map(p)).skip(13usize.saturating_sub(n)).collect()
+                }
+                _ => (last + 1 - n as u32..=last).map(p).collect(),
+            };
+            numbered.extend(positions.into_iter().zip(region.chars()));
+        }
+        numbered
+    }
+
+    #[test]
+    fn test_number_chain() {
+        // A heavy chain with Kabat-style insertion codes at 52A and 82A-C,
+        // a residue of another chain between, and a water after
+        let mut atoms = Vec::new();
+        let residues = [(1, ' '), (2, ' '), (52, ' '), (52, 'A'), (53, ' '), (82, ' '), (82, 'A'), (82, 'B'), (82, 'C'), (83, ' '), (200, ' ')];
+        for (k, &(res_seq, i_code)) in residues.iter().enumerate() {
+            let res_name = if res_seq == 200 { "HOH" } else { "GLY" };
+            for name in ["N", "CA"] {
+                atoms.push(crate::pdb::Atom {
+                    serial: atoms.len() as i32 + 1, name: name.into(), alt_loc: ' ', res_name: res_name.into(),
+                    chain_id: if k == 4 && name == "N" { 'L' } else { 'H' }, res_seq, i_code,
+                    pos: crate::pdb::Point::new(k as f64, 0.0, 0.0), occupancy: 1.0, temp_factor: 10.0, element: name[..1].into(),
+                });
+            }
+        }
+        let pdb = Pdb { atoms };
+        let strategy = crate::testing::MockStrategy { trim: 2, ..Default::default() };
+        let numbered = number_chain(&pdb, 'H', &strategy, Scheme::Martin).unwrap();
+        assert_eq!(numbered.chain_type, ChainType::Heavy);
+        let mapping: Vec<(String, Option<u32>)> = numbered.residues.iter().map(|r| (r.id.to_string(), r.position.map(|p| p.number))).collect();
+        let expected: Vec<(String, Option<u32>)> = ["H1", "H2", "H52", "H52A", "H53", "H82", "H82A", "H82B", "H82C", "H83", "H200"].iter()
+            .zip([None, None, Some(1), Some(2), Some(3), Some(4), Some(5), Some(6), Some(7), None, None])
+            .map(|(id, p)| (id.to_string(), p))
+            .collect();
+        assert_eq!(mapping, expected);
+        assert_eq!(numbered.numbered().len(), 7);
+        assert_eq!(pdb.extract_region(&numbered, Region::Fr1).atoms.iter().filter(|a| a.i_code == 'A').count(), 4);
+        assert!(pdb.extract_region(&numbered, Region::Cdr1).atoms.is_empty());
+
+        assert!(number_chain(&pdb, 'X', &strategy, Scheme::Martin).is_err());
+        assert!(number_chain(&pdb, 'H', &strategy, Scheme::Imgt).is_err());
+    }
+
+    #[test]
+    fn test_h3_loop_residues() {
+        // H93 to H104 with an insertion in H3, then a residue past the domain
+        let positions = [Some(Position::new(93, None)), Some(Position::new(95, None)), Some(Position::new(100, Some('A'))),
+            Some(Position::new(103, None)), Some(Position::new(104, None)), None];
+        let residues = positions.iter().enumerate().map(|(i, &position)| ChainResidue {
+            id: ResidueId { chain_id: 'H', res_seq: i as i32 + 1, i_code: ' ' }, residue: 'G', position, region: None,
+        }).collect();
+        let numbering = ChainNumbering { chain_type: ChainType::Heavy, scheme: Scheme::Martin, residues };
+        let mut found: Vec<i32> = numbering.h3_loop_residues().into_iter().map(|id| id.res_seq).collect();
+        found.sort();
+        assert_eq!(found, [2, 3, 4]);
+    }
+
+    #[test]
+    fn test_regions() {
+        // Trastuzumab, Martin-numbered regions as ANARCI gives them
+        let chains = [
+            (
+                "EVQLVESGGGLVQPGGSLRLSCAASGFNIKDTYIHWVRQAPGKGLEWVARIYPTNGYTRYADSVKGRFTISADTSKNTAYLQMNSLRAEDTAVYYCSRWGGDGFYAMDYWGQGTLVTVSS",
+                ["EVQLVESGGGLVQPGGSLRLSCAAS", "GFNIKDT", "YIHWVRQAPGKGLEWVARI", "YPTNGY", "TRYADSVKGRFTISADTSKNTAYLQMNSLRAEDTAVYYCSR", "WGGDGFYAMDY", "WGQGTLVTVSS"],
+            ),
+            (
+                "DIQMTQSPSSLSASVGDRVTITCRASQDVNTAVAWYQQKPGKAPKLLIYSASFLYSGVPSRFSGSRSGTDFTLTISSLQPEDFATYYCQQHYTTPPTFGQGTKVEIK",
+                ["DIQMTQSPSSLSASVGDRVTITC", "RASQDVNTAVA", "WYQQKPGKAPKLLIY", "SASFLYS", "GVPSRFSGSRSGTDFTLTISSLQPEDFATYYC", "QQHYTTPPT", "FGQGTKVEIK"],
+            ),
+        ];
+        for (sequence, expected) in chains {
+            let domain = HeuristicStrategy.number(sequence, "antibody").unwrap().remove(0);
+            assert_eq!(domain.regions.len(), domain.positions.len());
+            assert_eq!(Region::ALL.map(|region| domain.region_sequence(region)), expected);
+        }
+        let p = |s: &str| s.parse::<Position>().unwrap();
+        assert_eq!(Region::of(p("25"), Scheme::Martin, ChainType::Heavy), Region::Fr1);
+        assert_eq!(Region::of(p("100K"), Scheme::Martin, ChainType::Heavy), Region::Cdr3);
+        assert_eq!(Region::of(p("35B"), Scheme::Kabat, ChainType::Heavy), Region::Cdr1);
+        assert_eq!(Region::of(p("36"), Scheme::Kabat, ChainType::Heavy), Region::Fr2);
+        assert_eq!(Region::of(p("104"), Scheme::Imgt, ChainType::Kappa), Region::Fr3);
+        assert_eq!(Region::of(p("112A"), Scheme::Imgt, ChainType::Kappa), Region::Cdr3);
+
+        // Results cached before regions were kept get them on loading
+        let db = Db::open_in_memory().unwrap();
+        let domain = HeuristicStrategy.number(chains[0].0, "antibody").unwrap().remove(0);
+        let mut stored = serde_json::to_value([&domain]).unwrap();
+        stored[0].as_object_mut().unwrap().remove("regions");
+        db.get_conn().execute("INSERT INTO numbering_cache (key, result) VALUES ('old', ?1)", [stored.to_string()]).unwrap();
+        assert_eq!(db.cached_numbering("old").unwrap().unwrap(), [domain]);
+    }
+
+    #[test]
+    fn test_extract_cdrs() {
+        let result = |chain_type: ChainType, scheme: Scheme, positions: Numbered| NumberingResult::new(chain_type, 0.0, scheme, positions, 0, 0);
+        let cdrs = |[cdr1, cdr2, cdr3]: [&str; 3]| Cdrs { cdr1: cdr1.into(), cdr2: cdr2.into(), cdr3: cdr3.into() };
+        // Trastuzumab and adalimumab, whose CDR1s need no insertions, so
+        // their Martin numbering is their Kabat and Chothia numbering too.
+        // Published CDRs per scheme.
+        let antibodies = [
+            (
+                "EVQLVESGGGLVQPGGSLRLSCAASGFNIKDTYIHWVRQAPGKGLEWVARIYPTNGYTRYADSVKGRFTISADTSKNTAYLQMNSLRAEDTAVYYCSRWGGDGFYAMDYWGQGTLVTVSS",
+                ChainType::Heavy,
+                [["DTYIH", "RIYPTNGYTRYADSVKG", "WGGDGFYAMDY"], ["GFNIKDT", "YPTNGY", "WGGDGFYAMDY"]],
+                ["EVQLVESGGGLVQPGGSLRLSCAAS", "GFNIKDTY", "IHWVRQAPGKGLEWVAR", "IYPTNGYT", "RYADSVKGRFTISADTSKNTAYLQMNSLRAEDTAVYYC", "SRWGGDGFYAMDY", "WGQGTLVTVSS"],
+            ),
+            (
+                "DIQMTQSPSSLSASVGDRVTITCRASQDVNTAVAWYQQKPGKAPKLLIYSASFLYSGVPSRFSGSRSGTDFTLTISSLQPEDFATYYCQQHYTTPPTFGQGTKVEIK",
+                ChainType::Kappa,
+                [["RASQDVNTAVA", "SASFLYS", "QQHYTTPPT"], ["RASQDVNTAVA", "SASFLYS", "QQHYTTPPT"]],
+                ["DIQMTQSPSSLSASVGDRVTITCRAS", "QDVNTA", "VAWYQQKPGKAPKLLIY", "SAS", "FLYSGVPSRFSGSRSGTDFTLTISSLQPEDFATYYC", "QQHYTTPPT", "FGQGTKVEIK"],
+            ),
+            (
+                "EVQLVESGGGLVQPGRSLRLSCAASGFTFDDYAMHWVRQAPGKGLEWVSAITWNSGHIDYADSVEGRFTISRDNAKNSLYLQMNSLRAEDTAVYYCAKVSYLSTASSLDYWGQGTLVTVSS",
+                ChainType::Heavy,
+                [["DYAMH", "AITWNSGHIDYADSVEG", "VSYLSTASSLDY"], ["GFTFDDY", "TWNSGH", "VSYLSTASSLDY"]],
+                ["EVQLVESGGGLVQPGRSLRLSCAAS", "GFTFDDYA", "MHWVRQAPGKGLEWVSA", "ITWNSGHI", "DYADSVEGRFTISRDNAKNSLYLQMNSLRAEDTAVYYC", "AKVSYLSTASSLDY", "WGQGTLVTVSS"],
+            ),
+        ];
+        for (sequence, chain, [kabat, chothia], imgt) in antibodies {
+            let numbered = HeuristicStrategy.number(sequence, "antibody").unwrap().remove(0).positions;
+            assert_eq!(extract_cdrs(&result(chain, Scheme::Kabat, numbered.clone())), cdrs(kabat));
+            assert_eq!(extract_cdrs(&result(chain, Scheme::Chothia, numbered.clone())), cdrs(chothia));
+            assert_eq!(extract_cdrs(&result(chain, Scheme::Martin, numbered.clone())), cdrs(chothia));
+            assert_eq!(cdr_sequences(&numbered, chain), cdrs(chothia).into_array());
+
+            let numbered = imgt_numbering(imgt);
+            assert_eq!(numbered.iter().map(|&(_, r)| r).collect::<String>(), sequence);
+            assert_eq!(extract_cdrs(&result(chain, Scheme::Imgt, numbered)), cdrs([imgt[1], imgt[3], imgt[5]]));
+        }
+
+        // A long IMGT CDR3 runs through both insertion series
+        let h3 = "ARDRGYSSGWYPYYYYGMDV";
+        let numbered = imgt_numbering(["EVQLVESGGGLVQPGGSLRLSCAAS", "GFTFSSYA", "MSWVRQAPGKGLEWVSA", "ISGSGGST", "YYADSVKGRFTISRDNSKNTLYLQMNSLRAEDTAVYYC", h3, "WGQGTTVTVSS"]);
+        let insertions: Vec<String> = numbered.iter().filter(|(p, _)| p.insertion.is_some()).map(|(p, _)| p.to_string()).collect();
+        assert_eq!(insertions, ["111A", "111B", "111C", "111D", "112C", "112B", "112A"]);
+        assert!(numbered.windows(2).all(|w| w[0].0.cmp_in(&w[1].0, Scheme::Imgt).is_lt()));
+        assert_eq!(extract_cdrs(&result(ChainType::Heavy, Scheme::Imgt, numbered)).cdr3, h3);
+    }
+
+    #[test]
+    fn test_heuristic_numbering() {
+        // Trastuzumab with the start of CH1, adalimumab, a lambda chain; CDRs
+        // as numbered by ANARCI
+        let ch1 = "ASTKGPSVFPLAPSSKSTSGGTAALGCLVKDYFPEPVTVSWNSGALTSGVHTFPAVLQSSGLYSLSSVVTVPSSSLGTQTYICNVNHKPS";
+        let chains = [
+            (
+                format!("EVQLVESGGGLVQPGGSLRLSCAASGFNIKDTYIHWVRQAPGKGLEWVARIYPTNGYTRYADSVKGRFTISADTSKNTAYLQMNSLRAEDTAVYYCSRWGGDGFYAMDYWGQGTLVTVSS{}", ch1),
+                ChainType::Heavy,
+                ["GFNIKDT", "YPTNGY", "WGGDGFYAMDY"],
+            ),
+            (
+                "DIQMTQSPSSLSASVGDRVTITCRASQDVNTAVAWYQQKPGKAPKLLIYSASFLYSGVPSRFSGSRSGTDFTLTISSLQPEDFATYYCQQHYTTPPTFGQGTKVEIK".to_string(),
+                ChainType::Kappa,
+                ["RASQDVNTAVA", "SASFLYS", "QQHYTTPPT"],
+            ),
+            (
+                "EVQLVESGGGLVQPGRSLRLSCAASGFTFDDYAMHWVRQAPGKGLEWVSAITWNSGHIDYADSVEGRFTISRDNAKNSLYLQMNSLRAEDTAVYYCAKVSYLSTASSLDYWGQGTLVTVSS".to_string(),
+                ChainType::Heavy,
+                ["GFTFDDY", "TWNSGH", "VSYLSTASSLDY"],
+            ),
+            (
+                "DIQMTQSPSSLSASVGDRVTITCRASQGIRNYLAWYQQKPGKAPKLLIYAASTLQSGVPSRFSGSGSGTDFTLTISSLQPEDVATYYCQRYNRAPYTFGQGTKVEIK".to_string(),
+                ChainType::Kappa,
+                ["RASQGIRNYLA", "AASTLQS", "QRYNRAPYT"],
+            ),
+            (
+                "QSALTQPASVSGSPGQSITISCTGTSSDVGGYNYVSWYQQHPGKAPKLMIYEVSNRPSGVSNRFSGSKSGNTASLTISGLQAEDEADYYCSSYTSSSTLVFGGGTKLTVL".to_string(),
+                ChainType::Lambda,
+                ["TGTSSDVGGYNYVS", "EVSNRPS", "SSYTSSSTLV"],
+            ),
+        ];
+        for (sequence, chain, reference) in &chains {
+            let domains = HeuristicStrategy.number(sequence, "antibody").unwrap();
+            assert_eq!(domains.iter().map(|d| d.chain_type).collect::<Vec<_>>(), [*chain]);
+            assert_eq!(domains[0].check_sequence(sequence), Ok(()));
+            let numbered = domains[0].positions.clone();
+            let cdrs = cdr_sequences(&numbered, *chain);
+            for (cdr, expected) in cdrs.iter().zip(reference) {
+                let (start, end) = span(sequence, cdr);
+                let (expected_start, expected_end) = span(sequence, expected);
+                assert!(start.abs_diff(expected_start) <= 1 && end.abs_diff(expected_end) <= 1, "{} for {}", cdr, expected);
+            }
+            let positions: Vec<Position> = numbered.iter().map(|&(p, _)| p).collect();
+            assert!(positions.windows(2).all(|w| w[0] < w[1]));
+            if *chain != ChainType::Heavy {
+                assert_eq!(infer_light_type(&positions), Some(*chain));
+            }
+        }
+        // The constant domain is left out
+        assert_eq!(HeuristicStrategy.number(&chains[0].0, "antibody").unwrap()[0].positions.last().unwrap().0, Position::new(113, None));
+        assert!(HeuristicStrategy.number("ASTKGPSVFPLAPSSKSTSGG", "antibody").is_err());
+
+        // An scFv: VH, a (G4S)3 linker, VL
+        let scfv = format!("{}GGGGSGGGGSGGGGS{}", &chains[2].0, &chains[3].0);
+        let domains = HeuristicStrategy.number(&scfv, "antibody").unwrap();
+        assert_eq!(domains.iter().map(|d| d.chain_type).collect::<Vec<_>>(), [ChainType::Heavy, ChainType::Kappa]);
+        let vl_start = chains[2].0.len() + 15;
+        assert_eq!(domains[1].query_start, vl_start);
+        assert_eq!(domains[1].query_end, scfv.len() - 1);
+        assert!(domains.iter().all(|d| d.check_sequence(&scfv).is_ok()));
+        assert_eq!(domains[1].positions, HeuristicStrategy.number(&chains[3].0, "antibody").unwrap()[0].positions);
+    }
+}
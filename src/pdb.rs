@@ -1,6 +1,10 @@
-use std::str::FromStr;
+use crate::numbering::{ChainNumbering, Region};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::collections::HashMap;
+
+/// Maximum C(i)–N(i+1) distance still considered a peptide bond.
+/// Peptide bond is ~1.33A; anything above this is treated as a chain break.
+pub const MAX_PEPTIDE_BOND: f64 = 2.0;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Point {
@@ -49,7 +53,7 @@ impl Point {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Atom {
     pub serial: i32,
     pub name: String,
@@ -104,13 +108,145 @@ impl Atom {
             element,
         })
     }
+
+    /// The atom as a PDB ATOM record, as `from_line` reads it.
+    pub fn to_line(&self) -> String {
+        // Names of one-letter elements start in the second column of the field
+        let name = if self.name.len() < 4 && self.element.len() < 2 { format!(" {:<3}", self.name) } else { format!("{:<4}", self.name) };
+        format!(
+            "ATOM  {:>5} {}{}{:>3} {}{:>4}{}   {:>8.3}{:>8.3}{:>8.3}{:>6.2}{:>6.2}          {:>2}",
+            self.serial % 100_000, name, self.alt_loc, self.res_name, self.chain_id, self.res_seq, self.i_code,
+            self.pos.x, self.pos.y, self.pos.z, self.occupancy, self.temp_factor, self.element
+        )
+    }
+}
+
+/// Unique residue key within a structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub struct ResidueId {
+    pub chain_id: char,
+    pub res_seq: i32,
+    pub i_code: char,
+}
+
+impl fmt::Display for ResidueId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.chain_id, self.res_seq)?;
+        if self.i_code != ' ' {
+            write!(f, "{}", self.i_code)?;
+        }
+        Ok(())
+    }
+}
+
+/// A group of consecutive atoms sharing (chain_id, res_seq, i_code).
+#[derive(Debug, Clone)]
+pub struct Residue<'a> {
+    pub id: ResidueId,
+    pub res_name: &'a str,
+    pub atoms: Vec<&'a Atom>,
+}
+
+impl<'a> Residue<'a> {
+    pub fn atom(&self, name: &str) -> Option<&'a Atom> {
+        self.atoms.iter().copied().find(|a| a.name == name)
+    }
+
+    /// Drops alternate conformers, keeping blank altLocs and the lowest altLoc label (usually 'A').
+    pub fn collapse_altlocs(&mut self) {
+        let primary = self.atoms.iter().map(|a| a.alt_loc).filter(|&c| c != ' ').min();
+        if let Some(primary) = primary {
+            self.atoms.retain(|a| a.alt_loc == ' ' || a.alt_loc == primary);
+        }
+    }
+
+    /// True if C of `self` and N of `next` form a peptide bond (same chain, no break).
+    pub fn is_bonded_to(&self, next: &Residue) -> bool {
+        if self.id.chain_id != next.id.chain_id {
+            return false;
+        }
+        match (self.atom("C"), next.atom("N")) {
+            (Some(c), Some(n)) => c.pos.distance(&n.pos) <= MAX_PEPTIDE_BOND,
+            _ => false,
+        }
+    }
 }
 
+/// Groups atoms into residues, assuming standard PDB ordering (atoms of a residue are contiguous).
+pub fn group_residues(atoms: &[Atom]) -> Vec<Residue<'_>> {
+    let mut residues: Vec<Residue> = Vec::new();
+    for atom in atoms {
+        let id = ResidueId { chain_id: atom.chain_id, res_seq: atom.res_seq, i_code: atom.i_code };
+        match residues.last_mut() {
+            Some(last) if last.id == id => last.atoms.push(atom),
+            _ => residues.push(Residue { id, res_name: &atom.res_name, atoms: vec![atom] }),
+        }
+    }
+    residues
+}
+
+/// On-disk format of a stored structure, recorded in the `format` column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StructureFormat {
+    #[default]
+    Pdb,
+    Mmcif,
+}
+
+impl StructureFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StructureFormat::Pdb => "pdb",
+            StructureFormat::Mmcif => "cif",
+        }
+    }
+
+    /// Column value to format; rows from before the column existed are PDB.
+    pub fn from_column(value: Option<&str>) -> Self {
+        match value {
+            Some("cif") => StructureFormat::Mmcif,
+            _ => StructureFormat::Pdb,
+        }
+    }
+}
+
+// Whitespace-separated mmCIF tokens, honoring single and double quotes
+fn cif_tokens(line: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let bytes = line.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i].is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        let quote = bytes[i];
+        if quote == b'\'' || quote == b'"' {
+            // A quote only closes when followed by whitespace or the end of line
+            let mut j = i + 1;
+            while j < bytes.len() && !(bytes[j] == quote && bytes.get(j + 1).is_none_or(|b| b.is_ascii_whitespace())) {
+                j += 1;
+            }
+            tokens.push(&line[i + 1..j.min(bytes.len())]);
+            i = j + 1;
+        } else {
+            let start = i;
+            while i < bytes.len() && !bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            tokens.push(&line[start..i]);
+        }
+    }
+    tokens
+}
+
+#[derive(Debug, Clone)]
 pub struct Pdb {
     pub atoms: Vec<Atom>,
 }
 
 impl Pdb {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(content: &str) -> Self {
         let atoms = content
             .lines()
@@ -119,29 +255,134 @@ impl Pdb {
         Self { atoms }
     }
 
+    /// Parses the `_atom_site` loop of an mmCIF file, keeping the first model.
+    /// Author chain, residue and atom names are used where present, so atoms
+    /// match what the PDB format would give; chain IDs are truncated to their
+    /// first character.
+    pub fn from_mmcif(content: &str) -> Self {
+        let mut atoms = Vec::new();
+        let mut columns: Vec<&str> = Vec::new();
+        let mut lines = content.lines().peekable();
+        while let Some(line) = lines.next() {
+            if line.trim() != "loop_" || !lines.peek().is_some_and(|l| l.starts_with("_atom_site.")) {
+                continue;
+            }
+            while let Some(header) = lines.next_if(|l| l.starts_with("_atom_site.")) {
+                columns.push(header.trim()["_atom_site.".len()..].trim());
+            }
+            let col = |name: &str| columns.iter().position(|c| *c == name);
+            let pick = |a: &str, b: &str| col(a).or_else(|| col(b));
+            let (Some(x), Some(y), Some(z)) = (col("Cartn_x"), col("Cartn_y"), col("Cartn_z")) else { break };
+            let serial = col("id");
+            let group = col("group_PDB");
+            let name = pick("auth_atom_id", "label_atom_id");
+            let alt = col("label_alt_id");
+            let res_name = pick("auth_comp_id", "label_comp_id");
+            let chain = pick("auth_asym_id", "label_asym_id");
+            let res_seq = pick("auth_seq_id", "label_seq_id");
+            let i_code = col("pdbx_PDB_ins_code");
+            let occupancy = col("occupancy");
+            let b = col("B_iso_or_equiv");
+            let element = col("type_symbol");
+            let model = col("pdbx_PDB_model_num");
+
+            let mut first_model = None;
+            while let Some(row) = lines.next_if(|l| !l.starts_with('_') && !l.starts_with('#') && l.trim() != "loop_") {
+                let tokens = cif_tokens(row);
+                if tokens.len() != columns.len() {
+                    continue;
+                }
+                let get = |i: Option<usize>| i.map(|i| tokens[i]).filter(|v| *v != "?" && *v != ".");
+                if get(group).is_some_and(|g| g != "ATOM" && g != "HETATM") {
+                    continue;
+                }
+                let this_model = get(model);
+                if *first_model.get_or_insert(this_model) != this_model {
+                    continue;
+                }
+                let (Some(px), Some(py), Some(pz)) = (
+                    get(Some(x)).and_then(|v| v.parse().ok()),
+                    get(Some(y)).and_then(|v| v.parse().ok()),
+                    get(Some(z)).and_then(|v| v.parse().ok()),
+                ) else { continue };
+                atoms.push(Atom {
+                    serial: get(serial).and_then(|v| v.parse().ok()).unwrap_or(0),
+                    name: get(name).unwrap_or("").to_string(),
+                    alt_loc: get(alt).and_then(|v| v.chars().next()).unwrap_or(' '),
+                    res_name: get(res_name).unwrap_or("").to_string(),
+                    chain_id: get(chain).and_then(|v| v.chars().next()).unwrap_or(' '),
+                    res_seq: get(res_seq).and_then(|v| v.parse().ok()).unwrap_or(0),
+                    i_code: get(i_code).and_then(|v| v.chars().next()).unwrap_or(' '),
+                    pos: Point { x: px, y: py, z: pz },
+                    occupancy: get(occupancy).and_then(|v| v.parse().ok()).unwrap_or(1.0),
+                    temp_factor: get(b).and_then(|v| v.parse().ok()).unwrap_or(0.0),
+                    element: get(element).unwrap_or("").to_string(),
+                });
+            }
+            break;
+        }
+        Self { atoms }
+    }
+
+    pub fn parse(content: &str, format: StructureFormat) -> Self {
+        match format {
+            StructureFormat::Pdb => Self::from_str(content),
+            StructureFormat::Mmcif => Self::from_mmcif(content),
+        }
+    }
+
+    /// The atoms of the listed chains only, e.g. one Fab of an entry with
+    /// several in the asymmetric unit.
+    pub fn select_chains(&self, chains: &[char]) -> Self {
+        Self { atoms: self.atoms.iter().filter(|a| chains.contains(&a.chain_id)).cloned().collect() }
+    }
+
     pub fn get_sequence(&self, chain_id: char) -> String {
-        let mut seq = String::new();
+        self.numbered_sequence(chain_id).into_iter().map(|(_, code)| code).collect()
+    }
+
+    /// The residues of a chain in file order with their one-letter codes,
+    /// each once however its atoms are spread; `get_sequence` is the codes.
+    pub fn numbered_sequence(&self, chain_id: char) -> Vec<(ResidueId, char)> {
         let mut seen_residues = std::collections::HashSet::new();
-        
-        // Filter by chain
-        let mut chain_atoms: Vec<&Atom> = self.atoms.iter()
-            .filter(|a| a.chain_id == chain_id)
+        self.atoms.iter()
+            .filter(|a| a.chain_id == chain_id && seen_residues.insert((a.res_seq, a.i_code)))
+            .map(|a| (ResidueId { chain_id, res_seq: a.res_seq, i_code: a.i_code }, three_to_one(&a.res_name)))
+            .collect()
+    }
+
+    /// The atoms of the residues `numbering` puts in `region`.
+    pub fn extract_region(&self, numbering: &ChainNumbering, region: Region) -> Pdb {
+        let residues: std::collections::HashSet<ResidueId> = numbering.residues.iter()
+            .filter(|r| r.region == Some(region))
+            .map(|r| r.id)
             .collect();
-        
-        // Sort by residue sequence and i_code? 
-        // Typically atoms are sorted, but we should be robust.
-        // PDB residue ordering: res_seq asc, then i_code (A, B, ...).
-        // Let's rely on simple iteration order for now if file is standard.
-        
-        for atom in chain_atoms {
-            let key = (atom.res_seq, atom.i_code);
-            if !seen_residues.contains(&key) {
-                seen_residues.insert(key);
-                // Simple 3to1 mapping
-                seq.push(three_to_one(&atom.res_name));
-            }
+        Pdb { atoms: self.atoms.iter().filter(|a| residues.contains(&ResidueId { chain_id: a.chain_id, res_seq: a.res_seq, i_code: a.i_code })).cloned().collect() }
+    }
+
+    /// The atoms of `chains`, in a chain with residues in `numbered` only
+    /// those: the variable domains of a Fab, where a chain that could not
+    /// be numbered is kept whole.
+    pub fn extract_fv(&self, chains: &[char], numbered: &HashSet<ResidueId>) -> Pdb {
+        let numbered_chains: HashSet<char> = numbered.iter().map(|id| id.chain_id).collect();
+        Pdb {
+            atoms: self.atoms.iter()
+                .filter(|a| chains.contains(&a.chain_id))
+                .filter(|a| !numbered_chains.contains(&a.chain_id) || numbered.contains(&ResidueId { chain_id: a.chain_id, res_seq: a.res_seq, i_code: a.i_code }))
+                .cloned()
+                .collect(),
         }
-        seq
+    }
+
+    /// The atoms as PDB text, one ATOM record each.
+    pub fn to_pdb_string(&self) -> String {
+        let mut lines: Vec<String> = self.atoms.iter().map(Atom::to_line).collect();
+        lines.push("END".to_string());
+        lines.join("\n")
+    }
+
+    pub fn residues(&self) -> Vec<Residue<'_>> {
+        group_residues(&self.atoms)
     }
 
     pub fn validate(&self) -> QualityReport {
@@ -153,7 +394,7 @@ impl Pdb {
             chains.entry(atom.chain_id).or_default().push(atom);
         }
 
-        for (chain_id, atoms) in chains {
+        for atoms in chains.into_values() {
             // Group by residue
             let mut residues: Vec<Vec<&Atom>> = Vec::new();
             let mut curr_res = Vec::new();
@@ -203,33 +444,98 @@ impl Pdb {
                     }
 
                     // Check geometric gap
-                    if dist > 2.0 {
+                    if dist > MAX_PEPTIDE_BOND {
                         report.geometric_gaps += 1;
                     }
                 }
             }
         }
+
+        report.cis_nonproline_count = crate::analysis::omega_angles(&self.atoms)
+            .iter()
+            .filter(|o| o.is_cis && o.res_name != "PRO")
+            .count();
+        report.rama_outlier_fraction = crate::analysis::rama_outlier_fraction(&self.atoms);
+        report.degenerate_torsions = crate::analysis::degenerate_torsion_count(&self.atoms);
         report
     }
 }
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Default, Clone, Serialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct QualityReport {
     pub missing_backbone_residues: usize,
     pub numbering_gaps: usize,
     pub geometric_gaps: usize,
+    /// Cis peptide bonds preceding non-proline residues, usually a model error
+    pub cis_nonproline_count: usize,
+    /// Fraction of residues in the Ramachandran outlier region
+    pub rama_outlier_fraction: f64,
+    /// Residues without phi/psi because of collinear backbone atoms
+    pub degenerate_torsions: usize,
+    /// The heavy and light chain are not in contact, likely from different
+    /// Fabs of the asymmetric unit; set by processing
+    pub pairing_suspect: bool,
+}
+
+/// Limits of a `QualityReport` passing quality control. The defaults suit
+/// crystal structures; cryo-EM models often have a few broken loops.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct QcThresholds {
+    pub max_geometric_gaps: usize,
+    /// Residues missing backbone atoms
+    pub max_missing_backbone: usize,
+    /// Unchecked if None
+    pub max_numbering_gaps: Option<usize>,
+    /// Unchecked if None
+    pub max_rama_outlier_fraction: Option<f64>,
+}
+
+impl Default for QcThresholds {
+    fn default() -> Self {
+        Self { max_geometric_gaps: 0, max_missing_backbone: 4, max_numbering_gaps: None, max_rama_outlier_fraction: None }
+    }
 }
 
 impl QualityReport {
     pub fn is_pass(&self) -> bool {
-        // Strict criteria: No gaps, few missing atoms
-        self.geometric_gaps == 0 && self.missing_backbone_residues < 5
+        self.is_pass_with(&QcThresholds::default())
+    }
+
+    /// Same as `is_pass`, additionally rejecting structures whose Ramachandran
+    /// outlier fraction exceeds `max_outlier_fraction`.
+    pub fn is_pass_with_rama_limit(&self, max_outlier_fraction: Option<f64>) -> bool {
+        self.is_pass_with(&QcThresholds { max_rama_outlier_fraction: max_outlier_fraction, ..Default::default() })
+    }
+
+    pub fn is_pass_with(&self, thresholds: &QcThresholds) -> bool {
+        !self.pairing_suspect
+            && self.geometric_gaps <= thresholds.max_geometric_gaps
+            && self.missing_backbone_residues <= thresholds.max_missing_backbone
+            && thresholds.max_numbering_gaps.is_none_or(|max| self.numbering_gaps <= max)
+            && thresholds.max_rama_outlier_fraction.is_none_or(|max| self.rama_outlier_fraction <= max)
     }
 }
 
-fn three_to_one(res: &str) -> char {
+/// Maps common modified amino acids to their standard parent residue.
+pub fn standard_parent(res: &str) -> Option<&'static str> {
+    match res {
+        "MSE" => Some("MET"), "SEP" => Some("SER"), "TPO" => Some("THR"), "PTR" => Some("TYR"),
+        "HYP" => Some("PRO"), "CSO" => Some("CYS"), "CME" => Some("CYS"), "MLY" => Some("LYS"),
+        "PCA" => Some("GLN"), "KCX" => Some("LYS"), "LLP" => Some("LYS"), "CSD" => Some("CYS"),
+        _ => None,
+    }
+}
+
+/// True for the 20 standard amino acids and mapped modified ones.
+pub fn is_amino_acid(res: &str) -> bool {
+    three_to_one(res) != 'X'
+}
+
+pub fn three_to_one(res: &str) -> char {
+    let res = standard_parent(res).unwrap_or(res);
     match res {
         "ALA" => 'A', "CYS" => 'C', "ASP" => 'D', "GLU" => 'E', "PHE" => 'F',
         "GLY" => 'G', "HIS" => 'H', "ILE" => 'I', "LYS" => 'K', "LEU" => 'L',
@@ -239,6 +545,17 @@ fn three_to_one(res: &str) -> char {
     }
 }
 
+/// Residue name of a one-letter code, "UNK" for unknown ones.
+pub fn one_to_three(code: char) -> &'static str {
+    match code {
+        'A' => "ALA", 'C' => "CYS", 'D' => "ASP", 'E' => "GLU", 'F' => "PHE",
+        'G' => "GLY", 'H' => "HIS", 'I' => "ILE", 'K' => "LYS", 'L' => "LEU",
+        'M' => "MET", 'N' => "ASN", 'P' => "PRO", 'Q' => "GLN", 'R' => "ARG",
+        'S' => "SER", 'T' => "THR", 'V' => "VAL", 'W' => "TRP", 'Y' => "TYR",
+        _ => "UNK",
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -250,6 +567,18 @@ mod tests {
         assert_eq!(p1.distance(&p2), 5.0);
     }
 
+    #[test]
+    fn test_qc_thresholds() {
+        let report = QualityReport { geometric_gaps: 2, missing_backbone_residues: 4, numbering_gaps: 3, rama_outlier_fraction: 0.1, ..Default::default() };
+        assert!(!report.is_pass());
+        let loose = QcThresholds { max_geometric_gaps: 2, ..Default::default() };
+        assert!(report.is_pass_with(&loose));
+        assert!(!report.is_pass_with(&QcThresholds { max_numbering_gaps: Some(2), ..loose }));
+        assert!(!report.is_pass_with(&QcThresholds { max_rama_outlier_fraction: Some(0.05), ..loose }));
+        let missing = QualityReport { missing_backbone_residues: 5, ..report };
+        assert!(!missing.is_pass_with(&loose));
+    }
+
     #[test]
     fn test_atom_parsing() {
         let line = "ATOM      1  N   ALA A   1      10.000  10.000  10.000  1.00  0.00           N";
@@ -259,6 +588,25 @@ mod tests {
         assert_eq!(atom.res_name, "ALA");
         assert_eq!(atom.chain_id, 'A');
         assert_eq!(atom.pos.x, 10.0);
+        // Written back column for column
+        assert_eq!(atom.to_line(), line);
+        let atom = Atom { name: "OXT".into(), element: "O".into(), alt_loc: 'B', i_code: 'A', ..atom };
+        assert_eq!(Atom::from_line(&atom.to_line()), Some(atom));
+    }
+
+    #[test]
+    fn test_extract_fv() {
+        let atom = |chain_id: char, res_seq: i32| Atom {
+            serial: res_seq, name: "CA".into(), alt_loc: ' ', res_name: "GLY".into(), chain_id, res_seq, i_code: ' ',
+            pos: Point::new(res_seq as f64, 0.0, 0.0), occupancy: 1.0, temp_factor: 20.0, element: "C".into(),
+        };
+        let pdb = Pdb { atoms: ['H', 'L', 'A'].iter().flat_map(|&c| (1..=5).map(move |i| atom(c, i))).collect() };
+        // Only the heavy chain is numbered, residues 2-4
+        let numbered: HashSet<ResidueId> = (2..=4).map(|res_seq| ResidueId { chain_id: 'H', res_seq, i_code: ' ' }).collect();
+        let fv = pdb.extract_fv(&['H', 'L'], &numbered);
+        let kept: Vec<(char, i32)> = fv.atoms.iter().map(|a| (a.chain_id, a.res_seq)).collect();
+        assert_eq!(kept, [('H', 2), ('H', 3), ('H', 4), ('L', 1), ('L', 2), ('L', 3), ('L', 4), ('L', 5)]);
+        assert_eq!(Pdb::from_str(&fv.to_pdb_string()).atoms, fv.atoms);
     }
 
     #[test]
@@ -269,9 +617,53 @@ mod tests {
         assert_eq!(pdb.get_sequence('A'), "AG");
     }
     
+    #[test]
+    fn test_mmcif_parsing() {
+        let content = "data_TEST\n\
+#\n\
+loop_\n\
+_atom_site.group_PDB\n\
+_atom_site.id\n\
+_atom_site.type_symbol\n\
+_atom_site.label_atom_id\n\
+_atom_site.label_alt_id\n\
+_atom_site.label_comp_id\n\
+_atom_site.label_asym_id\n\
+_atom_site.label_seq_id\n\
+_atom_site.pdbx_PDB_ins_code\n\
+_atom_site.Cartn_x\n\
+_atom_site.Cartn_y\n\
+_atom_site.Cartn_z\n\
+_atom_site.occupancy\n\
+_atom_site.B_iso_or_equiv\n\
+_atom_site.auth_seq_id\n\
+_atom_site.auth_asym_id\n\
+_atom_site.pdbx_PDB_model_num\n\
+ATOM   1 N N   . ALA A 1 ? 10.000 10.000 10.000 1.00 20.00 1   H 1\n\
+ATOM   2 C CA  . ALA A 1 ? 11.500 10.000 10.000 1.00 20.00 1   H 1\n\
+ATOM   3 N N   B GLY A 2 A 12.000 10.000 10.000 0.50 30.00 100 H 1\n\
+HETATM 4 O \"O1'\" . HOH B . ? 0.000 0.000 0.000 1.00 10.00 201 H 1\n\
+ATOM   5 N N   . ALA A 1 ? 99.000 99.000 99.000 1.00 20.00 1   H 2\n\
+#\n";
+        let pdb = Pdb::parse(content, StructureFormat::Mmcif);
+        assert_eq!(pdb.atoms.len(), 4);
+        let ca = &pdb.atoms[1];
+        assert_eq!((ca.name.as_str(), ca.res_name.as_str(), ca.chain_id, ca.res_seq), ("CA", "ALA", 'H', 1));
+        assert_eq!(ca.pos.x, 11.5);
+        let gly = &pdb.atoms[2];
+        assert_eq!((gly.alt_loc, gly.i_code, gly.res_seq), ('B', 'A', 100));
+        assert_eq!(pdb.atoms[3].name, "O1'");
+        assert_eq!(pdb.get_sequence('H'), "AGX");
+    }
+
     #[test]
     fn test_three_to_one() {
         assert_eq!(three_to_one("ALA"), 'A');
         assert_eq!(three_to_one("UNK"), 'X');
+        assert_eq!(three_to_one("MSE"), 'M');
+        assert!(!is_amino_acid("HOH"));
+        for code in "ACDEFGHIKLMNPQRSTVWYX".chars() {
+            assert_eq!(three_to_one(one_to_three(code)), code);
+        }
     }
 }
\ No newline at end of file
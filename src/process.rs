@@ -1,99 +1,1541 @@
-use crate::db::Db;
-use crate::pdb::Pdb;
-use crate::numbering::{AnarciStrategy, NumberingStrategy};
+pub mod canonical;
+
+use crate::db::{AntibodyRecord, Db, DbFilter, LightType, StoredStructure, ProcessingStage, LAST_PROCESSING_RUN_KEY, NUMBERING_TOOL_KEY, QC_THRESHOLDS_KEY};
+use crate::pdb::{Atom, Pdb, Point, QcThresholds, QualityReport, ResidueId};
+use crate::progress::ProgressSink;
+use crate::analysis::{self, Shape};
+use crate::features::{self, ChainFeatures};
+use crate::numbering::{self, CachedStrategy, ChainNumbering, ChainType, LimitedStrategy, NumberingError, NumberingOutcome, NumberingResult, NumberingStrategy};
 use anyhow::Result;
-use log::{info, debug};
+use chrono::Utc;
+use log::{info, debug, warn};
 use rayon::prelude::*;
 use rusqlite::params;
+use serde::Serialize;
 use serde_json::json;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-pub fn process_all(db: &mut Db) -> Result<()> {
-    info!("Starting processing pipeline...");
-    
-    // Select unprocessed PDBs
-    let mut tasks = Vec::new();
-    {
-        let conn = db.get_conn();
-        let mut stmt = conn.prepare("SELECT pdb_id, pdb_blob, h_chain, l_chain FROM antibodies WHERE processed = FALSE AND pdb_blob IS NOT NULL")?;
-        let rows = stmt.query_map([], |row| {
-            let id: String = row.get(0)?;
-            let blob: Vec<u8> = row.get(1)?;
-            let h: String = row.get(2)?;
-            let l: String = row.get(3)?;
-            Ok((id, blob, h, l))
-        })?;
-        
-        for r in rows {
-            tasks.push(r?);
+/// k-mer length of the stored sequence profiles
+pub const KMER_K: usize = 3;
+
+/// Fabs whose chains are numbered in one batch and written back in one
+/// transaction unless configured otherwise
+pub const DEFAULT_CHUNK_SIZE: usize = 100;
+
+/// Fabs of a chunk whose structures are loaded and parsed at once unless
+/// configured otherwise
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 16;
+
+// Outcome of processing one entry, written back in a single transaction
+struct Processed {
+    fab_id: i64,
+    pdb_id: String,
+    json: serde_json::Value,
+    passed_qc: bool,
+    kmers: Vec<u8>,
+    shape: Shape,
+    fingerprint: Vec<f32>,
+    /// The Fab's chains, emptied once the features are computed
+    pdb: Pdb,
+    h_id: char,
+    l_id: char,
+    alternates: Vec<AlternateChain>,
+    h_seq: String,
+    l_seq: String,
+    /// Residues of the chains `h_seq` and `l_seq` are the codes of
+    h_residues: Vec<(ResidueId, char)>,
+    l_residues: Vec<(ResidueId, char)>,
+    /// Light chain type inferred from the numbering, kept only where the
+    /// summary had none
+    light_type: Option<&'static str>,
+    heavy_numbering: Option<ChainNumbering>,
+    light_numbering: Option<ChainNumbering>,
+    /// Domains of the other type than annotated in a chain with several, as
+    /// in scFvs
+    other_domains: Vec<ChainNumbering>,
+    /// Chains in which numbering found no antibody domain
+    without_domain: Vec<ChainType>,
+    /// Variable domains of `pdb`; None without a numbered chain
+    fv: Option<Pdb>,
+    /// Of `fv`, or of the whole chains without it
+    features: Vec<ChainFeatures>,
+    /// Of the numbered CDR-H3 and its anchor, as JSON; None without them
+    h3_loop: Option<String>,
+    /// For nearest-neighbour retrieval; None without a numbered heavy chain
+    embedding: Option<Vec<f32>>,
+    /// Stages that failed, replacing those recorded before
+    errors: Vec<(ProcessingStage, String)>,
+}
+
+impl Processed {
+    // Takes the domains numbered in one chain, or records why there are
+    // none. The chain's numbering is its first domain of the annotated type;
+    // without one the summary likely swapped or mislabelled the chains and
+    // nothing is kept.
+    fn set_numbering(&mut self, chain: ChainType, outcome: NumberingOutcome) {
+        let heavy = chain == ChainType::Heavy;
+        let stage = if heavy { ProcessingStage::NumberingH } else { ProcessingStage::NumberingL };
+        let domains = match outcome {
+            Ok(domains) => domains,
+            Err(e) => {
+                debug!("Failed to number {:?} chain of Fab {}: {}", chain, self.fab_id, e);
+                if e == NumberingError::NoDomainDetected {
+                    self.without_domain.push(chain);
+                }
+                self.errors.push((stage, e.to_string()));
+                return;
+            }
+        };
+        let annotated = |d: &NumberingResult| (d.chain_type == ChainType::Heavy) == heavy;
+        let residues = if heavy { &self.h_residues } else { &self.l_residues };
+        let map = |domain: &NumberingResult| ChainNumbering::map(residues, std::slice::from_ref(domain)).map_err(|e| e.to_string());
+        match domains.iter().find(|d| annotated(d)).map(map) {
+            Some(Ok(numbering)) if heavy => self.heavy_numbering = Some(numbering),
+            Some(Ok(numbering)) => self.light_numbering = Some(numbering),
+            Some(Err(e)) => self.errors.push((stage, e)),
+            None => {
+                let types: Vec<String> = domains.iter().map(|d| format!("{:?}", d.chain_type)).collect();
+                let error = format!("annotated as {} chain, numbered as {}", if heavy { "heavy" } else { "light" }, types.join(" and "));
+                warn!("Fab {}: {}", self.fab_id, error);
+                self.errors.push((stage, error));
+            }
+        }
+        if domains.len() > 1 {
+            self.other_domains.extend(domains.iter().filter(|d| !annotated(d)).filter_map(|d| map(d).ok()));
         }
     }
 
-    if tasks.is_empty() {
-        info!("Nothing to process.");
-        return Ok(());
+    // What follows from the numbering: the chains missing one numbered from
+    // the other domain of a single-chain construct, failing QC if that
+    // leaves a chain without any antibody domain, light chain type,
+    // embedding, the Fv atoms with the features taken from them and the
+    // CDR-H3 loop
+    fn finish_numbering(&mut self) {
+        if self.heavy_numbering.is_none() {
+            self.heavy_numbering = self.other_domains.iter().find(|d| d.chain_type == ChainType::Heavy).cloned();
+        }
+        if self.light_numbering.is_none() {
+            self.light_numbering = self.other_domains.iter().find(|d| d.chain_type != ChainType::Heavy).cloned();
+        }
+        let without_domain: Vec<&str> = std::mem::take(&mut self.without_domain).into_iter()
+            .filter_map(|chain| match chain {
+                ChainType::Heavy => self.heavy_numbering.is_none().then_some("heavy"),
+                _ => self.light_numbering.is_none().then_some("light"),
+            })
+            .collect();
+        if !without_domain.is_empty() {
+            self.passed_qc = false;
+            let chains = if without_domain.len() > 1 { "chains" } else { "chain" };
+            self.errors.push((ProcessingStage::Qc, format!("no antibody domain in the {} {}", without_domain.join(" and "), chains)));
+        }
+        let heavy = self.heavy_numbering.as_ref().map(ChainNumbering::numbered).unwrap_or_default();
+        let l_positions: Vec<_> = self.light_numbering.iter().flat_map(|n| n.residues.iter().filter_map(|r| r.position)).collect();
+        self.light_type = numbering::infer_light_type(&l_positions).map(|t| match t {
+            ChainType::Lambda => LightType::Lambda.as_str(),
+            _ => LightType::Kappa.as_str(),
+        });
+        self.embedding = (!heavy.is_empty())
+            .then(|| analysis::fab_embedding(&self.fingerprint, numbering::cdr_sequences(&heavy, ChainType::Heavy).map(|s| s.len())))
+            .flatten();
+        let numbered: HashSet<ResidueId> = self.heavy_numbering.iter().chain(&self.light_numbering)
+            .flat_map(|n| n.residues.iter().filter(|r| r.position.is_some()).map(|r| r.id))
+            .collect();
+        self.fv = (!numbered.is_empty()).then(|| self.pdb.extract_fv(&[self.h_id, self.l_id], &numbered));
+        self.features = features::fab_features(self.fv.as_ref().unwrap_or(&self.pdb), self.h_id, self.l_id);
+        // CDR-H3 through its W103 anchor
+        self.h3_loop = self.heavy_numbering.as_ref()
+            .and_then(|numbering| analysis::residue_loop_descriptors(&self.pdb, &numbering.h3_loop_residues()))
+            .and_then(|descriptors| serde_json::to_string(&descriptors).ok());
+        // Only the Fv, the features and the H3 loop are written back
+        self.pdb = Pdb { atoms: Vec::new() };
     }
+}
 
-    info!("Processing {} PDBs...", tasks.len());
-    
-    let strategy = AnarciStrategy::new();
+// A listed copy of a chain other than the one processed, kept in the JSON
+#[derive(Serialize)]
+struct AlternateChain {
+    chain_id: char,
+    #[serde(skip)]
+    chain: ChainType,
+    role: &'static str,
+    sequence: String,
+    resolved_residues: usize,
+    passed_qc: bool,
+    /// Whether numbering found a domain of the annotated type; None if the
+    /// copy was not numbered
+    numbered: Option<bool>,
+}
 
-    let processed_results: Vec<(String, String, usize, usize, bool)> = tasks.par_iter().map(|(id, blob, h_chain, l_chain)| {
-        let content = String::from_utf8_lossy(blob);
-        let pdb = Pdb::from_str(&content);
-        
-        // 1. Validation
-        let report = pdb.validate();
-        let passed_qc = report.is_pass();
-
-        // Extract sequences for chains
-        // H_chain field in DB might be "H" or "H,I" etc.
-        // We take the first one for MVP simplicity
-        let h_id = h_chain.chars().next().unwrap_or('H');
-        let l_id = l_chain.chars().next().unwrap_or('L');
-
-        let h_seq = pdb.get_sequence(h_id);
-        let l_seq = pdb.get_sequence(l_id);
-        
-        let mut numbered_h = Vec::new();
-        let mut numbered_l = Vec::new();
-
-        // Attempt numbering only if QC passed (optimization)
-        if passed_qc {
-            if !h_seq.is_empty() {
-                 match strategy.number(&h_seq, "antibody") {
-                     Ok(res) => numbered_h = res,
-                     Err(e) => debug!("Failed to number H chain for {}: {}", id, e),
-                 }
+// A listed copy of a chain with what choosing between copies goes by
+struct ChainCopy {
+    chain_id: char,
+    residues: Vec<(ResidueId, char)>,
+    passed_qc: bool,
+}
+
+impl ChainCopy {
+    fn alternate(&self, chain: ChainType) -> AlternateChain {
+        AlternateChain {
+            chain_id: self.chain_id,
+            chain,
+            role: if chain == ChainType::Heavy { "heavy" } else { "light" },
+            sequence: self.residues.iter().map(|&(_, code)| code).collect(),
+            resolved_residues: self.residues.len(),
+            passed_qc: self.passed_qc,
+            numbered: None,
+        }
+    }
+}
+
+// An entry whose structure could not be read; its Fab stays unprocessed
+struct ParseFailure {
+    fab_id: i64,
+    pdb_id: String,
+    error: String,
+}
+
+// A Fab's structure as quality control found it
+struct CheckedFab {
+    /// Chains standing for the Fab among the listed copies
+    h_id: char,
+    l_id: char,
+    /// Of the heavy with the light chain
+    interface_contacts: usize,
+    report: QualityReport,
+    passed_qc: bool,
+}
+
+/// How the Fabs of a QC run came out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QcSummary {
+    /// Fabs checked, whether they passed or not
+    pub checked: usize,
+    pub passed_qc: usize,
+    pub failed_qc: usize,
+    /// Fabs whose structure could not be read, left unchecked
+    pub unreadable: usize,
+    /// What changed of the Fabs checked before, under new thresholds
+    pub changes: QcChanges,
+}
+
+impl fmt::Display for QcSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.changes != QcChanges::default() {
+            writeln!(f, "{}", self.changes)?;
+        }
+        write!(
+            f,
+            "Checked {} Fabs: {} passed QC, {} failed QC; {} unreadable",
+            self.checked, self.passed_qc, self.failed_qc, self.unreadable
+        )
+    }
+}
+
+/// How the Fabs of a processing run came out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProcessingSummary {
+    /// Fabs processed, whether they passed QC or not
+    pub processed: usize,
+    pub passed_qc: usize,
+    pub failed_qc: usize,
+    /// Processed Fabs with a chain that could not be numbered
+    pub numbering_failed: usize,
+    /// Fabs whose structure could not be read, left unprocessed
+    pub unreadable: usize,
+}
+
+impl fmt::Display for ProcessingSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Processed {} Fabs: {} passed QC, {} failed QC, {} with numbering failures; {} unreadable",
+            self.processed, self.passed_qc, self.failed_qc, self.numbering_failed, self.unreadable
+        )
+    }
+}
+
+// Running totals of a processing run, kept up by the workers
+#[derive(Default)]
+struct Tally {
+    processed: AtomicUsize,
+    passed_qc: AtomicUsize,
+    numbering_failed: AtomicUsize,
+    unreadable: AtomicUsize,
+}
+
+impl Tally {
+    // Counts an entry parsed and checked, or left unread
+    fn entry(&self, outcome: &std::result::Result<Processed, ParseFailure>, progress: &dyn ProgressSink) {
+        match outcome {
+            Ok(p) => {
+                self.processed.fetch_add(1, Ordering::Relaxed);
+                if p.passed_qc {
+                    self.passed_qc.fetch_add(1, Ordering::Relaxed);
+                }
+                progress.succeeded(&p.pdb_id);
             }
-            if !l_seq.is_empty() {
-                 match strategy.number(&l_seq, "antibody") {
-                     Ok(res) => numbered_l = res,
-                     Err(e) => debug!("Failed to number L chain for {}: {}", id, e),
-                 }
+            Err(failure) => {
+                self.unreadable.fetch_add(1, Ordering::Relaxed);
+                progress.failed(&failure.pdb_id, &failure.error);
             }
         }
+        progress.status(&self.status());
+    }
+
+    // Counts what numbering changed of a Fab counted by `entry`
+    fn numbered(&self, p: &Processed, passed_structure_qc: bool) {
+        if passed_structure_qc && !p.passed_qc {
+            self.passed_qc.fetch_sub(1, Ordering::Relaxed);
+        }
+        if p.errors.iter().any(|(stage, _)| matches!(stage, ProcessingStage::NumberingH | ProcessingStage::NumberingL)) {
+            self.numbering_failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn summary(&self) -> ProcessingSummary {
+        let (processed, passed_qc) = (self.processed.load(Ordering::Relaxed), self.passed_qc.load(Ordering::Relaxed));
+        ProcessingSummary {
+            processed,
+            passed_qc,
+            failed_qc: processed - passed_qc,
+            numbering_failed: self.numbering_failed.load(Ordering::Relaxed),
+            unreadable: self.unreadable.load(Ordering::Relaxed),
+        }
+    }
+
+    fn status(&self) -> String {
+        let summary = self.summary();
+        format!(
+            "{:.0}% passed QC, {} numbering failures",
+            100.0 * summary.passed_qc as f64 / summary.processed.max(1) as f64,
+            summary.numbering_failed
+        )
+    }
+}
+
+/// Settings of the processing stage.
+#[derive(Debug, Clone)]
+pub struct ProcessOptions {
+    /// Identity both chains need for two Fabs to count as the same clone
+    pub cluster_identity: f64,
+    /// Numbering tool processes run at once for single sequences; batches
+    /// are one run anyway
+    pub numbering_concurrency: usize,
+    /// Fabs processed and committed together. Whatever a run committed is
+    /// kept if it is interrupted, the next one resumes after it.
+    pub chunk_size: usize,
+    /// Fabs of a chunk whose structures are loaded and parsed at once. Each
+    /// structure is dropped once parsed and the Fab's chains once its
+    /// features are, so peak memory is about this many entries plus a
+    /// chunk of Fabs.
+    pub max_in_flight: usize,
+    /// QC thresholds to apply from now on, re-evaluating the processed Fabs
+    /// if they changed; None keeps those in use
+    pub qc: Option<QcThresholds>,
+}
+
+impl Default for ProcessOptions {
+    fn default() -> Self {
+        Self {
+            cluster_identity: analysis::DEFAULT_CLUSTER_IDENTITY,
+            numbering_concurrency: numbering::default_concurrency(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            qc: None,
+        }
+    }
+}
+
+/// `process_all` numbering with the backend `numbering::detect` finds,
+/// unless the numbering cache has the chains. The tool and its version are
+/// recorded for diagnostics.
+pub fn process_all_default(db: &Db, options: &ProcessOptions, progress: &dyn ProgressSink) -> Result<()> {
+    db.meta_set(NUMBERING_TOOL_KEY, &numbering::detected_backend().version())?;
+    process_all(db, &CachedStrategy::new(numbering::detect(), db), options, progress)
+}
+
+/// Checks every pending Fab with `run_qc`, then numbers and describes it
+/// with `run_numbering`, reporting each to `progress`.
+pub fn process_all(db: &Db, strategy: &(dyn NumberingStrategy + Sync), options: &ProcessOptions, progress: &dyn ProgressSink) -> Result<()> {
+    let thresholds = match &options.qc {
+        Some(thresholds) => *thresholds,
+        None => qc_thresholds(db)?,
+    };
+    run_qc(db, &thresholds, options.chunk_size, progress)?;
+    run_numbering(db, strategy, options, progress)?;
+    Ok(())
+}
+
+/// Quality control on its own: applies `thresholds` to the Fabs checked
+/// before if they differ from those in use, then checks those with a stored
+/// structure not checked yet. Only the QC columns and QC failures are
+/// written; numbering is left to `run_numbering`.
+pub fn run_qc(db: &Db, thresholds: &QcThresholds, chunk_size: usize, progress: &dyn ProgressSink) -> Result<QcSummary> {
+    let changes = reevaluate_qc(db, thresholds)?;
+    if changes != QcChanges::default() {
+        info!("{}", changes);
+    }
+    let records = db.list_antibodies(&DbFilter { qc_checked: Some(false), with_structure: true, skip_blacklisted: true, ..Default::default() })?;
+    if records.is_empty() {
+        return Ok(QcSummary { changes, ..Default::default() });
+    }
+    info!("Checking {} Fabs...", records.len());
+    progress.start("Checking", records.len());
+    let mut summary = QcSummary { changes, ..Default::default() };
+    for chunk in records.chunks(chunk_size.max(1)) {
+        check_chunk(db, chunk, thresholds, &mut summary, progress)?;
+    }
+    progress.finish();
+    info!("{}", summary);
+    Ok(summary)
+}
+
+// Checks some Fabs and writes back their QC columns
+fn check_chunk(db: &Db, records: &[AntibodyRecord], qc: &QcThresholds, summary: &mut QcSummary, progress: &dyn ProgressSink) -> Result<()> {
+    let mut tasks = Vec::new();
+    for record in records {
+        if let Some(structure) = db.load_structure(&record.pdb_id)? {
+            tasks.push((record, structure));
+        }
+    }
+    let outcomes: Vec<(&AntibodyRecord, std::result::Result<CheckedFab, ParseFailure>)> = tasks.par_iter()
+        .map(|&(record, ref structure)| (record, check_fab(record, structure, qc)))
+        .collect();
+
+    db.with_transaction(|tx| {
+        let mut stmt = tx.prepare("UPDATE antibodies SET missing_backbone = ?1, gaps = ?2, cis_nonproline = ?3, rama_outlier_fraction = ?4, passed_qc = ?5, primary_h_chain = ?6, primary_l_chain = ?7, interface_contacts = ?8, qc_report = ?9 WHERE fab_id = ?10")?;
+        for (record, outcome) in outcomes {
+            let fab_id = record.fab_id;
+            let checked = match outcome {
+                Ok(checked) => checked,
+                Err(failure) => {
+                    summary.unreadable += 1;
+                    progress.failed(&failure.pdb_id, &failure.error);
+                    db.record_processing_error(fab_id, ProcessingStage::Parse, &failure.error)?;
+                    continue;
+                }
+            };
+            let r = &checked.report;
+            stmt.execute(params![
+                r.missing_backbone_residues as u32,
+                (r.geometric_gaps + r.numbering_gaps) as u32,
+                r.cis_nonproline_count as u32,
+                r.rama_outlier_fraction,
+                checked.passed_qc,
+                checked.h_id.to_string(),
+                checked.l_id.to_string(),
+                checked.interface_contacts as i64,
+                serde_json::to_string(r)?,
+                fab_id
+            ])?;
+            db.clear_processing_error(fab_id, ProcessingStage::Parse)?;
+            if checked.passed_qc {
+                summary.passed_qc += 1;
+                db.clear_processing_error(fab_id, ProcessingStage::Qc)?;
+            } else {
+                summary.failed_qc += 1;
+                db.record_processing_error(fab_id, ProcessingStage::Qc, &qc_error(r))?;
+            }
+            summary.checked += 1;
+            progress.succeeded(&record.pdb_id);
+        }
+        Ok(())
+    })?;
+    db.checkpoint()?;
+    Ok(())
+}
+
+/// Numbers and describes with `strategy` every Fab `run_qc` checked that is
+/// not processed yet, then regroups the clones if anything changed. The QC
+/// columns are left as checked.
+pub fn run_numbering(db: &Db, strategy: &(dyn NumberingStrategy + Sync), options: &ProcessOptions, progress: &dyn ProgressSink) -> Result<ProcessingSummary> {
+    let strategy = LimitedStrategy::new(strategy, options.numbering_concurrency);
+    let summary = process_pending(db, &strategy, options, progress)?;
+    backfill_features(db)?;
+    // Representatives are chosen by QC too, so a clone whose representative
+    // fails QC while another member passes is out of date
+    let stale: bool = db.get_conn().query_row(
+        "SELECT EXISTS (SELECT 1 FROM antibodies WHERE processed = TRUE AND status = 'current' AND cluster_id IS NULL)
+             OR EXISTS (SELECT 1 FROM antibodies r JOIN antibodies m ON m.cluster_id = r.fab_id AND m.fab_id <> r.fab_id
+                 WHERE r.cluster_representative = TRUE AND r.passed_qc IS NOT TRUE AND m.passed_qc = TRUE AND m.status = 'current')",
+        [],
+        |row| row.get(0),
+    )?;
+    if summary.processed > 0 || stale {
+        cluster_clones(db, options.cluster_identity)?;
+    }
+    db.meta_set(LAST_PROCESSING_RUN_KEY, &Utc::now().to_rfc3339())?;
+    Ok(summary)
+}
+
+/// Processes again the Fabs whose numbering failed or is missing for a chain,
+/// e.g. once the numbering tool is installed, under the QC thresholds in
+/// use. Regroups the clones if there were any.
+pub fn process_failed(db: &Db, strategy: &(dyn NumberingStrategy + Sync), options: &ProcessOptions, progress: &dyn ProgressSink) -> Result<ProcessingSummary> {
+    let records = db.fabs_with_failed_numbering()?;
+    if records.is_empty() {
+        info!("No failed numbering to retry.");
+        return Ok(ProcessingSummary::default());
+    }
+    info!("Retrying {} Fabs with failed numbering...", records.len());
+    let strategy = LimitedStrategy::new(strategy, options.numbering_concurrency);
+    let chunks = records.chunks(options.chunk_size.max(1)).map(|chunk| Ok(chunk.to_vec()));
+    let summary = process_chunks(db, &strategy, records.len(), chunks, options.max_in_flight, progress)?;
+    cluster_clones(db, options.cluster_identity)?;
+    Ok(summary)
+}
+
+/// `process_failed` with the backend `process_all_default` uses.
+pub fn process_failed_default(db: &Db, options: &ProcessOptions, progress: &dyn ProgressSink) -> Result<ProcessingSummary> {
+    db.meta_set(NUMBERING_TOOL_KEY, &numbering::detected_backend().version())?;
+    process_failed(db, &CachedStrategy::new(numbering::detect(), db), options, progress)
+}
+
+// Numbers and describes the unprocessed Fabs QC checked, recording the
+// stages that failed. The Fabs are read a chunk at a time, so their number
+// does not bound memory either.
+fn process_pending(db: &Db, strategy: &(dyn NumberingStrategy + Sync), options: &ProcessOptions, progress: &dyn ProgressSink) -> Result<ProcessingSummary> {
+    info!("Starting processing pipeline...");
+    let filter = DbFilter { processed: Some(false), qc_checked: Some(true), with_structure: true, skip_blacklisted: true, ..Default::default() };
+    let pending = db.count_antibodies(&filter)?;
+    if pending == 0 {
+        info!("Nothing to process.");
+        return Ok(ProcessingSummary::default());
+    }
+
+    info!("Processing {} Fabs...", pending);
+    // Fabs that fail to parse stay pending, so the pages go by fab_id
+    let mut after = None;
+    let chunks = std::iter::from_fn(|| {
+        let page = db.list_antibodies_page(&filter, after, options.chunk_size.max(1));
+        if let Ok(page) = &page {
+            after = Some(page.last()?.fab_id);
+        }
+        Some(page.map_err(Into::into))
+    });
+    process_chunks(db, strategy, pending, chunks, options.max_in_flight, progress)
+}
+
+// Processes chunks of Fabs under the QC thresholds in use. Each chunk is
+// committed, along with what it added to the numbering cache, before the
+// next one is read.
+fn process_chunks(
+    db: &Db,
+    strategy: &(dyn NumberingStrategy + Sync),
+    total: usize,
+    chunks: impl Iterator<Item = Result<Vec<AntibodyRecord>>>,
+    max_in_flight: usize,
+    progress: &dyn ProgressSink,
+) -> Result<ProcessingSummary> {
+    progress.start("Processing", total);
+    let qc = qc_thresholds(db)?;
+    let tally = Tally::default();
+    for chunk in chunks {
+        process_chunk(db, strategy, &chunk?, &qc, max_in_flight, &tally, progress)?;
+        progress.status(&tally.status());
+    }
+    progress.finish();
+    let summary = tally.summary();
+    info!("{}", summary);
+    Ok(summary)
+}
+
+// Processes some Fabs, numbering all their chains in one batch, and writes
+// them back, counting them in `tally` as they go.
+fn process_chunk(
+    db: &Db,
+    strategy: &(dyn NumberingStrategy + Sync),
+    records: &[AntibodyRecord],
+    qc: &QcThresholds,
+    max_in_flight: usize,
+    tally: &Tally,
+    progress: &dyn ProgressSink,
+) -> Result<()> {
+    let describe = |(record, structure): (&AntibodyRecord, StoredStructure)| -> std::result::Result<Processed, ParseFailure> {
+        let AntibodyRecord { fab_id, pdb_id: id, h_chain, l_chain, .. } = record;
+        // 1. The Fab's chains and quality report as `run_qc` stored them.
+        // Its QC flag may stand for a failed numbering, to be retried.
+        let entry = parse_structure(record, &structure)?;
+        drop(structure);
+        let Some(report) = record.qc_report.as_deref().and_then(|r| serde_json::from_str::<QualityReport>(r).ok()) else {
+            return Err(ParseFailure { fab_id: *fab_id, pdb_id: id.clone(), error: "no readable QC report".to_string() });
+        };
+        let passed_qc = report.is_pass_with(qc);
+        let (h_id, l_id) = record.chain_ids();
+        let alternates = alternate_chains(&entry, record, h_id, l_id, qc);
+        let pdb = select_fab(entry, h_id, l_id);
+        let mut errors = Vec::new();
+        if !passed_qc {
+            errors.push((ProcessingStage::Qc, qc_error(&report)));
+        }
+
+        let fv_ca: Vec<Point> = analysis::ca_trace(&pdb.atoms).iter()
+            .filter(|a| a.chain_id == h_id || a.chain_id == l_id)
+            .map(|a| a.pos)
+            .collect();
+        let shape = analysis::shape_descriptors(&fv_ca);
+
+        let h_residues = pdb.numbered_sequence(h_id);
+        let l_residues = pdb.numbered_sequence(l_id);
+        let h_seq: String = h_residues.iter().map(|&(_, code)| code).collect();
+        let l_seq: String = l_residues.iter().map(|&(_, code)| code).collect();
+
+        let rama = analysis::ramachandran(&pdb.atoms);
+        let angles: Vec<(f64, f64)> = rama.iter().map(|p| (p.phi, p.psi)).collect();
+        let fingerprint = analysis::rama_fingerprint(&angles, analysis::FINGERPRINT_BINS);
+
+        // Conformational state string per chain
+        let mut rama_strings: BTreeMap<String, String> = BTreeMap::new();
+        for p in &rama {
+            rama_strings.entry(p.chain_id.to_string()).or_default().push(analysis::rama_state(p.phi, p.psi));
+        }
 
         // Store result as JSON
         let json_meta = json!({
             "status": "processed", 
             "id": id,
+            "h_chain": h_chain,
+            "l_chain": l_chain,
             "h_chain_seq": h_seq,
             "l_chain_seq": l_seq,
-            "h_numbering": numbered_h,
-            "l_numbering": numbered_l,
-            "qc": report
+            "qc": report,
+            "rama": rama,
+            "shape": shape,
+            "rama_strings": rama_strings,
+            "approximate_numbering": strategy.is_approximate()
         });
         
-        (id.clone(), json_meta.to_string(), report.missing_backbone_residues, report.geometric_gaps + report.numbering_gaps, passed_qc)
+        let kmers = analysis::kmer_profile(&analysis::structure_sequence(&pdb.atoms), KMER_K).to_bytes();
+
+        Ok(Processed {
+            fab_id: *fab_id,
+            pdb_id: id.clone(),
+            json: json_meta,
+            passed_qc,
+            kmers,
+            shape,
+            fingerprint,
+            pdb,
+            h_id,
+            l_id,
+            alternates,
+            h_seq,
+            l_seq,
+            h_residues,
+            l_residues,
+            light_type: None,
+            heavy_numbering: None,
+            light_numbering: None,
+            other_domains: Vec::new(),
+            without_domain: Vec::new(),
+            fv: None,
+            features: Vec::new(),
+            h3_loop: None,
+            embedding: None,
+            errors,
+        })
+    };
+    // Structures are loaded `max_in_flight` at a time, still compressed,
+    // and each is dropped once parsed
+    let mut outcomes = Vec::with_capacity(records.len());
+    for group in records.chunks(max_in_flight.max(1)) {
+        let mut tasks = Vec::new();
+        for record in group {
+            if let Some(structure) = db.load_structure(&record.pdb_id)? {
+                tasks.push((record, structure));
+            }
+        }
+        outcomes.par_extend(tasks.into_par_iter().map(&describe).inspect(|outcome| tally.entry(outcome, progress)));
+    }
+
+    // 2. Numbering of the chains of the Fabs that passed QC, all in one go
+    let mut chains = Vec::new();
+    let mut sequences = Vec::new();
+    for (i, outcome) in outcomes.iter().enumerate() {
+        let Ok(p) = outcome else { continue };
+        if !p.passed_qc {
+            continue;
+        }
+        for (chain, name, seq) in [(ChainType::Heavy, "H", &p.h_seq), (ChainType::Kappa, "L", &p.l_seq)] {
+            if !seq.is_empty() {
+                chains.push((i, chain, None));
+                sequences.push((format!("{}_{}", p.fab_id, name), seq.clone()));
+            }
+        }
+        for (k, alternate) in p.alternates.iter().enumerate() {
+            if !alternate.sequence.is_empty() {
+                chains.push((i, alternate.chain, Some(k)));
+                sequences.push((format!("{}_alt_{}", p.fab_id, alternate.chain_id), alternate.sequence.clone()));
+            }
+        }
+    }
+    let numbered = number_chains(strategy, &sequences)?;
+    // Whatever the strategy, numbering that does not map back onto the
+    // chain's residues is not kept
+    for (((i, chain, alternate), (_, sequence)), outcome) in chains.into_iter().zip(&sequences).zip(numbered) {
+        let outcome = outcome.and_then(|domains| {
+            domains.iter().try_for_each(|d| d.check_sequence(sequence))?;
+            Ok(domains)
+        });
+        let Ok(p) = &mut outcomes[i] else { continue };
+        match alternate {
+            Some(k) => {
+                let annotated = |d: &NumberingResult| (d.chain_type == ChainType::Heavy) == (chain == ChainType::Heavy);
+                p.alternates[k].numbered = Some(outcome.is_ok_and(|domains| domains.iter().any(annotated)));
+            }
+            None => p.set_numbering(chain, outcome),
+        }
+    }
+    outcomes.par_iter_mut().filter_map(|outcome| outcome.as_mut().ok()).for_each(|p| {
+        let passed_structure_qc = p.passed_qc;
+        p.finish_numbering();
+        tally.numbered(p, passed_structure_qc);
+    });
+
+    db.with_transaction(|tx| {
+        // The QC columns are `run_qc`'s, which checked these Fabs already
+        let mut stmt = tx.prepare("UPDATE antibodies SET processed = TRUE, json_blob = ?1, passed_qc = ?2, kmer_profile = ?3, rg = ?4, asphericity = ?5, acylindricity = ?6, rama_fingerprint = ?7, light_type = COALESCE(light_type, ?8), numbered_h = ?9, numbered_l = ?10, h3_loop = ?11 WHERE fab_id = ?12")?;
+        for outcome in outcomes {
+            let mut p = match outcome {
+                Ok(p) => p,
+                Err(failure) => {
+                    db.record_processing_errors(failure.fab_id, &[(ProcessingStage::Parse, failure.error)])?;
+                    continue;
+                }
+            };
+            if !p.alternates.is_empty() {
+                p.json["alternate_chains"] = json!(p.alternates);
+            }
+            stmt.execute(params![
+                p.json.to_string(),
+                p.passed_qc,
+                p.kmers,
+                p.shape.rg,
+                p.shape.asphericity,
+                p.shape.acylindricity,
+                analysis::fingerprint_to_bytes(&p.fingerprint),
+                p.light_type,
+                p.heavy_numbering.is_some(),
+                p.light_numbering.is_some(),
+                p.h3_loop,
+                p.fab_id
+            ])?;
+            for (chain, numbered) in [(ChainType::Heavy, &p.heavy_numbering), (ChainType::Kappa, &p.light_numbering)] {
+                match numbered {
+                    Some(numbered) => db.store_chain_numbering(p.fab_id, chain, numbered)?,
+                    None => db.store_numbering(p.fab_id, chain, numbering::SCHEME, &[])?,
+                }
+            }
+            db.store_features(p.fab_id, &p.features)?;
+            db.store_fv(p.fab_id, p.fv.as_ref())?;
+            db.store_embedding(p.fab_id, p.embedding.as_deref())?;
+            db.record_processing_errors(p.fab_id, &p.errors)?;
+        }
+        Ok(())
+    })?;
+    db.checkpoint()?;
+    Ok(())
+}
+
+// Numbering of the chains of a chunk. Chains whose run failed are numbered
+// once more together, as the tool may fail for passing reasons like running
+// out of memory. Errs if the tool is missing, which no chain gets past.
+fn number_chains(strategy: &(dyn NumberingStrategy + Sync), sequences: &[(String, String)]) -> Result<Vec<NumberingOutcome>> {
+    let mut outcomes = match strategy.number_batch(sequences) {
+        Ok(outcomes) => outcomes,
+        Err(e) => vec![Err(e); sequences.len()],
+    };
+    let failed: Vec<usize> = (0..outcomes.len()).filter(|&i| matches!(outcomes[i], Err(NumberingError::ExecutionFailed { .. }))).collect();
+    if !failed.is_empty() {
+        warn!("Numbering failed for {} chains, retrying them once", failed.len());
+        let retry: Vec<(String, String)> = failed.iter().map(|&i| sequences[i].clone()).collect();
+        match strategy.number_batch(&retry) {
+            Ok(retried) => failed.into_iter().zip(retried).for_each(|(i, outcome)| outcomes[i] = outcome),
+            Err(e) => failed.into_iter().for_each(|i| outcomes[i] = Err(e.clone())),
+        }
+    }
+    if let Some(Err(e @ NumberingError::BinaryNotFound(_))) = outcomes.iter().find(|o| matches!(o, Err(NumberingError::BinaryNotFound(_)))) {
+        return Err(anyhow::anyhow!(e.clone()).context("Processing stopped, the numbering tool is missing"));
+    }
+    Ok(outcomes)
+}
+
+// Parses a Fab's structure, picks its chains among the listed copies and
+// validates them
+fn check_fab(record: &AntibodyRecord, structure: &StoredStructure, qc: &QcThresholds) -> std::result::Result<CheckedFab, ParseFailure> {
+    let entry = parse_structure(record, structure)?;
+
+    // Of several listed copies of a chain the best stands for the Fab
+    let h_copies = chain_copies(&entry, &record.h_chains(), 'H', qc);
+    let h_id = h_copies[0].chain_id;
+    // The summary now and then pairs a heavy chain with the light chain
+    // of another Fab, so a copy in contact with it is preferred
+    let trace = analysis::ca_trace(&entry.atoms);
+    let chain_ca = |chain_id: char| -> Vec<Atom> { trace.iter().filter(|a| a.chain_id == chain_id).cloned().collect() };
+    let h_ca = chain_ca(h_id);
+    let contacts = |chain_id: char| analysis::interface_contacts(&h_ca, &chain_ca(chain_id), analysis::INTERFACE_CUTOFF);
+    let mut l_copies = chain_copies(&entry, &record.l_chains(), 'L', qc);
+    if l_copies.len() > 1 {
+        l_copies.sort_by_cached_key(|c| std::cmp::Reverse(contacts(c.chain_id) > 0));
+    }
+    let l_id = l_copies[0].chain_id;
+    let interface_contacts = contacts(l_id);
+    let pairing_suspect = interface_contacts == 0 && !h_copies[0].residues.is_empty() && !l_copies[0].residues.is_empty();
+
+    // Everything below describes this Fab only, other copies in the
+    // asymmetric unit have their own rows
+    let pdb = select_fab(entry, h_id, l_id);
+    let mut report = pdb.validate();
+    report.pairing_suspect = pairing_suspect;
+    let passed_qc = report.is_pass_with(qc);
+    Ok(CheckedFab { h_id, l_id, interface_contacts, report, passed_qc })
+}
+
+// Parses the stored structure of a Fab's entry
+fn parse_structure(record: &AntibodyRecord, structure: &StoredStructure) -> std::result::Result<Pdb, ParseFailure> {
+    let id = &record.pdb_id;
+    let content = match structure.text() {
+        Ok(content) => content,
+        Err(e) => {
+            warn!("Skipping {}, its stored structure is unreadable: {}", id, e);
+            return Err(ParseFailure { fab_id: record.fab_id, pdb_id: id.clone(), error: format!("unreadable structure: {}", e) });
+        }
+    };
+    let entry = Pdb::parse(&content, structure.format);
+    if entry.atoms.is_empty() {
+        warn!("Skipping {}, no atoms in its structure", id);
+        return Err(ParseFailure { fab_id: record.fab_id, pdb_id: id.clone(), error: "no atoms in the structure".to_string() });
+    }
+    Ok(entry)
+}
+
+// The listed copies of the Fab's chains other than `h_id` and `l_id`, which
+// are only described, best first
+fn alternate_chains(entry: &Pdb, record: &AntibodyRecord, h_id: char, l_id: char, qc: &QcThresholds) -> Vec<AlternateChain> {
+    [(ChainType::Heavy, record.h_chains(), 'H', h_id), (ChainType::Kappa, record.l_chains(), 'L', l_id)].into_iter()
+        .flat_map(|(chain, listed, default, chosen)| {
+            chain_copies(entry, &listed, default, qc).into_iter()
+                .filter(move |c| c.chain_id != chosen)
+                .map(move |c| c.alternate(chain))
+        })
+        .collect()
+}
+
+// The listed copies of a chain, `default` if none is listed, best first:
+// present in the structure, passing QC on its own, most residues resolved,
+// then in listed order
+fn chain_copies(entry: &Pdb, listed: &[char], default: char, qc: &QcThresholds) -> Vec<ChainCopy> {
+    let listed = if listed.is_empty() { vec![default] } else { listed.to_vec() };
+    let several = listed.len() > 1;
+    let mut copies: Vec<ChainCopy> = listed.into_iter().map(|chain_id| ChainCopy {
+        chain_id,
+        residues: entry.numbered_sequence(chain_id),
+        // Nothing to choose between with a single copy
+        passed_qc: several && entry.select_chains(&[chain_id]).validate().is_pass_with(qc),
     }).collect();
+    copies.sort_by_key(|c| std::cmp::Reverse((!c.residues.is_empty(), c.passed_qc, c.residues.len())));
+    copies
+}
+
+// The Fab's chains of an entry, the whole entry if none of them are present
+fn select_fab(entry: Pdb, h_id: char, l_id: char) -> Pdb {
+    let fab = entry.select_chains(&[h_id, l_id]);
+    if fab.atoms.is_empty() { entry } else { fab }
+}
+
+// Why a structure failed quality control
+fn qc_error(report: &QualityReport) -> String {
+    if report.pairing_suspect {
+        return "heavy and light chain not in contact, likely from different Fabs".to_string();
+    }
+    format!(
+        "{} geometric gaps, {} residues missing backbone atoms, {} numbering gaps",
+        report.geometric_gaps, report.missing_backbone_residues, report.numbering_gaps
+    )
+}
+
+/// The QC thresholds the stored QC flags follow.
+pub fn qc_thresholds(db: &Db) -> Result<QcThresholds> {
+    Ok(db.meta_get(QC_THRESHOLDS_KEY)?.and_then(|t| serde_json::from_str(&t).ok()).unwrap_or_default())
+}
+
+/// Checked Fabs whose QC outcome `reevaluate_qc` changed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QcChanges {
+    pub failed: usize,
+    /// Passing now; those processed already are left for processing to
+    /// number
+    pub passed: usize,
+}
+
+impl fmt::Display for QcChanges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QC thresholds changed: {} Fabs fail now, {} pass", self.failed, self.passed)
+    }
+}
+
+/// Applies `thresholds` to the stored quality reports of the checked Fabs
+/// if they differ from those the QC flags follow. Fabs failing now are only
+/// marked failed; processed ones passing now were never numbered and are
+/// marked unprocessed. Fabs failing for want of an antibody domain stay
+/// failed.
+pub fn reevaluate_qc(db: &Db, thresholds: &QcThresholds) -> Result<QcChanges> {
+    let previous = qc_thresholds(db)?;
+    if previous == *thresholds {
+        return Ok(QcChanges::default());
+    }
+    let changes = db.with_transaction(|tx| {
+        let mut stmt = tx.prepare("SELECT fab_id, processed, passed_qc, qc_report FROM antibodies WHERE qc_report IS NOT NULL")?;
+        let rows = stmt.query_map([], |row| Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, bool>(1)?,
+            row.get::<_, Option<bool>>(2)?.unwrap_or(false),
+            row.get::<_, String>(3)?,
+        )))?.collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut changes = QcChanges::default();
+        for (fab_id, processed, passed_qc, report) in rows {
+            let Ok(report) = serde_json::from_str::<QualityReport>(&report) else { continue };
+            let passes = report.is_pass_with(thresholds);
+            if passed_qc && !passes {
+                tx.execute("UPDATE antibodies SET passed_qc = FALSE WHERE fab_id = ?1", [fab_id])?;
+                db.record_processing_error(fab_id, ProcessingStage::Qc, &qc_error(&report))?;
+                changes.failed += 1;
+            } else if !passed_qc && passes && !(processed && report.is_pass_with(&previous)) {
+                if processed {
+                    tx.execute("UPDATE antibodies SET processed = FALSE WHERE fab_id = ?1", [fab_id])?;
+                } else {
+                    tx.execute("UPDATE antibodies SET passed_qc = TRUE WHERE fab_id = ?1", [fab_id])?;
+                    db.clear_processing_error(fab_id, ProcessingStage::Qc)?;
+                }
+                changes.passed += 1;
+            }
+        }
+        db.meta_set(QC_THRESHOLDS_KEY, &serde_json::to_string(thresholds)?)?;
+        Ok(changes)
+    })?;
+    db.checkpoint()?;
+    Ok(changes)
+}
 
+// Computes the matching features of Fabs processed before they were kept,
+// or kept in an older version, with their Fv atoms from the stored
+// numbering. Fabs whose chains are missing from their structure get none
+// and are retried. Returns how many got features.
+fn backfill_features(db: &Db) -> Result<usize> {
+    let records = db.fabs_needing_features()?;
+    if records.is_empty() {
+        return Ok(0);
+    }
+    info!("Computing matching features of {} Fabs...", records.len());
+    let computed: Vec<(i64, Option<Pdb>, Vec<ChainFeatures>)> = records.par_iter().filter_map(|record| {
+        let structure = match db.load_structure(&record.pdb_id) {
+            Ok(structure) => structure?,
+            Err(e) => {
+                warn!("Could not load the structure of {}: {}", record.pdb_id, e);
+                return None;
+            }
+        };
+        let content = structure.text().inspect_err(|e| warn!("Skipping {}, its stored structure is unreadable: {}", record.pdb_id, e)).ok()?;
+        let (h_id, l_id) = record.chain_ids();
+        let pdb = select_fab(Pdb::parse(&content, structure.format), h_id, l_id);
+        let numbered = db.numbered_residues(record.fab_id).inspect_err(|e| warn!("Could not load the numbering of {}: {}", record.pdb_id, e)).ok()?;
+        let fv = (!numbered.is_empty()).then(|| pdb.extract_fv(&[h_id, l_id], &numbered));
+        let chains = features::fab_features(fv.as_ref().unwrap_or(&pdb), h_id, l_id);
+        Some((record.fab_id, fv, chains))
+    }).filter(|(_, _, chains)| !chains.is_empty()).collect();
+
+    db.with_transaction(|_| {
+        for (fab_id, fv, chains) in &computed {
+            db.store_features(*fab_id, chains)?;
+            db.store_fv(*fab_id, fv.as_ref())?;
+        }
+        Ok(())
+    })?;
+    db.checkpoint()?;
+    Ok(computed.len())
+}
+
+/// Groups the current processed Fabs into clones at `identity` over both
+/// chains. Representatives are preferred by QC, then resolution; every member
+/// stores its representative's fab_id as cluster_id. Returns the cluster count.
+pub fn cluster_clones(db: &Db, identity: f64) -> Result<usize> {
     let conn = db.get_conn();
-    conn.execute("BEGIN TRANSACTION", [])?;
-    let mut stmt = conn.prepare("UPDATE antibodies SET processed = TRUE, json_blob = ?1, missing_backbone = ?2, gaps = ?3, passed_qc = ?4 WHERE pdb_id = ?5")?;
-    for (id, json, missing, gaps, passed) in processed_results {
-        stmt.execute(params![json, missing as u32, gaps as u32, passed, id])?;
+    let mut stmt = conn.prepare(
+        "SELECT fab_id, json_blob FROM antibodies
+         WHERE processed = TRUE AND status = 'current' AND json_blob IS NOT NULL
+         ORDER BY passed_qc DESC, resolution IS NULL, resolution, fab_id"
+    )?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?;
+    let mut fab_ids = Vec::new();
+    let mut pairs = Vec::new();
+    for r in rows {
+        let (fab_id, json) = r?;
+        let meta: serde_json::Value = serde_json::from_str(&json).unwrap_or_default();
+        let chain = |key: &str| meta.get(key).and_then(|s| s.as_str()).unwrap_or_default().to_string();
+        fab_ids.push(fab_id);
+        pairs.push((chain("h_chain_seq"), chain("l_chain_seq")));
     }
-    conn.execute("COMMIT", [])?;
 
-    Ok(())
-}
\ No newline at end of file
+    let assignment = analysis::greedy_clusters(&pairs, identity);
+    db.with_transaction(|tx| {
+        let mut update = tx.prepare("UPDATE antibodies SET cluster_id = ?1, cluster_representative = ?2 WHERE fab_id = ?3")?;
+        for (i, &r) in assignment.iter().enumerate() {
+            update.execute(params![fab_ids[r], i == r, fab_ids[i]])?;
+        }
+        Ok(())
+    })?;
+
+    let clusters = assignment.iter().enumerate().filter(|&(i, &r)| i == r).count();
+    info!("Grouped {} Fabs into {} clones", assignment.len(), clusters);
+    Ok(clusters)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdb::StructureFormat;
+    use crate::progress::tests::RecordingProgress;
+    use crate::progress::NoProgress;
+    use crate::testing::{paired_fab, seed_fab, without_residues, MockStrategy};
+    use crate::numbering::Region;
+
+    // Both stages of `process_all`, with what numbering came to
+    fn check_and_process(db: &Db, strategy: &(dyn NumberingStrategy + Sync), options: &ProcessOptions, progress: &dyn ProgressSink) -> Result<ProcessingSummary> {
+        run_qc(db, &qc_thresholds(db)?, options.chunk_size, progress)?;
+        process_pending(db, strategy, options, progress)
+    }
+
+    #[test]
+    fn test_process_all() {
+        let db = Db::open_in_memory().unwrap();
+        // 2abc is a re-deposit of 1abc, 4abc is excluded
+        for (id, seed) in [("1abc", 1), ("2abc", 1), ("3abc", 2), ("4abc", 3)] {
+            db.insert_raw(id, "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+            db.put_structure(id, &paired_fab(seed, 30), StructureFormat::Pdb).unwrap();
+        }
+        db.blacklist("4abc", "test").unwrap();
+        let pdbs: Vec<Pdb> = (1..=2).map(|seed| Pdb::parse(&paired_fab(seed, 30), StructureFormat::Pdb)).collect();
+        let strategy = MockStrategy { kappa: pdbs.iter().map(|p| p.get_sequence('L')).collect(), ..Default::default() };
+        process_all(&db, &strategy, &ProcessOptions::default(), &NoProgress).unwrap();
+
+        let record = |id: &str| db.get_antibody(id).unwrap().unwrap();
+        for id in ["1abc", "2abc", "3abc"] {
+            let r = record(id);
+            assert!(r.processed && r.passed_qc, "{}", id);
+            assert_eq!(db.get_numbering(id, ChainType::Heavy).unwrap().len(), 30);
+            assert_eq!(db.get_numbering(id, ChainType::Kappa).unwrap().len(), 30);
+            assert_eq!(db.get_features(id).unwrap().len(), 2);
+        }
+        assert!(!record("4abc").processed);
+        let heavy = pdbs[0].get_sequence('H');
+        assert_eq!(record("1abc").cdr_h1.as_deref(), Some(&heavy[25..]));
+        // The CDR atoms are found from the stored numbering
+        let numbered = db.chain_numbering("1abc", ChainType::Heavy).unwrap().unwrap();
+        assert_eq!(numbered.residues.len(), 30);
+        let h1 = pdbs[0].extract_region(&numbered, Region::Cdr1);
+        assert_eq!(h1.atoms.iter().map(|a| a.res_seq).collect::<std::collections::BTreeSet<_>>(), (26..=30).collect());
+        assert!(h1.atoms.iter().all(|a| a.chain_id == 'H'));
+        let json: String = db.get_conn().query_row("SELECT json_blob FROM antibodies WHERE pdb_id = '1abc'", [], |row| row.get(0)).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(json["approximate_numbering"], false);
+        assert_eq!(json["h_chain_seq"], heavy.as_str());
+
+        // The re-deposit joins the clone of the original
+        assert_eq!(record("1abc").cluster_id, record("2abc").cluster_id);
+        assert_ne!(record("1abc").cluster_id, record("3abc").cluster_id);
+        assert_eq!(db.knn(&vec![0.0; analysis::EMBEDDING_DIM], 3, |_| true).unwrap().len(), 3);
+        assert!(db.meta_get(LAST_PROCESSING_RUN_KEY).unwrap().is_some());
+        assert!(db.failed_entries(ProcessingStage::NumberingH).unwrap().is_empty());
+
+        // Nothing left to do but features of an older version
+        db.get_conn().execute("UPDATE features SET version = 0 WHERE pdb_id = '1abc'", []).unwrap();
+        let failing = MockStrategy { fail_on: heavy, ..Default::default() };
+        process_all(&db, &failing, &ProcessOptions::default(), &NoProgress).unwrap();
+        assert!(db.failed_entries(ProcessingStage::NumberingH).unwrap().is_empty());
+        assert!(db.fabs_needing_features().unwrap().is_empty());
+        assert_eq!(db.get_features("1abc").unwrap().len(), 2);
+
+        // Once the original fails QC the re-deposit stands for the clone
+        assert_eq!(record("1abc").cluster_representative, Some(true));
+        db.get_conn().execute("UPDATE antibodies SET passed_qc = FALSE WHERE pdb_id = '1abc'", []).unwrap();
+        run_numbering(&db, &failing, &ProcessOptions::default(), &NoProgress).unwrap();
+        assert_eq!(record("2abc").cluster_representative, Some(true));
+        assert_eq!(record("1abc").cluster_id, record("2abc").cluster_id);
+    }
+
+    #[test]
+    fn test_processing_errors() {
+        let db = Db::open_in_memory().unwrap();
+        for (id, content) in [("1abc", paired_fab(1, 30)), ("2abc", paired_fab(2, 30)), ("3abc", "HEADER    IMMUNE SYSTEM".to_string())] {
+            db.insert_raw(id, "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+            db.put_structure(id, &content, StructureFormat::Pdb).unwrap();
+        }
+        let heavy = Pdb::parse(&paired_fab(1, 30), StructureFormat::Pdb).get_sequence('H');
+        let kappa: Vec<String> = (1..=2).map(|seed| Pdb::parse(&paired_fab(seed, 30), StructureFormat::Pdb).get_sequence('L')).collect();
+        let strategy = MockStrategy { fail_on: heavy, kappa: kappa.clone(), ..Default::default() };
+        assert_eq!(check_and_process(&db, &strategy, &ProcessOptions::default(), &NoProgress).unwrap().processed, 2);
+
+        let numbering = db.failed_entries(ProcessingStage::NumberingH).unwrap();
+        assert_eq!(numbering.iter().map(|e| (e.pdb_id.as_str(), e.error.as_str())).collect::<Vec<_>>(), [("1abc", "No antibody variable domain found")]);
+        let parse = db.failed_entries(ProcessingStage::Parse).unwrap();
+        assert_eq!(parse.iter().map(|e| e.pdb_id.as_str()).collect::<Vec<_>>(), ["3abc"]);
+        // A chain that is no antibody fails QC
+        let qc = db.failed_entries(ProcessingStage::Qc).unwrap();
+        assert_eq!(qc.iter().map(|e| (e.pdb_id.as_str(), e.error.as_str())).collect::<Vec<_>>(), [("1abc", "no antibody domain in the heavy chain")]);
+        assert!(!db.get_antibody("1abc").unwrap().unwrap().passed_qc);
+        let counts = db.stats().unwrap().processing_errors;
+        assert_eq!(counts.into_iter().collect::<Vec<_>>(), [("numbering_h".to_string(), 1), ("parse".to_string(), 1), ("qc".to_string(), 1)]);
+        // The unparsable entry is left to retry
+        assert!(!db.get_antibody("3abc").unwrap().unwrap().processed);
+
+        // Processed again without failing, the errors are cleared
+        db.get_conn().execute("UPDATE antibodies SET processed = FALSE WHERE pdb_id = '1abc'", []).unwrap();
+        let strategy = MockStrategy { kappa: kappa.clone(), ..Default::default() };
+        assert_eq!(check_and_process(&db, &strategy, &ProcessOptions::default(), &NoProgress).unwrap().processed, 1);
+        assert!(db.failed_entries(ProcessingStage::NumberingH).unwrap().is_empty());
+        assert!(db.failed_entries(ProcessingStage::Qc).unwrap().is_empty());
+        assert!(db.get_antibody("1abc").unwrap().unwrap().passed_qc);
+        assert_eq!(db.failed_entries(ProcessingStage::Parse).unwrap().len(), 1);
+        assert_eq!(db.get_numbering("1abc", ChainType::Kappa).unwrap().len(), 30);
+
+        // A light chain numbered as a heavy one is flagged and not kept
+        db.get_conn().execute("UPDATE antibodies SET processed = FALSE WHERE pdb_id = '1abc'", []).unwrap();
+        let strategy = MockStrategy::default();
+        assert_eq!(check_and_process(&db, &strategy, &ProcessOptions::default(), &NoProgress).unwrap().processed, 1);
+        let mismatch = db.failed_entries(ProcessingStage::NumberingL).unwrap();
+        assert_eq!(mismatch.iter().map(|e| (e.pdb_id.as_str(), e.error.as_str())).collect::<Vec<_>>(), [("1abc", "annotated as light chain, numbered as Heavy")]);
+        assert!(db.get_numbering("1abc", ChainType::Kappa).unwrap().is_empty());
+        assert_eq!(db.get_numbering("1abc", ChainType::Heavy).unwrap().len(), 30);
+
+        // Numbering that disagrees with the chain is flagged, not stored
+        db.get_conn().execute("UPDATE antibodies SET processed = FALSE WHERE pdb_id = '1abc'", []).unwrap();
+        let heavy = Pdb::parse(&paired_fab(1, 30), StructureFormat::Pdb).get_sequence('H');
+        let strategy = MockStrategy { kappa, substitute: vec![heavy], ..Default::default() };
+        assert_eq!(check_and_process(&db, &strategy, &ProcessOptions::default(), &NoProgress).unwrap().processed, 1);
+        let mismatch = db.failed_entries(ProcessingStage::NumberingH).unwrap();
+        assert_eq!(mismatch.len(), 1);
+        assert!(mismatch[0].error.starts_with("Numbered residue 5 is "), "{}", mismatch[0].error);
+        assert!(db.get_numbering("1abc", ChainType::Heavy).unwrap().is_empty());
+        assert_eq!(db.get_numbering("1abc", ChainType::Kappa).unwrap().len(), 30);
+    }
+
+    #[test]
+    fn test_processing_progress() {
+        let db = Db::open_in_memory().unwrap();
+        for (id, content) in [
+            ("1abc", paired_fab(1, 30)),
+            ("2abc", paired_fab(2, 30)),
+            ("3abc", paired_fab(3, 30)),
+            ("4abc", paired_fab(4, 30)),
+            ("5abc", "HEADER    IMMUNE SYSTEM".to_string()),
+        ] {
+            db.insert_raw(id, "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+            db.put_structure(id, &content, StructureFormat::Pdb).unwrap();
+        }
+        // 4abc's heavy chain is no antibody
+        let heavy = Pdb::parse(&paired_fab(4, 30), StructureFormat::Pdb).get_sequence('H');
+        let kappa = (1..=4).map(|seed| Pdb::parse(&paired_fab(seed, 30), StructureFormat::Pdb).get_sequence('L')).collect();
+        let strategy = MockStrategy { fail_on: heavy, kappa, ..Default::default() };
+        let progress = RecordingProgress::default();
+        // QC finds the unreadable structure, numbering takes the others
+        assert_eq!(run_qc(&db, &QcThresholds::default(), DEFAULT_CHUNK_SIZE, &progress).unwrap().unreadable, 1);
+        let summary = process_pending(&db, &strategy, &ProcessOptions::default(), &progress).unwrap();
+        assert_eq!(summary, ProcessingSummary { processed: 4, passed_qc: 3, failed_qc: 1, numbering_failed: 1, unreadable: 0 });
+        assert_eq!(summary.to_string(), "Processed 4 Fabs: 3 passed QC, 1 failed QC, 1 with numbering failures; 0 unreadable");
+
+        let events = progress.events.lock().unwrap().clone();
+        assert_eq!(events.first().map(String::as_str), Some("start Checking 5"));
+        let start = events.iter().position(|e| e == "start Processing 4").unwrap();
+        assert!(events[..start].contains(&"failed 5abc".to_string()));
+        let events = &events[start..];
+        assert_eq!(events.iter().filter(|e| e.starts_with("ok ")).count(), 4);
+        assert!(!events.iter().any(|e| e.starts_with("failed ")));
+        // The totals so far after every entry, and after numbering
+        assert_eq!(events.iter().filter(|e| e.starts_with("status ")).count(), 5);
+        assert_eq!(events[events.len() - 2], "status 75% passed QC, 1 numbering failures");
+        assert_eq!(events.last().map(String::as_str), Some("finish"));
+        let stats = db.stats().unwrap();
+        assert_eq!((stats.processed, stats.passed_qc, stats.failed_qc), (4, 3, 1));
+    }
+
+    // Fails its first `failures` batches as a crashing tool does, or every
+    // one as a missing tool does if `missing`
+    struct FlakyStrategy {
+        inner: MockStrategy,
+        failures: std::sync::atomic::AtomicUsize,
+        missing: bool,
+    }
+
+    impl NumberingStrategy for FlakyStrategy {
+        fn number(&self, sequence: &str, chain_type: &str) -> NumberingOutcome {
+            self.inner.number(sequence, chain_type)
+        }
+
+        fn number_batch(&self, sequences: &[(String, String)]) -> std::result::Result<Vec<NumberingOutcome>, NumberingError> {
+            use std::sync::atomic::Ordering;
+            if self.missing {
+                return Err(NumberingError::BinaryNotFound("ANARCII not found".to_string()));
+            }
+            if self.failures.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+                return Err(NumberingError::ExecutionFailed { stderr: "CUDA out of memory".to_string() });
+            }
+            self.inner.number_batch(sequences)
+        }
+    }
+
+    #[test]
+    fn test_numbering_failures() {
+        let db = Db::open_in_memory().unwrap();
+        db.insert_raw("1abc", "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+        db.put_structure("1abc", &paired_fab(1, 30), StructureFormat::Pdb).unwrap();
+        let kappa = vec![Pdb::parse(&paired_fab(1, 30), StructureFormat::Pdb).get_sequence('L')];
+        let flaky = |failures: usize, missing: bool| FlakyStrategy {
+            inner: MockStrategy { kappa: kappa.clone(), ..Default::default() },
+            failures: failures.into(),
+            missing,
+        };
+
+        // A missing tool stops processing with the Fab left to do
+        let error = process_all(&db, &flaky(0, true), &ProcessOptions::default(), &NoProgress).unwrap_err();
+        assert!(format!("{:#}", error).contains("ANARCII not found"), "{:#}", error);
+        assert!(!db.get_antibody("1abc").unwrap().unwrap().processed);
+
+        // One failed run is retried
+        assert_eq!(check_and_process(&db, &flaky(1, false), &ProcessOptions::default(), &NoProgress).unwrap().processed, 1);
+        assert_eq!(db.get_numbering("1abc", ChainType::Heavy).unwrap().len(), 30);
+
+        // Two are recorded
+        db.get_conn().execute("UPDATE antibodies SET processed = FALSE", []).unwrap();
+        assert_eq!(check_and_process(&db, &flaky(2, false), &ProcessOptions::default(), &NoProgress).unwrap().processed, 1);
+        for stage in [ProcessingStage::NumberingH, ProcessingStage::NumberingL] {
+            let failed = db.failed_entries(stage).unwrap();
+            assert_eq!(failed.iter().map(|e| e.error.as_str()).collect::<Vec<_>>(), ["Numbering tool failed: CUDA out of memory"]);
+        }
+        assert!(db.get_antibody("1abc").unwrap().unwrap().passed_qc);
+    }
+
+    // Numbers as `inner` does, panicking on `panic_on` as a crash would
+    struct CrashingStrategy {
+        inner: MockStrategy,
+        panic_on: String,
+    }
+
+    impl NumberingStrategy for CrashingStrategy {
+        fn number(&self, sequence: &str, chain_type: &str) -> NumberingOutcome {
+            assert_ne!(sequence, self.panic_on, "crashed");
+            self.inner.number(sequence, chain_type)
+        }
+    }
+
+    #[test]
+    fn test_resume_after_crash() {
+        let db = Db::open_in_memory().unwrap();
+        let ids = ["1abc", "2abc", "3abc", "4abc", "5abc"];
+        for (seed, id) in (1..).zip(ids) {
+            db.insert_raw(id, "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+            db.put_structure(id, &paired_fab(seed, 30), StructureFormat::Pdb).unwrap();
+        }
+        let pdbs: Vec<Pdb> = (1..=5).map(|seed| Pdb::parse(&paired_fab(seed, 30), StructureFormat::Pdb)).collect();
+        let inner = MockStrategy { kappa: pdbs.iter().map(|p| p.get_sequence('L')).collect(), ..Default::default() };
+        let options = ProcessOptions { chunk_size: 2, ..Default::default() };
+
+        // Crashes numbering the third Fab, in the second chunk
+        let crashing = CrashingStrategy { inner: inner.clone(), panic_on: pdbs[2].get_sequence('H') };
+        let crash = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| process_all(&db, &crashing, &options, &NoProgress)));
+        assert!(crash.is_err());
+        let processed = |id: &str| db.get_antibody(id).unwrap().unwrap().processed;
+        assert_eq!(ids.map(processed), [true, true, false, false, false]);
+        assert_eq!(db.get_numbering("2abc", ChainType::Heavy).unwrap().len(), 30);
+
+        // The rerun picks up after the first chunk
+        assert_eq!(check_and_process(&db, &inner, &options, &NoProgress).unwrap().processed, 3);
+        assert!(ids.map(processed).iter().all(|&p| p));
+    }
+
+    // Notes how many Fabs were committed as processed whenever a chunk is
+    // numbered
+    struct CommitWatchingStrategy<'a> {
+        inner: MockStrategy,
+        db: &'a Db,
+        batches: std::sync::Mutex<Vec<(usize, usize)>>,
+    }
+
+    impl NumberingStrategy for CommitWatchingStrategy<'_> {
+        fn number(&self, sequence: &str, chain_type: &str) -> NumberingOutcome {
+            self.inner.number(sequence, chain_type)
+        }
+
+        fn number_batch(&self, sequences: &[(String, String)]) -> std::result::Result<Vec<NumberingOutcome>, NumberingError> {
+            let processed = self.db.count_antibodies(&DbFilter { processed: Some(true), ..Default::default() }).unwrap();
+            self.batches.lock().unwrap().push((processed, sequences.len()));
+            self.inner.number_batch(sequences)
+        }
+    }
+
+    #[test]
+    fn test_bounded_batches() {
+        let db = Db::open_in_memory().unwrap();
+        let ids = ["1abc", "2abc", "3abc", "4abc", "5abc"];
+        let mut kappa = Vec::new();
+        for (seed, id) in (1..).zip(ids) {
+            let content = paired_fab(seed, 30);
+            kappa.push(Pdb::parse(&content, StructureFormat::Pdb).get_sequence('L'));
+            // Padded to about a megabyte, as large entries are
+            let padding: String = (0..12_000).map(|i| format!("REMARK 999 {:<69}\n", format!("{} {}", id, i * seed))).collect();
+            db.insert_raw(id, "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+            db.put_structure(id, &(padding + &content), StructureFormat::Pdb).unwrap();
+        }
+        let strategy = CommitWatchingStrategy { inner: MockStrategy { kappa, ..Default::default() }, db: &db, batches: Default::default() };
+        let options = ProcessOptions { chunk_size: 2, max_in_flight: 1, ..Default::default() };
+        assert_eq!(check_and_process(&db, &strategy, &options, &NoProgress).unwrap().processed, 5);
+
+        // Each chunk was committed before the next one was read
+        assert_eq!(*strategy.batches.lock().unwrap(), [(0, 4), (2, 4), (4, 2)]);
+        assert_eq!(db.get_numbering("5abc", ChainType::Kappa).unwrap().len(), 30);
+        assert_eq!(db.count_antibodies(&DbFilter { processed: Some(false), ..Default::default() }).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_single_chain_domains() {
+        let db = Db::open_in_memory().unwrap();
+        let content = paired_fab(3, 30);
+        db.insert_raw("1abc", "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+        db.put_structure("1abc", &content, StructureFormat::Pdb).unwrap();
+        let pdb = Pdb::parse(&content, StructureFormat::Pdb);
+        let (heavy, light) = (pdb.get_sequence('H'), pdb.get_sequence('L'));
+
+        // The heavy chain holds both domains, the light one numbers nothing
+        let strategy = MockStrategy { fail_on: light, scfv: vec![heavy.clone()], ..Default::default() };
+        assert_eq!(check_and_process(&db, &strategy, &ProcessOptions::default(), &NoProgress).unwrap().processed, 1);
+        let residues = |chain| db.get_numbering("1abc", chain).unwrap().iter().map(|&(_, r)| r).collect::<String>();
+        assert_eq!(residues(ChainType::Heavy), heavy[..15]);
+        assert_eq!(residues(ChainType::Kappa), heavy[15..]);
+        assert_eq!(db.failed_entries(ProcessingStage::NumberingL).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_fv_subset() {
+        let db = Db::open_in_memory().unwrap();
+        // The Fab bound to an antigen, chain A
+        let fab = paired_fab(1, 30);
+        let antigen: Vec<String> = paired_fab(2, 40).lines().filter(|l| &l[21..22] == "L").map(|l| format!("{}A{}", &l[..21], &l[22..])).collect();
+        db.insert_raw("1abc", "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+        db.put_structure("1abc", &format!("{}\n{}", fab, antigen.join("\n")), StructureFormat::Pdb).unwrap();
+        // The domains leave out two residues at either end of each chain
+        let pdb = Pdb::parse(&fab, StructureFormat::Pdb);
+        let strategy = MockStrategy { kappa: vec![pdb.get_sequence('L')], trim: 2, ..Default::default() };
+        assert_eq!(check_and_process(&db, &strategy, &ProcessOptions::default(), &NoProgress).unwrap().passed_qc, 1);
+
+        let fab_id = db.get_antibody("1abc").unwrap().unwrap().fab_id;
+        let fv = db.load_fv(fab_id).unwrap().unwrap();
+        assert!(fv.atoms.iter().all(|a| a.chain_id != 'A'));
+        for chain_id in ['H', 'L'] {
+            let residues: Vec<i32> = fv.numbered_sequence(chain_id).iter().map(|(id, _)| id.res_seq).collect();
+            assert_eq!(residues, (3..=28).collect::<Vec<_>>());
+        }
+        assert_eq!(fv.atoms.len(), 2 * 26 * 3);
+        let features = db.get_features("1abc").unwrap();
+        assert_eq!(features.iter().map(|c| (c.chain_id, c.seq.len())).collect::<Vec<_>>(), [('L', 26), ('H', 26)]);
+
+        // Taken again from the stored numbering with the features
+        db.store_fv(fab_id, None).unwrap();
+        db.get_conn().execute("UPDATE features SET version = 0", []).unwrap();
+        assert_eq!(backfill_features(&db).unwrap(), 1);
+        assert_eq!(db.load_fv(fab_id).unwrap().unwrap().atoms, fv.atoms);
+        assert_eq!(db.get_features("1abc").unwrap(), features);
+    }
+
+    #[test]
+    fn test_h3_loop_descriptors() {
+        let db = Db::open_in_memory().unwrap();
+        // Long enough for the mock numbering to reach H103
+        let fab = paired_fab(4, 115);
+        db.insert_raw("1abc", "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+        db.put_structure("1abc", &fab, StructureFormat::Pdb).unwrap();
+        let pdb = Pdb::parse(&fab, StructureFormat::Pdb);
+        let strategy = MockStrategy { kappa: vec![pdb.get_sequence('L')], ..Default::default() };
+        assert_eq!(check_and_process(&db, &strategy, &ProcessOptions::default(), &NoProgress).unwrap().passed_qc, 1);
+
+        // H95-H103 are residues 95-103 of the heavy chain
+        let h3: Vec<Atom> = pdb.atoms.iter().filter(|a| a.chain_id == 'H' && (95..=103).contains(&a.res_seq)).cloned().collect();
+        let expected = analysis::loop_descriptors(&h3);
+        assert_eq!(expected.length, 8);
+        let json = db.get_antibody("1abc").unwrap().unwrap().h3_loop.unwrap();
+        let stored: analysis::LoopDescriptors = serde_json::from_str(&json).unwrap();
+        // Up to the last bit JSON keeps
+        assert!(stored.distance(&expected) < 1e-9);
+    }
+
+    #[test]
+    fn test_heavy_chain_copies() {
+        let db = Db::open_in_memory().unwrap();
+        // Two copies of the heavy chain: H with residues 10-14 unresolved,
+        // I complete
+        let fab = paired_fab(5, 30);
+        let copy: Vec<String> = fab.lines().filter(|l| &l[21..22] == "H").map(|l| format!("{}I{}", &l[..21], &l[22..])).collect();
+        db.insert_raw("1abc", "H,I", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+        db.put_structure("1abc", &format!("{}\n{}", without_residues(&fab, 'H', 10..=14), copy.join("\n")), StructureFormat::Pdb).unwrap();
+        let pdb = Pdb::parse(&fab, StructureFormat::Pdb);
+        let strategy = MockStrategy { kappa: vec![pdb.get_sequence('L')], ..Default::default() };
+        assert_eq!(check_and_process(&db, &strategy, &ProcessOptions::default(), &NoProgress).unwrap().passed_qc, 1);
+
+        let record = db.get_antibody("1abc").unwrap().unwrap();
+        assert_eq!(record.primary_h_chain.as_deref(), Some("I"));
+        assert_eq!(record.chain_ids(), ('I', 'L'));
+        let residues = db.get_numbering("1abc", ChainType::Heavy).unwrap().iter().map(|&(_, r)| r).collect::<String>();
+        assert_eq!(residues, pdb.get_sequence('H'));
+        let features = db.get_features("1abc").unwrap();
+        assert_eq!(features.iter().map(|c| (c.chain_id, c.seq.len())).collect::<Vec<_>>(), [('L', 30), ('I', 30)]);
+
+        let json: String = db.get_conn().query_row("SELECT json_blob FROM antibodies WHERE pdb_id = '1abc'", [], |row| row.get(0)).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let alternates = json["alternate_chains"].as_array().unwrap();
+        assert_eq!(alternates.len(), 1);
+        assert_eq!(alternates[0]["chain_id"], "H");
+        assert_eq!(alternates[0]["resolved_residues"], 25);
+        assert_eq!(alternates[0]["passed_qc"], false);
+        assert_eq!(alternates[0]["numbered"], true);
+    }
+
+    #[test]
+    fn test_qc_thresholds() {
+        let db = Db::open_in_memory().unwrap();
+        // A chain break, as in a cryo-EM model with a disordered loop
+        let fab = paired_fab(6, 30);
+        seed_fab(&db, "1abc", &without_residues(&fab, 'H', 10..=14));
+        let pdb = Pdb::parse(&fab, StructureFormat::Pdb);
+        let strategy = MockStrategy { kappa: vec![pdb.get_sequence('L')], ..Default::default() };
+        assert_eq!(check_and_process(&db, &strategy, &ProcessOptions::default(), &NoProgress).unwrap().failed_qc, 1);
+        assert!(db.get_numbering("1abc", ChainType::Heavy).unwrap().is_empty());
+
+        // Passing under loose thresholds, it is numbered now
+        let loose = QcThresholds { max_geometric_gaps: 2, ..Default::default() };
+        assert_eq!(reevaluate_qc(&db, &loose).unwrap(), QcChanges { failed: 0, passed: 1 });
+        assert_eq!(reevaluate_qc(&db, &loose).unwrap(), QcChanges::default());
+        assert_eq!(qc_thresholds(&db).unwrap(), loose);
+        assert_eq!(check_and_process(&db, &strategy, &ProcessOptions::default(), &NoProgress).unwrap().passed_qc, 1);
+        assert_eq!(db.get_numbering("1abc", ChainType::Heavy).unwrap().len(), 25);
+        assert!(db.failed_entries(ProcessingStage::Qc).unwrap().is_empty());
+
+        // Back to the defaults it fails again, the numbering kept
+        assert_eq!(reevaluate_qc(&db, &QcThresholds::default()).unwrap(), QcChanges { failed: 1, passed: 0 });
+        let record = db.get_antibody("1abc").unwrap().unwrap();
+        assert_eq!((record.processed, record.passed_qc), (true, false));
+        assert_eq!(db.get_numbering("1abc", ChainType::Heavy).unwrap().len(), 25);
+        assert_eq!(db.failed_entries(ProcessingStage::Qc).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_run_qc() {
+        let db = Db::open_in_memory().unwrap();
+        // 2abc has residues 10-14 of its heavy chain unresolved, 3abc no atoms
+        seed_fab(&db, "1abc", &paired_fab(1, 30));
+        seed_fab(&db, "2abc", &without_residues(&paired_fab(2, 30), 'H', 10..=14));
+        seed_fab(&db, "3abc", "HEADER    IMMUNE SYSTEM");
+        let progress = RecordingProgress::default();
+        let summary = run_qc(&db, &QcThresholds::default(), DEFAULT_CHUNK_SIZE, &progress).unwrap();
+        assert_eq!(summary, QcSummary { checked: 2, passed_qc: 1, failed_qc: 1, unreadable: 1, changes: QcChanges::default() });
+        assert_eq!(progress.events.lock().unwrap().first().map(String::as_str), Some("start Checking 3"));
+
+        // Checked, but nothing of numbering is there
+        let record = db.get_antibody("1abc").unwrap().unwrap();
+        assert_eq!((record.processed, record.passed_qc), (false, true));
+        assert_eq!(record.primary_h_chain.as_deref(), Some("H"));
+        assert!(record.interface_contacts.unwrap() > 0);
+        let (report, numbered_h, json): (Option<String>, Option<bool>, Option<String>) = db.get_conn().query_row(
+            "SELECT qc_report, numbered_h, json_blob FROM antibodies WHERE pdb_id = '1abc'", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        ).unwrap();
+        assert!(serde_json::from_str::<QualityReport>(&report.unwrap()).unwrap().is_pass());
+        assert_eq!((numbered_h, json), (None, None));
+        assert!(db.get_numbering("1abc", ChainType::Heavy).unwrap().is_empty());
+        assert!(db.get_features("1abc").unwrap().is_empty());
+        assert_eq!(db.get_antibody("2abc").unwrap().unwrap().gaps, 2);
+        assert_eq!(db.failed_entries(ProcessingStage::Qc).unwrap().len(), 1);
+        assert_eq!(db.failed_entries(ProcessingStage::Parse).unwrap().len(), 1);
+        // Checked Fabs are not checked again
+        assert_eq!(run_qc(&db, &QcThresholds::default(), DEFAULT_CHUNK_SIZE, &NoProgress).unwrap().checked, 0);
+
+        // New thresholds apply to the unnumbered Fabs as they are
+        let loose = QcThresholds { max_geometric_gaps: 2, ..Default::default() };
+        assert_eq!(run_qc(&db, &loose, DEFAULT_CHUNK_SIZE, &NoProgress).unwrap().changes, QcChanges { failed: 0, passed: 1 });
+        let record = db.get_antibody("2abc").unwrap().unwrap();
+        assert_eq!((record.processed, record.passed_qc), (false, true));
+        assert!(db.failed_entries(ProcessingStage::Qc).unwrap().is_empty());
+
+        // Numbering takes the Fabs from there, leaving their QC columns
+        let kappa = [1, 2].map(|seed| Pdb::parse(&paired_fab(seed, 30), StructureFormat::Pdb).get_sequence('L')).to_vec();
+        let strategy = MockStrategy { kappa, ..Default::default() };
+        let summary = run_numbering(&db, &strategy, &ProcessOptions::default(), &NoProgress).unwrap();
+        assert_eq!((summary.processed, summary.passed_qc), (2, 2));
+        assert_eq!(db.get_numbering("1abc", ChainType::Heavy).unwrap().len(), 30);
+        assert_eq!(db.get_antibody("2abc").unwrap().unwrap().gaps, 2);
+    }
+
+    #[test]
+    fn test_process_failed() {
+        let db = Db::open_in_memory().unwrap();
+        let fabs: Vec<String> = (1..=3).map(|seed| paired_fab(seed, 30)).collect();
+        seed_fab(&db, "1abc", &fabs[0]);
+        seed_fab(&db, "2abc", &fabs[1]);
+        // 3abc fails QC, with residues 10-14 of its heavy chain unresolved
+        seed_fab(&db, "3abc", &without_residues(&fabs[2], 'H', 10..=14));
+        let pdbs: Vec<Pdb> = fabs.iter().map(|f| Pdb::parse(f, StructureFormat::Pdb)).collect();
+        let kappa: Vec<String> = pdbs.iter().map(|p| p.get_sequence('L')).collect();
+        let failing = MockStrategy { fail_on: pdbs[0].get_sequence('H'), kappa: kappa.clone(), ..Default::default() };
+        process_all(&db, &failing, &ProcessOptions::default(), &NoProgress).unwrap();
+        // 2abc as left by a run before the numbering was recorded per chain
+        db.get_conn().execute_batch(
+            "DELETE FROM numbering WHERE pdb_id = '2abc' AND chain_type = 'H';
+             UPDATE antibodies SET numbered_h = NULL WHERE pdb_id = '2abc';",
+        ).unwrap();
+        let ids = |db: &Db| db.fabs_with_failed_numbering().unwrap().into_iter().map(|r| r.pdb_id).collect::<Vec<_>>();
+        assert_eq!(ids(&db), ["1abc", "2abc"]);
+        assert!(db.get_numbering("1abc", ChainType::Heavy).unwrap().is_empty());
+
+        let strategy = MockStrategy { kappa, ..Default::default() };
+        let summary = process_failed(&db, &strategy, &ProcessOptions::default(), &NoProgress).unwrap();
+        assert_eq!((summary.processed, summary.passed_qc, summary.numbering_failed), (2, 2, 0));
+        for id in ["1abc", "2abc"] {
+            assert_eq!(db.get_numbering(id, ChainType::Heavy).unwrap().len(), 30, "{}", id);
+            assert!(db.get_antibody(id).unwrap().unwrap().passed_qc);
+        }
+        assert!(db.failed_entries(ProcessingStage::NumberingH).unwrap().is_empty());
+        assert!(ids(&db).is_empty());
+        assert_eq!(process_failed(&db, &strategy, &ProcessOptions::default(), &NoProgress).unwrap(), ProcessingSummary::default());
+    }
+
+    #[test]
+    fn test_chain_pairing() {
+        let db = Db::open_in_memory().unwrap();
+        // Two Fabs in the asymmetric unit, H/L and I/M 80 A apart
+        let fab = paired_fab(1, 30);
+        let moved = fab.lines().map(|l| {
+            let chain = if &l[21..22] == "H" { 'I' } else { 'M' };
+            let x: f64 = l[30..38].trim().parse().unwrap();
+            format!("{}{}{}{:>8.3}{}", &l[..21], chain, &l[22..30], x + 80.0, &l[38..])
+        });
+        let content: Vec<String> = fab.lines().map(str::to_string).chain(moved).collect();
+        // The first lists a light chain of the other Fab first, the second
+        // is paired across Fabs
+        db.insert_raw("1abc", "H", "M,L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+        db.insert_raw("1abc", "I", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+        db.put_structure("1abc", &content.join("\n"), StructureFormat::Pdb).unwrap();
+        let kappa = vec![Pdb::parse(&fab, StructureFormat::Pdb).get_sequence('L')];
+        let strategy = MockStrategy { kappa, ..Default::default() };
+        let summary = check_and_process(&db, &strategy, &ProcessOptions::default(), &NoProgress).unwrap();
+        assert_eq!((summary.passed_qc, summary.failed_qc), (1, 1));
+
+        let records = db.list_antibodies(&DbFilter::default()).unwrap();
+        assert_eq!(records[0].chain_ids(), ('H', 'L'));
+        assert!(records[0].passed_qc);
+        assert!(records[0].interface_contacts.unwrap() > 0);
+        assert_eq!(records[1].interface_contacts, Some(0));
+        assert!(!records[1].passed_qc);
+        let qc = db.failed_entries(ProcessingStage::Qc).unwrap();
+        assert_eq!(qc.iter().map(|e| (e.fab_id, e.error.as_str())).collect::<Vec<_>>(), [(records[1].fab_id, "heavy and light chain not in contact, likely from different Fabs")]);
+        let json: String = db.get_conn().query_row("SELECT json_blob FROM antibodies WHERE fab_id = ?1", [records[1].fab_id], |row| row.get(0)).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(json["qc"]["pairing_suspect"], true);
+    }
+}
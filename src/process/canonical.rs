@@ -0,0 +1,160 @@
+//! Canonical classes of the non-H3 CDR loops (Chothia & Lesk 1987,
+//! Al-Lazikani et al. 1997): the backbone conformation of L1-L3, H1 and H2
+//! follows from the loop length and a few key residues, looked up in `RULES`
+//! on the Martin numbering. CDR-H3 has no canonical classes.
+use crate::numbering::{self, ChainType, Position, Scheme};
+
+/// Class of a loop no rule matches
+pub const UNKNOWN: &str = "none";
+
+/// A CDR loop with canonical classes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Loop {
+    L1,
+    L2,
+    L3,
+    H1,
+    H2,
+}
+
+impl Loop {
+    pub const ALL: [Loop; 5] = [Loop::L1, Loop::L2, Loop::L3, Loop::H1, Loop::H2];
+
+    /// The loops of a chain; kappa and lambda have the same.
+    pub fn of(chain: ChainType) -> &'static [Loop] {
+        match chain {
+            ChainType::Heavy => &[Loop::H1, Loop::H2],
+            ChainType::Kappa | ChainType::Lambda => &[Loop::L1, Loop::L2, Loop::L3],
+        }
+    }
+
+    /// Column of the `antibodies` table holding the class.
+    pub fn column(self) -> &'static str {
+        match self {
+            Loop::L1 => "canonical_l1",
+            Loop::L2 => "canonical_l2",
+            Loop::L3 => "canonical_l3",
+            Loop::H1 => "canonical_h1",
+            Loop::H2 => "canonical_h2",
+        }
+    }
+
+    // Chothia definition of the loop, as the CDR columns use
+    fn range(self) -> (Position, Position) {
+        let (chain, cdr) = match self {
+            Loop::L1 => (ChainType::Kappa, 0),
+            Loop::L2 => (ChainType::Kappa, 1),
+            Loop::L3 => (ChainType::Kappa, 2),
+            Loop::H1 => (ChainType::Heavy, 0),
+            Loop::H2 => (ChainType::Heavy, 1),
+        };
+        let range = numbering::cdr_ranges(Scheme::Martin, chain)[cdr].clone();
+        (*range.start(), *range.end())
+    }
+}
+
+struct Rule {
+    cdr: Loop,
+    /// Only for kappa light chains
+    kappa_only: bool,
+    /// Residues within the loop
+    length: usize,
+    /// Allowed residues per key position
+    key: &'static [(&'static str, &'static str)],
+    class: &'static str,
+}
+
+/// Classes with their defining length and key residues, first match wins.
+/// Lambda L1 and L3 classes, and the rarer kappa ones, are not covered.
+const RULES: &[Rule] = &[
+    Rule { cdr: Loop::L1, kappa_only: true, length: 10, key: &[("2", "I"), ("25", "A"), ("30", "V"), ("33", "ML"), ("71", "Y")], class: "1" },
+    Rule { cdr: Loop::L1, kappa_only: true, length: 11, key: &[("2", "I"), ("25", "A"), ("29", "IVL"), ("33", "LVM"), ("71", "FY")], class: "2" },
+    Rule { cdr: Loop::L1, kappa_only: true, length: 17, key: &[("2", "I"), ("25", "S"), ("33", "L"), ("71", "F")], class: "3" },
+    Rule { cdr: Loop::L1, kappa_only: true, length: 16, key: &[("2", "VI"), ("25", "S"), ("33", "L"), ("71", "F")], class: "4" },
+    Rule { cdr: Loop::L1, kappa_only: true, length: 12, key: &[("2", "I"), ("25", "A"), ("29", "V"), ("33", "L"), ("71", "F")], class: "6" },
+    Rule { cdr: Loop::L2, kappa_only: false, length: 7, key: &[("48", "IV"), ("64", "G")], class: "1" },
+    Rule { cdr: Loop::L3, kappa_only: true, length: 9, key: &[("90", "QNH"), ("95", "P")], class: "1" },
+    Rule { cdr: Loop::H1, kappa_only: false, length: 7, key: &[("24", "ATGV"), ("26", "G"), ("27", "FYG"), ("29", "FLI"), ("34", "MWIV"), ("94", "RK")], class: "1" },
+    Rule { cdr: Loop::H1, kappa_only: false, length: 8, key: &[("24", "VF"), ("26", "G"), ("27", "FYG"), ("29", "IL"), ("34", "WV"), ("94", "R")], class: "2" },
+    Rule { cdr: Loop::H1, kappa_only: false, length: 9, key: &[("24", "VG"), ("26", "G"), ("27", "FYG"), ("29", "LI"), ("34", "W"), ("94", "RH")], class: "3" },
+    Rule { cdr: Loop::H2, kappa_only: false, length: 5, key: &[("55", "GD"), ("71", "VKR")], class: "1" },
+    Rule { cdr: Loop::H2, kappa_only: false, length: 6, key: &[("52A", "PTA"), ("55", "GS"), ("71", "ALT")], class: "2" },
+    Rule { cdr: Loop::H2, kappa_only: false, length: 6, key: &[("54", "GSN"), ("71", "R")], class: "3" },
+    Rule { cdr: Loop::H2, kappa_only: false, length: 8, key: &[("52B", "KGNT"), ("55", "GY"), ("71", "R")], class: "4" },
+];
+
+/// Class of a loop from the Martin numbering of its chain, `UNKNOWN` if no
+/// rule matches.
+pub fn classify(cdr: Loop, numbered: &[(Position, char)], lambda: bool) -> &'static str {
+    let (first, last) = cdr.range();
+    let length = numbered.iter().filter(|(p, _)| p.in_range(first, last, Scheme::Martin)).count();
+    let residue_at = |position: &str| numbered.iter().find(|(p, _)| p.to_string() == position).map(|&(_, r)| r);
+    RULES.iter()
+        .filter(|rule| rule.cdr == cdr && rule.length == length && !(rule.kappa_only && lambda))
+        .find(|rule| rule.key.iter().all(|(position, allowed)| residue_at(position).is_some_and(|r| allowed.contains(r))))
+        .map_or(UNKNOWN, |rule| rule.class)
+}
+
+/// Classes of the loops of a numbered chain. A light chain counts as lambda
+/// if `chain` says so or its numbering shows it.
+pub fn chain_classes(numbered: &[(Position, char)], chain: ChainType) -> Vec<(Loop, &'static str)> {
+    let positions: Vec<Position> = numbered.iter().map(|&(p, _)| p).collect();
+    let lambda = chain == ChainType::Lambda || numbering::infer_light_type(&positions) == Some(ChainType::Lambda);
+    Loop::of(chain).iter().map(|&cdr| (cdr, classify(cdr, numbered, lambda))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Residues from `first` on, one position each
+    fn run(first: u32, residues: &str) -> Vec<(Position, char)> {
+        residues.chars().enumerate().map(|(i, r)| (Position::new(first + i as u32, None), r)).collect()
+    }
+
+    fn at(position: &str, residue: char) -> (Position, char) {
+        (position.parse().unwrap(), residue)
+    }
+
+    #[test]
+    fn test_published_classes() {
+        // D1.3 (1VFB): H1 1, H2 1, kappa L1 2 (11 residues), L2 1, L3 1
+        let mut heavy = run(24, "VSGFSLTGYGV");
+        heavy.extend(run(52, "WGDGN"));
+        heavy.extend([at("71", 'K'), at("94", 'R')]);
+        assert_eq!(chain_classes(&heavy, ChainType::Heavy), [(Loop::H1, "1"), (Loop::H2, "1")]);
+        let mut light = vec![at("2", 'I')];
+        light.extend(run(24, "RASGNIHNYLA"));
+        light.extend(run(48, "VYYTTTLAD"));
+        light.extend([at("64", 'G'), at("71", 'Y')]);
+        light.extend(run(89, "QHFWSTPRT"));
+        assert_eq!(chain_classes(&light, ChainType::Kappa), [(Loop::L1, "2"), (Loop::L2, "1"), (Loop::L3, "1")]);
+
+        // Trastuzumab (1N8Z): H2 2, with its insertion at H52A
+        let mut heavy = run(24, "ASGFNIKDTYI");
+        heavy.extend([at("52", 'Y'), at("52A", 'P')]);
+        heavy.extend(run(53, "TNGY"));
+        heavy.extend([at("71", 'A'), at("94", 'R')]);
+        assert_eq!(chain_classes(&heavy, ChainType::Heavy), [(Loop::H1, "1"), (Loop::H2, "2")]);
+        // L1 class 2 with valine at L33
+        let mut light = vec![at("2", 'I'), at("71", 'F')];
+        light.extend(run(24, "RASQDVNTAVA"));
+        assert_eq!(classify(Loop::L1, &light, false), "2");
+        // Kappa-only classes do not apply to lambda chains
+        assert_eq!(classify(Loop::L1, &light, true), UNKNOWN);
+    }
+
+    #[test]
+    fn test_unknown_combinations() {
+        // Right length, wrong key residue: glycine at H71 fits no H2 class
+        let mut heavy = run(52, "WGDGN");
+        heavy.push(at("71", 'G'));
+        assert_eq!(classify(Loop::H2, &heavy, false), UNKNOWN);
+        // No class of that length
+        let heavy = run(52, "WGD");
+        assert_eq!(classify(Loop::H2, &heavy, false), UNKNOWN);
+        // Key residue missing from the numbering
+        assert_eq!(classify(Loop::H1, &run(26, "GFSLTGY"), false), UNKNOWN);
+        assert_eq!(chain_classes(&[], ChainType::Kappa), [(Loop::L1, UNKNOWN), (Loop::L2, UNKNOWN), (Loop::L3, UNKNOWN)]);
+    }
+}
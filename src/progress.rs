@@ -0,0 +1,139 @@
+//! Progress reporting for the long-running pipeline stages.
+use indicatif::{ProgressBar, ProgressStyle};
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Receives per-item progress of a stage. Called from worker threads.
+pub trait ProgressSink: Sync {
+    fn start(&self, stage: &str, total: usize);
+    fn succeeded(&self, id: &str);
+    fn failed(&self, id: &str, error: &str);
+    fn finish(&self);
+
+    /// Running totals of the stage beyond the item counts, replacing the
+    /// last ones.
+    fn status(&self, _status: &str) {}
+}
+
+/// Discards all progress.
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn start(&self, _stage: &str, _total: usize) {}
+    fn succeeded(&self, _id: &str) {}
+    fn failed(&self, _id: &str, _error: &str) {}
+    fn finish(&self) {}
+}
+
+/// Terminal progress bar with completed/total, rate, failure count, the
+/// stage's status and ETA.
+pub struct BarProgress {
+    bar: ProgressBar,
+    failures: AtomicUsize,
+    status: Mutex<String>,
+}
+
+impl BarProgress {
+    pub fn new() -> Self {
+        let bar = ProgressBar::hidden();
+        bar.set_style(
+            ProgressStyle::with_template("{prefix} [{bar:40}] {pos}/{len} {msg} ({per_sec}, ETA {eta})")
+                .expect("valid template")
+                .progress_chars("=> "),
+        );
+        Self { bar, failures: AtomicUsize::new(0), status: Mutex::new(String::new()) }
+    }
+
+    // The failure count, then the status
+    fn update_message(&self) {
+        let failures = self.failures.load(Ordering::Relaxed);
+        let status = self.status.lock();
+        let message = match (failures, status.is_empty()) {
+            (0, _) => status.clone(),
+            (n, true) => format!("{} failed", n),
+            (n, false) => format!("{} failed, {}", n, status),
+        };
+        self.bar.set_message(message);
+    }
+}
+
+impl Default for BarProgress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressSink for BarProgress {
+    fn start(&self, stage: &str, total: usize) {
+        self.failures.store(0, Ordering::Relaxed);
+        self.status.lock().clear();
+        self.bar.set_draw_target(indicatif::ProgressDrawTarget::stderr());
+        self.bar.reset();
+        self.bar.set_length(total as u64);
+        self.bar.set_prefix(stage.to_string());
+        self.bar.set_message("");
+    }
+
+    fn succeeded(&self, _id: &str) {
+        self.bar.inc(1);
+    }
+
+    fn failed(&self, _id: &str, _error: &str) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        self.update_message();
+        self.bar.inc(1);
+    }
+
+    fn finish(&self) {
+        self.bar.finish();
+    }
+
+    fn status(&self, status: &str) {
+        *self.status.lock() = status.to_string();
+        self.update_message();
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Keeps every event as a string, for asserting on the reported sequence.
+    #[derive(Default)]
+    pub(crate) struct RecordingProgress {
+        pub events: Mutex<Vec<String>>,
+    }
+
+    impl ProgressSink for RecordingProgress {
+        fn start(&self, stage: &str, total: usize) {
+            self.events.lock().unwrap().push(format!("start {} {}", stage, total));
+        }
+        fn succeeded(&self, id: &str) {
+            self.events.lock().unwrap().push(format!("ok {}", id));
+        }
+        fn failed(&self, id: &str, _error: &str) {
+            self.events.lock().unwrap().push(format!("failed {}", id));
+        }
+        fn finish(&self) {
+            self.events.lock().unwrap().push("finish".to_string());
+        }
+        fn status(&self, status: &str) {
+            self.events.lock().unwrap().push(format!("status {}", status));
+        }
+    }
+
+    #[test]
+    fn test_bar_counts_failures() {
+        let progress = BarProgress::new();
+        progress.start("Downloading", 3);
+        progress.succeeded("1abc");
+        progress.failed("2abc", "404");
+        progress.failed("3abc", "timeout");
+        assert_eq!(progress.bar.position(), 3);
+        assert_eq!(progress.bar.message(), "2 failed");
+        progress.status("1 retried");
+        assert_eq!(progress.bar.message(), "2 failed, 1 retried");
+        progress.finish();
+    }
+}
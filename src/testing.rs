@@ -0,0 +1,118 @@
+//! Fixtures shared by the unit tests of several modules.
+use crate::db::Db;
+use crate::numbering::{ChainType, NumberingError, NumberingOutcome, NumberingResult, NumberingStrategy, Position, Scheme};
+use crate::pdb::{Atom, Point, StructureFormat};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::ops::RangeInclusive;
+
+const RESIDUES: &[u8] = b"ACDEFGHIKLMNPQRSTVWY";
+
+/// PDB text of a Fab, light then heavy chain, as jittered N-CA-C helices of
+/// random residues: `length` residues per chain, determined by `seed`.
+pub fn synthetic_fab(seed: u64, length: usize) -> String {
+    fab_with_offset(seed, length, 25.0)
+}
+
+/// As `synthetic_fab`, with the heavy chain packed against the light chain
+/// as processing expects of a Fab.
+pub fn paired_fab(seed: u64, length: usize) -> String {
+    fab_with_offset(seed, length, 9.0)
+}
+
+// Helix axes `h_offset` apart along x
+fn fab_with_offset(seed: u64, length: usize, h_offset: f64) -> String {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut lines = Vec::new();
+    for (chain_id, offset) in [('L', 0.0), ('H', h_offset)] {
+        for i in 0..length {
+            let res_name = crate::pdb::one_to_three(RESIDUES[rng.random_range(0..RESIDUES.len())] as char);
+            for (j, name) in ["N", "CA", "C"].iter().enumerate() {
+                let k = (i * 3 + j) as f64;
+                let jitter = |rng: &mut StdRng| rng.random_range(-0.15..0.15);
+                let (x, y, z) = (offset + 2.3 * (0.6 * k).cos() + jitter(&mut rng), 2.3 * (0.6 * k).sin() + jitter(&mut rng), 0.5 * k);
+                lines.push(format!(
+                    "ATOM  {:>5} {:<4} {:>3} {}{:>4}    {:>8.3}{:>8.3}{:>8.3}{:>6.2}{:>6.2}          {:>2}",
+                    lines.len() + 1, name, res_name, chain_id, i + 1, x, y, z, rng.random_range(0.5..1.0), rng.random_range(10.0..60.0), &name[..1]
+                ));
+            }
+        }
+    }
+    lines.join("\n")
+}
+
+/// PDB text with the residues `res_seqs` of chain `chain_id` left out, as
+/// if unresolved.
+pub fn without_residues(pdb: &str, chain_id: char, res_seqs: RangeInclusive<i32>) -> String {
+    pdb.lines()
+        .filter(|l| {
+            let res_seq = l.get(22..26).and_then(|s| s.trim().parse().ok());
+            l.chars().nth(21) != Some(chain_id) || !res_seq.is_some_and(|r| res_seqs.contains(&r))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Adds an X-ray entry of a Fab with heavy chain H and light chain L and
+/// stores `pdb` as its structure.
+pub fn seed_fab(db: &Db, pdb_id: &str, pdb: &str) {
+    db.insert_raw(pdb_id, "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+    db.put_structure(pdb_id, pdb, StructureFormat::Pdb).unwrap();
+}
+
+/// Planar zig-zag backbone (N, CA, C per residue) with ~1.44A spacing between consecutive atoms
+pub fn backbone(chain_id: char, residues: &[(i32, char)], origin: Point) -> Vec<Atom> {
+    let mut atoms = Vec::new();
+    for (i, &(res_seq, i_code)) in residues.iter().enumerate() {
+        for (j, name) in ["N", "CA", "C"].iter().enumerate() {
+            let k = i * 3 + j;
+            let pos = Point::new(origin.x + k as f64 * 1.2, origin.y + (k % 2) as f64 * 0.8, origin.z);
+            atoms.push(Atom {
+                serial: k as i32 + 1, name: name.to_string(), alt_loc: ' ', res_name: "ALA".into(),
+                chain_id, res_seq, i_code, pos, occupancy: 1.0, temp_factor: 0.0, element: name[..1].into()
+            });
+        }
+    }
+    atoms
+}
+
+/// Numbering without ANARCI for tests: residues numbered from 1 as a heavy
+/// domain, or kappa if listed in `kappa`. `fail_on` numbers nothing,
+/// sequences in `scfv` split into a heavy and a kappa half, and those in
+/// `substitute` come back with their fifth residue replaced. Otherwise the
+/// `trim` residues at either end are left outside the domain.
+#[derive(Debug, Default, Clone)]
+pub struct MockStrategy {
+    pub fail_on: String,
+    pub kappa: Vec<String>,
+    pub scfv: Vec<String>,
+    pub substitute: Vec<String>,
+    pub trim: usize,
+}
+
+fn mock_domain(chain_type: ChainType, sequence: &str, query_start: usize) -> NumberingResult {
+    let positions = sequence.chars().enumerate().map(|(i, c)| (Position::new(i as u32 + 1, None), c)).collect();
+    NumberingResult::new(chain_type, 30.0, Scheme::Martin, positions, query_start, query_start + sequence.len() - 1)
+}
+
+impl NumberingStrategy for MockStrategy {
+    fn number(&self, sequence: &str, _chain_type: &str) -> NumberingOutcome {
+        if sequence == self.fail_on {
+            return Err(NumberingError::NoDomainDetected);
+        }
+        if self.scfv.iter().any(|s| s == sequence) {
+            let half = sequence.len() / 2;
+            return Ok(vec![mock_domain(ChainType::Heavy, &sequence[..half], 0), mock_domain(ChainType::Kappa, &sequence[half..], half)]);
+        }
+        let chain_type = if self.kappa.iter().any(|s| s == sequence) { ChainType::Kappa } else { ChainType::Heavy };
+        let trimmed = &sequence[self.trim.min(sequence.len())..sequence.len().saturating_sub(self.trim)];
+        if trimmed.is_empty() {
+            return Err(NumberingError::NoDomainDetected);
+        }
+        let mut domain = mock_domain(chain_type, trimmed, self.trim);
+        if self.substitute.iter().any(|s| s == sequence) {
+            domain.positions[4].1 = if domain.positions[4].1 == 'X' { 'A' } else { 'X' };
+        }
+        Ok(vec![domain])
+    }
+}
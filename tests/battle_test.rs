@@ -23,7 +23,7 @@ impl XorShift {
 #[test]
 fn test_battle_shake() {
     let output_init = Command::new("cargo")
-        .args(&["run", "--", "dummy_init.pdb"])
+        .args(["run", "--", "dummy_init.pdb"])
         .current_dir(".")
         .output()
         .expect("Failed to run init");
@@ -80,7 +80,7 @@ fn test_battle_shake() {
     fs::write(perturbed_file, perturbed_lines.join("\n")).unwrap();
 
     let output = Command::new("cargo")
-        .args(&["run", "--release", "--", perturbed_file]) 
+        .args(["run", "--release", "--", perturbed_file]) 
         .current_dir(".")
         .output()
         .expect("Failed to run match");
@@ -1,11 +1,15 @@
+use scaffolding_lna_rs::db::{self, Db};
+use scaffolding_lna_rs::pdb::StructureFormat;
+use scaffolding_lna_rs::process;
+use scaffolding_lna_rs::progress::NoProgress;
 use std::process::Command;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::fs;
 
 #[test]
 fn test_cli_help() {
     let output = Command::new("cargo")
-        .args(&["run", "--", "--help"])
+        .args(["run", "--", "--help"])
         .current_dir(".")
         .output()
         .expect("Failed to run cargo");
@@ -15,44 +19,68 @@ fn test_cli_help() {
     assert!(stdout.contains("Usage:"));
 }
 
+// A database in a temporary directory holding one processed Fab, so
+// matching needs neither the network nor ./data
+fn seeded_db(dir: &Path) -> PathBuf {
+    let path = dir.join("antibodies.db");
+    let db = Db::open(&path).unwrap();
+    db.insert_raw("1abc", "H", "L", Some(2.0), "homo sapiens", "X-RAY DIFFRACTION", false).unwrap();
+    let mut lines = Vec::new();
+    for (chain_id, offset) in [('L', 0.0), ('H', 20.0)] {
+        for i in 0..12 {
+            for (j, name) in ["N", "CA", "C"].iter().enumerate() {
+                let k = (i * 3 + j) as f64;
+                lines.push(format!(
+                    "ATOM  {:>5} {:<4} TYR {}{:>4}    {:>8.3}{:>8.3}{:>8.3}  1.00 20.00           {}",
+                    lines.len() + 1, name, chain_id, i + 1, offset + 2.3 * (0.6 * k).cos(), 2.3 * (0.6 * k).sin(), 0.5 * k, &name[..1]
+                ));
+            }
+        }
+    }
+    db.put_structure("1abc", &lines.join("\n"), StructureFormat::Pdb).unwrap();
+    process::process_all_default(&db, &process::ProcessOptions::default(), &NoProgress).unwrap();
+    assert!(db.is_populated().unwrap());
+    path
+}
+
+const TEST_INPUT: &str = "ATOM      1  N   ALA A   1      10.000  10.000  10.000  1.00  0.00           N\n\
+                          ATOM      2  CA  ALA A   1      11.500  10.000  10.000  1.00  0.00           C";
+
 #[test]
 fn test_match_command() {
-    // Setup dummy DB
-    let data_dir = Path::new("data");
-    let _ = fs::create_dir_all(data_dir);
-    
-    // We assume the DB might be empty or partially filled from previous runs.
-    // We create a dummy input pdb
-    let test_pdb = "test_input.pdb";
-    fs::write(test_pdb, 
-        "ATOM      1  N   ALA A   1      10.000  10.000  10.000  1.00  0.00           N\n\
-         ATOM      2  CA  ALA A   1      11.500  10.000  10.000  1.00  0.00           C"
-    ).unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = seeded_db(dir.path());
+    let test_pdb = dir.path().join("test_input.pdb");
+    fs::write(&test_pdb, TEST_INPUT).unwrap();
 
     let output = Command::new("cargo")
         // Just pass the file path directly, no subcommand
-        .args(&["run", "--", test_pdb])
+        .arg("run").arg("--").arg(&test_pdb).arg("--db-path").arg(&db_path)
         .current_dir(".")
         .env("RUST_LOG", "debug")
         .output()
         .expect("Failed to run match");
 
-    let _ = fs::remove_file(test_pdb);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "STDERR: {}", String::from_utf8_lossy(&output.stderr));
+    // Expect JSON output
+    assert!(stdout.trim().starts_with("["));
+    assert!(stdout.trim().ends_with("]"));
+}
 
-    // It might fail if DB is not populated (network issue etc during test), 
-    // but at least it should run.
-    // If it fails with "No such file or directory" for DB items, it means logic ran.
-    
-    // Check if it panicked
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        println!("STDERR: {}", stderr);
-        // We allow failure if it's just "network" or "empty db" related, 
-        // but we want to ensure the binary executes.
-    } else {
-         let stdout = String::from_utf8_lossy(&output.stdout);
-         // Expect JSON output
-         assert!(stdout.trim().starts_with("["));
-         assert!(stdout.trim().ends_with("]"));
-    }
+#[test]
+fn test_db_path_from_env() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = seeded_db(dir.path());
+
+    let output = Command::new("cargo")
+        .args(["run", "--", "stats"])
+        .current_dir(".")
+        .env(db::DB_PATH_ENV, &db_path)
+        .output()
+        .expect("Failed to run stats");
+
+    assert!(output.status.success(), "STDERR: {}", String::from_utf8_lossy(&output.stderr));
+    let stats: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(stats["fabs"], 1);
 }